@@ -0,0 +1,95 @@
+// Times VM::run on a few standard programs. Each program is compiled once and
+// run repeatedly, so scanning and compiling are not part of the numbers.
+//
+// Lox has no control flow yet, so the loops are unrolled by the generators
+// below: additions steps Fibonacci numbers with global additions and
+// assignments, increment is `i = i + 1`, loop is a counting loop body with its
+// condition, and strings builds a string one concatenation at a time.
+//
+//     cargo bench
+//     cargo bench --features nan-boxing
+//     cargo bench -- additions
+use lox::lox::vm::VM;
+
+use std::env;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 200;
+
+fn additions(n: usize) -> String {
+    let mut source = String::from("var a = 0;\nvar b = 1;\nvar t = 0;\n");
+    for _ in 0..n {
+        source.push_str("t = a + b;\na = b;\nb = t;\n");
+    }
+    source + "print a;\n"
+}
+
+fn increments(n: usize) -> String {
+    let mut source = String::from("var i = 0;\n");
+    for _ in 0..n {
+        source.push_str("i = i + 1;\n");
+    }
+    source + "print i;\n"
+}
+
+fn loops(n: usize) -> String {
+    let mut source = String::from("var i = 0;\nvar sum = 0;\n");
+    for _ in 0..n {
+        source.push_str("i < 100000 == true;\nsum = sum + i * 2 - i / 4;\ni = i + 1;\n");
+    }
+    source + "print sum;\n"
+}
+
+fn strings(n: usize) -> String {
+    let mut source = String::from("var s = \"\";\n");
+    for i in 0..n {
+        source.push_str(if i % 2 == 0 {
+            "s = s + \"ab\";\n"
+        } else {
+            "s = \"\" + s;\n"
+        });
+    }
+    source + "print s == s;\n"
+}
+
+fn bench(name: &str, source: &str) {
+    let mut vm = VM::new();
+    let chunk = vm.compile(source).expect("benchmark failed to compile");
+    vm.interpret_chunk(chunk).expect("benchmark failed to run");
+
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            vm.run().expect("benchmark failed to run");
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+
+    println!(
+        "{:<10} min {:>10.3?}  median {:>10.3?}",
+        name,
+        times[0],
+        times[RUNS / 2]
+    );
+}
+
+fn main() {
+    // cargo bench passes --bench; anything else filters by name.
+    let filters: Vec<String> = env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+    let programs = [
+        ("additions", additions(10_000)),
+        ("increment", increments(10_000)),
+        ("loop", loops(10_000)),
+        ("strings", strings(2_000)),
+    ];
+
+    for (name, source) in programs.iter() {
+        if filters.is_empty() || filters.iter().any(|f| name.contains(f.as_str())) {
+            bench(name, source);
+        }
+    }
+}
@@ -0,0 +1,4 @@
+// Library code returns errors instead of unwrapping; tests may unwrap.
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used))]
+
+pub mod lox;
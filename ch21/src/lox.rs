@@ -1,7 +1,31 @@
+pub mod analysis;
+pub mod assembler;
+pub mod ast;
+pub mod ast_compiler;
+pub mod ast_printer;
+pub mod bytecode;
 pub mod chunk;
 pub mod compiler;
+pub mod conformance;
+pub mod dap;
+pub mod debugger;
+pub mod differential;
+pub mod evaluator;
 pub mod fnv;
+pub mod formatter;
+pub mod fuzz;
+pub mod highlight;
 pub mod interner;
+pub mod json;
+pub mod lsp;
+pub mod optimizer;
+pub mod parser;
+pub mod profile;
+pub mod resolver;
 pub mod scanner;
+#[cfg(test)]
+pub mod test_support;
+pub mod trace;
 pub mod value;
+pub mod verifier;
 pub mod vm;
@@ -0,0 +1,532 @@
+use crate::lox::resolver::{self, Locals};
+use crate::lox::scanner::{Scanner, Token, TokenType};
+
+// What a name is declared as.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Global,
+    Local,
+    Parameter,
+    Function,
+    Class,
+    Method,
+}
+
+impl Kind {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Kind::Global => "global variable",
+            Kind::Local => "local variable",
+            Kind::Parameter => "parameter",
+            Kind::Function => "function",
+            Kind::Class => "class",
+            Kind::Method => "method",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub kind: Kind,
+    pub line: usize,
+    // The byte range of the name, and of the whole declaration.
+    pub start: usize,
+    pub end: usize,
+    pub extent: (usize, usize),
+    // How many blocks the declaration is nested in.
+    pub depth: usize,
+    // The function a parameter belongs to, or the class a method belongs to.
+    pub parent: Option<usize>,
+    // The type of a variable whose initializer is a lone literal.
+    pub inferred: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Declaration,
+    Reference,
+    Property,
+}
+
+// One identifier in the source, and the definition it resolves to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occurrence {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub role: Role,
+    pub definition: Option<usize>,
+}
+
+#[derive(Default)]
+struct Scope {
+    definitions: Vec<usize>,
+    // The function, method or class whose body this is.
+    owner: Option<usize>,
+}
+
+// What the names in a source file refer to. The declarations come from its
+// tokens alone, so that a file that does not parse still gets an answer, and
+// so do the scopes of a file that doesn't check: blocks, function bodies and
+// class bodies open scopes, globals may be used before they are declared,
+// and a for loop's variable is scoped as if it were declared just before the
+// loop. A file that checks has its names bound by the resolver lox check
+// and the tree-walker use instead, so that it gets the same answer they do.
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub tokens: Vec<Token>,
+    pub definitions: Vec<Definition>,
+    pub occurrences: Vec<Occurrence>,
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Analysis {
+        let mut analysis = Analysis {
+            tokens: Scanner::new(source).collect(),
+            ..Analysis::default()
+        };
+        analysis.resolve();
+        if let Ok((_, locals)) = resolver::check(source) {
+            analysis.bind(&locals);
+        }
+        analysis
+    }
+
+    // Rebinds every reference the way the resolver did: to the declaration
+    // it found, or to a global when it found none.
+    fn bind(&mut self, locals: &Locals) {
+        for definition in self.definitions.iter_mut() {
+            if definition.kind == Kind::Global && locals.declaration(definition.start).is_some() {
+                definition.kind = Kind::Local;
+            }
+        }
+        for occurrence in self.occurrences.iter_mut() {
+            if occurrence.role != Role::Reference {
+                continue;
+            }
+            occurrence.definition = match locals.declaration(occurrence.start) {
+                Some(declaration) => self
+                    .definitions
+                    .iter()
+                    .position(|definition| definition.start == declaration),
+                None => self.definitions.iter().position(|definition| {
+                    definition.depth == 0
+                        && definition.kind != Kind::Method
+                        && definition.name == occurrence.name
+                        && locals.declaration(definition.start).is_none()
+                }),
+            };
+        }
+    }
+
+    fn resolve(&mut self) {
+        let mut scopes = vec![Scope::default()];
+        // A body about to open, with the parameters to declare in it.
+        let mut pending: Option<(usize, Vec<usize>)> = None;
+        let mut i = 0;
+        while i < self.tokens.len() {
+            let token = self.tokens[i].clone();
+            let depth = scopes.len() - 1;
+            match &token.token_type {
+                TokenType::LeftBrace => {
+                    let (owner, definitions) = match pending.take() {
+                        Some((owner, parameters)) => (Some(owner), parameters),
+                        None => (None, vec![]),
+                    };
+                    scopes.push(Scope { definitions, owner });
+                }
+                TokenType::RightBrace if scopes.len() > 1 => {
+                    if let Some(owner) = scopes.pop().and_then(|scope| scope.owner) {
+                        self.definitions[owner].extent.1 = token.end;
+                    }
+                }
+                TokenType::Var => {
+                    if let Some(name) = self.name_at(i + 1) {
+                        let kind = if depth == 0 {
+                            Kind::Global
+                        } else {
+                            Kind::Local
+                        };
+                        let definition = self.declare(&mut scopes, i, name, kind, None);
+                        self.definitions[definition].inferred = self.literal_initializer(i + 2);
+                        i += 1;
+                    }
+                }
+                TokenType::Fun => {
+                    if let Some(name) = self.name_at(i + 1) {
+                        let function = self.declare(&mut scopes, i, name, Kind::Function, None);
+                        i = self.parameters(i + 2, function, &mut pending);
+                        continue;
+                    }
+                }
+                TokenType::Class => {
+                    if let Some(name) = self.name_at(i + 1) {
+                        let class = self.declare(&mut scopes, i, name, Kind::Class, None);
+                        i += 2;
+                        if self.token_type(i) == Some(&TokenType::Less) {
+                            if let Some(superclass) = self.name_at(i + 1) {
+                                self.reference(&scopes, superclass);
+                                i += 2;
+                            }
+                        }
+                        if self.token_type(i) == Some(&TokenType::LeftBrace) {
+                            pending = Some((class, vec![]));
+                        }
+                        continue;
+                    }
+                }
+                TokenType::Identifier(name) => {
+                    let class = scopes[depth]
+                        .owner
+                        .filter(|owner| self.definitions[*owner].kind == Kind::Class);
+                    let after_dot = i > 0 && self.token_type(i - 1) == Some(&TokenType::Dot);
+                    if after_dot {
+                        self.occurrences.push(Occurrence {
+                            name: name.clone(),
+                            start: token.start,
+                            end: token.end,
+                            role: Role::Property,
+                            definition: None,
+                        });
+                    } else if class.is_some()
+                        && self.token_type(i + 1) == Some(&TokenType::LeftParen)
+                    {
+                        let method = self.define(i, i, depth, Kind::Method, class);
+                        i = self.parameters(i + 1, method, &mut pending);
+                        continue;
+                    } else {
+                        self.reference(&scopes, i);
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+
+        // Globals are looked up when the code runs, so a use that comes
+        // before the declaration still refers to it.
+        for occurrence in self.occurrences.iter_mut() {
+            if occurrence.role == Role::Reference && occurrence.definition.is_none() {
+                occurrence.definition = self.definitions.iter().position(|definition| {
+                    definition.depth == 0
+                        && definition.kind != Kind::Method
+                        && definition.name == occurrence.name
+                });
+            }
+        }
+    }
+
+    // Declares the parameters of the function or method whose parameter list
+    // starts at index, and returns the index after it.
+    fn parameters(
+        &mut self,
+        mut index: usize,
+        function: usize,
+        pending: &mut Option<(usize, Vec<usize>)>,
+    ) -> usize {
+        if self.token_type(index) != Some(&TokenType::LeftParen) {
+            return index;
+        }
+        index += 1;
+        let depth = self.definitions[function].depth + 1;
+        let mut parameters = vec![];
+        while let Some(token_type) = self.token_type(index) {
+            match token_type {
+                TokenType::RightParen => {
+                    index += 1;
+                    break;
+                }
+                TokenType::Identifier(_) => {
+                    parameters.push(self.define(
+                        index,
+                        index,
+                        depth,
+                        Kind::Parameter,
+                        Some(function),
+                    ));
+                }
+                TokenType::Comma => {}
+                _ => break,
+            }
+            index += 1;
+        }
+        if self.token_type(index) == Some(&TokenType::LeftBrace) {
+            *pending = Some((function, parameters));
+        }
+        index
+    }
+
+    fn declare(
+        &mut self,
+        scopes: &mut [Scope],
+        keyword: usize,
+        name: usize,
+        kind: Kind,
+        parent: Option<usize>,
+    ) -> usize {
+        let depth = scopes.len() - 1;
+        let definition = self.define(keyword, name, depth, kind, parent);
+        scopes[depth].definitions.push(definition);
+        definition
+    }
+
+    fn define(
+        &mut self,
+        keyword: usize,
+        name: usize,
+        depth: usize,
+        kind: Kind,
+        parent: Option<usize>,
+    ) -> usize {
+        let token = &self.tokens[name];
+        // Definitions are only made for identifiers.
+        let name = match &token.token_type {
+            TokenType::Identifier(text) => text.clone(),
+            _ => String::new(),
+        };
+        let definition = self.definitions.len();
+        self.definitions.push(Definition {
+            name: name.clone(),
+            kind,
+            line: token.line,
+            start: token.start,
+            end: token.end,
+            extent: (self.tokens[keyword].start, token.end),
+            depth,
+            parent,
+            inferred: None,
+        });
+        self.occurrences.push(Occurrence {
+            name,
+            start: token.start,
+            end: token.end,
+            role: Role::Declaration,
+            definition: Some(definition),
+        });
+        definition
+    }
+
+    fn reference(&mut self, scopes: &[Scope], index: usize) {
+        let token = &self.tokens[index];
+        let TokenType::Identifier(name) = &token.token_type else {
+            return;
+        };
+        let definition = scopes.iter().rev().find_map(|scope| {
+            scope
+                .definitions
+                .iter()
+                .rev()
+                .find(|definition| &self.definitions[**definition].name == name)
+                .copied()
+        });
+        self.occurrences.push(Occurrence {
+            name: name.clone(),
+            start: token.start,
+            end: token.end,
+            role: Role::Reference,
+            definition,
+        });
+    }
+
+    fn token_type(&self, index: usize) -> Option<&TokenType> {
+        self.tokens.get(index).map(|token| &token.token_type)
+    }
+
+    fn name_at(&self, index: usize) -> Option<usize> {
+        match self.token_type(index) {
+            Some(TokenType::Identifier(_)) => Some(index),
+            _ => None,
+        }
+    }
+
+    // The type of `= literal;` starting at index, if that is what is there.
+    fn literal_initializer(&self, index: usize) -> Option<&'static str> {
+        if self.token_type(index) != Some(&TokenType::Equal)
+            || self.token_type(index + 2) != Some(&TokenType::Semicolon)
+        {
+            return None;
+        }
+        match self.token_type(index + 1)? {
+            TokenType::Number(_) => Some("number"),
+            TokenType::String(_) => Some("string"),
+            TokenType::True | TokenType::False => Some("boolean"),
+            TokenType::Nil => Some("nil"),
+            _ => None,
+        }
+    }
+
+    // The identifier at or just before a byte offset.
+    pub fn occurrence_at(&self, offset: usize) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|occurrence| occurrence.start <= offset && offset <= occurrence.end)
+    }
+
+    pub fn occurrences_of(&self, definition: usize) -> Vec<&Occurrence> {
+        self.occurrences
+            .iter()
+            .filter(|occurrence| occurrence.definition == Some(definition))
+            .collect()
+    }
+
+    pub fn parameters_of(&self, function: usize) -> Vec<&Definition> {
+        self.definitions
+            .iter()
+            .filter(|definition| {
+                definition.kind == Kind::Parameter && definition.parent == Some(function)
+            })
+            .collect()
+    }
+
+    // A one line description of a definition, as a hover would show it.
+    pub fn describe(&self, definition: usize) -> String {
+        let d = &self.definitions[definition];
+        let parameters = || {
+            let names: Vec<&str> = self
+                .parameters_of(definition)
+                .iter()
+                .map(|parameter| parameter.name.as_str())
+                .collect();
+            names.join(", ")
+        };
+        match d.kind {
+            Kind::Function => format!("({}) {}({})", d.kind.describe(), d.name, parameters()),
+            Kind::Method => {
+                let class = d
+                    .parent
+                    .map(|class| format!("{}.", self.definitions[class].name))
+                    .unwrap_or_default();
+                format!(
+                    "({}) {}{}({})",
+                    d.kind.describe(),
+                    class,
+                    d.name,
+                    parameters()
+                )
+            }
+            _ => match d.inferred {
+                Some(inferred) => format!("({}) {}: {}", d.kind.describe(), d.name, inferred),
+                None => format!("({}) {}", d.kind.describe(), d.name),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "var a = 1;
+fun add(x, y) {
+  var sum = x + y;
+  return sum + a;
+}
+class Point < Base {
+  norm(p) { return this.x * p; }
+}
+print b;
+var b = add(a, 2);
+";
+
+    // Each identifier in order, with what it was resolved to.
+    fn resolved(analysis: &Analysis, source: &str) -> Vec<String> {
+        analysis
+            .occurrences
+            .iter()
+            .map(|occurrence| {
+                let name = &source[occurrence.start..occurrence.end];
+                match (occurrence.role, occurrence.definition) {
+                    (Role::Property, _) => format!(".{}", name),
+                    (Role::Declaration, _) => format!("{}:", name),
+                    (Role::Reference, Some(d)) => {
+                        format!("{}@{}", name, analysis.definitions[d].line)
+                    }
+                    (Role::Reference, None) => format!("{}?", name),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_resolves_names_through_scopes() {
+        let analysis = Analysis::new(SOURCE);
+        assert_eq!(
+            vec![
+                "a:", "add:", "x:", "y:", "sum:", "x@2", "y@2", "sum@3", "a@1", "Point:", "Base?",
+                "norm:", "p:", ".x", "p@7", "b@10", "b:", "add@2", "a@1",
+            ],
+            resolved(&analysis, SOURCE)
+        );
+    }
+
+    #[test]
+    fn it_infers_kinds() {
+        let analysis = Analysis::new(SOURCE);
+        let described: Vec<String> = (0..analysis.definitions.len())
+            .map(|d| analysis.describe(d))
+            .collect();
+        assert_eq!(
+            vec![
+                "(global variable) a: number",
+                "(function) add(x, y)",
+                "(parameter) x",
+                "(parameter) y",
+                "(local variable) sum",
+                "(class) Point",
+                "(method) Point.norm(p)",
+                "(parameter) p",
+                "(global variable) b",
+            ],
+            described
+        );
+        assert_eq!(1, analysis.definitions[4].depth);
+        assert_eq!(
+            "fun add(x, y) {\n  var sum = x + y;\n  return sum + a;\n}",
+            {
+                let (start, end) = analysis.definitions[1].extent;
+                &SOURCE[start..end]
+            }
+        );
+    }
+
+    #[test]
+    fn it_tolerates_half_typed_code() {
+        let source = "var a = ;\nfun f(x, { print x + a\nprint \"open";
+        let analysis = Analysis::new(source);
+        assert_eq!(
+            vec!["a:", "f:", "x:", "x@2", "a@1"],
+            resolved(&analysis, source)
+        );
+    }
+
+    #[test]
+    fn it_scopes_names_like_the_resolver_once_the_file_checks() {
+        let source = "for (var i = 0; i < 1; i = i + 1) print i;\nprint i;";
+        let analysis = Analysis::new(source);
+        assert_eq!(
+            vec!["i:", "i@1", "i@1", "i@1", "i@1", "i?"],
+            resolved(&analysis, source)
+        );
+        assert_eq!("(local variable) i: number", analysis.describe(0));
+
+        // Until it checks, the loop's variable is in the enclosing scope.
+        let source = "for (var i = 0; i < 1; i = i + 1) print i;\nprint i";
+        let analysis = Analysis::new(source);
+        assert_eq!("i@1", resolved(&analysis, source)[5]);
+        assert_eq!("(global variable) i: number", analysis.describe(0));
+    }
+
+    #[test]
+    fn it_finds_occurrences() {
+        let analysis = Analysis::new(SOURCE);
+        assert!(analysis.occurrence_at(0).is_none());
+
+        let sum = analysis
+            .occurrence_at(SOURCE.find("sum + a").unwrap() + 3)
+            .unwrap();
+        assert_eq!(Some(4), sum.definition);
+        assert_eq!(2, analysis.occurrences_of(4).len());
+        assert_eq!(3, analysis.occurrences_of(0).len());
+    }
+}
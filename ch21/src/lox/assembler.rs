@@ -0,0 +1,480 @@
+use crate::lox::chunk::{format_value, Chunk, OpCode};
+use crate::lox::interner::Interner;
+use crate::lox::value::{Unpacked, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+// Reads the listing Chunk::disassemble writes back into a chunk:
+//
+//     == name ==
+//     0000  123 OP_CONSTANT         0 '1.2
+//     0001    | OP_PRINT
+//     0002    | OP_RETURN
+//     -- constants --
+//     0000 1.2
+//
+// Columns only need to be separated by whitespace, and lines starting with ;
+// are comments. The constants section is optional; without it each constant
+// comes from the '-annotation of an instruction that uses it. Strings are
+// written as quoted, escaped text.
+#[derive(Debug, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.line, self.message)
+    }
+}
+
+const CONSTANTS_HEADER: &str = "-- constants --";
+
+// A listing that assembles back to the same chunk, strings and unused
+// constants included.
+pub fn listing(chunk: &Chunk, name: &str, interner: &Interner) -> Result<String, fmt::Error> {
+    let mut output = chunk.disassemble_with(name, Some(interner))?;
+    writeln!(output, "{}", CONSTANTS_HEADER)?;
+    for (index, value) in chunk.constants().iter().enumerate() {
+        writeln!(
+            output,
+            "{:04} {}",
+            index,
+            format_value(value, Some(interner))
+        )?;
+    }
+    Ok(output)
+}
+
+enum Operand {
+    None(OpCode),
+    Constant(fn(usize) -> OpCode),
+    SmallInt,
+}
+
+pub fn assemble(text: &str, interner: &mut Interner) -> Result<Chunk, AssembleError> {
+    // Each instruction with its source line and the line of the listing it is on.
+    let mut instructions: Vec<(OpCode, usize, usize)> = vec![];
+    let mut annotations: BTreeMap<usize, (Value, usize)> = BTreeMap::new();
+    let mut constants: Option<Vec<Value>> = None;
+
+    for (number, text) in text.lines().enumerate() {
+        let number = number + 1;
+        let error = |message: String| AssembleError {
+            line: number,
+            message,
+        };
+        let text = text.trim();
+        if text.is_empty() || text.starts_with(';') {
+            continue;
+        }
+        if text == CONSTANTS_HEADER {
+            if constants.is_some() {
+                return Err(error(String::from("Constants are already listed.")));
+            }
+            constants = Some(vec![]);
+            continue;
+        }
+        if text.starts_with("== ") && text.ends_with(" ==") {
+            if !instructions.is_empty() || constants.is_some() {
+                return Err(error(String::from("Name must come before the code.")));
+            }
+            continue;
+        }
+
+        let (index, rest) = next_field(text);
+        if let Some(constants) = constants.as_mut() {
+            expect_index(index, constants.len()).map_err(error)?;
+            let value = parse_value(rest, interner).map_err(error)?;
+            constants.push(value);
+            continue;
+        }
+        expect_index(index, instructions.len()).map_err(error)?;
+
+        let (line, rest) = next_field(rest);
+        let line = match (line, instructions.last()) {
+            ("|", Some((_, previous, _))) => *previous,
+            ("|", None) => return Err(error(String::from("The first instruction needs a line."))),
+            (line, _) => parse_number(line, "line").map_err(error)?,
+        };
+
+        let (name, rest) = next_field(rest);
+        let (operand, rest) = next_field(rest);
+        let code = match opcode(name) {
+            Some(Operand::None(code)) if operand.is_empty() => code,
+            Some(Operand::None(_)) => {
+                return Err(error(format!("Unexpected '{}' after {}.", operand, name)))
+            }
+            Some(Operand::SmallInt) if rest.is_empty() => {
+                OpCode::SmallInt(parse_number(operand, "small integer").map_err(error)?)
+            }
+            Some(Operand::SmallInt) => {
+                return Err(error(format!("Unexpected '{}' after {}.", rest, name)))
+            }
+            Some(Operand::Constant(make)) => {
+                let constant = parse_number(operand, "constant").map_err(error)?;
+                if let Some(annotation) = rest.strip_prefix('\'') {
+                    let value = parse_value(annotation, interner).map_err(error)?;
+                    match annotations.get(&constant) {
+                        Some((other, _)) if !same_value(other, &value) => {
+                            return Err(error(format!(
+                                "Constant {} was already given as {}.",
+                                constant,
+                                format_value(other, Some(interner))
+                            )))
+                        }
+                        Some(_) => {}
+                        None => {
+                            annotations.insert(constant, (value, number));
+                        }
+                    }
+                } else if !rest.is_empty() {
+                    return Err(error(format!("Unexpected '{}' after {}.", rest, name)));
+                }
+                make(constant)
+            }
+            None => return Err(error(format!("Unknown instruction '{}'.", name))),
+        };
+        instructions.push((code, line, number));
+    }
+
+    let constants = match constants {
+        Some(constants) => {
+            for (constant, (value, number)) in annotations.iter() {
+                let error = |message: String| AssembleError {
+                    line: *number,
+                    message,
+                };
+                match constants.get(*constant) {
+                    None => return Err(error(format!("Constant {} is not listed.", constant))),
+                    Some(listed) if !same_value(listed, value) => {
+                        return Err(error(format!(
+                            "Constant {} is listed as {}.",
+                            constant,
+                            format_value(listed, Some(interner))
+                        )))
+                    }
+                    Some(_) => {}
+                }
+            }
+            constants
+        }
+        None => {
+            let mut constants = vec![];
+            for (constant, (value, number)) in annotations.into_iter() {
+                if constant != constants.len() {
+                    return Err(AssembleError {
+                        line: number,
+                        message: format!(
+                            "Constant {} has no value. List the constants after '{}'.",
+                            constants.len(),
+                            CONSTANTS_HEADER
+                        ),
+                    });
+                }
+                constants.push(value);
+            }
+            constants
+        }
+    };
+
+    let mut chunk = Chunk::from_parts(vec![], &[], constants);
+    for (code, line, number) in instructions {
+        if let OpCode::Constant(c)
+        | OpCode::GetGlobal(c)
+        | OpCode::DefineGlobal(c)
+        | OpCode::SetGlobal(c) = code
+        {
+            if c >= chunk.constants().len() {
+                return Err(AssembleError {
+                    line: number,
+                    message: format!("Constant {} has no value.", c),
+                });
+            }
+        }
+        chunk
+            .write_chunk(code, line)
+            .map_err(|message| AssembleError {
+                line: number,
+                message,
+            })?;
+    }
+    Ok(chunk)
+}
+
+fn next_field(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn expect_index(index: &str, expected: usize) -> Result<(), String> {
+    match index.parse::<usize>() {
+        Ok(i) if i == expected => Ok(()),
+        _ => Err(format!("Expected {:04}, found '{}'.", expected, index)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str, what: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("Invalid {} '{}'.", what, text))
+}
+
+fn opcode(name: &str) -> Option<Operand> {
+    match name {
+        "OP_CONSTANT" => Some(Operand::Constant(OpCode::Constant)),
+        "OP_GET_GLOBAL" => Some(Operand::Constant(OpCode::GetGlobal)),
+        "OP_DEFINE_GLOBAL" => Some(Operand::Constant(OpCode::DefineGlobal)),
+        "OP_SET_GLOBAL" => Some(Operand::Constant(OpCode::SetGlobal)),
+        "OP_SMALL_INT" => Some(Operand::SmallInt),
+        _ => simple_opcode(name).map(Operand::None),
+    }
+}
+
+fn simple_opcode(name: &str) -> Option<OpCode> {
+    match name {
+        "OP_NIL" => Some(OpCode::Nil),
+        "OP_TRUE" => Some(OpCode::True),
+        "OP_FALSE" => Some(OpCode::False),
+        "OP_EQUAL" => Some(OpCode::Equal),
+        "OP_NOT_EQUAL" => Some(OpCode::NotEqual),
+        "OP_GREATER" => Some(OpCode::Greater),
+        "OP_GREATER_EQUAL" => Some(OpCode::GreaterEqual),
+        "OP_LESS" => Some(OpCode::Less),
+        "OP_LESS_EQUAL" => Some(OpCode::LessEqual),
+        "OP_ADD" => Some(OpCode::Add),
+        "OP_SUBTRACT" => Some(OpCode::Subtract),
+        "OP_MULTIPLY" => Some(OpCode::Multiply),
+        "OP_DIVIDE" => Some(OpCode::Divide),
+        "OP_NOT" => Some(OpCode::Not),
+        "OP_NEGATE" => Some(OpCode::Negate),
+        "OP_POP" => Some(OpCode::Pop),
+        "OP_PRINT" => Some(OpCode::Print),
+        "OP_RETURN" => Some(OpCode::Return),
+        _ => None,
+    }
+}
+
+// Numbers are compared by bits, so -0 and NaN constants can be told apart.
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a.unpack(), b.unpack()) {
+        (Unpacked::Number(a), Unpacked::Number(b)) => a.to_bits() == b.to_bits(),
+        (a, b) => a == b,
+    }
+}
+
+fn parse_value(text: &str, interner: &mut Interner) -> Result<Value, String> {
+    match text {
+        "nil" => return Ok(Value::Nil),
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if text.starts_with("<string ") {
+        return Err(format!(
+            "String {} has no text. List it with its strings resolved.",
+            text
+        ));
+    }
+    if let Some(quoted) = text.strip_prefix('"') {
+        let s = unescape(quoted).ok_or_else(|| format!("Invalid string {}.", text))?;
+        return Ok(Value::String(interner.intern(s.as_str())));
+    }
+    parse_number(text, "constant").map(Value::Number)
+}
+
+// Reverses the escaping of {:?} on a str. The text starts after the opening
+// quote and has to end at the closing one.
+fn unescape(text: &str) -> Option<String> {
+    let mut s = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return chars.next().is_none().then_some(s),
+            '\\' => s.push(match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                '\'' => '\'',
+                'u' => {
+                    let rest = chars.as_str().strip_prefix('{')?;
+                    let (hex, rest) = rest.split_once('}')?;
+                    chars = rest.chars();
+                    char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            c => s.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::compiler::{compile_with, CompileOptions};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn assert_round_trips(source: &str, options: CompileOptions) {
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let chunk = compile_with(source, interner.clone(), options).expect("compile failed");
+
+        let text = listing(&chunk, "code", &interner.borrow()).expect("listing failed");
+        let assembled = assemble(&text, &mut interner.borrow_mut()).expect("assemble failed");
+        assert_eq!(
+            Ok(text.clone()),
+            listing(&assembled, "code", &interner.borrow()),
+            "failed with: {}",
+            source
+        );
+        assert_eq!(chunk.code, assembled.code);
+        assert_eq!(chunk.line_runs(), assembled.line_runs());
+
+        // Without the interner only a listing with no strings in it can be
+        // assembled again.
+        let plain = chunk.disassemble("code").expect("disassemble failed");
+        let assembled = assemble(&plain, &mut interner.borrow_mut());
+        if chunk
+            .constants()
+            .iter()
+            .any(|c| matches!(c.unpack(), Unpacked::String(_)))
+        {
+            assert!(assembled.is_err(), "assembled {}", plain);
+        } else {
+            let assembled = assembled.expect("assemble failed");
+            assert_eq!(Ok(plain), assembled.disassemble("code"));
+        }
+    }
+
+    #[test]
+    fn it_round_trips_listings() {
+        for source in [
+            "print 1 + 2;",
+            "print -1.5 * 300 / 7 >= 0.1;\nprint nil == false;",
+            "var a = \"hi\"; a = a + \" \\ there\nagain\";\n\nprint !a;",
+            "print 0 / 0; print -0; print 1 / 0; print -1 / 0;",
+            "print \"a\" + \"b\";",
+            "var e = \"é \u{1b}[1mbold\u{1b}[0m\";",
+        ] {
+            assert_round_trips(source, CompileOptions::O0);
+            assert_round_trips(source, CompileOptions::O1);
+        }
+    }
+
+    #[test]
+    fn it_round_trips_long_constants() {
+        let source: String = (0..300).map(|n| format!("print {}.5;\n", n)).collect();
+        assert_round_trips(source.as_str(), CompileOptions::O0);
+    }
+
+    #[test]
+    fn it_assembles_hand_written_code() {
+        let mut interner = Interner::default();
+        let chunk = assemble(
+            "0000 1 OP_CONSTANT 0 '\"a b\"\n\
+             0001 | OP_DEFINE_GLOBAL 0\n\
+             \n\
+             0002 2 OP_SMALL_INT -3\n\
+             0003 2 OP_RETURN\n",
+            &mut interner,
+        )
+        .expect("assemble failed");
+
+        assert_eq!(
+            "== code ==\n\
+             0000    1 OP_CONSTANT         0 '\"a b\"\n\
+             0001    | OP_DEFINE_GLOBAL    0 '\"a b\"\n\
+             0002    2 OP_SMALL_INT       -3\n\
+             0003    | OP_RETURN\n",
+            chunk
+                .disassemble_with("code", Some(&interner))
+                .expect("disassemble failed")
+        );
+    }
+
+    #[test]
+    fn it_keeps_unused_and_repeated_constants() {
+        let mut interner = Interner::default();
+        let chunk = assemble(
+            "0000 1 OP_CONSTANT 1 '2\n\
+             0001 | OP_RETURN\n\
+             -- constants --\n\
+             0000 nil\n\
+             0001 2\n\
+             0002 2\n",
+            &mut interner,
+        )
+        .expect("assemble failed");
+
+        assert_eq!(
+            vec![Value::Nil, Value::Number(2.0), Value::Number(2.0)],
+            chunk.constants()
+        );
+    }
+
+    fn assemble_err(text: &str) -> String {
+        let error = assemble(text, &mut Interner::default()).expect_err("assembled bad code");
+        error.to_string()
+    }
+
+    #[test]
+    fn it_reports_errors_with_lines() {
+        assert_eq!(
+            "[line 2] Error: Unknown instruction 'OP_JUMP'.",
+            assemble_err("0000 1 OP_NIL\n0001 | OP_JUMP 3\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Expected 0000, found '0001'.",
+            assemble_err("0001 1 OP_NIL\n")
+        );
+        assert_eq!(
+            "[line 1] Error: The first instruction needs a line.",
+            assemble_err("0000 | OP_NIL\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Invalid small integer '300'.",
+            assemble_err("0000 1 OP_SMALL_INT 300\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Unexpected '1' after OP_NIL.",
+            assemble_err("0000 1 OP_NIL 1\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Invalid constant 'x'.",
+            assemble_err("0000 1 OP_CONSTANT 0 'x\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Invalid string \"a.",
+            assemble_err("0000 1 OP_CONSTANT 0 '\"a\n")
+        );
+        assert_eq!(
+            "[line 2] Error: Constant 0 was already given as 1.",
+            assemble_err("0000 1 OP_CONSTANT 0 '1\n0001 1 OP_CONSTANT 0 '2\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Constant 0 has no value. List the constants after '-- constants --'.",
+            assemble_err("0000 1 OP_CONSTANT 1 '1\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Constant 0 has no value.",
+            assemble_err("0000 1 OP_CONSTANT 0\n")
+        );
+        assert_eq!(
+            "[line 1] Error: String <string 2> has no text. List it with its strings resolved.",
+            assemble_err("0000 1 OP_CONSTANT 0 '<string 2>\n")
+        );
+        assert_eq!(
+            "[line 1] Error: Constant 0 is listed as nil.",
+            assemble_err("0000 1 OP_CONSTANT 0 '1\n-- constants --\n0000 nil\n")
+        );
+    }
+}
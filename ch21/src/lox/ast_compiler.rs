@@ -0,0 +1,264 @@
+use crate::lox::ast::*;
+use crate::lox::chunk::{Chunk, OpCode};
+use crate::lox::compiler::{CompileOptions, Emitter, ParserError};
+use crate::lox::interner::Interner;
+use crate::lox::value::Value;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Compiles a parsed program to the same bytecode the single-pass compiler
+// writes for its source, line numbers and constant order included. Only the
+// part of the language the VM runs is supported; the rest is an error.
+pub fn compile(
+    source: &str,
+    statements: &[Stmt],
+    interner: Rc<RefCell<Interner>>,
+    options: CompileOptions,
+) -> Result<Chunk, ParserError> {
+    let mut compiler = AstCompiler {
+        emitter: Emitter::new(interner, options),
+        newlines: source
+            .bytes()
+            .enumerate()
+            .filter(|(_, byte)| *byte == b'\n')
+            .map(|(i, _)| i)
+            .collect(),
+    };
+    for statement in statements {
+        compiler.statement(statement)?;
+    }
+    let line = compiler.line(source.len());
+    compiler
+        .emitter
+        .finish(line)
+        .map_err(|message| error(message, Span::new(source.len(), source.len())))
+}
+
+struct AstCompiler {
+    emitter: Emitter,
+    // Where each newline in the source is, to find lines from spans.
+    newlines: Vec<usize>,
+}
+
+impl AstCompiler {
+    // The single-pass compiler writes each instruction on the line of the
+    // last token it has read, which is the line a node's span ends on.
+    fn line(&self, end: usize) -> usize {
+        1 + self.newlines.partition_point(|&newline| newline < end)
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), ParserError> {
+        let line = self.line(statement.span.end);
+        match &statement.kind {
+            StmtKind::Expression(expression) => {
+                self.expression(expression)?;
+                self.emit(OpCode::Pop, line, statement.span)?;
+            }
+            StmtKind::Print(value) => {
+                self.expression(value)?;
+                self.emit(OpCode::Print, line, statement.span)?;
+            }
+            StmtKind::Var { name, initializer } => {
+                let global = self.identifier_constant(name)?;
+                match initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    None => self.emit(OpCode::Nil, self.line(name.span.end), name.span)?,
+                }
+                self.emit(OpCode::DefineGlobal(global), line, statement.span)?;
+            }
+            StmtKind::Block(_) => return Err(unsupported("blocks", statement.span)),
+            StmtKind::Class { .. } => return Err(unsupported("classes", statement.span)),
+            StmtKind::Function(_) => return Err(unsupported("functions", statement.span)),
+            StmtKind::If { .. } => return Err(unsupported("if statements", statement.span)),
+            StmtKind::Return(_) => return Err(unsupported("return statements", statement.span)),
+            StmtKind::While { .. } => return Err(unsupported("loops", statement.span)),
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &Expr) -> Result<(), ParserError> {
+        let line = self.line(expression.span.end);
+        let span = expression.span;
+        match &expression.kind {
+            ExprKind::Literal(literal) => match literal {
+                Literal::Nil => self.emit(OpCode::Nil, line, span)?,
+                Literal::Bool(true) => self.emit(OpCode::True, line, span)?,
+                Literal::Bool(false) => self.emit(OpCode::False, line, span)?,
+                Literal::Number(n) => self.constant(Value::Number(*n), line, span)?,
+                Literal::String(s) => {
+                    let value = self.emitter.string(s);
+                    self.constant(value, line, span)?
+                }
+            },
+            ExprKind::Grouping(inner) => self.expression(inner)?,
+            ExprKind::Variable(name) => {
+                let arg = self.identifier_constant(name)?;
+                self.emit(OpCode::GetGlobal(arg), line, span)?;
+            }
+            ExprKind::Assign { name, value } => {
+                let arg = self.identifier_constant(name)?;
+                self.expression(value)?;
+                self.emit(OpCode::SetGlobal(arg), line, span)?;
+            }
+            ExprKind::Unary { op, operand } => {
+                let start = self.emitter.mark();
+                self.expression(operand)?;
+                let code = match op {
+                    UnaryOp::Negate => OpCode::Negate,
+                    UnaryOp::Not => OpCode::Not,
+                };
+                self.emitter
+                    .unary(code, start, line)
+                    .map_err(|message| error(message, span))?;
+            }
+            ExprKind::Binary { left, op, right } => {
+                let left_start = self.emitter.mark();
+                self.expression(left)?;
+                let right_start = self.emitter.mark();
+                self.expression(right)?;
+                let code = match op {
+                    BinaryOp::Add => OpCode::Add,
+                    BinaryOp::Subtract => OpCode::Subtract,
+                    BinaryOp::Multiply => OpCode::Multiply,
+                    BinaryOp::Divide => OpCode::Divide,
+                    BinaryOp::Equal => OpCode::Equal,
+                    BinaryOp::NotEqual => OpCode::NotEqual,
+                    BinaryOp::Greater => OpCode::Greater,
+                    BinaryOp::GreaterEqual => OpCode::GreaterEqual,
+                    BinaryOp::Less => OpCode::Less,
+                    BinaryOp::LessEqual => OpCode::LessEqual,
+                };
+                self.emitter
+                    .binary(code, left_start, right_start, line)
+                    .map_err(|message| error(message, span))?;
+            }
+            ExprKind::Call { .. } => return Err(unsupported("calls", span)),
+            ExprKind::Get { .. } | ExprKind::Set { .. } => {
+                return Err(unsupported("properties", span))
+            }
+            ExprKind::Logical { .. } => return Err(unsupported("'and' and 'or'", span)),
+            ExprKind::Super { .. } => return Err(unsupported("'super'", span)),
+            ExprKind::This => return Err(unsupported("'this'", span)),
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, code: OpCode, line: usize, span: Span) -> Result<(), ParserError> {
+        self.emitter
+            .emit(code, line)
+            .map_err(|message| error(message, span))
+    }
+
+    fn constant(&mut self, value: Value, line: usize, span: Span) -> Result<(), ParserError> {
+        self.emitter
+            .constant(value, line)
+            .map_err(|message| error(message, span))
+    }
+
+    fn identifier_constant(&mut self, name: &Name) -> Result<usize, ParserError> {
+        self.emitter
+            .identifier_constant(&name.name)
+            .map_err(|message| error(message, name.span))
+    }
+}
+
+fn error(message: String, span: Span) -> ParserError {
+    ParserError {
+        token: None,
+        message,
+        previous: (span.start, span.end),
+    }
+}
+
+fn unsupported(what: &str, span: Span) -> ParserError {
+    error(format!("Can't compile {} yet.", what), span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::compiler::compile_with;
+    use crate::lox::parser::parse;
+
+    fn compile_ast(
+        source: &str,
+        interner: Rc<RefCell<Interner>>,
+        options: CompileOptions,
+    ) -> Result<Chunk, ParserError> {
+        let statements = parse(source).expect("parse failed");
+        compile(source, &statements, interner, options)
+    }
+
+    // Everything the single-pass compiler takes, across lines.
+    const CORPUS: &[&str] = &[
+        "",
+        "print 1;",
+        "print 1 + 2 * 3 - 4 / 5;",
+        "print -(1 + 2);\nprint !true;",
+        "print \"a\" + \"b\";",
+        "print \"multi\nline\" + \"\";",
+        "var a;\nvar b = 1;\nprint a;",
+        "var a = 1; a = a + 2; print a;",
+        "var a\n=\n1\n;\n a =\n (a +\n 1\n )\n;",
+        "print 1 <\n 2;\nprint 3 >= 4;\nprint 5 <= 6;\nprint 7 > 8;",
+        "print 1 == 2;\nprint nil != false;",
+        "print 1 == 2 < 3;\nprint 1 < 2 != 3 >= 4 == true;",
+        "print 1000 * 1000; print 0.5; print -0;",
+        "print -a; print !!b;\n\n",
+        "a = b = c;",
+        "print \"x\" + 1;",
+        "print 0 / 0 >= 1;",
+        "1;\n\"s\";\nnil;\n",
+        "// comment\nprint 1; // trailing\n",
+    ];
+
+    fn assert_same_chunks(options: CompileOptions) {
+        for source in CORPUS {
+            let interner = Rc::new(RefCell::new(Interner::default()));
+            let expected = compile_with(source, interner.clone(), options).expect("compile failed");
+            let actual_interner = Rc::new(RefCell::new(Interner::default()));
+            let actual = compile_ast(source, actual_interner.clone(), options).unwrap();
+
+            assert_eq!(expected.code, actual.code, "code for {:?}", source);
+            assert_eq!(
+                expected.lines_by_offset(),
+                actual.lines_by_offset(),
+                "lines for {:?}",
+                source
+            );
+            assert_eq!(
+                expected.constants(),
+                actual.constants(),
+                "constants for {:?}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn it_compiles_like_the_single_pass_compiler() {
+        assert_same_chunks(CompileOptions::O0);
+    }
+
+    #[test]
+    fn it_folds_like_the_single_pass_compiler() {
+        assert_same_chunks(CompileOptions::O1);
+    }
+
+    #[test]
+    fn it_rejects_what_the_vm_cannot_run() {
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        for (source, message) in [
+            ("{ print 1; }", "Can't compile blocks yet."),
+            ("if (true) print 1;", "Can't compile if statements yet."),
+            ("print a and b;", "Can't compile 'and' and 'or' yet."),
+            ("f();", "Can't compile calls yet."),
+            ("print 1;\nfun f() {}", "Can't compile functions yet."),
+        ] {
+            let error = compile_ast(source, interner.clone(), CompileOptions::O1).unwrap_err();
+            assert_eq!(message, error.message);
+            assert!(error.token.is_none());
+        }
+    }
+}
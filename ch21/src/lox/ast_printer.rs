@@ -0,0 +1,157 @@
+use crate::lox::ast::*;
+
+// The tree as S-expressions, a statement to a line. Expression statements are
+// printed as their bare expression.
+pub fn print(statements: &[Stmt]) -> String {
+    statements
+        .iter()
+        .map(stmt)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn stmt(statement: &Stmt) -> String {
+    match &statement.kind {
+        StmtKind::Block(statements) => list("block", statements.iter().map(stmt)),
+        StmtKind::Class {
+            name,
+            superclass,
+            methods,
+        } => {
+            let mut items = vec![name.name.clone()];
+            if let Some(superclass) = superclass {
+                items.push(format!("(< {})", superclass.name));
+            }
+            items.extend(methods.iter().map(function));
+            list("class", items)
+        }
+        StmtKind::Expression(expression) => expr(expression),
+        StmtKind::Function(f) => function(f),
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            let mut items = vec![expr(condition), stmt(then_branch)];
+            if let Some(else_branch) = else_branch {
+                items.push(stmt(else_branch));
+            }
+            list("if", items)
+        }
+        StmtKind::Print(value) => list("print", [expr(value)]),
+        StmtKind::Return(value) => list("return", value.iter().map(expr)),
+        StmtKind::Var { name, initializer } => {
+            let mut items = vec![name.name.clone()];
+            items.extend(initializer.iter().map(expr));
+            list("var", items)
+        }
+        StmtKind::While { condition, body } => list("while", [expr(condition), stmt(body)]),
+    }
+}
+
+pub fn expr(expression: &Expr) -> String {
+    match &expression.kind {
+        ExprKind::Assign { name, value } => list("=", [name.name.clone(), expr(value)]),
+        ExprKind::Binary { left, op, right } => list(op.lexeme(), [expr(left), expr(right)]),
+        ExprKind::Call { callee, arguments } => list(
+            "call",
+            [expr(callee)].into_iter().chain(arguments.iter().map(expr)),
+        ),
+        ExprKind::Get { object, name } => list(".", [expr(object), name.name.clone()]),
+        ExprKind::Grouping(inner) => list("group", [expr(inner)]),
+        ExprKind::Literal(literal) => match literal {
+            Literal::Nil => String::from("nil"),
+            Literal::Bool(b) => b.to_string(),
+            Literal::Number(n) => format!("{:?}", n),
+            Literal::String(s) => format!("{:?}", s),
+        },
+        ExprKind::Logical { left, op, right } => list(op.lexeme(), [expr(left), expr(right)]),
+        ExprKind::Set {
+            object,
+            name,
+            value,
+        } => list(
+            "=",
+            [list(".", [expr(object), name.name.clone()]), expr(value)],
+        ),
+        ExprKind::Super { method } => list("super", [method.name.clone()]),
+        ExprKind::This => String::from("this"),
+        ExprKind::Unary { op, operand } => list(op.lexeme(), [expr(operand)]),
+        ExprKind::Variable(name) => name.name.clone(),
+    }
+}
+
+fn function(f: &Function) -> String {
+    let signature = list(
+        &f.name.name,
+        f.params.iter().map(|param| param.name.clone()),
+    );
+    list(
+        "def",
+        [signature].into_iter().chain(f.body.iter().map(stmt)),
+    )
+}
+
+fn list(head: &str, items: impl IntoIterator<Item = String>) -> String {
+    let mut output = format!("({}", head);
+    for item in items {
+        output.push(' ');
+        output.push_str(&item);
+    }
+    output.push(')');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::parser::parse;
+
+    fn assert_prints(expected: &str, source: &str) {
+        let statements = parse(source).expect("parse failed");
+        assert_eq!(expected, print(&statements));
+    }
+
+    #[test]
+    fn it_prints_expressions() {
+        assert_prints("(* (- 123.0) (group 45.67))", "-123 * (45.67);");
+        assert_prints("(+ 1.0 (* 2.0 3.0))", "1 + 2 * 3;");
+        assert_prints("(== a (< b c))", "a == b < c;");
+        assert_prints("(or a (and b (! c)))", "a or b and !c;");
+        assert_prints("(= a (= b \"hi\\n\"))", "a = b = \"hi\n\";");
+        assert_prints("(call (. (call f 1.0 nil) g) true)", "f(1, nil).g(true);");
+        assert_prints("(= (. (. a b) c) false)", "a.b.c = false;");
+        assert_prints("(call (super init) this)", "super.init(this);");
+    }
+
+    #[test]
+    fn it_prints_statements() {
+        assert_prints(
+            "(var a)\n(var b 1.0)\n(print (+ a b))",
+            "var a; var b = 1; print a + b;",
+        );
+        assert_prints(
+            "(if (< a 1.0) (block (print a)) (if b (return) (return 2.0)))",
+            "if (a < 1) { print a; } else if (b) return; else return 2;",
+        );
+        assert_prints(
+            "(def (add a b) (return (+ a b)))",
+            "fun add(a, b) { return a + b; }",
+        );
+        assert_prints("(def (f))", "fun f() {}");
+        assert_prints(
+            "(class BostonCream (< Doughnut) (def (cook) (print this)))",
+            "class BostonCream < Doughnut { cook() { print this; } }",
+        );
+        assert_prints("(class A)", "class A {}");
+    }
+
+    #[test]
+    fn it_prints_desugared_for_loops() {
+        assert_prints(
+            "(block (var i 0.0) (while (< i 10.0) (block (block (print i)) (= i (+ i 1.0)))))",
+            "for (var i = 0; i < 10; i = i + 1) { print i; }",
+        );
+        assert_prints("(while true (print 1.0))", "for (;;) print 1;");
+    }
+}
@@ -0,0 +1,314 @@
+use crate::lox::chunk::Chunk;
+use crate::lox::fnv::FnvBuildHasher;
+use crate::lox::interner::{Interner, Symbol};
+use crate::lox::value::{Unpacked, Value};
+use std::collections::HashMap;
+
+// Layout of a .loxc file. All integers are little-endian.
+//
+//     magic      "LOXC"
+//     version    u16
+//     strings    u32 count, then per string: u32 byte length, UTF-8 bytes
+//     constants  u32 count, then per constant: u8 tag and its payload
+//     lines      u32 count, then per run: u32 line, u32 byte length
+//     code       u32 byte length, then the bytes
+//
+// Strings are saved by content and interned again on load, so the symbols in
+// a loaded chunk belong to the interner it was loaded into.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Fails for a chunk that wasn't compiled against interner, or that is too
+// big for the format's lengths.
+pub fn save(chunk: &Chunk, interner: &Interner) -> Result<Vec<u8>, String> {
+    let mut strings: Vec<Symbol> = vec![];
+    let mut string_indexes: HashMap<Symbol, u32, FnvBuildHasher> = HashMap::default();
+    let mut constants = vec![];
+    for value in chunk.constants().iter() {
+        match value.unpack() {
+            Unpacked::Nil => constants.push(TAG_NIL),
+            Unpacked::Bool(false) => constants.push(TAG_FALSE),
+            Unpacked::Bool(true) => constants.push(TAG_TRUE),
+            Unpacked::Number(n) => {
+                constants.push(TAG_NUMBER);
+                constants.extend(n.to_bits().to_le_bytes());
+            }
+            Unpacked::String(s) => {
+                let index = *string_indexes.entry(s).or_insert_with(|| {
+                    strings.push(s);
+                    strings.len() as u32 - 1
+                });
+                constants.push(TAG_STRING);
+                constants.extend(index.to_le_bytes());
+            }
+        }
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+
+    write_length(&mut bytes, strings.len())?;
+    for symbol in strings {
+        let s = interner
+            .lookup(symbol)
+            .ok_or_else(|| format!("String {} is not interned.", symbol))?;
+        write_length(&mut bytes, s.len())?;
+        bytes.extend(s.as_bytes());
+    }
+
+    write_length(&mut bytes, chunk.constants().len())?;
+    bytes.extend(constants);
+
+    let runs = chunk.line_runs();
+    write_length(&mut bytes, runs.len())?;
+    for (line, length) in runs {
+        write_length(&mut bytes, line)?;
+        write_length(&mut bytes, length)?;
+    }
+
+    write_length(&mut bytes, chunk.code.len())?;
+    bytes.extend(chunk.code.iter());
+    Ok(bytes)
+}
+
+pub fn load(bytes: &[u8], interner: &mut Interner) -> Result<Chunk, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(String::from("Not a compiled Lox file."));
+    }
+    let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {} (expected {}).",
+            version, VERSION
+        ));
+    }
+
+    let string_count = reader.u32()?;
+    let mut strings = vec![];
+    for _ in 0..string_count {
+        let length = reader.u32()? as usize;
+        let s = std::str::from_utf8(reader.take(length)?)
+            .map_err(|_| String::from("String constant is not valid UTF-8."))?;
+        strings.push(interner.intern(s));
+    }
+
+    let constant_count = reader.u32()?;
+    let mut constants = vec![];
+    for _ in 0..constant_count {
+        let value = match reader.u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_FALSE => Value::Bool(false),
+            TAG_TRUE => Value::Bool(true),
+            TAG_NUMBER => Value::Number(f64::from_bits(reader.u64()?)),
+            TAG_STRING => {
+                let index = reader.u32()? as usize;
+                match strings.get(index) {
+                    Some(symbol) => Value::String(*symbol),
+                    None => return Err(format!("String index {} is out of range.", index)),
+                }
+            }
+            tag => return Err(format!("Unknown constant tag {}.", tag)),
+        };
+        constants.push(value);
+    }
+
+    let run_count = reader.u32()?;
+    let mut runs = vec![];
+    for _ in 0..run_count {
+        runs.push((reader.u32()? as usize, reader.u32()? as usize));
+    }
+
+    let code_length = reader.u32()? as usize;
+    let code = reader.take(code_length)?.to_vec();
+    if runs.iter().map(|(_, length)| length).sum::<usize>() != code.len() {
+        return Err(String::from("Line table does not match the code."));
+    }
+    if reader.offset != bytes.len() {
+        return Err(String::from("Unexpected data after the code."));
+    }
+
+    Ok(Chunk::from_parts(code, &runs, constants))
+}
+
+fn write_length(bytes: &mut Vec<u8>, length: usize) -> Result<(), String> {
+    let length = u32::try_from(length).map_err(|_| String::from("Chunk is too large to save."))?;
+    bytes.extend(length.to_le_bytes());
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.saturating_add(length);
+        match self.bytes.get(self.offset..end) {
+            Some(bytes) => {
+                self.offset = end;
+                Ok(bytes)
+            }
+            None => Err(String::from("Unexpected end of file.")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::compiler::compile;
+    use crate::lox::vm::VM;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PROGRAMS: [&str; 4] = [
+        "print 1 + 2 * 3;",
+        "var a = \"st\" + \"ri\" + \"ng\"; print a + \"!\";",
+        "var b = true; b = !b == nil; print b; print -0; print 0 / 0;",
+        "print 1; print 1.5;\n\nprint \"x\";\nprint 2 >= 3;",
+    ];
+
+    fn compile_and_save(source: &str, interner: &Rc<RefCell<Interner>>) -> (Chunk, Vec<u8>) {
+        let chunk = compile(source, interner.clone()).expect("compile failed");
+        let bytes = save(&chunk, &interner.borrow()).expect("save failed");
+        (chunk, bytes)
+    }
+
+    #[test]
+    fn it_round_trips_chunks() {
+        for source in PROGRAMS {
+            let interner = Rc::new(RefCell::new(Interner::default()));
+            let (chunk, bytes) = compile_and_save(source, &interner);
+            let loaded = load(&bytes, &mut interner.borrow_mut()).expect("load failed");
+
+            assert_eq!(chunk.disassemble("code"), loaded.disassemble("code"));
+            assert_eq!(chunk.code, loaded.code);
+            // Debug output, since NaN constants are not equal to themselves.
+            assert_eq!(
+                format!("{:?}", chunk.constants()),
+                format!("{:?}", loaded.constants())
+            );
+            assert_eq!(chunk.line_runs(), loaded.line_runs());
+        }
+    }
+
+    #[test]
+    fn it_round_trips_long_constants() {
+        let source: String = (0..300).map(|n| format!("print {}.5;\n", n)).collect();
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let (chunk, bytes) = compile_and_save(source.as_str(), &interner);
+        let loaded = load(&bytes, &mut interner.borrow_mut()).expect("load failed");
+
+        assert_eq!(chunk.disassemble("code"), loaded.disassemble("code"));
+    }
+
+    #[test]
+    fn it_remaps_strings_into_another_interner() {
+        for source in PROGRAMS {
+            let interner = Rc::new(RefCell::new(Interner::default()));
+            let (_, bytes) = compile_and_save(source, &interner);
+
+            let mut expected = VM::new();
+            let mut actual = VM::new();
+            actual.interner().borrow_mut().intern("unrelated");
+            let chunk = load(&bytes, &mut actual.interner().borrow_mut()).expect("load failed");
+
+            assert_eq!(expected.interpret(source), actual.interpret_chunk(chunk));
+        }
+    }
+
+    #[test]
+    fn it_refuses_to_save_strings_from_another_interner() {
+        let chunk = compile("print \"a\";", Rc::new(RefCell::new(Interner::default())))
+            .expect("compile failed");
+        assert_eq!(
+            Err(String::from("String 0 is not interned.")),
+            save(&chunk, &Interner::default())
+        );
+    }
+
+    #[test]
+    fn it_keeps_equal_constants_at_their_indexes() {
+        let mut chunk = Chunk::new();
+        chunk.replace_constants(vec![Value::Number(1.0), Value::Number(1.0)]);
+        let bytes = save(&chunk, &Interner::default()).expect("save failed");
+        let mut loaded = load(&bytes, &mut Interner::default()).expect("load failed");
+
+        assert_eq!(chunk.constants(), loaded.constants());
+        assert_eq!(0, loaded.add_constant(Value::Number(1.0)));
+    }
+
+    fn load_err(bytes: &[u8]) -> String {
+        load(bytes, &mut Interner::default()).expect_err("loaded a bad file")
+    }
+
+    #[test]
+    fn it_rejects_files_that_are_not_bytecode() {
+        assert_eq!("Not a compiled Lox file.", load_err(b"print 1;"));
+        assert_eq!("Not a compiled Lox file.", load_err(b""));
+    }
+
+    #[test]
+    fn it_rejects_other_versions() {
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let (_, mut bytes) = compile_and_save("print 1;", &interner);
+        bytes[4] = 2;
+        bytes[5] = 0;
+
+        assert_eq!(
+            "Unsupported bytecode version 2 (expected 1).",
+            load_err(&bytes)
+        );
+    }
+
+    #[test]
+    fn it_rejects_corrupted_files() {
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let (_, bytes) = compile_and_save("print \"a\" + \"b\"; print 1.5;", &interner);
+
+        for length in 4..bytes.len() {
+            assert_eq!("Unexpected end of file.", load_err(&bytes[..length]));
+        }
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!("Unexpected data after the code.", load_err(&extra));
+
+        // The first constant's tag comes after the one string, "ab".
+        let mut tag = bytes.clone();
+        tag[4 + 2 + 4 + 4 + 2 + 4] = 9;
+        assert_eq!("Unknown constant tag 9.", load_err(&tag));
+
+        let mut string = bytes.clone();
+        string[4 + 2 + 4 + 4] = 0xff;
+        assert_eq!("String constant is not valid UTF-8.", load_err(&string));
+    }
+}
@@ -1,16 +1,25 @@
-use crate::lox::value::Value;
+use crate::lox::fnv::FnvBuildHasher;
+use crate::lox::interner::{Interner, Symbol};
+use crate::lox::value::{Unpacked, Value};
+use std::collections::HashMap;
 use std::fmt::Write;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OpCode {
     Constant(usize),
+    SmallInt(i8),
     Nil,
     True,
     False,
+    GetGlobal(usize),
     DefineGlobal(usize),
+    SetGlobal(usize),
     Equal,
+    NotEqual,
     Greater,
+    GreaterEqual,
     Less,
+    LessEqual,
     Add,
     Subtract,
     Multiply,
@@ -22,6 +31,68 @@ pub enum OpCode {
     Return,
 }
 
+impl OpCode {
+    // Every opcode, with operands of zero.
+    pub const ALL: [OpCode; 23] = [
+        OpCode::Constant(0),
+        OpCode::SmallInt(0),
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::GetGlobal(0),
+        OpCode::DefineGlobal(0),
+        OpCode::SetGlobal(0),
+        OpCode::Equal,
+        OpCode::NotEqual,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Pop,
+        OpCode::Print,
+        OpCode::Return,
+    ];
+
+    // The opcode listings call name, like OP_ADD, with operands of zero.
+    pub fn named(name: &str) -> Option<OpCode> {
+        OpCode::ALL.into_iter().find(|code| code.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::Constant(_) => "OP_CONSTANT",
+            OpCode::SmallInt(_) => "OP_SMALL_INT",
+            OpCode::Nil => "OP_NIL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::GetGlobal(_) => "OP_GET_GLOBAL",
+            OpCode::DefineGlobal(_) => "OP_DEFINE_GLOBAL",
+            OpCode::SetGlobal(_) => "OP_SET_GLOBAL",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::Less => "OP_LESS",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Pop => "OP_POP",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Return => "OP_RETURN",
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Instruction {
     pub code: OpCode,
@@ -34,88 +105,484 @@ impl Instruction {
     }
 }
 
+// Byte values of the opcodes in Chunk::code. Opcodes that take a constant
+// index have a _LONG form with a 24 bit little-endian operand, used once a
+// chunk has more than 256 constants.
+pub const OP_CONSTANT: u8 = 0;
+pub const OP_CONSTANT_LONG: u8 = 1;
+pub const OP_SMALL_INT: u8 = 2;
+pub const OP_NIL: u8 = 3;
+pub const OP_TRUE: u8 = 4;
+pub const OP_FALSE: u8 = 5;
+pub const OP_GET_GLOBAL: u8 = 6;
+pub const OP_GET_GLOBAL_LONG: u8 = 7;
+pub const OP_DEFINE_GLOBAL: u8 = 8;
+pub const OP_DEFINE_GLOBAL_LONG: u8 = 9;
+pub const OP_SET_GLOBAL: u8 = 10;
+pub const OP_SET_GLOBAL_LONG: u8 = 11;
+pub const OP_EQUAL: u8 = 12;
+pub const OP_NOT_EQUAL: u8 = 13;
+pub const OP_GREATER: u8 = 14;
+pub const OP_GREATER_EQUAL: u8 = 15;
+pub const OP_LESS: u8 = 16;
+pub const OP_LESS_EQUAL: u8 = 17;
+pub const OP_ADD: u8 = 18;
+pub const OP_SUBTRACT: u8 = 19;
+pub const OP_MULTIPLY: u8 = 20;
+pub const OP_DIVIDE: u8 = 21;
+pub const OP_NOT: u8 = 22;
+pub const OP_NEGATE: u8 = 23;
+pub const OP_POP: u8 = 24;
+pub const OP_PRINT: u8 = 25;
+pub const OP_RETURN: u8 = 26;
+
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+// Listings number instructions rather than bytes, and show a line only when
+// it changes. This has the number of the instruction at each offset and the
+// line of the one before it, worked out once for a chunk so that DEBUG=1
+// doesn't decode the code from the start for every instruction it runs.
+pub struct Listing {
+    positions: Vec<(usize, Option<usize>)>,
+    lines: Vec<usize>,
+}
+
+impl Listing {
+    fn position(&self, offset: usize) -> (usize, Option<usize>) {
+        let last = self.positions.len() - 1;
+        self.positions[offset.min(last)]
+    }
+}
+
+// A run of bytes in Chunk::code that all came from the same line.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct LineRun {
+    line: usize,
+    length: usize,
+}
+
+// What a constant is deduplicated by. Numbers compare by bit pattern, so 0
+// and -0 stay apart and a NaN is shared with an identical NaN.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Bool(bool),
+    Nil,
+    Number(u64),
+    String(Symbol),
+}
+
+impl From<&Value> for ConstantKey {
+    fn from(value: &Value) -> Self {
+        match value.unpack() {
+            Unpacked::Bool(b) => ConstantKey::Bool(b),
+            Unpacked::Nil => ConstantKey::Nil,
+            Unpacked::Number(n) => ConstantKey::Number(n.to_bits()),
+            Unpacked::String(s) => ConstantKey::String(s),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Chunk {
-    pub instructions: Vec<Instruction>,
-    pub constants: Vec<Value>,
+    pub code: Vec<u8>,
+    lines: Vec<LineRun>,
+    constants: Vec<Value>,
+    constant_indexes: HashMap<ConstantKey, usize, FnvBuildHasher>,
+}
+
+// How a constant is written in listings. Strings are quoted and escaped the
+// way Rust writes string literals, when there is an interner to look them up.
+// Without one they are shown by symbol in a form no value is written in, so
+// that a listing without its strings can't be assembled into other values.
+pub fn format_value(value: &Value, interner: Option<&Interner>) -> String {
+    let Unpacked::String(symbol) = value.unpack() else {
+        return value.to_string();
+    };
+    match interner.and_then(|interner| interner.lookup(symbol)) {
+        Some(s) => format!("{:?}", s),
+        None => format!("<string {}>", symbol),
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
-            instructions: vec![],
+            code: vec![],
+            lines: vec![],
             constants: vec![],
+            constant_indexes: HashMap::default(),
         }
     }
 
+    // Rebuilds a saved chunk, with its constants at the indexes they were
+    // saved with.
+    pub fn from_parts(code: Vec<u8>, line_runs: &[(usize, usize)], constants: Vec<Value>) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.code = code;
+        for &(line, length) in line_runs {
+            chunk.add_line(line, length);
+        }
+        chunk.replace_constants(constants);
+        chunk
+    }
+
+    // The line table as (line, number of bytes) runs, in code order.
+    pub fn line_runs(&self) -> Vec<(usize, usize)> {
+        self.lines
+            .iter()
+            .map(|run| (run.line, run.length))
+            .collect()
+    }
+
+    // The line of every byte of code, for looking lines up while running.
+    pub fn lines_by_offset(&self) -> Vec<usize> {
+        let mut lines = Vec::with_capacity(self.code.len());
+        for run in self.lines.iter() {
+            lines.extend(std::iter::repeat_n(run.line, run.length));
+        }
+        lines
+    }
+
     pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::from(&value);
+        if let Some(constant) = self.constant_indexes.get(&key) {
+            return *constant;
+        }
         let constant = self.constants.len();
         self.constants.push(value);
+        self.constant_indexes.insert(key, constant);
         constant
     }
 
-    pub fn write_chunk(&mut self, code: OpCode, line: usize) {
-        self.instructions.push(Instruction::new(code, line));
+    // Drops constants from len onwards. Nothing may still refer to them.
+    pub fn truncate_constants(&mut self, len: usize) {
+        for value in self.constants.drain(len.min(self.constants.len())..) {
+            self.constant_indexes.remove(&ConstantKey::from(&value));
+        }
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    // Constants keep their indexes, even if two of them are equal, and
+    // add_constant finds the first of those.
+    pub fn replace_constants(&mut self, constants: Vec<Value>) {
+        self.constant_indexes.clear();
+        for (index, value) in constants.iter().enumerate() {
+            self.constant_indexes
+                .entry(ConstantKey::from(value))
+                .or_insert(index);
+        }
+        self.constants = constants;
+    }
+
+    pub fn write_chunk(&mut self, code: OpCode, line: usize) -> Result<(), String> {
+        let start = self.code.len();
+        match code {
+            OpCode::Constant(c) => self.write_indexed(OP_CONSTANT, OP_CONSTANT_LONG, c)?,
+            OpCode::SmallInt(n) => self.code.extend([OP_SMALL_INT, n as u8]),
+            OpCode::Nil => self.code.push(OP_NIL),
+            OpCode::True => self.code.push(OP_TRUE),
+            OpCode::False => self.code.push(OP_FALSE),
+            OpCode::GetGlobal(c) => self.write_indexed(OP_GET_GLOBAL, OP_GET_GLOBAL_LONG, c)?,
+            OpCode::DefineGlobal(c) => {
+                self.write_indexed(OP_DEFINE_GLOBAL, OP_DEFINE_GLOBAL_LONG, c)?
+            }
+            OpCode::SetGlobal(c) => self.write_indexed(OP_SET_GLOBAL, OP_SET_GLOBAL_LONG, c)?,
+            OpCode::Equal => self.code.push(OP_EQUAL),
+            OpCode::NotEqual => self.code.push(OP_NOT_EQUAL),
+            OpCode::Greater => self.code.push(OP_GREATER),
+            OpCode::GreaterEqual => self.code.push(OP_GREATER_EQUAL),
+            OpCode::Less => self.code.push(OP_LESS),
+            OpCode::LessEqual => self.code.push(OP_LESS_EQUAL),
+            OpCode::Add => self.code.push(OP_ADD),
+            OpCode::Subtract => self.code.push(OP_SUBTRACT),
+            OpCode::Multiply => self.code.push(OP_MULTIPLY),
+            OpCode::Divide => self.code.push(OP_DIVIDE),
+            OpCode::Not => self.code.push(OP_NOT),
+            OpCode::Negate => self.code.push(OP_NEGATE),
+            OpCode::Pop => self.code.push(OP_POP),
+            OpCode::Print => self.code.push(OP_PRINT),
+            OpCode::Return => self.code.push(OP_RETURN),
+        }
+        self.add_line(line, self.code.len() - start);
+        Ok(())
+    }
+
+    // Indexes take one byte, or three after the long form of the opcode, so
+    // there is no way to write one from MAX_CONSTANTS on.
+    fn write_indexed(&mut self, short: u8, long: u8, index: usize) -> Result<(), String> {
+        if index >= MAX_CONSTANTS {
+            return Err(format!("Constant index {} is out of range.", index));
+        }
+        if index <= u8::MAX as usize {
+            self.code.extend([short, index as u8]);
+        } else {
+            let [a, b, c, _] = (index as u32).to_le_bytes();
+            self.code.extend([long, a, b, c]);
+        }
+        Ok(())
+    }
+
+    fn add_line(&mut self, line: usize, length: usize) {
+        match self.lines.last_mut() {
+            Some(run) if run.line == line => run.length += length,
+            _ => self.lines.push(LineRun { line, length }),
+        }
+    }
+
+    // Reads the instruction at offset, returning it with the offset of the
+    // next one, or None if the code there is not a whole instruction.
+    pub fn decode(&self, offset: usize) -> Option<(OpCode, usize)> {
+        let byte = |i: usize| self.code.get(offset + i).copied();
+        let short = || Some((byte(1)? as usize, offset + 2));
+        let long = || {
+            let index = u32::from_le_bytes([byte(1)?, byte(2)?, byte(3)?, 0]);
+            Some((index as usize, offset + 4))
+        };
+
+        let simple = |code: OpCode| Some((code, offset + 1));
+        match byte(0)? {
+            OP_CONSTANT => short().map(|(c, next)| (OpCode::Constant(c), next)),
+            OP_CONSTANT_LONG => long().map(|(c, next)| (OpCode::Constant(c), next)),
+            OP_SMALL_INT => Some((OpCode::SmallInt(byte(1)? as i8), offset + 2)),
+            OP_NIL => simple(OpCode::Nil),
+            OP_TRUE => simple(OpCode::True),
+            OP_FALSE => simple(OpCode::False),
+            OP_GET_GLOBAL => short().map(|(c, next)| (OpCode::GetGlobal(c), next)),
+            OP_GET_GLOBAL_LONG => long().map(|(c, next)| (OpCode::GetGlobal(c), next)),
+            OP_DEFINE_GLOBAL => short().map(|(c, next)| (OpCode::DefineGlobal(c), next)),
+            OP_DEFINE_GLOBAL_LONG => long().map(|(c, next)| (OpCode::DefineGlobal(c), next)),
+            OP_SET_GLOBAL => short().map(|(c, next)| (OpCode::SetGlobal(c), next)),
+            OP_SET_GLOBAL_LONG => long().map(|(c, next)| (OpCode::SetGlobal(c), next)),
+            OP_EQUAL => simple(OpCode::Equal),
+            OP_NOT_EQUAL => simple(OpCode::NotEqual),
+            OP_GREATER => simple(OpCode::Greater),
+            OP_GREATER_EQUAL => simple(OpCode::GreaterEqual),
+            OP_LESS => simple(OpCode::Less),
+            OP_LESS_EQUAL => simple(OpCode::LessEqual),
+            OP_ADD => simple(OpCode::Add),
+            OP_SUBTRACT => simple(OpCode::Subtract),
+            OP_MULTIPLY => simple(OpCode::Multiply),
+            OP_DIVIDE => simple(OpCode::Divide),
+            OP_NOT => simple(OpCode::Not),
+            OP_NEGATE => simple(OpCode::Negate),
+            OP_POP => simple(OpCode::Pop),
+            OP_PRINT => simple(OpCode::Print),
+            OP_RETURN => simple(OpCode::Return),
+            _ => None,
+        }
+    }
+
+    pub fn line(&self, offset: usize) -> usize {
+        let mut end = 0;
+        for run in self.lines.iter() {
+            end += run.length;
+            if offset < end {
+                return run.line;
+            }
+        }
+        self.lines.last().map_or(0, |run| run.line)
+    }
+
+    // Drops all code from offset onwards.
+    pub fn truncate(&mut self, offset: usize) {
+        self.code.truncate(offset);
+
+        let mut end = 0;
+        let mut keep = 0;
+        for run in self.lines.iter_mut() {
+            if end >= offset {
+                break;
+            }
+            run.length = run.length.min(offset - end);
+            end += run.length;
+            keep += 1;
+        }
+        self.lines.truncate(keep);
+    }
+
+    pub fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        let mut runs = self.lines.iter();
+        let mut run = LineRun { line: 0, length: 0 };
+        while let Some((code, next)) = self.decode(offset) {
+            while run.length == 0 {
+                match runs.next() {
+                    Some(r) => run = *r,
+                    None => break,
+                }
+            }
+            instructions.push(Instruction::new(code, run.line));
+            run.length = run.length.saturating_sub(next - offset);
+            offset = next;
+        }
+        instructions
+    }
+
+    pub fn replace_instructions(&mut self, instructions: &[Instruction]) -> Result<(), String> {
+        self.code.clear();
+        self.lines.clear();
+        for instruction in instructions {
+            self.write_chunk(instruction.code, instruction.line)?;
+        }
+        Ok(())
     }
 
     pub fn disassemble(&self, name: &str) -> Result<String, std::fmt::Error> {
+        self.disassemble_with(name, None)
+    }
+
+    // With an interner, string constants are shown as quoted text rather than
+    // as their symbol.
+    pub fn disassemble_with(
+        &self,
+        name: &str,
+        interner: Option<&Interner>,
+    ) -> Result<String, std::fmt::Error> {
+        self.disassemble_lines(name, interner, "")
+    }
+
+    // Like disassemble_with, with each line of source shown as a comment
+    // above the instructions compiled from it.
+    pub fn disassemble_source(
+        &self,
+        name: &str,
+        source: &str,
+        interner: &Interner,
+    ) -> Result<String, std::fmt::Error> {
+        self.disassemble_lines(name, Some(interner), source)
+    }
+
+    fn disassemble_lines(
+        &self,
+        name: &str,
+        interner: Option<&Interner>,
+        source: &str,
+    ) -> Result<String, std::fmt::Error> {
         let mut output = String::from("");
         writeln!(output, "== {} ==", name)?;
-        for (offset, instruction) in self.instructions.iter().enumerate() {
+        let source: Vec<&str> = source.lines().collect();
+        let mut shown = 0;
+        let mut previous_line = None;
+        for (index, instruction) in self.instructions().iter().enumerate() {
+            while shown < instruction.line.min(source.len()) {
+                let text = format!("; {:4} | {}", shown + 1, source[shown]);
+                writeln!(output, "{}", text.trim_end())?;
+                shown += 1;
+            }
             write!(
                 output,
                 "{}",
-                self.disassemble_instruction(instruction, offset)?
+                self.format_instruction(instruction, index, previous_line, interner)?
             )?;
+            previous_line = Some(instruction.line);
         }
         Ok(output)
     }
 
+    // Where each offset falls in a listing, for listing instructions one at
+    // a time.
+    pub fn listing(&self) -> Listing {
+        let lines = self.lines_by_offset();
+        let line = |offset: usize| {
+            lines
+                .get(offset)
+                .copied()
+                .unwrap_or_else(|| self.line(offset))
+        };
+        let mut positions = Vec::with_capacity(self.code.len() + 1);
+        let mut position = (0, None);
+        let mut offset = 0;
+        while let Some((_, next)) = self.decode(offset) {
+            positions.push(position);
+            position = (position.0 + 1, Some(line(offset)));
+            positions.resize(next, position);
+            offset = next;
+        }
+        positions.resize(self.code.len() + 1, position);
+        Listing { positions, lines }
+    }
+
     pub fn disassemble_instruction(
         &self,
-        instruction: &Instruction,
         offset: usize,
+        listing: &Listing,
+    ) -> Result<String, std::fmt::Error> {
+        let (index, previous_line) = listing.position(offset);
+        match self.decode(offset) {
+            Some((code, _)) => {
+                let line = listing.lines.get(offset).copied();
+                let instruction = Instruction::new(code, line.unwrap_or_else(|| self.line(offset)));
+                self.format_instruction(&instruction, index, previous_line, None)
+            }
+            None => Ok(format!("{:04} <invalid>\n", index)),
+        }
+    }
+
+    fn format_instruction(
+        &self,
+        instruction: &Instruction,
+        index: usize,
+        previous_line: Option<usize>,
+        interner: Option<&Interner>,
     ) -> Result<String, std::fmt::Error> {
         let mut output = String::new();
-        write!(output, "{:04} ", offset)?;
+        write!(output, "{:04} ", index)?;
 
-        if offset > 0 && instruction.line == self.instructions.get(offset - 1).unwrap().line {
+        if previous_line == Some(instruction.line) {
             write!(output, "   | ")?;
         } else {
             write!(output, "{:4} ", instruction.line)?;
         }
 
-        output.push_str(self.disassemble_chunk(instruction)?.as_str());
+        output.push_str(self.disassemble_chunk(instruction, interner)?.as_str());
 
         Ok(output)
     }
 
-    fn disassemble_chunk(&self, instruction: &Instruction) -> Result<String, std::fmt::Error> {
-        Ok(match instruction.code {
-            OpCode::Constant(c) => self.constant_instruction("OP_CONSTANT", c),
-            OpCode::Nil => self.simple_instruction("OP_NIL"),
-            OpCode::True => self.simple_instruction("OP_TRUE"),
-            OpCode::False => self.simple_instruction("OP_FALSE"),
-            OpCode::DefineGlobal(c) => self.constant_instruction("OP_DEFINE_GLOBAL", c),
-            OpCode::Equal => self.simple_instruction("OP_EQUAL"),
-            OpCode::Greater => self.simple_instruction("OP_GREATER"),
-            OpCode::Less => self.simple_instruction("OP_LESS"),
-            OpCode::Add => self.simple_instruction("OP_ADD"),
-            OpCode::Subtract => self.simple_instruction("OP_SUBTRACT"),
-            OpCode::Multiply => self.simple_instruction("OP_MULTIPLY"),
-            OpCode::Divide => self.simple_instruction("OP_DIVIDE"),
-            OpCode::Not => self.simple_instruction("OP_NOT"),
-            OpCode::Negate => self.simple_instruction("OP_NEGATE"),
-            OpCode::Pop => self.simple_instruction("OP_POP"),
-            OpCode::Print => self.simple_instruction("OP_PRINT"),
-            OpCode::Return => self.simple_instruction("OP_RETURN"),
-        }?)
-    }
-
-    fn constant_instruction(&self, name: &str, constant: usize) -> Result<String, std::fmt::Error> {
+    fn disassemble_chunk(
+        &self,
+        instruction: &Instruction,
+        interner: Option<&Interner>,
+    ) -> Result<String, std::fmt::Error> {
+        let name = instruction.code.name();
+        match instruction.code {
+            OpCode::Constant(c)
+            | OpCode::GetGlobal(c)
+            | OpCode::DefineGlobal(c)
+            | OpCode::SetGlobal(c) => self.constant_instruction(name, c, interner),
+            OpCode::SmallInt(n) => self.immediate_instruction(name, n),
+            _ => self.simple_instruction(name),
+        }
+    }
+
+    fn constant_instruction(
+        &self,
+        name: &str,
+        constant: usize,
+        interner: Option<&Interner>,
+    ) -> Result<String, std::fmt::Error> {
         let mut output = String::from("");
         write!(output, "{:<16} {:4} '", name, constant)?;
-        writeln!(output, "{}", self.constants.get(constant).unwrap())?;
+        match self.constants.get(constant) {
+            Some(value) => writeln!(output, "{}", format_value(value, interner))?,
+            None => writeln!(output, "<invalid>")?,
+        }
+        Ok(output)
+    }
+
+    fn immediate_instruction(&self, name: &str, operand: i8) -> Result<String, std::fmt::Error> {
+        let mut output = String::from("");
+        writeln!(output, "{:<16} {:4}", name, operand)?;
         Ok(output)
     }
 
@@ -128,9 +595,11 @@ impl Chunk {
 
 #[cfg(test)]
 mod tests {
-    use crate::lox::chunk::Chunk;
-    use crate::lox::chunk::OpCode;
-    use crate::lox::value::Value;
+    use super::*;
+    use crate::lox::assembler::assemble;
+    use crate::lox::compiler::compile;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn it_dissasembles() {
@@ -141,10 +610,218 @@ mod tests {
         let mut chunk = Chunk::new();
 
         let constant = chunk.add_constant(Value::Number(1.2));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
-        chunk.write_chunk(OpCode::Return, 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
+        chunk.write_chunk(OpCode::Return, 123).unwrap();
 
         let actual = chunk.disassemble("test chunk").expect("Could not write");
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn it_finds_every_opcode_by_name() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Nil);
+        for code in OpCode::ALL {
+            assert_eq!(Some(code), OpCode::named(code.name()));
+            let offset = chunk.code.len();
+            chunk.write_chunk(code, 1).unwrap();
+            assert_eq!(Some(code), chunk.decode(offset).map(|(code, _)| code));
+        }
+        assert_eq!(None, OpCode::named("OP_CONSTANT_LONG"));
+        assert_eq!(None, OpCode::named("ADD"));
+    }
+
+    #[test]
+    fn it_dissasembles_long_constants_the_same_way() {
+        let expected = "== test chunk ==\n\
+                        0000    1 OP_CONSTANT       300 '300\n\
+                        0001    2 OP_RETURN\n";
+
+        let mut chunk = Chunk::new();
+        for n in 0..=300 {
+            chunk.add_constant(Value::Number(n as f64));
+        }
+        chunk.write_chunk(OpCode::Constant(300), 1).unwrap();
+        chunk.write_chunk(OpCode::Return, 2).unwrap();
+
+        let actual = chunk.disassemble("test chunk").expect("Could not write");
+        assert_eq!(expected, actual);
+        assert_eq!(
+            "0001    2 OP_RETURN\n",
+            chunk
+                .disassemble_instruction(4, &chunk.listing())
+                .expect("Could not write")
+        );
+    }
+
+    #[test]
+    fn it_lists_instructions_one_at_a_time_as_a_whole_listing_does() {
+        let source = "var a = 1;\nprint a;\n\nprint a + 2; a = 3;\nprint a;";
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let chunk = compile(source, interner).expect("compile failed");
+        let listing = chunk.listing();
+        let mut one_at_a_time = String::from("== code ==\n");
+        let mut offset = 0;
+        while let Some((_, next)) = chunk.decode(offset) {
+            one_at_a_time += &chunk.disassemble_instruction(offset, &listing).unwrap();
+            offset = next;
+        }
+        assert_eq!(chunk.disassemble("code"), Ok(one_at_a_time));
+        assert_eq!(
+            Ok(format!("{:04} <invalid>\n", chunk.instructions().len())),
+            chunk.disassemble_instruction(offset + 5, &listing)
+        );
+    }
+
+    #[test]
+    fn it_dissasembles_with_source_lines() {
+        let source = "var greeting = \"Hello\";\n\n// Say it.\nprint greeting;\n";
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let chunk = compile(source, interner.clone()).expect("compile failed");
+
+        let expected = "== hello.lox ==\n\
+                        ;    1 | var greeting = \"Hello\";\n\
+                        0000    1 OP_CONSTANT         0 '\"Hello\"\n\
+                        0001    | OP_DEFINE_GLOBAL    1 '\"greeting\"\n\
+                        ;    2 |\n\
+                        ;    3 | // Say it.\n\
+                        ;    4 | print greeting;\n\
+                        0002    4 OP_GET_GLOBAL       1 '\"greeting\"\n\
+                        0003    | OP_PRINT\n\
+                        0004    5 OP_RETURN\n";
+        let actual = chunk
+            .disassemble_source("hello.lox", source, &interner.borrow())
+            .expect("Could not write");
+        assert_eq!(expected, actual);
+
+        let assembled = assemble(&actual, &mut interner.borrow_mut()).expect("assemble failed");
+        assert_eq!(chunk.code, assembled.code);
+    }
+
+    #[test]
+    fn it_deduplicates_constants() {
+        let mut chunk = Chunk::new();
+
+        let one = chunk.add_constant(Value::Number(1.0));
+        let name = chunk.add_constant(Value::String(7));
+        let zero = chunk.add_constant(Value::Number(0.0));
+        let negative_zero = chunk.add_constant(Value::Number(-0.0));
+        let nan = chunk.add_constant(Value::Number(f64::NAN));
+
+        assert_eq!(one, chunk.add_constant(Value::Number(1.0)));
+        assert_eq!(name, chunk.add_constant(Value::String(7)));
+        assert_ne!(zero, negative_zero);
+        assert_eq!(nan, chunk.add_constant(Value::Number(f64::NAN)));
+        assert_eq!(
+            chunk.add_constant(Value::Nil),
+            chunk.add_constant(Value::Nil)
+        );
+        assert_ne!(
+            chunk.add_constant(Value::Bool(true)),
+            chunk.add_constant(Value::Bool(false))
+        );
+        assert_eq!(8, chunk.constants.len());
+    }
+
+    #[test]
+    fn it_forgets_truncated_constants() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::Number(1.0));
+        chunk.add_constant(Value::Number(2.0));
+        chunk.truncate_constants(1);
+
+        assert_eq!(1, chunk.add_constant(Value::Number(3.0)));
+        assert_eq!(2, chunk.add_constant(Value::Number(2.0)));
+        assert_eq!(0, chunk.add_constant(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn it_encodes_operands_in_bytes() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant(1), 1).unwrap();
+        chunk.write_chunk(OpCode::SmallInt(-1), 1).unwrap();
+        chunk.write_chunk(OpCode::Constant(0x123456), 2).unwrap();
+        chunk.write_chunk(OpCode::Return, 2).unwrap();
+
+        assert_eq!(
+            vec![
+                OP_CONSTANT,
+                1,
+                OP_SMALL_INT,
+                0xff,
+                OP_CONSTANT_LONG,
+                0x56,
+                0x34,
+                0x12,
+                OP_RETURN
+            ],
+            chunk.code
+        );
+        assert_eq!(
+            vec![
+                LineRun { line: 1, length: 4 },
+                LineRun { line: 2, length: 5 }
+            ],
+            chunk.lines
+        );
+    }
+
+    #[test]
+    fn it_refuses_indexes_it_cannot_encode() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Nil, 1).unwrap();
+
+        assert_eq!(
+            Err(String::from("Constant index 16777216 is out of range.")),
+            chunk.write_chunk(OpCode::GetGlobal(MAX_CONSTANTS), 2)
+        );
+        assert_eq!(vec![OP_NIL], chunk.code);
+        assert_eq!(vec![1], chunk.lines_by_offset());
+    }
+
+    #[test]
+    fn it_decodes_what_it_encodes() {
+        let instructions = vec![
+            Instruction::new(OpCode::Constant(300), 1),
+            Instruction::new(OpCode::SmallInt(-128), 1),
+            Instruction::new(OpCode::DefineGlobal(2), 2),
+            Instruction::new(OpCode::GetGlobal(70000), 3),
+            Instruction::new(OpCode::SetGlobal(255), 3),
+            Instruction::new(OpCode::Return, 3),
+        ];
+
+        let mut chunk = Chunk::new();
+        chunk.replace_instructions(&instructions).unwrap();
+
+        assert_eq!(instructions, chunk.instructions());
+    }
+
+    #[test]
+    fn it_truncates_code_and_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Nil, 1).unwrap();
+        chunk.write_chunk(OpCode::Constant(0), 2).unwrap();
+        chunk.write_chunk(OpCode::True, 2).unwrap();
+        chunk.truncate(3);
+        chunk.write_chunk(OpCode::False, 3).unwrap();
+
+        assert_eq!(
+            vec![
+                Instruction::new(OpCode::Nil, 1),
+                Instruction::new(OpCode::Constant(0), 2),
+                Instruction::new(OpCode::False, 3),
+            ],
+            chunk.instructions()
+        );
+        assert_eq!(vec![1, 2, 2, 3], chunk.lines_by_offset());
+    }
+
+    #[test]
+    fn it_rejects_partial_instructions() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant(0x123456), 1).unwrap();
+        chunk.code.pop();
+
+        assert_eq!(None, chunk.decode(0));
+    }
 }
@@ -1,17 +1,89 @@
-use crate::lox::chunk::{Chunk, OpCode};
+use crate::lox::chunk::{Chunk, OpCode, MAX_CONSTANTS};
 use crate::lox::interner::Interner;
+use crate::lox::optimizer;
 use crate::lox::scanner::{Scanner, Token, TokenType};
-use crate::lox::value::Value;
+use crate::lox::value::{Unpacked, Value};
 
 use std::cell::RefCell;
+use std::mem;
 use std::rc::Rc;
 
+// Expressions and statements nest on the Rust stack, so the front ends refuse
+// to nest deeper than this rather than overflow it. The AST parser takes the
+// most stack per level, and this leaves it room even on a test thread's 2MB.
+pub const MAX_NESTING: usize = 64;
+
+// Chains of operators, calls and property accesses make the AST taller
+// without the parser recursing, but walking it still recurses. A level of
+// chain takes far less stack than a level of nesting, so more are allowed.
+pub const MAX_CHAIN: usize = 128;
+
 pub struct Parser {
     scanner: Scanner,
     previous: Option<Token>,
     current: Option<Token>,
-    chunk: Chunk,
-    interner: Rc<RefCell<Interner>>,
+    emitter: Emitter,
+    operand: Mark,
+    depth: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompileOptions {
+    pub fold_constants: bool,
+    pub peephole: bool,
+}
+
+impl CompileOptions {
+    pub const O0: CompileOptions = CompileOptions {
+        fold_constants: false,
+        peephole: false,
+    };
+
+    pub const O1: CompileOptions = CompileOptions {
+        fold_constants: true,
+        peephole: true,
+    };
+
+    pub fn from_flag(flag: &str) -> Option<CompileOptions> {
+        match flag {
+            "-O0" => Some(Self::O0),
+            "-O1" => Some(Self::O1),
+            _ => None,
+        }
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self::O1
+    }
+}
+
+// Which compiler turns source into bytecode: this one, straight from
+// tokens, or the AST parser and ast_compiler. Both write the same chunk.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FrontEnd {
+    #[default]
+    SinglePass,
+    Ast,
+}
+
+impl FrontEnd {
+    pub fn from_name(name: &str) -> Option<FrontEnd> {
+        match name {
+            "single-pass" => Some(FrontEnd::SinglePass),
+            "ast" => Some(FrontEnd::Ast),
+            _ => None,
+        }
+    }
+}
+
+// Where the code for an operand starts, so that a literal operand can be
+// folded away by truncating back to it.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Mark {
+    code: usize,
+    constant: usize,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -57,7 +129,7 @@ impl Precedence {
     }
 }
 
-type ParseFn = fn(&mut Parser) -> Result<(), ParserError>;
+type ParseFn = fn(&mut Parser, bool) -> Result<(), ParserError>;
 
 #[derive(Copy, Clone)]
 struct ParseRule {
@@ -77,57 +149,269 @@ impl ParseRule {
 }
 
 pub fn compile(source: &str, interner: Rc<RefCell<Interner>>) -> Result<Chunk, ParserError> {
+    compile_with(source, interner, CompileOptions::default())
+}
+
+pub fn compile_with(
+    source: &str,
+    interner: Rc<RefCell<Interner>>,
+    options: CompileOptions,
+) -> Result<Chunk, ParserError> {
     let scanner = Scanner::new(source);
-    let mut parser = Parser::new(scanner, interner);
+    let mut parser = Parser::new(scanner, interner, options);
 
     parser.advance()?;
-    loop {
-        match parser.match_token(TokenType::EOF) {
-            Ok(true) => {}
-            Ok(false) => {
-                parser.declaration()?;
-                break;
-            }
-            Err(e) => return Err(e),
-        }
+    while !parser.match_token(TokenType::EOF)? {
+        parser.declaration()?;
     }
-    parser.end_complier();
+    let line = parser.line();
+    parser
+        .emitter
+        .finish(line)
+        .map_err(|message| parser.error(&message))
+}
 
-    Ok(parser.chunk)
+// Whole numbers that fit in an i8 are pushed with OP_SMALL_INT instead of
+// taking a slot in the constant pool. -0 is not one of them.
+fn small_int(n: f64) -> Option<i8> {
+    let whole = n.fract() == 0.0 && !(n == 0.0 && n.is_sign_negative());
+    if whole && n >= i8::MIN as f64 && n <= i8::MAX as f64 {
+        Some(n as i8)
+    } else {
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ParserError {
     pub token: Option<Token>,
     pub message: String,
+    // The byte range of the last token consumed, which places errors that
+    // have no token of their own.
+    pub previous: (usize, usize),
+}
+
+// Writes the bytecode for a compiler front end, folding constants as it
+// goes and running the peephole pass at the end. Errors are messages for
+// the front end to place.
+pub struct Emitter {
+    chunk: Chunk,
+    interner: Rc<RefCell<Interner>>,
+    options: CompileOptions,
+}
+
+impl Emitter {
+    pub fn new(interner: Rc<RefCell<Interner>>, options: CompileOptions) -> Emitter {
+        Emitter {
+            chunk: Chunk::new(),
+            interner,
+            options,
+        }
+    }
+
+    pub fn emit(&mut self, code: OpCode, line: usize) -> Result<(), String> {
+        self.chunk.write_chunk(code, line)
+    }
+
+    pub fn mark(&self) -> Mark {
+        Mark {
+            code: self.chunk.code.len(),
+            constant: self.chunk.constants().len(),
+        }
+    }
+
+    pub fn string(&mut self, s: &str) -> Value {
+        Value::String(self.interner.borrow_mut().intern(s))
+    }
+
+    pub fn make_constant(&mut self, value: Value) -> Result<usize, String> {
+        if self.chunk.constants().len() >= MAX_CONSTANTS {
+            return Err(String::from("Too many constants in one chunk."));
+        }
+        Ok(self.chunk.add_constant(value))
+    }
+
+    pub fn identifier_constant(&mut self, name: &str) -> Result<usize, String> {
+        let value = self.string(name);
+        self.make_constant(value)
+    }
+
+    pub fn constant(&mut self, value: Value, line: usize) -> Result<(), String> {
+        if let Unpacked::Number(n) = value.unpack() {
+            if let Some(i) = small_int(n) {
+                return self.emit(OpCode::SmallInt(i), line);
+            }
+        }
+        let constant = self.make_constant(value)?;
+        self.emit(OpCode::Constant(constant), line)
+    }
+
+    // Emits a unary operator for the operand written since operand, or
+    // folds the two into a literal.
+    pub fn unary(&mut self, code: OpCode, operand: Mark, line: usize) -> Result<(), String> {
+        if !self.fold_unary(&code, operand, line)? {
+            self.emit(code, line)?;
+        }
+        Ok(())
+    }
+
+    // Emits a binary operator for the operands written since left and right,
+    // or folds the three into a literal.
+    pub fn binary(
+        &mut self,
+        code: OpCode,
+        left: Mark,
+        right: Mark,
+        line: usize,
+    ) -> Result<(), String> {
+        if !self.fold_binary(&code, left, right, line)? {
+            self.emit(code, line)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self, line: usize) -> Result<Chunk, String> {
+        self.emit(OpCode::Return, line)?;
+        if self.options.peephole {
+            optimizer::optimize(&mut self.chunk)?;
+        }
+        Ok(mem::take(&mut self.chunk))
+    }
+
+    // The value of the code between start and end, if it is a single literal.
+    fn literal_value(&self, start: usize, end: usize) -> Option<Value> {
+        let (code, next) = self.chunk.decode(start)?;
+        if next != end {
+            return None;
+        }
+        match code {
+            OpCode::Constant(c) => Some(self.chunk.constants()[c]),
+            OpCode::SmallInt(n) => Some(Value::Number(n as f64)),
+            OpCode::Nil => Some(Value::Nil),
+            OpCode::True => Some(Value::Bool(true)),
+            OpCode::False => Some(Value::Bool(false)),
+            _ => None,
+        }
+    }
+
+    fn fold_unary(&mut self, code: &OpCode, operand: Mark, line: usize) -> Result<bool, String> {
+        if !self.options.fold_constants {
+            return Ok(false);
+        }
+        let end = self.chunk.code.len();
+        let Some(value) = self.literal_value(operand.code, end) else {
+            return Ok(false);
+        };
+
+        // Anything that would be a runtime error is left for the VM to report.
+        let folded = match (code, value.unpack()) {
+            (OpCode::Not, _) => Value::Bool(value.is_falsey()),
+            (OpCode::Negate, Unpacked::Number(n)) => Value::Number(-n),
+            _ => return Ok(false),
+        };
+
+        self.replace_with_literal(operand, folded, line)?;
+        Ok(true)
+    }
+
+    // >= and <= are folded as the negations the VM runs, so NaN compares the
+    // same way at compile time as it does at run time.
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    fn fold_binary(
+        &mut self,
+        code: &OpCode,
+        left: Mark,
+        right: Mark,
+        line: usize,
+    ) -> Result<bool, String> {
+        if !self.options.fold_constants {
+            return Ok(false);
+        }
+        let end = self.chunk.code.len();
+        let (Some(a), Some(b)) = (
+            self.literal_value(left.code, right.code),
+            self.literal_value(right.code, end),
+        ) else {
+            return Ok(false);
+        };
+
+        let folded = match (code, a.unpack(), b.unpack()) {
+            (OpCode::NotEqual, a, b) => Value::Bool(a != b),
+            (OpCode::Equal, a, b) => Value::Bool(a == b),
+            (OpCode::Greater, Unpacked::Number(a), Unpacked::Number(b)) => Value::Bool(a > b),
+            (OpCode::GreaterEqual, Unpacked::Number(a), Unpacked::Number(b)) => {
+                Value::Bool(!(a < b))
+            }
+            (OpCode::Less, Unpacked::Number(a), Unpacked::Number(b)) => Value::Bool(a < b),
+            (OpCode::LessEqual, Unpacked::Number(a), Unpacked::Number(b)) => Value::Bool(!(a > b)),
+            (OpCode::Add, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a + b),
+            (OpCode::Add, Unpacked::String(a), Unpacked::String(b)) => {
+                let interner = self.interner.borrow();
+                let (Some(a), Some(b)) = (interner.lookup(a), interner.lookup(b)) else {
+                    return Ok(false);
+                };
+                let s = format!("{}{}", a, b);
+                drop(interner);
+                Value::String(self.interner.borrow_mut().intern(s.as_str()))
+            }
+            (OpCode::Subtract, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a - b),
+            (OpCode::Multiply, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a * b),
+            (OpCode::Divide, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a / b),
+            _ => return Ok(false),
+        };
+
+        self.replace_with_literal(left, folded, line)?;
+        Ok(true)
+    }
+
+    fn replace_with_literal(
+        &mut self,
+        start: Mark,
+        value: Value,
+        line: usize,
+    ) -> Result<(), String> {
+        self.chunk.truncate(start.code);
+        self.chunk.truncate_constants(start.constant);
+        match value.unpack() {
+            Unpacked::Nil => self.emit(OpCode::Nil, line),
+            Unpacked::Bool(true) => self.emit(OpCode::True, line),
+            Unpacked::Bool(false) => self.emit(OpCode::False, line),
+            _ => self.constant(value, line),
+        }
+    }
 }
 
 impl Parser {
-    pub fn new(scanner: Scanner, interner: Rc<RefCell<Interner>>) -> Parser {
+    pub fn new(
+        scanner: Scanner,
+        interner: Rc<RefCell<Interner>>,
+        options: CompileOptions,
+    ) -> Parser {
         Parser {
             scanner,
             previous: None,
             current: None,
-            chunk: Chunk::new(),
-            interner,
+            emitter: Emitter::new(interner, options),
+            operand: Mark::default(),
+            depth: 0,
         }
     }
 
     fn advance(&mut self) -> Result<(), ParserError> {
-        self.previous = self.current.clone();
-        loop {
-            self.current = Some(self.scanner.scan_token());
-            match self.current.clone().unwrap().token_type {
-                TokenType::Error(e) => return Err(self.error_at_current(e.as_str())),
-                _ => break,
-            }
+        let token = self.scanner.scan_token();
+        let error = match &token.token_type {
+            TokenType::Error(e) => Some(e.clone()),
+            _ => None,
+        };
+        self.previous = self.current.replace(token);
+        match error {
+            Some(e) => Err(self.error_at_current(e.as_str())),
+            None => Ok(()),
         }
-
-        Ok(())
     }
 
     fn consume(&mut self, token_type: TokenType, message: &str) -> Result<(), ParserError> {
-        if self.current.is_some() && self.current.clone().unwrap().token_type == token_type {
+        if self.check(token_type) {
             self.advance()?;
             return Ok(());
         }
@@ -136,7 +420,24 @@ impl Parser {
     }
 
     fn check(&self, token_type: TokenType) -> bool {
-        self.current.as_ref().unwrap().token_type == token_type
+        self.current.as_ref().is_some_and(|token| {
+            mem::discriminant(&token.token_type) == mem::discriminant(&token_type)
+        })
+    }
+
+    // Every rule runs after advancing, so there is always a token behind
+    // and one ahead. Missing one is a compile error all the same.
+    fn previous_token(&self) -> Result<Token, ParserError> {
+        self.previous
+            .clone()
+            .ok_or_else(|| self.error_at_current("Expect expression."))
+    }
+
+    fn current_type(&self) -> Result<TokenType, ParserError> {
+        self.current
+            .as_ref()
+            .map(|token| token.token_type.clone())
+            .ok_or_else(|| self.error("Expect expression."))
     }
 
     fn match_token(&mut self, token_type: TokenType) -> Result<bool, ParserError> {
@@ -147,125 +448,162 @@ impl Parser {
         Ok(true)
     }
 
-    fn grouping(&mut self) -> Result<(), ParserError> {
+    fn grouping(&mut self, _can_assign: bool) -> Result<(), ParserError> {
         self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after expression.")
     }
 
-    fn string(&mut self) -> Result<(), ParserError> {
-        match self.previous.clone().unwrap().token_type {
-            TokenType::String(s) => {
-                let id = self.interner.borrow_mut().intern(s.as_str());
-                self.emit_constant(Value::String(id))
-            }
-            _ => {} // Unreachable.
+    fn string(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        if let TokenType::String(s) = self.previous_token()?.token_type {
+            let value = self.emitter.string(s.as_str());
+            self.emit_constant(value)?;
         }
         Ok(())
     }
 
-    fn number(&mut self) -> Result<(), ParserError> {
-        match self.previous.clone().unwrap().token_type {
-            TokenType::Number(n) => self.emit_constant(Value::Number(n)),
+    fn number(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        match self.previous_token()?.token_type {
+            TokenType::Number(n) => self.emit_constant(Value::Number(n))?,
             _ => return Err(self.error_at_current("Expected number.")),
         }
         Ok(())
     }
 
-    fn unary(&mut self) -> Result<(), ParserError> {
-        let operator_type = self.previous.clone().unwrap().token_type;
+    fn unary(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        let operator_type = self.previous_token()?.token_type;
+        let operand = self.mark();
 
         // compile the operand.
         self.parse_precendence(Precedence::Unary)?;
 
         // emit the operator instruction
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not),
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            _ => {} // Unreachable.
-        }
-
-        Ok(())
+        let code = match operator_type {
+            TokenType::Bang => OpCode::Not,
+            TokenType::Minus => OpCode::Negate,
+            _ => return Ok(()), // Unreachable.
+        };
+        let line = self.line();
+        self.emitter
+            .unary(code, operand, line)
+            .map_err(|message| self.error(&message))
     }
 
-    fn binary(&mut self) -> Result<(), ParserError> {
-        let operator_type = self.previous.clone().unwrap().token_type;
+    fn binary(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        let left = self.operand;
+        let operator_type = self.previous_token()?.token_type;
         let rule = self.get_rule(operator_type.clone());
+        let right = self.mark();
         self.parse_precendence(rule.precedence.add(1))?;
 
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(&[OpCode::Equal, OpCode::Not]),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(&[OpCode::Less, OpCode::Not]),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(&[OpCode::Greater, OpCode::Not]),
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            _ => {} // Unreachable.
-        }
+        let code = match operator_type {
+            TokenType::BangEqual => OpCode::NotEqual,
+            TokenType::EqualEqual => OpCode::Equal,
+            TokenType::Greater => OpCode::Greater,
+            TokenType::GreaterEqual => OpCode::GreaterEqual,
+            TokenType::Less => OpCode::Less,
+            TokenType::LessEqual => OpCode::LessEqual,
+            TokenType::Plus => OpCode::Add,
+            TokenType::Minus => OpCode::Subtract,
+            TokenType::Star => OpCode::Multiply,
+            TokenType::Slash => OpCode::Divide,
+            _ => return Ok(()), // Unreachable.
+        };
+        let line = self.line();
+        self.emitter
+            .binary(code, left, right, line)
+            .map_err(|message| self.error(&message))
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<(), ParserError> {
+        let name = self.previous_token()?;
+        self.named_variable(&name, can_assign)
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) -> Result<(), ParserError> {
+        let arg = self.identifier_constant(name)?;
 
+        if can_assign && self.match_token(TokenType::Equal)? {
+            self.expression()?;
+            self.emit_byte(OpCode::SetGlobal(arg))?;
+        } else {
+            self.emit_byte(OpCode::GetGlobal(arg))?;
+        }
         Ok(())
     }
 
-    fn literal(&mut self) -> Result<(), ParserError> {
-        let operator_type = self.previous.clone().unwrap().token_type;
+    fn literal(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        let operator_type = self.previous_token()?.token_type;
         match operator_type {
-            TokenType::True => self.emit_byte(OpCode::True),
-            TokenType::False => self.emit_byte(OpCode::False),
-            TokenType::Nil => self.emit_byte(OpCode::Nil),
+            TokenType::True => self.emit_byte(OpCode::True)?,
+            TokenType::False => self.emit_byte(OpCode::False)?,
+            TokenType::Nil => self.emit_byte(OpCode::Nil)?,
             _ => {} // Unreachable.
         }
         Ok(())
     }
 
     fn parse_precendence(&mut self, precedence: Precedence) -> Result<(), ParserError> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error_at_current("Too much nesting."));
+        }
+        self.depth += 1;
+        let result = self.parse_operand(precedence);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_operand(&mut self, precedence: Precedence) -> Result<(), ParserError> {
         self.advance()?;
 
-        let token_type = self.previous.clone().unwrap().token_type;
+        let start = self.mark();
+        let token_type = self.previous_token()?.token_type;
         let prefix_rule = self.get_rule(token_type).prefix;
 
+        let can_assign = precedence.value() <= Precedence::Assignment.value();
         match prefix_rule {
             None => return Err(self.error("Expect expression.")),
-            Some(r) => r(self)?,
+            Some(r) => r(self, can_assign)?,
         }
 
-        while precedence.value()
-            <= self
-                .get_rule(self.current.clone().unwrap().token_type)
-                .precedence
-                .value()
-        {
+        while precedence.value() <= self.get_rule(self.current_type()?).precedence.value() {
             self.advance()?;
-            let infix_rule = self
-                .get_rule(self.previous.clone().unwrap().token_type)
-                .infix;
-            infix_rule.unwrap()(self)?;
+            self.operand = start;
+            match self.get_rule(self.previous_token()?.token_type).infix {
+                None => return Err(self.error("Expect expression.")),
+                Some(r) => r(self, can_assign)?,
+            }
+        }
+
+        if can_assign && self.match_token(TokenType::Equal)? {
+            return Err(self.error("Invalid assignment target."));
         }
 
         Ok(())
     }
 
+    fn mark(&self) -> Mark {
+        self.emitter.mark()
+    }
+
     fn identifier_constant(&mut self, name: &Token) -> Result<usize, ParserError> {
         match &name.token_type {
-            TokenType::Identifier(i) => {
-                let id = self.interner.borrow_mut().intern(i.as_str());
-                self.make_constant(Value::String(id));
-                Ok(id as usize)
-            }
-            _ => panic!("wrong path"),
+            TokenType::Identifier(i) => self
+                .emitter
+                .identifier_constant(i.as_str())
+                .map_err(|message| self.error(&message)),
+            _ => Err(self.error("Expect variable name.")),
         }
     }
 
     fn parse_variable(&mut self, error_message: &str) -> Result<usize, ParserError> {
         self.consume(TokenType::Identifier(String::new()), error_message)?;
 
-        self.identifier_constant(&self.previous.clone().unwrap())
+        let name = self.previous_token()?;
+        self.identifier_constant(&name)
     }
 
-    fn define_variable(&mut self, global: usize) {
-        self.emit_byte(OpCode::DefineGlobal(global));
+    fn define_variable(&mut self, global: usize) -> Result<(), ParserError> {
+        self.emit_byte(OpCode::DefineGlobal(global))
     }
 
     fn get_rule(&self, token_type: TokenType) -> ParseRule {
@@ -280,9 +618,7 @@ impl Parser {
             TokenType::Star => ParseRule::new(None, Some(Self::binary), Precedence::Factor),
             TokenType::Bang => ParseRule::new(Some(Self::unary), None, Precedence::None),
             TokenType::BangEqual => ParseRule::new(None, Some(Self::binary), Precedence::Equality),
-            TokenType::EqualEqual => {
-                ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
-            }
+            TokenType::EqualEqual => ParseRule::new(None, Some(Self::binary), Precedence::Equality),
             TokenType::Greater => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
             TokenType::GreaterEqual => {
                 ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
@@ -291,6 +627,9 @@ impl Parser {
             TokenType::LessEqual => {
                 ParseRule::new(None, Some(Self::binary), Precedence::Comparison)
             }
+            TokenType::Identifier(_) => {
+                ParseRule::new(Some(Self::variable), None, Precedence::None)
+            }
             TokenType::String(_) => ParseRule::new(Some(Self::string), None, Precedence::None),
             TokenType::Number(_) => ParseRule::new(Some(Self::number), None, Precedence::None),
             TokenType::Nil => ParseRule::new(Some(Self::literal), None, Precedence::None),
@@ -310,7 +649,7 @@ impl Parser {
         if self.match_token(TokenType::Equal)? {
             self.expression()?;
         } else {
-            self.emit_byte(OpCode::Nil);
+            self.emit_byte(OpCode::Nil)?;
         }
 
         self.consume(
@@ -318,30 +657,30 @@ impl Parser {
             "Expect ';' after variable declaration.",
         )?;
 
-        self.define_variable(global);
-        Ok(())
+        self.define_variable(global)
     }
 
     fn expression_statement(&mut self) -> Result<(), ParserError> {
         self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
-        self.emit_byte(OpCode::Pop);
+        self.emit_byte(OpCode::Pop)?;
         Ok(())
     }
 
     fn print_statement(&mut self) -> Result<(), ParserError> {
         self.expression()?;
         self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
-        self.emit_byte(OpCode::Print);
+        self.emit_byte(OpCode::Print)?;
         Ok(())
     }
 
     fn synchronize(&mut self) -> Result<(), ParserError> {
-        while self.current.as_ref().unwrap().token_type != TokenType::EOF {
-            if self.previous.as_ref().unwrap().token_type == TokenType::Semicolon {
+        while !self.check(TokenType::EOF) {
+            if self.previous.as_ref().map(|token| &token.token_type) == Some(&TokenType::Semicolon)
+            {
                 return Ok(());
             }
-            match self.current.as_ref().unwrap().token_type {
+            if let Some(
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
@@ -349,8 +688,10 @@ impl Parser {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return Ok(()),
-                _ => {}
+                | TokenType::Return,
+            ) = self.current.as_ref().map(|token| &token.token_type)
+            {
+                return Ok(());
             }
 
             self.advance()?;
@@ -382,48 +723,49 @@ impl Parser {
         }
     }
 
-    fn emit_byte(&mut self, byte: OpCode) {
-        self.chunk
-            .write_chunk(byte, self.previous.clone().unwrap().line);
-    }
-
-    fn emit_bytes(&mut self, bytes: &[OpCode]) {
-        for byte in bytes {
-            self.emit_byte(*byte);
-        }
-    }
-
-    fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return);
+    // The line code is emitted for: the line of the last token consumed.
+    fn line(&self) -> usize {
+        self.previous.as_ref().map(|token| token.line).unwrap_or(1)
     }
 
-    fn end_complier(&mut self) {
-        self.emit_return();
+    fn emit_byte(&mut self, byte: OpCode) -> Result<(), ParserError> {
+        let line = self.line();
+        self.emitter
+            .emit(byte, line)
+            .map_err(|message| self.error(&message))
     }
 
-    fn make_constant(&mut self, value: Value) -> OpCode {
-        let constant = self.chunk.add_constant(value);
-        OpCode::Constant(constant)
-    }
-
-    fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_byte(constant)
+    fn emit_constant(&mut self, value: Value) -> Result<(), ParserError> {
+        let line = self.line();
+        self.emitter
+            .constant(value, line)
+            .map_err(|message| self.error(&message))
     }
 
     fn error_at_current(&self, message: &str) -> ParserError {
         ParserError {
             token: self.current.clone(),
             message: String::from(message),
+            previous: self.previous_span(),
         }
     }
 
+    // Like clox, errors that aren't about the next token are reported at the
+    // one just consumed.
     fn error(&self, message: &str) -> ParserError {
         ParserError {
-            token: None,
+            token: self.previous.clone(),
             message: String::from(message),
+            previous: self.previous_span(),
         }
     }
+
+    fn previous_span(&self) -> (usize, usize) {
+        self.previous
+            .as_ref()
+            .map(|token| (token.start, token.end))
+            .unwrap_or_default()
+    }
 }
 
 #[cfg(test)]
@@ -447,21 +789,51 @@ mod tests {
     }
 
     fn assert_interned(interner: Rc<RefCell<Interner>>, id: Symbol, s: &str) {
-        assert_eq!(interner.borrow().lookup(id), s,)
+        assert_eq!(interner.borrow().lookup(id), Some(s))
     }
 
     fn assert_compiles(
         source: &str,
         expected_instructions: Vec<Instruction>,
         expected_constants: Vec<Value>,
+    ) -> Rc<RefCell<Interner>> {
+        assert_compiles_with(
+            CompileOptions::O0,
+            source,
+            expected_instructions,
+            expected_constants,
+        )
+    }
+
+    fn assert_folds(
+        source: &str,
+        expected_instructions: Vec<Instruction>,
+        expected_constants: Vec<Value>,
+    ) -> Rc<RefCell<Interner>> {
+        assert_compiles_with(
+            CompileOptions {
+                fold_constants: true,
+                peephole: false,
+            },
+            source,
+            expected_instructions,
+            expected_constants,
+        )
+    }
+
+    fn assert_compiles_with(
+        options: CompileOptions,
+        source: &str,
+        expected_instructions: Vec<Instruction>,
+        expected_constants: Vec<Value>,
     ) -> Rc<RefCell<Interner>> {
         let interner: Rc<RefCell<Interner>> = Rc::new(RefCell::new(Interner::default()));
-        let result = compile(source, interner.clone());
+        let result = compile_with(source, interner.clone(), options);
         assert!(result.is_ok(), "is not ok: {:?}", result.err());
 
         let chunk = result.unwrap();
-        assert_same(chunk.instructions, expected_instructions);
-        assert_same(chunk.constants, expected_constants);
+        assert_same(chunk.instructions(), expected_instructions);
+        assert_same(chunk.constants().to_vec(), expected_constants);
 
         interner
     }
@@ -471,13 +843,13 @@ mod tests {
         assert_compiles(
             "1 + 2;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::SmallInt(1), 1),
+                Instruction::new(OpCode::SmallInt(2), 1),
                 Instruction::new(OpCode::Add, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(1.0), Value::Number(2.0)],
+            vec![],
         );
     }
 
@@ -486,15 +858,32 @@ mod tests {
         assert_compiles(
             "2 * 3 + 4;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::SmallInt(2), 1),
+                Instruction::new(OpCode::SmallInt(3), 1),
                 Instruction::new(OpCode::Multiply, 1),
-                Instruction::new(OpCode::Constant(2), 1),
+                Instruction::new(OpCode::SmallInt(4), 1),
                 Instruction::new(OpCode::Add, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(2.0), Value::Number(3.0), Value::Number(4.0)],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn it_compiles_equality_below_comparison() {
+        assert_compiles(
+            "1 == 2 < 3;",
+            vec![
+                Instruction::new(OpCode::SmallInt(1), 1),
+                Instruction::new(OpCode::SmallInt(2), 1),
+                Instruction::new(OpCode::SmallInt(3), 1),
+                Instruction::new(OpCode::Less, 1),
+                Instruction::new(OpCode::Equal, 1),
+                Instruction::new(OpCode::Pop, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
         );
     }
 
@@ -503,24 +892,19 @@ mod tests {
         assert_compiles(
             "(-1 + 2) * 3 - -4;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
+                Instruction::new(OpCode::SmallInt(1), 1),
                 Instruction::new(OpCode::Negate, 1),
-                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::SmallInt(2), 1),
                 Instruction::new(OpCode::Add, 1),
-                Instruction::new(OpCode::Constant(2), 1),
+                Instruction::new(OpCode::SmallInt(3), 1),
                 Instruction::new(OpCode::Multiply, 1),
-                Instruction::new(OpCode::Constant(3), 1),
+                Instruction::new(OpCode::SmallInt(4), 1),
                 Instruction::new(OpCode::Negate, 1),
                 Instruction::new(OpCode::Subtract, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![
-                Value::Number(1.0),
-                Value::Number(2.0),
-                Value::Number(3.0),
-                Value::Number(4.0),
-            ],
+            vec![],
         );
     }
 
@@ -584,8 +968,7 @@ mod tests {
             vec![
                 Instruction::new(OpCode::True, 1),
                 Instruction::new(OpCode::False, 1),
-                Instruction::new(OpCode::Equal, 1),
-                Instruction::new(OpCode::Not, 1),
+                Instruction::new(OpCode::NotEqual, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
@@ -607,51 +990,49 @@ mod tests {
         assert_compiles(
             "5 > 4;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::SmallInt(5), 1),
+                Instruction::new(OpCode::SmallInt(4), 1),
                 Instruction::new(OpCode::Greater, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(5.0), Value::Number(4.0)],
+            vec![],
         );
 
         assert_compiles(
             "5 >= 4;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
-                Instruction::new(OpCode::Less, 1),
-                Instruction::new(OpCode::Not, 1),
+                Instruction::new(OpCode::SmallInt(5), 1),
+                Instruction::new(OpCode::SmallInt(4), 1),
+                Instruction::new(OpCode::GreaterEqual, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(5.0), Value::Number(4.0)],
+            vec![],
         );
 
         assert_compiles(
             "5 < 4;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::SmallInt(5), 1),
+                Instruction::new(OpCode::SmallInt(4), 1),
                 Instruction::new(OpCode::Less, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(5.0), Value::Number(4.0)],
+            vec![],
         );
 
         assert_compiles(
             "5 <= 4;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
-                Instruction::new(OpCode::Greater, 1),
-                Instruction::new(OpCode::Not, 1),
+                Instruction::new(OpCode::SmallInt(5), 1),
+                Instruction::new(OpCode::SmallInt(4), 1),
+                Instruction::new(OpCode::LessEqual, 1),
                 Instruction::new(OpCode::Pop, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(5.0), Value::Number(4.0)],
+            vec![],
         );
     }
 
@@ -675,13 +1056,13 @@ mod tests {
         assert_compiles(
             "print 1 + 2;",
             vec![
-                Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::SmallInt(1), 1),
+                Instruction::new(OpCode::SmallInt(2), 1),
                 Instruction::new(OpCode::Add, 1),
                 Instruction::new(OpCode::Print, 1),
                 Instruction::new(OpCode::Return, 1),
             ],
-            vec![Value::Number(1.0), Value::Number(2.0)],
+            vec![],
         );
     }
 
@@ -689,11 +1070,250 @@ mod tests {
     fn it_compiles_assignment() {
         assert_compiles(
             r#"var beverage = "cafe au lait";"#,
+            vec![
+                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::DefineGlobal(0), 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![Value::String(0), Value::String(1)],
+        );
+    }
+
+    #[test]
+    fn it_folds_arithmetic() {
+        assert_folds(
+            "print 1 + 2 * 3;",
+            vec![
+                Instruction::new(OpCode::SmallInt(7), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+
+        assert_folds(
+            "print (-1 + 2) * 3 - -4;",
+            vec![
+                Instruction::new(OpCode::SmallInt(7), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn it_folds_comparisons_to_literals() {
+        assert_folds(
+            "print 5 <= 4;",
+            vec![
+                Instruction::new(OpCode::False, 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+
+        assert_folds(
+            "print !(nil == false);",
+            vec![
+                Instruction::new(OpCode::True, 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn it_folds_string_concatenation() {
+        let interner = assert_folds(
+            r#"print "st" + "ri" + "ng";"#,
+            vec![
+                Instruction::new(OpCode::Constant(0), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![Value::String(4)],
+        );
+
+        assert_interned(interner, 4, "string");
+    }
+
+    #[test]
+    fn it_does_not_fold_type_errors() {
+        assert_folds(
+            r#"print -"str";"#,
             vec![
                 Instruction::new(OpCode::Constant(0), 1),
-                Instruction::new(OpCode::DefineGlobal(1), 1),
+                Instruction::new(OpCode::Negate, 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
             ],
             vec![Value::String(0)],
         );
+
+        assert_folds(
+            "print 1 + nil;",
+            vec![
+                Instruction::new(OpCode::SmallInt(1), 1),
+                Instruction::new(OpCode::Nil, 1),
+                Instruction::new(OpCode::Add, 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn it_folds_around_type_errors() {
+        assert_folds(
+            "print (1 + 2) * nil;",
+            vec![
+                Instruction::new(OpCode::SmallInt(3), 1),
+                Instruction::new(OpCode::Nil, 1),
+                Instruction::new(OpCode::Multiply, 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn it_optimizes_away_expression_statements() {
+        assert_compiles_with(
+            CompileOptions::O1,
+            "1 + 2; !true; print 3;",
+            vec![
+                Instruction::new(OpCode::SmallInt(3), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn it_reads_optimization_flags() {
+        assert_eq!(Some(CompileOptions::O0), CompileOptions::from_flag("-O0"));
+        assert_eq!(Some(CompileOptions::O1), CompileOptions::from_flag("-O1"));
+        assert_eq!(None, CompileOptions::from_flag("-O2"));
+    }
+
+    #[test]
+    fn it_compiles_small_ints() {
+        assert_compiles(
+            "print 127; print 128; print 1.5;",
+            vec![
+                Instruction::new(OpCode::SmallInt(127), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Constant(0), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Constant(1), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![Value::Number(128.0), Value::Number(1.5)],
+        );
+    }
+
+    #[test]
+    fn it_keeps_negative_zero_in_the_constant_pool() {
+        assert_folds(
+            "print -0;",
+            vec![
+                Instruction::new(OpCode::Constant(0), 1),
+                Instruction::new(OpCode::Print, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![Value::Number(-0.0)],
+        );
+    }
+
+    #[test]
+    fn it_compiles_globals() {
+        assert_compiles(
+            "var i = 0; i = i + 1;",
+            vec![
+                Instruction::new(OpCode::SmallInt(0), 1),
+                Instruction::new(OpCode::DefineGlobal(0), 1),
+                Instruction::new(OpCode::GetGlobal(0), 1),
+                Instruction::new(OpCode::SmallInt(1), 1),
+                Instruction::new(OpCode::Add, 1),
+                Instruction::new(OpCode::SetGlobal(0), 1),
+                Instruction::new(OpCode::Pop, 1),
+                Instruction::new(OpCode::Return, 1),
+            ],
+            vec![Value::String(0)],
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_assignment_targets() {
+        let interner: Rc<RefCell<Interner>> = Rc::new(RefCell::new(Interner::default()));
+        let result = compile("1 + 2 = 3;", interner);
+
+        assert_eq!(
+            "Invalid assignment target.",
+            result.expect_err("should not compile").message
+        );
+    }
+
+    #[test]
+    fn it_limits_nesting() {
+        let parens = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        let negations = |depth| format!("print {}1;", "-".repeat(depth));
+        let compiles = |source: String| {
+            let interner: Rc<RefCell<Interner>> = Rc::new(RefCell::new(Interner::default()));
+            compile(source.as_str(), interner)
+        };
+
+        assert!(compiles(parens(MAX_NESTING - 1)).is_ok());
+        assert!(compiles(negations(MAX_NESTING - 1)).is_ok());
+        for source in [parens(MAX_NESTING), negations(MAX_NESTING), parens(50_000)] {
+            let error = compiles(source).expect_err("should not compile");
+            assert_eq!("Too much nesting.", error.message);
+        }
+    }
+
+    #[test]
+    fn it_shares_repeated_constants() {
+        let source = r#"print "name"; var name = 1000;"#.repeat(500);
+        let interner: Rc<RefCell<Interner>> = Rc::new(RefCell::new(Interner::default()));
+        let chunk = compile_with(source.as_str(), interner, CompileOptions::O0).expect("failed");
+
+        // The variable's name is the same interned string as the literal.
+        assert_eq!(
+            vec![Value::String(0), Value::Number(1000.0)],
+            chunk.constants()
+        );
+    }
+
+    fn distinct_constants(count: usize) -> Chunk {
+        let source: String = (0..count).map(|n| format!("print {}.5;\n", n)).collect();
+        let interner: Rc<RefCell<Interner>> = Rc::new(RefCell::new(Interner::default()));
+        compile(source.as_str(), interner).expect("failed")
+    }
+
+    #[test]
+    fn it_compiles_more_than_256_distinct_constants() {
+        let chunk = distinct_constants(300);
+        let instructions = chunk.instructions();
+
+        assert_eq!(300, chunk.constants().len());
+        assert_eq!(Value::Number(299.5), chunk.constants()[299]);
+        assert_eq!(OpCode::Constant(299), instructions[598].code);
+        assert_eq!(300, instructions[598].line);
+    }
+
+    #[test]
+    fn it_compiles_more_than_65536_distinct_constants() {
+        let chunk = distinct_constants(70_000);
+        let instructions = chunk.instructions();
+
+        assert_eq!(70_000, chunk.constants().len());
+        assert_eq!(OpCode::Constant(69_999), instructions[139_998].code);
     }
 }
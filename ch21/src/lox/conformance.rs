@@ -0,0 +1,286 @@
+use crate::lox::differential::{corpus, diff, run_vm, Outcome};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Runs Lox programs that say in comments what they should do, the way the
+// official Crafting Interpreters suite does:
+//
+//   print 1 + 2; // expect: 3
+//   print -nil;  // expect runtime error: Operand must be number.
+//   print 1 +;   // Error at ';': Expect expression.
+//   // [line 3] Error at end: Expect ';' after value.
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Expectations {
+    pub output: Vec<String>,
+    pub compile_errors: Vec<String>,
+    // The message and the line of the comment that expects it.
+    pub runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    pub fn exit(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+pub fn expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations::default();
+    for (i, line) in source.lines().enumerate() {
+        // Annotations can follow code, strings with "//" in them included.
+        if let Some((_, output)) = line.split_once("// expect: ") {
+            expectations.output.push(String::from(output));
+        } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+            expectations.runtime_error = Some((String::from(message), i + 1));
+        } else if let Some((_, error)) = line.split_once("// Error") {
+            expectations
+                .compile_errors
+                .push(format!("[line {}] Error{}", i + 1, error));
+        } else if let Some((_, error)) = line.split_once("// [") {
+            // jlox's errors sometimes differ from clox's, and are marked
+            // "[java line N]". Only clox's are ours.
+            let error = error.strip_prefix("c ").unwrap_or(error);
+            if error.starts_with("line ") {
+                expectations.compile_errors.push(format!("[{}", error));
+            }
+        }
+    }
+    expectations
+}
+
+// Everything about how source ran that it didn't expect, each with a diff
+// where there is one. A run that went as expected has no problems.
+pub fn check(source: &str) -> Vec<String> {
+    let expected = expectations(source);
+    let actual = run_vm(source);
+    let mut problems = vec![];
+    if actual.exit != expected.exit() {
+        problems.push(format!(
+            "Expected exit code {} and got {}.",
+            expected.exit(),
+            actual.exit
+        ));
+    }
+
+    let output: String = expected
+        .output
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect();
+    if output != actual.stdout {
+        problems.push(format!(
+            "Output differs:\n{}",
+            diff(&output, &actual.stdout)
+        ));
+    }
+
+    match &expected.runtime_error {
+        Some((message, line)) => problems.extend(runtime_error(message, *line, &actual)),
+        None => {
+            let errors = expected.compile_errors.join("\n");
+            if errors != actual.stderr {
+                problems.push(format!("Errors differ:\n{}", diff(&errors, &actual.stderr)));
+            }
+        }
+    }
+    problems
+}
+
+// A runtime error is its message and then the line it happened on.
+fn runtime_error(message: &str, line: usize, actual: &Outcome) -> Option<String> {
+    let mut lines = actual.stderr.lines();
+    let reported = lines.next().unwrap_or("");
+    if reported != message {
+        return Some(format!(
+            "Expected runtime error '{}' and got '{}'.",
+            message, reported
+        ));
+    }
+    let trace = lines.next().unwrap_or("none");
+    if trace != format!("[line {}]", line) {
+        return Some(format!(
+            "Expected runtime error on [line {}] and got {}.",
+            line, trace
+        ));
+    }
+    None
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: Vec<PathBuf>,
+}
+
+// Runs every .lox file under dir, writing each failure as it happens and a
+// count of what passed at the end.
+pub fn run(dir: &Path, out: &mut dyn Write) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    for path in corpus(dir)? {
+        let source = fs::read_to_string(&path)?;
+        let problems = check(&source);
+        if problems.is_empty() {
+            summary.passed += 1;
+            continue;
+        }
+        writeln!(out, "FAIL {}", path.display())?;
+        for problem in problems {
+            for line in problem.lines() {
+                writeln!(out, "    {}", line)?;
+            }
+        }
+        summary.failed.push(path);
+    }
+    writeln!(
+        out,
+        "{} passed, {} failed.",
+        summary.passed,
+        summary.failed.len()
+    )?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_expectations() {
+        let source = "print 1; // expect: 1
+print \"a // b\"; // expect: a // b
+// [line 3] Error at 'x': Expect ';' after value.
+// [c line 4] Error at end: Expect expression.
+// [java line 5] Error at end: Something else.
+print 1 +; // Error at ';': Expect expression.
+print -nil; // expect runtime error: Operand must be number.";
+        assert_eq!(
+            Expectations {
+                output: vec![String::from("1"), String::from("a // b")],
+                compile_errors: vec![
+                    String::from("[line 3] Error at 'x': Expect ';' after value."),
+                    String::from("[line 4] Error at end: Expect expression."),
+                    String::from("[line 6] Error at ';': Expect expression."),
+                ],
+                runtime_error: Some((String::from("Operand must be number."), 7)),
+            },
+            expectations(source)
+        );
+    }
+
+    #[test]
+    fn it_passes_programs_that_do_what_they_expect() {
+        for source in [
+            "print 1 + 2; // expect: 3\nprint \"hi\"; // expect: hi",
+            "var a = 1;\nprint a +; // Error at ';': Expect expression.",
+            "print 1;\n// [line 3] Error at end: Expect ';' after value.\nprint 2",
+            "print -nil; // expect runtime error: Operand must be number.",
+            "print 1; // expect: 1\nprint -nil; // expect runtime error: Operand must be number.",
+            "",
+        ] {
+            assert_eq!(Vec::<String>::new(), check(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn it_reports_what_went_differently() {
+        assert_eq!(
+            vec!["Output differs:\n-   1 | 4\n+   1 | 3\n"],
+            check("print 1 + 2; // expect: 4")
+        );
+        assert_eq!(
+            vec!["Output differs:\n-   1 | 4\n+   1 | 3\n"],
+            check("print 3; // expect: 4\nprint -nil; // expect runtime error: Operand must be number.")
+        );
+        assert_eq!(
+            vec![
+                "Expected exit code 0 and got 70.",
+                "Errors differ:\n+   1 | Operand must be number.\n+   2 | [line 1]\n",
+            ],
+            check("print -nil;")
+        );
+        assert_eq!(
+            vec![
+                "Expected exit code 70 and got 0.",
+                "Expected runtime error 'Operand must be number.' and got ''.",
+            ],
+            check("print 1; // expect runtime error: Operand must be number.")
+                .into_iter()
+                .filter(|problem| !problem.starts_with("Output"))
+                .collect::<Vec<String>>()
+        );
+        assert_eq!(
+            vec![
+                "Expected exit code 65 and got 0.",
+                "Errors differ:\n-   1 | [line 1] Error at end: Oops.\n",
+            ],
+            check("// [line 1] Error at end: Oops.")
+        );
+    }
+
+    #[test]
+    fn it_checks_runtime_error_lines() {
+        let mut outcome = Outcome {
+            stdout: String::new(),
+            stderr: String::from("Oops."),
+            exit: 70,
+        };
+        assert_eq!(
+            Some(String::from(
+                "Expected runtime error on [line 2] and got none."
+            )),
+            runtime_error("Oops.", 2, &outcome)
+        );
+        outcome.stderr = String::from("Oops.\n[line 2]");
+        assert_eq!(None, runtime_error("Oops.", 2, &outcome));
+        assert_eq!(
+            Some(String::from(
+                "Expected runtime error on [line 3] and got [line 2]."
+            )),
+            runtime_error("Oops.", 3, &outcome)
+        );
+    }
+
+    #[test]
+    fn it_passes_the_conformance_directory() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("conformance");
+        let mut out = vec![];
+        let summary = run(&dir, &mut out).expect("could not run the tests");
+        assert!(
+            summary.failed.is_empty(),
+            "\n{}",
+            String::from_utf8_lossy(&out)
+        );
+        assert!(summary.passed > 0);
+    }
+
+    #[test]
+    fn it_runs_a_directory_and_summarizes() {
+        let dir = std::env::temp_dir().join(format!("lox-conformance-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("pass.lox"), "print 1; // expect: 1\n").unwrap();
+        fs::write(dir.join("nested/fail.lox"), "print 2; // expect: 1\n").unwrap();
+        fs::write(dir.join("ignored.txt"), "print 2; // expect: 1\n").unwrap();
+
+        let mut out = vec![];
+        let summary = run(&dir, &mut out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(1, summary.passed);
+        assert_eq!(vec![dir.join("nested/fail.lox")], summary.failed);
+        assert_eq!(
+            format!(
+                "FAIL {}\n    Output differs:\n    -   1 | 1\n    +   1 | 2\n1 passed, 1 failed.\n",
+                dir.join("nested/fail.lox").display()
+            ),
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,728 @@
+use crate::lox::chunk::{format_value, Chunk};
+use crate::lox::debugger::{Debugger, Resume, Stepper, Stop};
+use crate::lox::json::{read_message, write_message, Json};
+use crate::lox::value::{Unpacked, Value};
+use crate::lox::vm::{InterpretError, InterpretResult, VM};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// A Lox program has one thread and, until there are functions, one frame.
+const THREAD_ID: usize = 1;
+const FRAME_ID: usize = 0;
+const STACK_REFERENCE: usize = 1;
+const GLOBALS_REFERENCE: usize = 2;
+
+// A Debug Adapter Protocol server for one program. Requests are read and
+// answered one at a time; while the program runs, the VM's debugger hook
+// reads the requests that come in while it is paused.
+pub struct Server {
+    session: Rc<RefCell<Session>>,
+    vm: VM,
+    chunk: Option<Chunk>,
+}
+
+// What to do after a request.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Wait,
+    Resume(Resume),
+    Disconnect,
+}
+
+// Where the program is paused, for the requests that look at it.
+struct Paused<'a> {
+    vm: &'a mut VM,
+    ip: usize,
+}
+
+struct Session {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    seq: usize,
+    stepper: Stepper,
+    path: String,
+    // The lines the launched program has code on, to verify breakpoints.
+    code_lines: Option<BTreeSet<usize>>,
+    configured: bool,
+    disconnected: bool,
+    shown: usize,
+    // A write error from inside a run, which the VM can only report as text.
+    error: Option<io::Error>,
+}
+
+impl Server {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Server {
+        let session = Session {
+            input,
+            output,
+            seq: 0,
+            stepper: Stepper::new(false),
+            path: String::new(),
+            code_lines: None,
+            configured: false,
+            disconnected: false,
+            shown: 0,
+            error: None,
+        };
+        Server {
+            session: Rc::new(RefCell::new(session)),
+            vm: VM::new(),
+            chunk: None,
+        }
+    }
+
+    // Serves requests until the client disconnects or the input ends.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let Some(request) = self.session.borrow_mut().read()? else {
+                return Ok(());
+            };
+            let action = if command(&request) == "launch" {
+                self.launch(&request)?
+            } else {
+                self.session.borrow_mut().handle(&request, None)?
+            };
+            if action == Action::Disconnect {
+                return Ok(());
+            }
+            if self.chunk.is_some() && self.session.borrow().configured {
+                self.run()?;
+                if self.session.borrow().disconnected {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn launch(&mut self, request: &Json) -> io::Result<Action> {
+        let mut session = self.session.borrow_mut();
+        let arguments = request.get("arguments");
+        let Some(path) = arguments
+            .and_then(|a| a.get("program"))
+            .and_then(Json::as_str)
+        else {
+            return session.fail(request, "Launch needs a program.");
+        };
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return session.fail(request, &format!("Could not open file: {}", e)),
+        };
+        let chunk = match self.vm.compile(source.as_str()) {
+            Ok(chunk) => chunk,
+            Err(InterpretError::CompileError(e))
+            | Err(InterpretError::RuntimeError(e))
+            | Err(InterpretError::VerifyError(e)) => return session.fail(request, e.trim_end()),
+        };
+
+        session.path = path.to_string();
+        session.code_lines = Some(chunk.instructions().iter().map(|i| i.line).collect());
+        session.stepper.stop_on_entry = arguments
+            .and_then(|a| a.get("stopOnEntry"))
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        // Breakpoints set before the launch can be checked now.
+        let lines = session.code_lines.clone().unwrap_or_default();
+        session
+            .stepper
+            .breakpoints
+            .retain(|line| lines.contains(line));
+        self.chunk = Some(chunk);
+        session.respond(request, Json::object(vec![]))?;
+        Ok(Action::Wait)
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let Some(chunk) = self.chunk.take() else {
+            return Ok(());
+        };
+        self.vm
+            .set_debugger(Some(Box::new(Hook(self.session.clone()))));
+        let result = self.vm.interpret_chunk(chunk);
+        self.vm.set_debugger(None);
+        let mut session = self.session.borrow_mut();
+        match session.error.take() {
+            Some(e) => Err(e),
+            None => session.finish(&result),
+        }
+    }
+}
+
+impl Session {
+    // A message that isn't JSON has no seq to answer, so it is skipped.
+    fn read(&mut self) -> io::Result<Option<Json>> {
+        loop {
+            match read_message(&mut self.input)? {
+                Some(Ok(message)) => return Ok(Some(message)),
+                Some(Err(_)) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn send(&mut self, kind: &str, mut members: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        members.insert(0, ("seq", Json::from(self.seq)));
+        members.insert(1, ("type", Json::from(kind)));
+        write_message(&mut self.output, &Json::object(members))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(
+            "response",
+            vec![
+                ("request_seq", request_seq(request)),
+                ("success", Json::from(true)),
+                ("command", Json::from(command(request))),
+                ("body", body),
+            ],
+        )
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<Action> {
+        self.send(
+            "response",
+            vec![
+                ("request_seq", request_seq(request)),
+                ("success", Json::from(false)),
+                ("command", Json::from(command(request))),
+                ("message", Json::from(message)),
+            ],
+        )?;
+        Ok(Action::Wait)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send("event", vec![("event", Json::from(event)), ("body", body)])
+    }
+
+    fn show_output(&mut self, output: &str) -> io::Result<()> {
+        let new = output.get(self.shown..).unwrap_or("").to_string();
+        self.shown = output.len();
+        if new.is_empty() {
+            return Ok(());
+        }
+        self.event(
+            "output",
+            Json::object(vec![
+                ("category", Json::from("stdout")),
+                ("output", Json::from(new)),
+            ]),
+        )
+    }
+
+    fn handle(&mut self, request: &Json, paused: Option<Paused>) -> io::Result<Action> {
+        let arguments = request.get("arguments").cloned().unwrap_or(Json::Null);
+        match (command(request), paused) {
+            ("initialize", None) => {
+                let capabilities = Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsEvaluateForHovers", Json::from(true)),
+                ]);
+                self.respond(request, capabilities)?;
+                self.event("initialized", Json::object(vec![]))?;
+            }
+            ("setBreakpoints", _) => {
+                let body = self.set_breakpoints(&arguments);
+                self.respond(request, body)?;
+            }
+            ("configurationDone", _) => {
+                self.configured = true;
+                self.respond(request, Json::object(vec![]))?;
+            }
+            ("threads", _) => {
+                let thread = Json::object(vec![
+                    ("id", Json::from(THREAD_ID)),
+                    ("name", Json::from("main")),
+                ]);
+                let body = Json::object(vec![("threads", Json::from(vec![thread]))]);
+                self.respond(request, body)?;
+            }
+            ("stackTrace", Some(paused)) => {
+                let body = self.stack_trace(paused.ip);
+                self.respond(request, body)?;
+            }
+            ("scopes", Some(_)) => {
+                let scope = |name: &str, reference: usize| {
+                    Json::object(vec![
+                        ("name", Json::from(name)),
+                        ("variablesReference", Json::from(reference)),
+                        ("expensive", Json::from(false)),
+                    ])
+                };
+                let scopes = vec![
+                    scope("Stack", STACK_REFERENCE),
+                    scope("Globals", GLOBALS_REFERENCE),
+                ];
+                self.respond(request, Json::object(vec![("scopes", Json::from(scopes))]))?;
+            }
+            ("variables", Some(paused)) => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_usize);
+                let variables = match reference {
+                    Some(STACK_REFERENCE) => paused
+                        .vm
+                        .stack()
+                        .iter()
+                        .enumerate()
+                        .map(|(slot, value)| variable(paused.vm, slot.to_string(), value))
+                        .collect(),
+                    Some(GLOBALS_REFERENCE) => paused
+                        .vm
+                        .globals()
+                        .into_iter()
+                        .map(|(name, value)| variable(paused.vm, name, &value))
+                        .collect(),
+                    _ => vec![],
+                };
+                let body = Json::object(vec![("variables", Json::from(variables))]);
+                self.respond(request, body)?;
+            }
+            ("evaluate", Some(paused)) => {
+                let expression = arguments.get("expression").and_then(Json::as_str);
+                match paused.vm.evaluate(expression.unwrap_or("")) {
+                    Ok(result) => {
+                        let body = Json::object(vec![
+                            ("result", Json::from(result)),
+                            ("variablesReference", Json::from(0)),
+                        ]);
+                        self.respond(request, body)?;
+                    }
+                    Err(InterpretError::CompileError(e))
+                    | Err(InterpretError::RuntimeError(e))
+                    | Err(InterpretError::VerifyError(e)) => {
+                        return self.fail(request, e.trim_end());
+                    }
+                }
+            }
+            ("continue", Some(_)) => {
+                let body = Json::object(vec![("allThreadsContinued", Json::from(true))]);
+                self.respond(request, body)?;
+                return Ok(Action::Resume(Resume::Continue));
+            }
+            // Without functions there is nothing to step into or out of, so
+            // stepIn steps an instruction and stepOut runs on.
+            ("next", Some(_)) => {
+                self.respond(request, Json::object(vec![]))?;
+                return Ok(Action::Resume(Resume::Line));
+            }
+            ("stepIn", Some(_)) => {
+                self.respond(request, Json::object(vec![]))?;
+                return Ok(Action::Resume(Resume::Instruction));
+            }
+            ("stepOut", Some(_)) => {
+                self.respond(request, Json::object(vec![]))?;
+                return Ok(Action::Resume(Resume::Continue));
+            }
+            ("disconnect" | "terminate", _) => {
+                self.disconnected = true;
+                self.respond(request, Json::object(vec![]))?;
+                return Ok(Action::Disconnect);
+            }
+            (
+                "stackTrace" | "scopes" | "variables" | "evaluate" | "continue" | "next" | "stepIn"
+                | "stepOut",
+                None,
+            ) => return self.fail(request, "The program is not paused."),
+            (command, _) => {
+                return self.fail(request, &format!("Unsupported request '{}'.", command));
+            }
+        }
+        Ok(Action::Wait)
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let lines: Vec<usize> = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|b| b.get("line").and_then(Json::as_usize))
+            .collect();
+
+        self.stepper.breakpoints.clear();
+        let mut breakpoints = vec![];
+        for line in lines {
+            let verified = self
+                .code_lines
+                .as_ref()
+                .is_none_or(|lines| lines.contains(&line));
+            let mut breakpoint = vec![
+                ("verified", Json::from(verified)),
+                ("line", Json::from(line)),
+            ];
+            if verified {
+                self.stepper.breakpoints.insert(line);
+            } else {
+                breakpoint.push(("message", Json::from("No code on this line.")));
+            }
+            breakpoints.push(Json::object(breakpoint));
+        }
+        Json::object(vec![("breakpoints", Json::from(breakpoints))])
+    }
+
+    fn stack_trace(&self, ip: usize) -> Json {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let frame = Json::object(vec![
+            ("id", Json::from(FRAME_ID)),
+            ("name", Json::from("script")),
+            (
+                "source",
+                Json::object(vec![
+                    ("name", Json::from(name)),
+                    ("path", Json::from(self.path.as_str())),
+                ]),
+            ),
+            ("line", Json::from(self.stepper.line(ip))),
+            ("column", Json::from(1)),
+            ("instructionPointerReference", Json::from(ip.to_string())),
+        ]);
+        Json::object(vec![
+            ("stackFrames", Json::from(vec![frame])),
+            ("totalFrames", Json::from(1)),
+        ])
+    }
+
+    // Reports a stop and answers requests until one resumes the run. None
+    // means the client went away or disconnected.
+    fn pause(
+        &mut self,
+        vm: &mut VM,
+        ip: usize,
+        output: &str,
+        stop: Stop,
+    ) -> io::Result<Option<Resume>> {
+        self.show_output(output)?;
+        let reason = match stop {
+            Stop::Entry => "entry",
+            Stop::Step => "step",
+            Stop::Breakpoint => "breakpoint",
+        };
+        self.event(
+            "stopped",
+            Json::object(vec![
+                ("reason", Json::from(reason)),
+                ("threadId", Json::from(THREAD_ID)),
+                ("allThreadsStopped", Json::from(true)),
+            ]),
+        )?;
+        loop {
+            let Some(request) = self.read()? else {
+                return Ok(None);
+            };
+            let paused = Paused { vm, ip };
+            match self.handle(&request, Some(paused))? {
+                Action::Wait => {}
+                Action::Resume(resume) => return Ok(Some(resume)),
+                Action::Disconnect => return Ok(None),
+            }
+        }
+    }
+
+    fn finish(&mut self, result: &InterpretResult) -> io::Result<()> {
+        if self.disconnected {
+            return Ok(());
+        }
+        let exit_code = match result {
+            Ok(output) => {
+                self.show_output(output)?;
+                0
+            }
+            Err(InterpretError::RuntimeError(e)) => {
+                let message = format!("{}\n", e);
+                self.event(
+                    "output",
+                    Json::object(vec![
+                        ("category", Json::from("stderr")),
+                        ("output", Json::from(message)),
+                    ]),
+                )?;
+                70
+            }
+            Err(_) => 65,
+        };
+        self.event(
+            "exited",
+            Json::object(vec![("exitCode", Json::from(exit_code))]),
+        )?;
+        self.event("terminated", Json::object(vec![]))
+    }
+}
+
+// The VM's debugger while a program runs under the server.
+struct Hook(Rc<RefCell<Session>>);
+
+impl Debugger for Hook {
+    fn start(&mut self, chunk: &Chunk) {
+        let mut session = self.0.borrow_mut();
+        session.stepper.start(chunk);
+        session.shown = 0;
+    }
+
+    fn step(&mut self, vm: &mut VM, _chunk: &Chunk, ip: usize, output: &str) -> Result<(), String> {
+        let mut session = self.0.borrow_mut();
+        let Some(stop) = session.stepper.stop(ip) else {
+            return Ok(());
+        };
+        match session.pause(vm, ip, output, stop) {
+            Ok(Some(resume)) => {
+                session.stepper.resume(resume);
+                Ok(())
+            }
+            Ok(None) => Err(String::from("Stopped by the debugger.")),
+            Err(e) => {
+                let message = e.to_string();
+                session.error = Some(e);
+                Err(message)
+            }
+        }
+    }
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").and_then(Json::as_str).unwrap_or("")
+}
+
+fn request_seq(request: &Json) -> Json {
+    request.get("seq").cloned().unwrap_or(Json::Null)
+}
+
+fn variable(vm: &VM, name: String, value: &Value) -> Json {
+    let kind = match value.unpack() {
+        Unpacked::Bool(_) => "boolean",
+        Unpacked::Nil => "nil",
+        Unpacked::Number(_) => "number",
+        Unpacked::String(_) => "string",
+    };
+    let interner = vm.interner();
+    let value = format_value(value, Some(&interner.borrow()));
+    Json::object(vec![
+        ("name", Json::from(name)),
+        ("value", Json::from(value)),
+        ("type", Json::from(kind)),
+        ("variablesReference", Json::from(0)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::test_support::Shared;
+    use std::env;
+
+    // Runs the server over a scripted list of requests for a program saved
+    // under name, and returns what it sent, with the program's path written
+    // as $PROGRAM.
+    fn transcript(name: &str, program: &str, requests: &[&str]) -> Vec<String> {
+        let path = env::temp_dir().join(format!("lox-dap-{}-{}.lox", std::process::id(), name));
+        fs::write(&path, program).expect("could not write the program");
+        let quoted = Json::from(path.to_string_lossy().to_string()).to_string();
+
+        let mut input = vec![];
+        for request in requests {
+            let request = Json::parse(&request.replace("$PROGRAM", &quoted)).expect("bad request");
+            write_message(&mut input, &request).unwrap();
+        }
+        let out = Shared::default();
+        let mut server = Server::new(Box::new(io::Cursor::new(input)), Box::new(out.clone()));
+        server.serve().expect("server failed");
+        fs::remove_file(&path).ok();
+
+        let mut output = io::Cursor::new(out.take());
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(
+                message
+                    .unwrap()
+                    .to_string()
+                    .replace(&quoted[1..quoted.len() - 1], "$PROGRAM"),
+            );
+        }
+        messages
+    }
+
+    const PROGRAM: &str = "var a = 1;\nvar b = \"x\";\nprint a + 2;\nprint b;\n";
+
+    #[test]
+    fn it_skips_messages_that_are_not_json() {
+        let mut input = b"Content-Length: 1\r\n\r\n{".to_vec();
+        let disconnect = r#"{"seq":1,"type":"request","command":"disconnect"}"#;
+        write_message(&mut input, &Json::parse(disconnect).unwrap()).unwrap();
+        let out = Shared::default();
+        let mut server = Server::new(Box::new(io::Cursor::new(input)), Box::new(out.clone()));
+        server.serve().expect("server failed");
+
+        let mut output = io::Cursor::new(out.take());
+        let response = read_message(&mut output).unwrap().unwrap().unwrap();
+        assert_eq!(
+            Some("disconnect"),
+            response.get("command").and_then(Json::as_str)
+        );
+        assert_eq!(None, read_message(&mut output).unwrap());
+    }
+
+    #[test]
+    fn it_debugs_a_program() {
+        let messages = transcript(
+            "session",
+            PROGRAM,
+            &[
+                r#"{"seq":1,"type":"request","command":"initialize","arguments":{"adapterID":"lox"}}"#,
+                r#"{"seq":2,"type":"request","command":"launch","arguments":{"program":$PROGRAM}}"#,
+                r#"{"seq":3,"type":"request","command":"setBreakpoints","arguments":{"source":{"path":$PROGRAM},"breakpoints":[{"line":2},{"line":9}]}}"#,
+                r#"{"seq":4,"type":"request","command":"configurationDone"}"#,
+                r#"{"seq":5,"type":"request","command":"threads"}"#,
+                r#"{"seq":6,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#,
+                r#"{"seq":7,"type":"request","command":"scopes","arguments":{"frameId":0}}"#,
+                r#"{"seq":8,"type":"request","command":"variables","arguments":{"variablesReference":2}}"#,
+                r#"{"seq":9,"type":"request","command":"stepIn","arguments":{"threadId":1}}"#,
+                r#"{"seq":10,"type":"request","command":"variables","arguments":{"variablesReference":1}}"#,
+                r#"{"seq":11,"type":"request","command":"next","arguments":{"threadId":1}}"#,
+                r#"{"seq":12,"type":"request","command":"evaluate","arguments":{"expression":"a + 1"}}"#,
+                r#"{"seq":13,"type":"request","command":"continue","arguments":{"threadId":1}}"#,
+                r#"{"seq":14,"type":"request","command":"disconnect"}"#,
+            ],
+        );
+        assert_eq!(
+            vec![
+                r#"{"seq":1,"type":"response","request_seq":1,"success":true,"command":"initialize","body":{"supportsConfigurationDoneRequest":true,"supportsEvaluateForHovers":true}}"#,
+                r#"{"seq":2,"type":"event","event":"initialized","body":{}}"#,
+                r#"{"seq":3,"type":"response","request_seq":2,"success":true,"command":"launch","body":{}}"#,
+                r#"{"seq":4,"type":"response","request_seq":3,"success":true,"command":"setBreakpoints","body":{"breakpoints":[{"verified":true,"line":2},{"verified":false,"line":9,"message":"No code on this line."}]}}"#,
+                r#"{"seq":5,"type":"response","request_seq":4,"success":true,"command":"configurationDone","body":{}}"#,
+                r#"{"seq":6,"type":"event","event":"stopped","body":{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}}"#,
+                r#"{"seq":7,"type":"response","request_seq":5,"success":true,"command":"threads","body":{"threads":[{"id":1,"name":"main"}]}}"#,
+                r#"{"seq":8,"type":"response","request_seq":6,"success":true,"command":"stackTrace","body":{"stackFrames":[{"id":0,"name":"script","source":{"name":"lox-dap-ID-session.lox","path":"$PROGRAM"},"line":2,"column":1,"instructionPointerReference":"4"}],"totalFrames":1}}"#,
+                r#"{"seq":9,"type":"response","request_seq":7,"success":true,"command":"scopes","body":{"scopes":[{"name":"Stack","variablesReference":1,"expensive":false},{"name":"Globals","variablesReference":2,"expensive":false}]}}"#,
+                r#"{"seq":10,"type":"response","request_seq":8,"success":true,"command":"variables","body":{"variables":[{"name":"a","value":"1","type":"number","variablesReference":0}]}}"#,
+                r#"{"seq":11,"type":"response","request_seq":9,"success":true,"command":"stepIn","body":{}}"#,
+                r#"{"seq":12,"type":"event","event":"stopped","body":{"reason":"step","threadId":1,"allThreadsStopped":true}}"#,
+                r#"{"seq":13,"type":"response","request_seq":10,"success":true,"command":"variables","body":{"variables":[{"name":"0","value":"\"x\"","type":"string","variablesReference":0}]}}"#,
+                r#"{"seq":14,"type":"response","request_seq":11,"success":true,"command":"next","body":{}}"#,
+                r#"{"seq":15,"type":"event","event":"stopped","body":{"reason":"step","threadId":1,"allThreadsStopped":true}}"#,
+                r#"{"seq":16,"type":"response","request_seq":12,"success":true,"command":"evaluate","body":{"result":"2","variablesReference":0}}"#,
+                r#"{"seq":17,"type":"response","request_seq":13,"success":true,"command":"continue","body":{"allThreadsContinued":true}}"#,
+                r#"{"seq":18,"type":"event","event":"output","body":{"category":"stdout","output":"3\nx\n"}}"#,
+                r#"{"seq":19,"type":"event","event":"exited","body":{"exitCode":0}}"#,
+                r#"{"seq":20,"type":"event","event":"terminated","body":{}}"#,
+                r#"{"seq":21,"type":"response","request_seq":14,"success":true,"command":"disconnect","body":{}}"#,
+            ],
+            messages
+                .iter()
+                .map(|m| m.replace(&std::process::id().to_string(), "ID"))
+                .collect::<Vec<String>>()
+        );
+    }
+
+    fn events(messages: &[String]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|m| m.contains(r#""type":"event""#))
+            .map(|m| {
+                let message = Json::parse(m).unwrap();
+                format!(
+                    "{} {}",
+                    command_or_event(&message),
+                    message.get("body").unwrap()
+                )
+            })
+            .collect()
+    }
+
+    fn command_or_event(message: &Json) -> &str {
+        message
+            .get("event")
+            .or(message.get("command"))
+            .and_then(Json::as_str)
+            .unwrap_or("")
+    }
+
+    #[test]
+    fn it_stops_on_entry_and_reports_runtime_errors() {
+        let messages = transcript(
+            "error",
+            "print 1;\nprint -\"a\";\n",
+            &[
+                r#"{"seq":1,"command":"initialize"}"#,
+                r#"{"seq":2,"command":"launch","arguments":{"program":$PROGRAM,"stopOnEntry":true}}"#,
+                r#"{"seq":3,"command":"configurationDone"}"#,
+                r#"{"seq":4,"command":"next"}"#,
+                r#"{"seq":5,"command":"continue"}"#,
+            ],
+        );
+        assert_eq!(
+            vec![
+                r#"initialized {}"#,
+                r#"stopped {"reason":"entry","threadId":1,"allThreadsStopped":true}"#,
+                r#"output {"category":"stdout","output":"1\n"}"#,
+                r#"stopped {"reason":"step","threadId":1,"allThreadsStopped":true}"#,
+                r#"output {"category":"stderr","output":"Operand must be number.\n[line 2]\n"}"#,
+                r#"exited {"exitCode":70}"#,
+                r#"terminated {}"#,
+            ],
+            events(&messages)
+        );
+    }
+
+    #[test]
+    fn it_fails_requests_it_cannot_answer() {
+        let messages = transcript(
+            "fail",
+            "print 1 +;\n",
+            &[
+                r#"{"seq":1,"command":"stackTrace"}"#,
+                r#"{"seq":2,"command":"attach"}"#,
+                r#"{"seq":3,"command":"launch","arguments":{}}"#,
+                r#"{"seq":4,"command":"launch","arguments":{"program":$PROGRAM}}"#,
+            ],
+        );
+        let failures: Vec<String> = messages
+            .iter()
+            .map(|m| {
+                let message = Json::parse(m).unwrap();
+                assert_eq!(Some(false), message.get("success").and_then(Json::as_bool));
+                message.get("message").unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                r#""The program is not paused.""#,
+                r#""Unsupported request 'attach'.""#,
+                r#""Launch needs a program.""#,
+                r#""[line 1] Error at ';': Expect expression.""#,
+            ],
+            failures
+        );
+    }
+
+    #[test]
+    fn it_stops_the_program_on_disconnect() {
+        let messages = transcript(
+            "disconnect",
+            PROGRAM,
+            &[
+                r#"{"seq":1,"command":"launch","arguments":{"program":$PROGRAM}}"#,
+                r#"{"seq":2,"command":"setBreakpoints","arguments":{"breakpoints":[{"line":4}]}}"#,
+                r#"{"seq":3,"command":"configurationDone"}"#,
+                r#"{"seq":4,"command":"disconnect"}"#,
+                r#"{"seq":5,"command":"threads"}"#,
+            ],
+        );
+        assert_eq!(
+            vec![
+                r#"output {"category":"stdout","output":"3\n"}"#,
+                r#"stopped {"reason":"breakpoint","threadId":1,"allThreadsStopped":true}"#,
+            ],
+            events(&messages)
+        );
+        assert!(messages
+            .last()
+            .unwrap()
+            .contains(r#""command":"disconnect""#));
+    }
+}
@@ -0,0 +1,454 @@
+use crate::lox::chunk::{format_value, Chunk};
+use crate::lox::vm::{InterpretError, InterpretResult, VM};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+// Something that follows a run instruction by instruction. VM::run calls
+// start with the chunk, step before each instruction and finish with the
+// result, and only takes its traced loop while a debugger is set.
+pub trait Debugger {
+    fn start(&mut self, _chunk: &Chunk) {}
+
+    // Output is what the program has printed so far. An error stops the run
+    // as a runtime error.
+    fn step(&mut self, vm: &mut VM, chunk: &Chunk, ip: usize, output: &str) -> Result<(), String>;
+
+    fn finish(&mut self, _vm: &mut VM, _result: &InterpretResult) {}
+}
+
+impl fmt::Debug for dyn Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Debugger")
+    }
+}
+
+// Why a run paused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Entry,
+    Step,
+    Breakpoint,
+}
+
+// How a paused run goes on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    Instruction,
+    Line,
+    Continue,
+}
+
+// Decides where a run pauses. A breakpoint pauses the run at the first
+// instruction of its line, stepping by line pauses at the first instruction
+// of a line other than the one the run paused on.
+#[derive(Debug)]
+pub struct Stepper {
+    pub breakpoints: BTreeSet<usize>,
+    pub stop_on_entry: bool,
+    resume: Resume,
+    lines: Vec<usize>,
+    previous_line: Option<usize>,
+    paused_line: Option<usize>,
+}
+
+impl Stepper {
+    pub fn new(stop_on_entry: bool) -> Stepper {
+        Stepper {
+            breakpoints: BTreeSet::new(),
+            stop_on_entry,
+            resume: Resume::Continue,
+            lines: vec![],
+            previous_line: None,
+            paused_line: None,
+        }
+    }
+
+    pub fn start(&mut self, chunk: &Chunk) {
+        self.lines = chunk.lines_by_offset();
+        self.previous_line = None;
+        self.paused_line = None;
+    }
+
+    pub fn resume(&mut self, resume: Resume) {
+        self.resume = resume;
+    }
+
+    pub fn line(&self, ip: usize) -> usize {
+        self.lines.get(ip).copied().unwrap_or(0)
+    }
+
+    // Whether any code was compiled from line.
+    pub fn has_code(&self, line: usize) -> bool {
+        self.lines.contains(&line)
+    }
+
+    // Called before each instruction, with where the run pauses, if it does.
+    pub fn stop(&mut self, ip: usize) -> Option<Stop> {
+        let line = self.line(ip);
+        let first = self.previous_line.is_none();
+        let entered = self.previous_line != Some(line);
+        self.previous_line = Some(line);
+
+        let stop = if entered && self.breakpoints.contains(&line) {
+            Some(Stop::Breakpoint)
+        } else if first && self.stop_on_entry {
+            Some(Stop::Entry)
+        } else {
+            match self.resume {
+                Resume::Instruction => Some(Stop::Step),
+                Resume::Line if self.paused_line != Some(line) => Some(Stop::Step),
+                _ => None,
+            }
+        };
+        if stop.is_some() {
+            self.paused_line = Some(line);
+        }
+        stop
+    }
+}
+
+// The listing of each instruction in chunk, and the index of the one at ip.
+pub fn listing_at(vm: &VM, chunk: &Chunk, ip: usize) -> (Vec<String>, usize) {
+    let listing = chunk
+        .disassemble_with("", Some(&vm.interner().borrow()))
+        .unwrap_or_default();
+    let lines = listing.lines().skip(1).map(String::from).collect();
+
+    let mut index = 0;
+    let mut offset = 0;
+    while offset < ip {
+        match chunk.decode(offset) {
+            Some((_, next)) => offset = next,
+            None => break,
+        }
+        index += 1;
+    }
+    (lines, index)
+}
+
+const HELP: &str = "\
+break <line>   (b) pause when the run reaches line
+delete <line>  (d) remove the breakpoint on line
+breakpoints        list the breakpoints
+step           (s) run one instruction
+next           (n) run to the next line
+continue       (c) run to the next breakpoint
+stack              show the value stack, bottom first
+globals            show the global variables
+print <expr>   (p) evaluate an expression with the current globals
+list           (l) show the code around the current instruction
+quit           (q) stop the program
+";
+
+// The debugger behind lox debug. It reads commands from input and writes to
+// out, so it can be scripted. Program output is written as it is printed,
+// at each pause and when the run ends.
+pub struct Console {
+    input: Box<dyn BufRead>,
+    out: Box<dyn Write>,
+    source: Vec<String>,
+    stepper: Stepper,
+    shown: usize,
+}
+
+impl Console {
+    pub fn new(source: &str, input: Box<dyn BufRead>, out: Box<dyn Write>) -> Console {
+        Console {
+            input,
+            out,
+            source: source.lines().map(String::from).collect(),
+            stepper: Stepper::new(true),
+            shown: 0,
+        }
+    }
+
+    fn show_output(&mut self, output: &str) -> io::Result<()> {
+        if let Some(new) = output.get(self.shown..) {
+            write!(self.out, "{}", new)?;
+        }
+        self.shown = output.len();
+        Ok(())
+    }
+
+    fn show_stop(&mut self, vm: &VM, chunk: &Chunk, ip: usize, stop: Stop) -> io::Result<()> {
+        let line = self.stepper.line(ip);
+        let reason = match stop {
+            Stop::Entry => "Paused before line",
+            Stop::Step => "Stepped to line",
+            Stop::Breakpoint => "Hit the breakpoint on line",
+        };
+        writeln!(self.out, "{} {}.", reason, line)?;
+        if let Some(text) = self.source.get(line.wrapping_sub(1)) {
+            writeln!(self.out, "{:4} | {}", line, text)?;
+        }
+        let (listing, index) = listing_at(vm, chunk, ip);
+        if let Some(instruction) = listing.get(index) {
+            writeln!(self.out, "=> {}", instruction)?;
+        }
+        Ok(())
+    }
+
+    // Reads commands until one resumes the run, or None to quit.
+    fn prompt(&mut self, vm: &mut VM, chunk: &Chunk, ip: usize) -> io::Result<Option<Resume>> {
+        loop {
+            write!(self.out, "(lox) ")?;
+            self.out.flush()?;
+            let mut buffer = String::new();
+            if self.input.read_line(&mut buffer)? == 0 {
+                writeln!(self.out)?;
+                return Ok(None);
+            }
+            let (command, argument) = buffer.trim().split_once(' ').unwrap_or((buffer.trim(), ""));
+            let argument = argument.trim();
+            match command {
+                "step" | "s" => return Ok(Some(Resume::Instruction)),
+                "next" | "n" => return Ok(Some(Resume::Line)),
+                "continue" | "c" => return Ok(Some(Resume::Continue)),
+                "quit" | "q" => return Ok(None),
+                "break" | "b" => match argument.parse() {
+                    Ok(line) if self.stepper.has_code(line) => {
+                        self.stepper.breakpoints.insert(line);
+                        writeln!(self.out, "Breakpoint set at line {}.", line)?;
+                    }
+                    Ok(line) => writeln!(self.out, "No code on line {}.", line)?,
+                    Err(_) => writeln!(self.out, "Usage: break <line>")?,
+                },
+                "delete" | "d" => match argument.parse() {
+                    Ok(line) if self.stepper.breakpoints.remove(&line) => {
+                        writeln!(self.out, "Deleted the breakpoint at line {}.", line)?
+                    }
+                    Ok(line) => writeln!(self.out, "No breakpoint at line {}.", line)?,
+                    Err(_) => writeln!(self.out, "Usage: delete <line>")?,
+                },
+                "breakpoints" => {
+                    if self.stepper.breakpoints.is_empty() {
+                        writeln!(self.out, "No breakpoints.")?;
+                    }
+                    for line in self.stepper.breakpoints.iter() {
+                        writeln!(self.out, "line {}", line)?;
+                    }
+                }
+                "stack" => {
+                    let interner = vm.interner();
+                    let interner = interner.borrow();
+                    if vm.stack().is_empty() {
+                        writeln!(self.out, "The stack is empty.")?;
+                    }
+                    for (slot, value) in vm.stack().iter().enumerate() {
+                        writeln!(
+                            self.out,
+                            "{:4} {}",
+                            slot,
+                            format_value(value, Some(&interner))
+                        )?;
+                    }
+                }
+                "globals" => {
+                    let globals = vm.globals();
+                    let interner = vm.interner();
+                    let interner = interner.borrow();
+                    if globals.is_empty() {
+                        writeln!(self.out, "No globals.")?;
+                    }
+                    for (name, value) in globals {
+                        writeln!(
+                            self.out,
+                            "{} = {}",
+                            name,
+                            format_value(&value, Some(&interner))
+                        )?;
+                    }
+                }
+                "print" | "p" if !argument.is_empty() => match vm.evaluate(argument) {
+                    Ok(value) => writeln!(self.out, "{}", value)?,
+                    Err(InterpretError::CompileError(e))
+                    | Err(InterpretError::RuntimeError(e))
+                    | Err(InterpretError::VerifyError(e)) => {
+                        writeln!(self.out, "{}", e.trim_end())?
+                    }
+                },
+                "list" | "l" => {
+                    let (listing, index) = listing_at(vm, chunk, ip);
+                    let first = index.saturating_sub(4);
+                    for (i, instruction) in listing.iter().enumerate().skip(first).take(9) {
+                        let marker = if i == index { "=>" } else { "  " };
+                        writeln!(self.out, "{} {}", marker, instruction)?;
+                    }
+                }
+                "help" | "h" | "?" => write!(self.out, "{}", HELP)?,
+                "" => {}
+                _ => writeln!(self.out, "Unknown command '{}'. Try help.", buffer.trim())?,
+            }
+        }
+    }
+}
+
+impl Debugger for Console {
+    fn start(&mut self, chunk: &Chunk) {
+        self.stepper.start(chunk);
+        self.shown = 0;
+    }
+
+    fn step(&mut self, vm: &mut VM, chunk: &Chunk, ip: usize, output: &str) -> Result<(), String> {
+        let Some(stop) = self.stepper.stop(ip) else {
+            return Ok(());
+        };
+        let resume = self
+            .show_output(output)
+            .and_then(|_| self.show_stop(vm, chunk, ip, stop))
+            .and_then(|_| self.prompt(vm, chunk, ip))
+            .map_err(|e| e.to_string())?;
+        match resume {
+            Some(resume) => {
+                self.stepper.resume(resume);
+                Ok(())
+            }
+            None => Err(String::from("Stopped by the debugger.")),
+        }
+    }
+
+    fn finish(&mut self, _vm: &mut VM, result: &InterpretResult) {
+        if let Ok(output) = result {
+            let _ = self.show_output(output).and_then(|_| self.out.flush());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::test_support::Shared;
+
+    const SOURCE: &str = "var a = 1;\nvar b = \"x\";\nprint a + 2;\nprint b;\n";
+
+    fn debug(source: &str, commands: &str) -> (InterpretResult, String) {
+        let out = Shared::default();
+        let input = io::Cursor::new(commands.as_bytes().to_vec());
+        let mut vm = VM::new();
+        vm.set_debugger(Some(Box::new(Console::new(
+            source,
+            Box::new(input),
+            Box::new(out.clone()),
+        ))));
+        let result = vm.interpret(source);
+        let text = String::from_utf8(out.take()).expect("output is not UTF-8");
+        (result, text)
+    }
+
+    #[test]
+    fn it_steps_by_line_and_instruction() {
+        let (result, text) = debug(SOURCE, "next\nstep\nstep\ncontinue\n");
+        assert_eq!(Ok(String::from("3\nx\n")), result);
+        assert_eq!(
+            "\
+Paused before line 1.
+   1 | var a = 1;
+=> 0000    1 OP_SMALL_INT        1
+(lox) Stepped to line 2.
+   2 | var b = \"x\";
+=> 0002    2 OP_CONSTANT         1 '\"x\"
+(lox) Stepped to line 2.
+   2 | var b = \"x\";
+=> 0003    | OP_DEFINE_GLOBAL    2 '\"b\"
+(lox) Stepped to line 3.
+   3 | print a + 2;
+=> 0004    3 OP_GET_GLOBAL       0 '\"a\"
+(lox) 3
+x
+",
+            text
+        );
+    }
+
+    #[test]
+    fn it_stops_at_breakpoints() {
+        let (_, text) = debug(
+            SOURCE,
+            "break 4\nbreak 9\nbreakpoints\ncontinue\nglobals\nstack\nc\n",
+        );
+        assert!(text.contains(
+            "\
+(lox) Breakpoint set at line 4.
+(lox) No code on line 9.
+(lox) line 4
+(lox) 3
+Hit the breakpoint on line 4.
+   4 | print b;
+=> 0008    4 OP_GET_GLOBAL       2 '\"b\"
+(lox) a = 1
+b = \"x\"
+(lox) The stack is empty.
+(lox) x
+"
+        ));
+    }
+
+    #[test]
+    fn it_shows_the_stack_and_code() {
+        let (_, text) = debug(SOURCE, "break 3\nc\ns\ns\nstack\nlist\nq\n");
+        assert!(text.contains(
+            "\
+(lox)    0 1
+   1 2
+(lox)    0002    2 OP_CONSTANT         1 '\"x\"
+   0003    | OP_DEFINE_GLOBAL    2 '\"b\"
+   0004    3 OP_GET_GLOBAL       0 '\"a\"
+   0005    | OP_SMALL_INT        2
+=> 0006    | OP_ADD
+   0007    | OP_PRINT
+   0008    4 OP_GET_GLOBAL       2 '\"b\"
+   0009    | OP_PRINT
+   0010    5 OP_RETURN
+(lox) "
+        ));
+    }
+
+    #[test]
+    fn it_evaluates_expressions() {
+        let (result, text) = debug(
+            SOURCE,
+            "b 4\nc\np a * 10\np b + \"y\"\np b = \"z\"\np c\nc\n",
+        );
+        assert_eq!(Ok(String::from("3\nz\n")), result);
+        assert!(text.contains(
+            "\
+(lox) 10
+(lox) xy
+(lox) z
+(lox) Undefined variable 'c'.
+(lox) z
+"
+        ));
+    }
+
+    #[test]
+    fn it_quits() {
+        let (result, text) = debug(SOURCE, "bogus\nquit\n");
+        assert_eq!(
+            Err(InterpretError::RuntimeError(String::from(
+                "Stopped by the debugger.\n[line 1]"
+            ))),
+            result
+        );
+        assert!(text.contains("(lox) Unknown command 'bogus'. Try help.\n(lox) "));
+
+        let (result, _) = debug(SOURCE, "");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn it_pauses_on_breakpoints_once_per_entry() {
+        let mut chunk = Chunk::new();
+        for line in [1, 2, 2, 2, 3] {
+            chunk
+                .write_chunk(crate::lox::chunk::OpCode::Nil, line)
+                .unwrap();
+        }
+        let mut stepper = Stepper::new(false);
+        stepper.breakpoints.insert(2);
+        stepper.start(&chunk);
+        let stops: Vec<Option<Stop>> = (0..5).map(|ip| stepper.stop(ip)).collect();
+        assert_eq!(vec![None, Some(Stop::Breakpoint), None, None, None], stops);
+    }
+}
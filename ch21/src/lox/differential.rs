@@ -0,0 +1,269 @@
+use crate::lox::vm::{InterpretError, VM};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Runs Lox programs through ch13's Ruby tree-walker and this crate's VM and
+// compares what they print, what they report and how they exit.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub stdout: String,
+    // Without its trailing newline, which the two report differently.
+    pub stderr: String,
+    pub exit: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Stdout,
+    Stderr,
+    Exit,
+}
+
+impl Channel {
+    fn parse(name: &str) -> Option<Channel> {
+        match name {
+            "stdout" => Some(Channel::Stdout),
+            "stderr" => Some(Channel::Stderr),
+            "exit" => Some(Channel::Exit),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Stdout => write!(f, "stdout"),
+            Channel::Stderr => write!(f, "stderr"),
+            Channel::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub channel: Channel,
+    // A line diff from Ruby's output to the VM's.
+    pub diff: String,
+}
+
+// Runs source on a fresh VM, with the exit code lox would exit with. A program
+// that fails keeps what it printed before it failed.
+pub fn run_vm(source: &str) -> Outcome {
+    let mut vm = VM::new();
+    let (stdout, stderr, exit) = match vm.interpret(source) {
+        Ok(output) => (output, String::new(), 0),
+        Err(InterpretError::CompileError(e)) | Err(InterpretError::VerifyError(e)) => {
+            (String::new(), e, 65)
+        }
+        Err(InterpretError::RuntimeError(e)) => (vm.take_output(), e, 70),
+    };
+    Outcome {
+        stdout,
+        stderr: stderr.trim_end_matches('\n').to_string(),
+        exit,
+    }
+}
+
+// Where ch13's interpreter is, next to this crate.
+pub fn ruby_script() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../ch13/lox.rb")
+}
+
+// Runs a file on ch13's interpreter, or None if Ruby isn't installed.
+pub fn run_ruby(script: &Path, path: &Path) -> io::Result<Option<Outcome>> {
+    let output = match Command::new("ruby").arg(script).arg(path).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(Outcome {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr)
+            .trim_end_matches('\n')
+            .to_string(),
+        // A signal is reported as -1, which no Lox run exits with.
+        exit: output.status.code().unwrap_or(-1),
+    }))
+}
+
+// The channels a program says the two are known to differ on, from comments
+// like "// divergence stderr: The VM reports no line for runtime errors."
+pub fn known_divergences(source: &str) -> Vec<Channel> {
+    source
+        .lines()
+        .filter_map(|line| {
+            let annotation = line.split("// divergence ").nth(1)?;
+            let (channel, _reason) = annotation.split_once(':')?;
+            Channel::parse(channel.trim())
+        })
+        .collect()
+}
+
+pub fn compare(ruby: &Outcome, vm: &Outcome) -> Vec<Divergence> {
+    let mut divergences = vec![];
+    if ruby.stdout != vm.stdout {
+        divergences.push(Divergence {
+            channel: Channel::Stdout,
+            diff: diff(&ruby.stdout, &vm.stdout),
+        });
+    }
+    if ruby.stderr != vm.stderr {
+        divergences.push(Divergence {
+            channel: Channel::Stderr,
+            diff: diff(&ruby.stderr, &vm.stderr),
+        });
+    }
+    if ruby.exit != vm.exit {
+        divergences.push(Divergence {
+            channel: Channel::Exit,
+            diff: diff(&ruby.exit.to_string(), &vm.exit.to_string()),
+        });
+    }
+    divergences
+}
+
+// The fewest lines to take from expected ("-") and add from actual ("+") to
+// turn one into the other, each with its line number. Lines both share are
+// left out.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+
+    // common[i][j] is the length of the longest common subsequence of a[i..]
+    // and b[j..].
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut output = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+            output.push_str(&format!("-{:>4} | {}\n", i + 1, a[i]));
+            i += 1;
+        } else {
+            output.push_str(&format!("+{:>4} | {}\n", j + 1, b[j]));
+            j += 1;
+        }
+    }
+    output
+}
+
+// The .lox files in dir, sorted so reports come out in the same order.
+pub fn corpus(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            paths.extend(corpus(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(stdout: &str, stderr: &str, exit: i32) -> Outcome {
+        Outcome {
+            stdout: String::from(stdout),
+            stderr: String::from(stderr),
+            exit,
+        }
+    }
+
+    #[test]
+    fn it_diffs_only_the_lines_that_differ() {
+        assert_eq!("", diff("a\nb\n", "a\nb\n"));
+        assert_eq!("-   2 | b\n+   2 | x\n", diff("a\nb\nc\n", "a\nx\nc\n"));
+        assert_eq!("+   1 | z\n", diff("a\nb\n", "z\na\nb\n"));
+        assert_eq!("-   3 | c\n", diff("a\nb\nc", "a\nb"));
+    }
+
+    #[test]
+    fn it_compares_every_channel() {
+        let ruby = outcome("1\n", "Undefined variable 'x'.\n[line 2]", 70);
+        let vm = outcome("", "Undefined variable 'x'.", 70);
+        let divergences = compare(&ruby, &vm);
+        assert_eq!(
+            vec![Channel::Stdout, Channel::Stderr],
+            divergences
+                .iter()
+                .map(|divergence| divergence.channel)
+                .collect::<Vec<Channel>>()
+        );
+        assert_eq!("-   2 | [line 2]\n", divergences[1].diff);
+        assert!(compare(&vm, &vm).is_empty());
+    }
+
+    #[test]
+    fn it_reads_known_divergences() {
+        let source = "print 1;
+// divergence stderr: The VM reports no line.
+// divergence exit: Something else.
+// divergence bogus: Not a channel.
+// not a divergence stdout: at all";
+        assert_eq!(
+            vec![Channel::Stderr, Channel::Exit],
+            known_divergences(source)
+        );
+    }
+
+    #[test]
+    fn it_runs_the_vm_like_lox_does() {
+        assert_eq!(outcome("3\n", "", 0), run_vm("print 1 + 2;"));
+        assert_eq!(
+            outcome("1\n", "Operand must be number.\n[line 2]", 70),
+            run_vm("print 1;\nprint -nil;")
+        );
+        assert_eq!(65, run_vm("print ;").exit);
+    }
+
+    // Ruby isn't always installed, so this is skipped without it. Channels a
+    // file annotates as known to differ are left out of the comparison.
+    #[test]
+    fn it_agrees_with_ruby_on_the_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+        let script = ruby_script();
+        let mut report = String::new();
+        for path in corpus(&dir).expect("could not read the corpus") {
+            let Some(ruby) = run_ruby(&script, &path).expect("could not run ruby") else {
+                eprintln!("Skipping the differential corpus: ruby is not installed.");
+                return;
+            };
+            let source = fs::read_to_string(&path).expect("could not read a corpus file");
+            let known = known_divergences(&source);
+            for divergence in compare(&ruby, &run_vm(&source)) {
+                if !known.contains(&divergence.channel) {
+                    report.push_str(&format!(
+                        "{} diverges on {}:\n{}",
+                        path.display(),
+                        divergence.channel,
+                        divergence.diff
+                    ));
+                }
+            }
+        }
+        assert!(report.is_empty(), "\n{}", report);
+    }
+}
@@ -0,0 +1,831 @@
+use crate::lox::ast::*;
+use crate::lox::interner::{Interner, Symbol};
+use crate::lox::parser;
+use crate::lox::resolver::{self, Locals};
+use crate::lox::value::{Unpacked, Value, ADD_OPERANDS, NUMBER_OPERAND, NUMBER_OPERANDS};
+use crate::lox::vm::{InterpretError, InterpretResult};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Calls nest on the Rust stack, so they are limited well short of where it
+// would overflow: to clox's FRAMES_MAX.
+const MAX_CALL_DEPTH: usize = 64;
+
+// What the evaluator works with: the VM's values, and the functions, classes
+// and instances only the tree-walker has.
+#[derive(Clone)]
+pub enum Object {
+    Value(Value),
+    Function(Rc<LoxFunction>),
+    Native(Rc<Native>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<RefCell<Instance>>),
+}
+
+impl Object {
+    const NIL: Object = Object::Value(Value::Nil);
+
+    fn is_falsey(&self) -> bool {
+        match self {
+            Object::Value(value) => value.is_falsey(),
+            _ => false,
+        }
+    }
+
+    fn equals(&self, other: &Object) -> bool {
+        match (self, other) {
+            (Object::Value(a), Object::Value(b)) => a == b,
+            (Object::Function(a), Object::Function(b)) => Rc::ptr_eq(a, b),
+            (Object::Native(a), Object::Native(b)) => Rc::ptr_eq(a, b),
+            (Object::Class(a), Object::Class(b)) => Rc::ptr_eq(a, b),
+            (Object::Instance(a), Object::Instance(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+pub struct Native {
+    name: &'static str,
+    arity: usize,
+    function: fn(&[Object]) -> Object,
+}
+
+pub struct LoxFunction {
+    declaration: Rc<Function>,
+    closure: Env,
+    // The resolution of the program the function was declared in.
+    locals: Rc<Locals>,
+    is_initializer: bool,
+}
+
+pub struct LoxClass {
+    name: String,
+    superclass: Option<Rc<LoxClass>>,
+    methods: HashMap<String, Rc<LoxFunction>>,
+}
+
+impl LoxClass {
+    fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+
+    fn arity(&self) -> usize {
+        self.find_method("init")
+            .map(|init| init.declaration.params.len())
+            .unwrap_or(0)
+    }
+}
+
+pub struct Instance {
+    class: Rc<LoxClass>,
+    fields: HashMap<String, Object>,
+}
+
+type Env = Rc<RefCell<Environment>>;
+
+#[derive(Default)]
+struct Environment {
+    values: HashMap<Symbol, Object>,
+    enclosing: Option<Env>,
+}
+
+impl Environment {
+    fn new(enclosing: &Env) -> Env {
+        Rc::new(RefCell::new(Environment {
+            values: HashMap::new(),
+            enclosing: Some(enclosing.clone()),
+        }))
+    }
+
+    fn ancestor(env: &Env, distance: usize) -> Env {
+        let mut env = env.clone();
+        for _ in 0..distance {
+            let enclosing = env.borrow().enclosing.clone();
+            match enclosing {
+                Some(enclosing) => env = enclosing,
+                None => break,
+            }
+        }
+        env
+    }
+}
+
+// Why evaluation stopped early: a return unwinding to its call, or an error
+// unwinding all the way out.
+enum Unwind {
+    Return(Object),
+    Error(String, usize),
+}
+
+type Evaluated<T> = Result<T, Unwind>;
+
+fn error<T>(message: String, line: usize) -> Evaluated<T> {
+    Err(Unwind::Error(message, line))
+}
+
+// A tree-walking interpreter with ch13's semantics, for checking the VM
+// against. Globals last between runs, like the VM's.
+pub struct Evaluator {
+    interner: Rc<RefCell<Interner>>,
+    globals: Env,
+    environment: Env,
+    locals: Rc<Locals>,
+    depth: usize,
+    output: String,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Evaluator {
+    pub fn new() -> Evaluator {
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let globals: Env = Rc::default();
+        let clock = Native {
+            name: "clock",
+            arity: 0,
+            function: |_| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs_f64())
+                    .unwrap_or(0.0);
+                Object::Value(Value::Number(now))
+            },
+        };
+        let symbol = interner.borrow_mut().intern(clock.name);
+        globals
+            .borrow_mut()
+            .values
+            .insert(symbol, Object::Native(Rc::new(clock)));
+        Evaluator {
+            interner,
+            environment: globals.clone(),
+            globals,
+            locals: Rc::default(),
+            depth: 0,
+            output: String::new(),
+        }
+    }
+
+    pub fn interner(&self) -> Rc<RefCell<Interner>> {
+        self.interner.clone()
+    }
+
+    // Runs source and returns what it printed. Errors are reported the way
+    // ch13 reports them, with a runtime error's line after its message.
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        let (statements, locals) = resolver::check(source).map_err(|errors| {
+            let messages: Vec<String> = errors
+                .iter()
+                .map(|error| parser::format_error(source, error))
+                .collect();
+            InterpretError::CompileError(messages.join("\n"))
+        })?;
+
+        self.locals = Rc::new(locals);
+        self.output.clear();
+        self.environment = self.globals.clone();
+        self.depth = 0;
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement));
+        match result {
+            Ok(()) | Err(Unwind::Return(_)) => Ok(self.take_output()),
+            Err(Unwind::Error(message, line)) => Err(InterpretError::RuntimeError(format!(
+                "{}\n[line {}]",
+                message, line
+            ))),
+        }
+    }
+
+    // What the last run printed before it failed, like the VM's. A run that
+    // doesn't fail returns its output instead.
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    fn execute(&mut self, statement: &Stmt) -> Evaluated<()> {
+        match &statement.kind {
+            StmtKind::Block(statements) => {
+                let environment = Environment::new(&self.environment);
+                self.execute_block(statements, environment)
+            }
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            StmtKind::Expression(expression) => self.evaluate(expression).map(|_| ()),
+            StmtKind::Function(declaration) => {
+                let function = self.function(declaration, false);
+                self.define(&declaration.name.name, Object::Function(Rc::new(function)));
+                Ok(())
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if !self.evaluate(condition)?.is_falsey() {
+                    self.execute(then_branch)
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)
+                } else {
+                    Ok(())
+                }
+            }
+            StmtKind::Print(expression) => {
+                let value = self.evaluate(expression)?;
+                let text = self.stringify(&value);
+                // Writing to a String can't fail.
+                let _ = writeln!(self.output, "{}", text);
+                Ok(())
+            }
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Object::NIL,
+                };
+                Err(Unwind::Return(value))
+            }
+            StmtKind::Var { name, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Object::NIL,
+                };
+                self.define(&name.name, value);
+                Ok(())
+            }
+            StmtKind::While { condition, body } => {
+                while !self.evaluate(condition)?.is_falsey() {
+                    self.execute(body)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn execute_block(&mut self, statements: &[Stmt], environment: Env) -> Evaluated<()> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement));
+        self.environment = previous;
+        result
+    }
+
+    fn class(
+        &mut self,
+        name: &Name,
+        superclass: Option<&Name>,
+        methods: &[Function],
+    ) -> Evaluated<()> {
+        let superclass = match superclass {
+            Some(superclass) => match self.lookup_variable(superclass)? {
+                Object::Class(class) => Some(class),
+                _ => return error(String::from("Superclass must be a class."), superclass.line),
+            },
+            None => None,
+        };
+
+        self.define(&name.name, Object::NIL);
+
+        let enclosing = self.environment.clone();
+        if let Some(superclass) = &superclass {
+            self.environment = Environment::new(&enclosing);
+            self.define("super", Object::Class(superclass.clone()));
+        }
+
+        let methods = methods
+            .iter()
+            .map(|method| {
+                let function = self.function(method, method.name.name == "init");
+                (method.name.name.clone(), Rc::new(function))
+            })
+            .collect();
+        let class = LoxClass {
+            name: name.name.clone(),
+            superclass,
+            methods,
+        };
+
+        self.environment = enclosing;
+        self.define(&name.name, Object::Class(Rc::new(class)));
+        Ok(())
+    }
+
+    fn function(&self, declaration: &Function, is_initializer: bool) -> LoxFunction {
+        LoxFunction {
+            declaration: Rc::new(declaration.clone()),
+            closure: self.environment.clone(),
+            locals: self.locals.clone(),
+            is_initializer,
+        }
+    }
+
+    fn evaluate(&mut self, expression: &Expr) -> Evaluated<Object> {
+        let line = expression.line;
+        match &expression.kind {
+            ExprKind::Assign { name, value } => {
+                let value = self.evaluate(value)?;
+                match self.locals.depth(name.span.start) {
+                    Some(distance) => {
+                        let symbol = self.symbol(&name.name);
+                        Environment::ancestor(&self.environment, distance)
+                            .borrow_mut()
+                            .values
+                            .insert(symbol, value.clone());
+                    }
+                    None => self.assign_global(name, value.clone())?,
+                }
+                Ok(value)
+            }
+            ExprKind::Binary { left, op, right } => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+                self.binary(*op, left, right, line)
+            }
+            ExprKind::Call { callee, arguments } => {
+                let callee = self.evaluate(callee)?;
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.evaluate(argument))
+                    .collect::<Evaluated<Vec<Object>>>()?;
+                self.call(callee, arguments, line)
+            }
+            ExprKind::Get { object, name } => match self.evaluate(object)? {
+                Object::Instance(instance) => {
+                    if let Some(value) = instance.borrow().fields.get(&name.name) {
+                        return Ok(value.clone());
+                    }
+                    let method = instance.borrow().class.find_method(&name.name);
+                    match method {
+                        Some(method) => {
+                            let bound = self.bind(&method, Object::Instance(instance));
+                            Ok(Object::Function(Rc::new(bound)))
+                        }
+                        None => error(format!("Undefined property '{}'.", name.name), line),
+                    }
+                }
+                _ => error(String::from("Only instances have properties."), line),
+            },
+            ExprKind::Grouping(inner) => self.evaluate(inner),
+            ExprKind::Literal(literal) => Ok(Object::Value(match literal {
+                Literal::Nil => Value::Nil,
+                Literal::Bool(b) => Value::Bool(*b),
+                Literal::Number(n) => Value::Number(*n),
+                Literal::String(s) => Value::String(self.symbol(s)),
+            })),
+            ExprKind::Logical { left, op, right } => {
+                let left = self.evaluate(left)?;
+                let short_circuits = match op {
+                    LogicalOp::Or => !left.is_falsey(),
+                    LogicalOp::And => left.is_falsey(),
+                };
+                if short_circuits {
+                    Ok(left)
+                } else {
+                    self.evaluate(right)
+                }
+            }
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            } => {
+                let Object::Instance(instance) = self.evaluate(object)? else {
+                    return error(String::from("Only instances have fields."), line);
+                };
+                let value = self.evaluate(value)?;
+                instance
+                    .borrow_mut()
+                    .fields
+                    .insert(name.name.clone(), value.clone());
+                Ok(value)
+            }
+            ExprKind::Super { method } => {
+                let distance = self.locals.depth(expression.span.start).unwrap_or(0);
+                let superclass = self.get_at(distance, "super");
+                let object = self.get_at(distance.saturating_sub(1), "this");
+                let Object::Class(superclass) = superclass else {
+                    return error(String::from("Superclass must be a class."), line);
+                };
+                match superclass.find_method(&method.name) {
+                    Some(found) => Ok(Object::Function(Rc::new(self.bind(&found, object)))),
+                    None => error(format!("Undefined property '{}'.", method.name), line),
+                }
+            }
+            ExprKind::This => match self.locals.depth(expression.span.start) {
+                Some(distance) => Ok(self.get_at(distance, "this")),
+                None => Ok(Object::NIL),
+            },
+            ExprKind::Unary { op, operand } => {
+                let operand = self.evaluate(operand)?;
+                match op {
+                    UnaryOp::Not => Ok(Object::Value(Value::Bool(operand.is_falsey()))),
+                    UnaryOp::Negate => match operand {
+                        Object::Value(value) => match value.unpack() {
+                            Unpacked::Number(n) => Ok(Object::Value(Value::Number(-n))),
+                            _ => error(String::from(NUMBER_OPERAND), line),
+                        },
+                        _ => error(String::from(NUMBER_OPERAND), line),
+                    },
+                }
+            }
+            ExprKind::Variable(name) => self.lookup_variable(name),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        left: Object,
+        right: Object,
+        line: usize,
+    ) -> Evaluated<Object> {
+        if let BinaryOp::Equal | BinaryOp::NotEqual = op {
+            let equal = left.equals(&right);
+            return Ok(Object::Value(Value::Bool(equal == (op == BinaryOp::Equal))));
+        }
+        let (Object::Value(a), Object::Value(b)) = (left, right) else {
+            return error(operands_message(op), line);
+        };
+        let value = match (op, a.unpack(), b.unpack()) {
+            (BinaryOp::Add, Unpacked::String(a), Unpacked::String(b)) => {
+                let mut interner = self.interner.borrow_mut();
+                // Strings here only ever come from this interner.
+                let (a, b) = (interner.lookup(a), interner.lookup(b));
+                let s = format!("{}{}", a.unwrap_or_default(), b.unwrap_or_default());
+                Value::String(interner.intern(&s))
+            }
+            (BinaryOp::Add, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a + b),
+            (BinaryOp::Subtract, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a - b),
+            (BinaryOp::Multiply, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a * b),
+            (BinaryOp::Divide, Unpacked::Number(a), Unpacked::Number(b)) => Value::Number(a / b),
+            (BinaryOp::Greater, Unpacked::Number(a), Unpacked::Number(b)) => Value::Bool(a > b),
+            (BinaryOp::GreaterEqual, Unpacked::Number(a), Unpacked::Number(b)) => {
+                Value::Bool(a >= b)
+            }
+            (BinaryOp::Less, Unpacked::Number(a), Unpacked::Number(b)) => Value::Bool(a < b),
+            (BinaryOp::LessEqual, Unpacked::Number(a), Unpacked::Number(b)) => Value::Bool(a <= b),
+            _ => return error(operands_message(op), line),
+        };
+        Ok(Object::Value(value))
+    }
+
+    fn call(&mut self, callee: Object, arguments: Vec<Object>, line: usize) -> Evaluated<Object> {
+        match callee {
+            Object::Function(function) => {
+                check_arity(function.declaration.params.len(), arguments.len(), line)?;
+                self.call_function(&function, arguments, line)
+            }
+            Object::Native(native) => {
+                check_arity(native.arity, arguments.len(), line)?;
+                Ok((native.function)(&arguments))
+            }
+            Object::Class(class) => {
+                check_arity(class.arity(), arguments.len(), line)?;
+                let instance = Object::Instance(Rc::new(RefCell::new(Instance {
+                    class: class.clone(),
+                    fields: HashMap::new(),
+                })));
+                if let Some(init) = class.find_method("init") {
+                    let init = self.bind(&init, instance.clone());
+                    self.call_function(&init, arguments, line)?;
+                }
+                Ok(instance)
+            }
+            _ => error(String::from("Can only call functions and classes."), line),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &LoxFunction,
+        arguments: Vec<Object>,
+        line: usize,
+    ) -> Evaluated<Object> {
+        if self.depth >= MAX_CALL_DEPTH {
+            return error(String::from("Stack overflow."), line);
+        }
+        let environment = Environment::new(&function.closure);
+        for (param, argument) in function.declaration.params.iter().zip(arguments) {
+            let symbol = self.symbol(&param.name);
+            environment.borrow_mut().values.insert(symbol, argument);
+        }
+
+        self.depth += 1;
+        let locals = std::mem::replace(&mut self.locals, function.locals.clone());
+        let result = self.execute_block(&function.declaration.body, environment);
+        self.locals = locals;
+        self.depth -= 1;
+
+        let value = match result {
+            Ok(()) => Object::NIL,
+            Err(Unwind::Return(value)) => value,
+            Err(error) => return Err(error),
+        };
+        if function.is_initializer {
+            let symbol = self.symbol("this");
+            return Ok(function
+                .closure
+                .borrow()
+                .values
+                .get(&symbol)
+                .cloned()
+                .unwrap_or(Object::NIL));
+        }
+        Ok(value)
+    }
+
+    fn bind(&mut self, method: &LoxFunction, instance: Object) -> LoxFunction {
+        let environment = Environment::new(&method.closure);
+        let symbol = self.symbol("this");
+        environment.borrow_mut().values.insert(symbol, instance);
+        LoxFunction {
+            declaration: method.declaration.clone(),
+            closure: environment,
+            locals: method.locals.clone(),
+            is_initializer: method.is_initializer,
+        }
+    }
+
+    fn lookup_variable(&mut self, name: &Name) -> Evaluated<Object> {
+        match self.locals.depth(name.span.start) {
+            Some(distance) => Ok(self.get_at(distance, &name.name)),
+            None => {
+                let symbol = self.symbol(&name.name);
+                match self.globals.borrow().values.get(&symbol) {
+                    Some(value) => Ok(value.clone()),
+                    None => error(format!("Undefined variable '{}'.", name.name), name.line),
+                }
+            }
+        }
+    }
+
+    fn get_at(&mut self, distance: usize, name: &str) -> Object {
+        let symbol = self.symbol(name);
+        Environment::ancestor(&self.environment, distance)
+            .borrow()
+            .values
+            .get(&symbol)
+            .cloned()
+            .unwrap_or(Object::NIL)
+    }
+
+    fn assign_global(&mut self, name: &Name, value: Object) -> Evaluated<()> {
+        let symbol = self.symbol(&name.name);
+        let mut globals = self.globals.borrow_mut();
+        match globals.values.get_mut(&symbol) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => error(format!("Undefined variable '{}'.", name.name), name.line),
+        }
+    }
+
+    fn define(&mut self, name: &str, value: Object) {
+        let symbol = self.symbol(name);
+        self.environment.borrow_mut().values.insert(symbol, value);
+    }
+
+    fn symbol(&self, name: &str) -> Symbol {
+        self.interner.borrow_mut().intern(name)
+    }
+
+    fn stringify(&self, object: &Object) -> String {
+        match object {
+            Object::Value(value) => match value.unpack() {
+                Unpacked::String(s) => self
+                    .interner
+                    .borrow()
+                    .lookup(s)
+                    .unwrap_or_default()
+                    .to_string(),
+                other => other.to_string(),
+            },
+            Object::Function(function) => format!("<fn {}>", function.declaration.name.name),
+            Object::Native(_) => String::from("<native fn>"),
+            Object::Class(class) => class.name.clone(),
+            Object::Instance(instance) => format!("{} instance", instance.borrow().class.name),
+        }
+    }
+}
+
+fn check_arity(arity: usize, count: usize, line: usize) -> Evaluated<()> {
+    if arity == count {
+        Ok(())
+    } else {
+        error(
+            format!("Expected {} arguments but got {}.", arity, count),
+            line,
+        )
+    }
+}
+
+fn operands_message(op: BinaryOp) -> String {
+    match op {
+        BinaryOp::Add => String::from(ADD_OPERANDS),
+        _ => String::from(NUMBER_OPERANDS),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> InterpretResult {
+        Evaluator::new().interpret(source)
+    }
+
+    fn assert_prints(expected: &str, source: &str) {
+        assert_eq!(Ok(String::from(expected)), run(source), "{}", source);
+    }
+
+    fn assert_runtime_error(expected: &str, source: &str) {
+        assert_eq!(
+            Err(InterpretError::RuntimeError(String::from(expected))),
+            run(source),
+            "{}",
+            source
+        );
+    }
+
+    #[test]
+    fn it_evaluates_expressions() {
+        assert_prints("7\n", "print 1 + 2 * 3;");
+        assert_prints("-0.5\n", "print -(1 / 2);");
+        assert_prints("true\nfalse\n", "print 1 == 1.0; print nil == false;");
+        assert_prints("ab\n", "print \"a\" + \"b\";");
+        assert_prints("true\n", "print \"a\" + \"b\" == \"ab\";");
+        assert_prints("nil\n2\n", "print nil and 1; print nil or 2;");
+        assert_prints("false\ntrue\n", "print !true; print !nil;");
+    }
+
+    #[test]
+    fn it_scopes_variables() {
+        assert_prints(
+            "inner a\nouter b\nglobal c\nouter a\nouter b\nglobal c\nglobal a\nglobal b\nglobal c\n",
+            "var a = \"global a\"; var b = \"global b\"; var c = \"global c\";
+            {
+              var a = \"outer a\"; var b = \"outer b\";
+              { var a = \"inner a\"; print a; print b; print c; }
+              print a; print b; print c;
+            }
+            print a; print b; print c;",
+        );
+    }
+
+    #[test]
+    fn it_resolves_closures_statically() {
+        assert_prints(
+            "global\nglobal\n",
+            "var a = \"global\";
+            { fun showA() { print a; } showA(); var a = \"block\"; showA(); }",
+        );
+        assert_prints(
+            "1\n2\n",
+            "fun counter() { var i = 0; fun count() { i = i + 1; print i; } return count; }
+            var c = counter(); c(); c();",
+        );
+    }
+
+    #[test]
+    fn it_runs_loops_and_recursion() {
+        assert_prints(
+            "0\n1\n1\n2\n3\n5\n",
+            "var a = 0; var temp;
+            for (var b = 1; a < 8; b = temp + b) { print a; temp = a; a = b; }",
+        );
+        assert_prints(
+            "55\n",
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(10);",
+        );
+    }
+
+    #[test]
+    fn it_runs_classes() {
+        assert_prints(
+            "Point\nPoint instance\n2\n3\n",
+            "class Point {} print Point; print Point();
+            fun newPoint(x, y) { var point = Point(); point.x = x; point.y = y; return point; }
+            var p = newPoint(2, 3); print p.x; print p.y;",
+        );
+        assert_prints(
+            "The German chocolate cake is delicious!\n",
+            "class Cake {
+              taste() { var adjective = \"delicious\"; print \"The \" + this.flavor + \" cake is \" + adjective + \"!\"; }
+            }
+            var cake = Cake(); cake.flavor = \"German chocolate\"; cake.taste();",
+        );
+        assert_prints(
+            "Foo instance\n1\n",
+            "class Foo { init(x) { this.x = x; return; } } var foo = Foo(1); print foo.init(1); print foo.x;",
+        );
+        assert_prints(
+            "Fry until golden brown.\nPipe full of custard and coat with chocolate.\n",
+            "class Doughnut { cook() { print \"Fry until golden brown.\"; } }
+            class BostonCream < Doughnut {
+              cook() { super.cook(); print \"Pipe full of custard and coat with chocolate.\"; }
+            }
+            BostonCream().cook();",
+        );
+    }
+
+    #[test]
+    fn it_prints_callables() {
+        assert_prints(
+            "<fn f>\n<native fn>\n<fn m>\n",
+            "fun f() {} print f; print clock; class A { m() {} } print A().m;",
+        );
+    }
+
+    #[test]
+    fn it_reports_runtime_errors_with_lines() {
+        assert_runtime_error(
+            "Operands must be numbers.\n[line 2]",
+            "print 1;\nprint 1 - \"a\";",
+        );
+        assert_runtime_error(
+            "Operands must be numbers or strings.\n[line 1]",
+            "print 1 + nil;",
+        );
+        assert_runtime_error("Operand must be number.\n[line 1]", "print -\"a\";");
+        assert_runtime_error("Undefined variable 'b'.\n[line 1]", "b = 1;");
+        assert_runtime_error("Can only call functions and classes.\n[line 1]", "\"a\"();");
+        assert_runtime_error(
+            "Expected 1 arguments but got 2.\n[line 3]",
+            "fun f(a) {}\nf(1,\n2);",
+        );
+        assert_runtime_error("Only instances have properties.\n[line 1]", "print 1.x;");
+        assert_runtime_error(
+            "Undefined property 'y'.\n[line 1]",
+            "class A {} print A().y;",
+        );
+        assert_runtime_error(
+            "Superclass must be a class.\n[line 1]",
+            "var A = 1; class B < A {}",
+        );
+        assert_runtime_error("Stack overflow.\n[line 1]", "fun f() { f(); } f();");
+    }
+
+    #[test]
+    fn it_keeps_output_printed_before_a_runtime_error() {
+        let mut evaluator = Evaluator::new();
+        assert_eq!(
+            Err(InterpretError::RuntimeError(String::from(
+                "Operands must be numbers or strings.\n[line 2]"
+            ))),
+            evaluator.interpret("print 1;\nprint 1 + \"a\";")
+        );
+        assert_eq!("1\n", evaluator.take_output());
+        assert_eq!("", evaluator.take_output());
+    }
+
+    #[test]
+    fn it_reports_static_errors_before_running() {
+        assert_eq!(
+            Err(InterpretError::CompileError(String::from(
+                "[line 2] Error at 'return': Can't return from top-level code."
+            ))),
+            run("print 1;\nreturn;")
+        );
+    }
+
+    #[test]
+    fn it_refuses_to_nest_deeper_than_the_stack_allows() {
+        let source = format!(
+            "fun f() {{ {}print 1;{} }}",
+            "{".repeat(100),
+            "}".repeat(100)
+        );
+        assert_eq!(
+            Err(InterpretError::CompileError(String::from(
+                "[line 1] Error at '{': Too much nesting."
+            ))),
+            run(&source)
+        );
+        let source = format!("print {}1{};", "(".repeat(50_000), ")".repeat(50_000));
+        assert!(matches!(run(&source), Err(InterpretError::CompileError(_))));
+    }
+
+    #[test]
+    fn it_keeps_globals_between_runs() {
+        let mut evaluator = Evaluator::new();
+        evaluator
+            .interpret("var a = 1; fun f() { var b = 2; return a + b; }")
+            .unwrap();
+        assert_eq!(Ok(String::from("3\n")), evaluator.interpret("print f();"));
+    }
+}
@@ -42,7 +42,7 @@ mod tests {
     fn it_can_hash_string() {
         let mut id = hash_string("hello");
         assert_eq!(0xa430d84680aabd0b, id);
-        id = hash_string(&"hello world");
+        id = hash_string("hello world");
         assert_eq!(0x779a65e7023cd2e7, id);
     }
 }
@@ -0,0 +1,597 @@
+use crate::lox::parser;
+use crate::lox::scanner::{Scanner, Token, TokenType};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const INDENT: &str = "  ";
+const MAX_WIDTH: usize = 80;
+
+// What the formatter keeps from the source besides its tokens: comments,
+// which either end a line of code or sit on a line of their own, and
+// blank lines between statements.
+enum Item {
+    Token(Token),
+    Comment { text: String, trailing: bool },
+    Blank,
+}
+
+// Formats Lox source in the style of the book: two space indents, one
+// statement per line, spaces around binary operators and argument lists
+// that are too long broken out one per line. Only whitespace changes, and
+// formatting formatted source gives it back unchanged. Source that doesn't
+// parse is refused, since there would be no telling where its statements
+// end.
+pub fn format(source: &str) -> Result<String, String> {
+    let items = items(source)?;
+    if let Err(errors) = parser::parse(source) {
+        let messages: Vec<String> = errors
+            .iter()
+            .map(|error| parser::format_error(source, error))
+            .collect();
+        return Err(messages.join("\n"));
+    }
+    let mut formatter = Formatter {
+        source,
+        lines: vec![],
+        line: vec![],
+        comment: None,
+        line_indent: 0,
+        indent: 0,
+        continuation: false,
+        parens: 0,
+        ended: false,
+        blank: false,
+    };
+    let mut items = items.into_iter().peekable();
+    while let Some(item) = items.next() {
+        match item {
+            Item::Token(token) => {
+                let empty_block = token.token_type == TokenType::LeftBrace
+                    && matches!(items.peek(), Some(Item::Token(next)) if next.token_type == TokenType::RightBrace);
+                match items.next_if(|_| empty_block) {
+                    Some(Item::Token(close)) => formatter.empty_block(token, close),
+                    _ => formatter.token(token),
+                }
+            }
+            Item::Comment { text, trailing } => formatter.comment(text, trailing),
+            Item::Blank => formatter.blank(),
+        }
+    }
+    formatter.flush();
+
+    if formatter.lines.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(formatter.lines.join("\n") + "\n")
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    Read(io::Error),
+    Write(io::Error),
+    // The source doesn't parse, with the errors that say why.
+    Source(String),
+}
+
+// Formats the file at path where it is, unless only checking, and says
+// whether formatting changes it. A file that doesn't parse is left alone.
+pub fn format_file(path: &Path, check: bool) -> Result<bool, FileError> {
+    let source = fs::read_to_string(path).map_err(FileError::Read)?;
+    let formatted = format(&source).map_err(FileError::Source)?;
+    if formatted == source {
+        return Ok(false);
+    }
+    if !check {
+        fs::write(path, formatted).map_err(FileError::Write)?;
+    }
+    Ok(true)
+}
+
+fn items(source: &str) -> Result<Vec<Item>, String> {
+    let mut items = vec![];
+    // Whether a newline has been seen since the last token or comment.
+    let mut newline = false;
+    for token in Scanner::with_trivia(source) {
+        match &token.token_type {
+            TokenType::Whitespace(text) => {
+                let newlines = text.matches('\n').count();
+                // A block never starts with a blank line, so an empty one
+                // stays empty.
+                let after_open = matches!(items.last(), Some(Item::Token(t)) if t.token_type == TokenType::LeftBrace);
+                if newlines > 1 && !items.is_empty() && !after_open {
+                    items.push(Item::Blank);
+                }
+                newline = newline || newlines > 0;
+            }
+            TokenType::Comment(text) => {
+                let trailing = !newline && !items.is_empty();
+                items.push(Item::Comment {
+                    text: text.trim_end().to_string(),
+                    trailing,
+                });
+                newline = false;
+            }
+            TokenType::Error(message) => {
+                return Err(format!("[line {}] Error: {}", token.line, message));
+            }
+            _ => {
+                items.push(Item::Token(token));
+                newline = false;
+            }
+        }
+    }
+    Ok(items)
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    lines: Vec<String>,
+    // The tokens and trailing comment of the line being built, and its
+    // indent.
+    line: Vec<Token>,
+    comment: Option<String>,
+    line_indent: usize,
+    indent: usize,
+    // Whether a comment has broken the current statement across lines.
+    continuation: bool,
+    parens: usize,
+    // Whether the line being built is done, once the next token shows it
+    // does not continue it.
+    ended: bool,
+    // Whether a blank line was seen between statements.
+    blank: bool,
+}
+
+impl Formatter<'_> {
+    // Starts a new line for token if the line before is done, unless it
+    // is an else after a closing brace.
+    fn end_line_before(&mut self, token: &Token) {
+        if !self.ended {
+            return;
+        }
+        let else_after_brace = token.token_type == TokenType::Else
+            && self
+                .line
+                .last()
+                .is_some_and(|last| last.token_type == TokenType::RightBrace);
+        if !else_after_brace {
+            self.flush();
+        }
+        self.ended = false;
+    }
+
+    fn empty_block(&mut self, open: Token, close: Token) {
+        self.end_line_before(&open);
+        self.push(open);
+        self.push(close);
+        self.end_statement();
+    }
+
+    fn token(&mut self, token: Token) {
+        self.end_line_before(&token);
+        match token.token_type {
+            TokenType::LeftBrace => {
+                self.push(token);
+                self.indent += 1;
+                self.end_statement();
+            }
+            TokenType::RightBrace => {
+                self.flush();
+                self.indent = self.indent.saturating_sub(1);
+                self.continuation = false;
+                self.push(token);
+                self.end_statement();
+            }
+            TokenType::Semicolon => {
+                self.push(token);
+                if self.parens == 0 {
+                    self.end_statement();
+                }
+            }
+            TokenType::LeftParen => {
+                self.parens += 1;
+                self.push(token);
+            }
+            TokenType::RightParen => {
+                self.parens = self.parens.saturating_sub(1);
+                self.push(token);
+            }
+            _ => self.push(token),
+        }
+    }
+
+    fn end_statement(&mut self) {
+        self.ended = true;
+        self.continuation = false;
+        self.parens = 0;
+    }
+
+    fn comment(&mut self, text: String, trailing: bool) {
+        let in_statement = !self.ended && !self.line.is_empty();
+        if trailing && !self.line.is_empty() {
+            self.comment = Some(text);
+            self.flush();
+        } else {
+            self.flush();
+            self.start_line(false);
+            let indent = INDENT.repeat(self.line_indent);
+            self.lines.push(indent + text.as_str());
+        }
+        self.ended = false;
+        if in_statement {
+            self.continuation = true;
+        }
+    }
+
+    fn blank(&mut self) {
+        if self.ended || (self.line.is_empty() && !self.continuation) {
+            self.blank = true;
+        }
+    }
+
+    fn push(&mut self, token: Token) {
+        if self.line.is_empty() {
+            self.start_line(token.token_type == TokenType::RightBrace);
+        }
+        // Blank lines only go between statements.
+        self.blank = false;
+        self.line.push(token);
+    }
+
+    fn start_line(&mut self, closing: bool) {
+        let after_open = self.lines.last().is_some_and(|line| line.ends_with('{'));
+        if self.blank && !self.lines.is_empty() && !after_open && !closing && !self.continuation {
+            self.lines.push(String::new());
+        }
+        self.blank = false;
+        self.line_indent = self.indent + usize::from(self.continuation);
+    }
+
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        let mut lines = self.wrap(&self.line, self.line_indent);
+        if let (Some(comment), Some(last)) = (self.comment.take(), lines.last_mut()) {
+            last.push(' ');
+            last.push_str(&comment);
+        }
+        self.lines.extend(lines);
+        self.line.clear();
+    }
+
+    // Breaks the first argument or parameter list of a line that is too
+    // long out one per line, and goes on with what comes after it.
+    fn wrap(&self, tokens: &[Token], indent: usize) -> Vec<String> {
+        let text = INDENT.repeat(indent) + &self.render(tokens);
+        let width = text.lines().next().unwrap_or("").chars().count();
+        if width <= MAX_WIDTH {
+            return vec![text];
+        }
+        let Some((open, close)) = arguments(tokens) else {
+            return vec![text];
+        };
+
+        let mut lines = vec![INDENT.repeat(indent) + &self.render(&tokens[..=open])];
+        let mut start = open + 1;
+        let mut depth = 0;
+        for i in open + 1..close {
+            match tokens[i].token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                TokenType::Comma if depth == 0 => {
+                    lines.extend(self.wrap(&tokens[start..=i], indent + 1));
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        lines.extend(self.wrap(&tokens[start..close], indent + 1));
+        lines.extend(self.wrap(&tokens[close..], indent));
+        lines
+    }
+
+    fn render(&self, tokens: &[Token]) -> String {
+        let mut text = String::new();
+        let mut previous: Option<(&Token, bool)> = None;
+        for token in tokens {
+            let unary = matches!(token.token_type, TokenType::Minus | TokenType::Bang)
+                && previous.is_none_or(|(p, _)| !ends_operand(p));
+            if let Some((p, p_unary)) = previous {
+                if !p_unary && space_between(p, token) {
+                    text.push(' ');
+                }
+            }
+            text.push_str(&self.source[token.start..token.end]);
+            previous = Some((token, unary));
+        }
+        text
+    }
+}
+
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token.token_type,
+        TokenType::Identifier(_)
+            | TokenType::Number(_)
+            | TokenType::String(_)
+            | TokenType::RightParen
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::This
+            | TokenType::Super
+    )
+}
+
+fn space_between(previous: &Token, next: &Token) -> bool {
+    match (&previous.token_type, &next.token_type) {
+        (_, TokenType::Semicolon | TokenType::Comma | TokenType::Dot | TokenType::RightParen) => {
+            false
+        }
+        (TokenType::LeftParen | TokenType::Dot, _) => false,
+        (TokenType::LeftBrace, TokenType::RightBrace) => false,
+        // A call, or the parameters of a function or method.
+        (
+            TokenType::Identifier(_) | TokenType::RightParen | TokenType::This | TokenType::Super,
+            TokenType::LeftParen,
+        ) => false,
+        _ => true,
+    }
+}
+
+// The parentheses around the first argument or parameter list that has
+// something in it, outside of any other parentheses.
+fn arguments(tokens: &[Token]) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    let mut open = None;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.token_type {
+            TokenType::LeftParen => {
+                let call = i > 0
+                    && matches!(
+                        tokens[i - 1].token_type,
+                        TokenType::Identifier(_)
+                            | TokenType::RightParen
+                            | TokenType::This
+                            | TokenType::Super
+                    );
+                if depth == 0 && call {
+                    open = Some(i);
+                }
+                depth += 1;
+            }
+            TokenType::RightParen => {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some(open) = open.filter(|open| i > open + 1) {
+                        return Some((open, i));
+                    }
+                    open = None;
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::fuzz::{self, Random};
+
+    fn assert_formats(source: &str, expected: &str) {
+        assert_eq!(Ok(String::from(expected)), format(source));
+        assert_eq!(Ok(String::from(expected)), format(expected));
+    }
+
+    #[test]
+    fn it_normalizes_spacing() {
+        assert_formats(
+            "var  a=1+-2*(3- -x);print!a==nil  ;print a.b(c,d)   .e;",
+            "var a = 1 + -2 * (3 - -x);\nprint !a == nil;\nprint a.b(c, d).e;\n",
+        );
+    }
+
+    #[test]
+    fn it_reindents_blocks() {
+        assert_formats(
+            "fun fib(n){\nif(n<=1)return n;\n        return fib(n-2)+fib(n-1);}\nclass Point{}\n{\n{print 1;}} if (a) { print 1; } else { print 2; }",
+            "fun fib(n) {
+  if (n <= 1) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+class Point {}
+{
+  {
+    print 1;
+  }
+}
+if (a) {
+  print 1;
+} else {
+  print 2;
+}
+",
+        );
+        assert_formats(
+            "for(var i=0;i<20;i=i+1){print i;}",
+            "for (var i = 0; i < 20; i = i + 1) {\n  print i;\n}\n",
+        );
+    }
+
+    #[test]
+    fn it_keeps_comments_and_blank_lines() {
+        assert_formats(
+            "// Header\n\n\n\nvar a = 1;   // one   \n{\n\n   // inside\nprint a +  // split\n2;\n\n}\n\n\n// end",
+            "// Header
+
+var a = 1; // one
+{
+  // inside
+  print a + // split
+    2;
+}
+
+// end
+",
+        );
+    }
+
+    #[test]
+    fn it_wraps_long_argument_lists() {
+        assert_formats(
+            "print someFunction(firstArgument, secondArgument + 1, thirdArgument(a, b), fourth);",
+            "print someFunction(
+  firstArgument,
+  secondArgument + 1,
+  thirdArgument(a, b),
+  fourth
+);
+",
+        );
+        assert_formats(
+            "{ fun longFunctionName(firstParameter, secondParameter, thirdParameter, fourth) { return nil; } }",
+            "{
+  fun longFunctionName(
+    firstParameter,
+    secondParameter,
+    thirdParameter,
+    fourth
+  ) {
+    return nil;
+  }
+}
+",
+        );
+    }
+
+    #[test]
+    fn it_leaves_the_book_style_alone() {
+        let source = "var a = \"global\";
+{
+  fun showA() {
+    print a;
+  }
+
+  showA();
+  var a = \"block\";
+  showA();
+}
+class Point {}
+
+fun newPoint(x, y) {
+  var point = Point();
+  point.x = x;
+  return point;
+}
+";
+        assert_formats(source, source);
+    }
+
+    #[test]
+    fn it_refuses_source_that_does_not_scan() {
+        assert_eq!(
+            Err(String::from("[line 2] Error: Unterminated string.")),
+            format("print 1;\nprint \"a;")
+        );
+        assert_eq!(Ok(String::new()), format("  \n\n"));
+    }
+
+    #[test]
+    fn it_refuses_source_that_does_not_parse() {
+        assert_eq!(
+            Err(String::from(
+                "[line 1] Error at ';': Expect expression.\n\
+                 [line 2] Error at '=': Expect variable name."
+            )),
+            format("print (1 + ;\nvar = 3\nprint 2;\n")
+        );
+        assert!(format("/* not lox */\nfun f(a, b) {}\n").is_err());
+    }
+
+    #[test]
+    fn it_formats_files_in_place_only_when_they_parse() {
+        let dir = std::env::temp_dir().join(format!("lox-fmt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.lox");
+
+        let broken = "print (1 + ;\nvar = 3\nprint 2;\n";
+        fs::write(&path, broken).unwrap();
+        let refused = format_file(&path, false);
+        let kept = fs::read_to_string(&path).unwrap();
+
+        fs::write(&path, "print 1+2;\n").unwrap();
+        let checked = format_file(&path, true).unwrap();
+        let unchanged = fs::read_to_string(&path).unwrap();
+        let formatted = format_file(&path, false).unwrap();
+        let rewritten = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(refused, Err(FileError::Source(_))));
+        assert_eq!(broken, kept);
+        assert!(checked && formatted);
+        assert_eq!("print 1+2;\n", unchanged);
+        assert_eq!("print 1 + 2;\n", rewritten);
+    }
+
+    // Puts random whitespace and comments between the tokens of source.
+    fn scramble(random: &mut Random, source: &str) -> String {
+        let tokens: Vec<Token> = Scanner::new(source).collect();
+        let mut scrambled = String::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let tight = |t: &Token| {
+                matches!(
+                    t.token_type,
+                    TokenType::LeftParen
+                        | TokenType::RightParen
+                        | TokenType::LeftBrace
+                        | TokenType::RightBrace
+                        | TokenType::Semicolon
+                        | TokenType::Comma
+                )
+            };
+            if i > 0 {
+                let separator = random.pick(&["", " ", "   ", "\n", "\n\n\n", "\t", " // note\n"]);
+                let separator = match separator {
+                    "" if !tight(token) && !tight(&tokens[i - 1]) => " ",
+                    separator => separator,
+                };
+                scrambled.push_str(separator);
+            }
+            scrambled.push_str(&source[token.start..token.end]);
+        }
+        scrambled
+    }
+
+    fn significant(source: &str) -> Vec<(TokenType, usize)> {
+        Scanner::with_trivia(source)
+            .filter(|token| !matches!(token.token_type, TokenType::Whitespace(_)))
+            .enumerate()
+            .map(|(i, token)| (token.token_type, i))
+            .collect()
+    }
+
+    #[test]
+    fn it_is_idempotent_on_random_programs() {
+        let mut random = Random::new(1);
+        for _ in 0..500 {
+            let program = fuzz::program(&mut random);
+            let source = scramble(&mut random, &program);
+
+            let once = format(&source).expect("could not format");
+            let twice = format(&once).expect("could not format");
+            assert_eq!(once, twice, "not idempotent for:\n{}", source);
+            assert_eq!(
+                significant(&source),
+                significant(&once),
+                "tokens changed for:\n{}",
+                source
+            );
+            assert!(once.lines().all(|line| line == line.trim_end()));
+        }
+    }
+}
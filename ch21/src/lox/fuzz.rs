@@ -0,0 +1,708 @@
+use crate::lox::bytecode;
+use crate::lox::compiler::{FrontEnd, MAX_CHAIN, MAX_NESTING};
+use crate::lox::evaluator::Evaluator;
+use crate::lox::fnv::FnvHasher;
+use crate::lox::optimizer;
+use crate::lox::scanner::Scanner;
+use crate::lox::vm::VM;
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Once;
+
+// Throws random programs at the scanner, the compiler and the VM, and
+// random compiled files at the loader and the VM. Whatever the input, each
+// should return, with a result or a typed error, and never panic. Inputs
+// that do panic are shrunk and kept as files so they can be replayed once
+// fixed.
+//
+// Rust can't catch a stack overflow, which kills the process instead, so
+// some programs nest deeper than the front ends allow: they have to refuse
+// them with an error before their recursion gets that far. lox fuzz runs
+// each input in a process of its own, so that one that does abort is
+// found and shrunk like any other crash.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Scanner,
+    Compiler,
+    Vm,
+    // Compiled files, loaded, optimized and run.
+    Chunk,
+    // The AST parser, the resolver, the tree-walker and the AST compiler.
+    Tree,
+}
+
+impl Target {
+    pub const ALL: [Target; 5] = [
+        Target::Scanner,
+        Target::Compiler,
+        Target::Vm,
+        Target::Chunk,
+        Target::Tree,
+    ];
+
+    pub fn parse(name: &str) -> Option<Target> {
+        match name {
+            "scanner" => Some(Target::Scanner),
+            "compiler" => Some(Target::Compiler),
+            "vm" => Some(Target::Vm),
+            "chunk" => Some(Target::Chunk),
+            "tree" => Some(Target::Tree),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Scanner => write!(f, "scanner"),
+            Target::Compiler => write!(f, "compiler"),
+            Target::Vm => write!(f, "vm"),
+            Target::Chunk => write!(f, "chunk"),
+            Target::Tree => write!(f, "tree"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crash {
+    pub target: Target,
+    pub input: Vec<u8>,
+    // Where it panicked and why, which tells one crash from another.
+    pub message: String,
+}
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+// Panics caught by run are recorded instead of printed. Any other panic,
+// like a failing test's, goes to the hook that was there before.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(Cell::get) {
+                return previous(info);
+            }
+            let message = info
+                .payload()
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            let description = match info.location() {
+                Some(location) => format!("{}: {}", location, message),
+                None => message,
+            };
+            PANIC.with(|panic| *panic.borrow_mut() = Some(description));
+        }));
+    });
+}
+
+// Feeds input to target, as source when it is UTF-8 and as the closest
+// source there is when it isn't, and reports how it panicked if it did.
+pub fn run(target: Target, input: &[u8]) -> Result<(), String> {
+    install_hook();
+    let source = String::from_utf8_lossy(input);
+    CATCHING.with(|catching| catching.set(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match target {
+        Target::Scanner => {
+            Scanner::new(&source).for_each(drop);
+            Scanner::with_trivia(&source).for_each(drop);
+        }
+        Target::Compiler => drop(VM::new().compile(&source)),
+        Target::Vm => drop(VM::new().interpret(&source)),
+        Target::Chunk => {
+            let mut vm = VM::new();
+            let loaded = bytecode::load(input, &mut vm.interner().borrow_mut());
+            if let Ok(mut chunk) = loaded {
+                drop(optimizer::optimize(&mut chunk));
+            }
+            let loaded = bytecode::load(input, &mut vm.interner().borrow_mut());
+            if let Ok(chunk) = loaded {
+                drop(vm.interpret_chunk(chunk));
+            }
+        }
+        Target::Tree => {
+            drop(Evaluator::new().interpret(&source));
+            let mut vm = VM::new();
+            vm.set_front_end(FrontEnd::Ast);
+            drop(vm.interpret(&source));
+        }
+    }));
+    CATCHING.with(|catching| catching.set(false));
+    let panic = PANIC.with(|panic| panic.borrow_mut().take());
+    result.map_err(|_| panic.unwrap_or_default())
+}
+
+// How lox fuzz-input exits when its input panicked, as Rust would have.
+pub const PANICKED: i32 = 101;
+
+// Where inputs are run: in this process, which catches panics but goes down
+// with an abort, or each in a child running lox fuzz-input, whose abort is a
+// crash like a panic is.
+pub enum Runner {
+    InProcess,
+    // The lox executable.
+    Child(PathBuf),
+}
+
+impl Runner {
+    pub fn run(&self, target: Target, input: &[u8]) -> io::Result<Result<(), String>> {
+        match self {
+            Runner::InProcess => Ok(run(target, input)),
+            Runner::Child(program) => run_child(program, target, input),
+        }
+    }
+}
+
+fn run_child(program: &Path, target: Target, input: &[u8]) -> io::Result<Result<(), String>> {
+    let mut child = Command::new(program)
+        .arg("fuzz-input")
+        .arg(target.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A child that ended before reading it all has a status to say why.
+        match stdin.write_all(input) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+            _ => {}
+        }
+    }
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+    // Before an abort Rust names the thread with its id, which differs from
+    // one process to the next, so only the last line tells crashes apart.
+    Ok(match output.status.code() {
+        Some(0) => Ok(()),
+        Some(PANICKED) => Err(stderr.to_string()),
+        _ => Err(format!(
+            "{}: {}",
+            output.status,
+            stderr.lines().last().unwrap_or_default()
+        )),
+    })
+}
+
+// A xorshift generator, so that a seed always makes the same programs.
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        // Zero would only ever make zeroes.
+        Random(if seed == 0 {
+            0x2545_f491_4f6c_dd1d
+        } else {
+            seed
+        })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+        choices[self.below(choices.len())]
+    }
+}
+
+// A few names, so that programs use variables they declared as well as ones
+// they didn't.
+const NAMES: [&str; 4] = ["a", "b", "count", "name"];
+
+// A random program from Lox's grammar. Most of it is what this VM runs, with
+// now and then something from later chapters that it doesn't compile yet.
+pub fn program(random: &mut Random) -> String {
+    (0..1 + random.below(8))
+        .map(|_| declaration(random, 2))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Parentheses, unary operators or blocks nested up to twice as deep as the
+// front ends allow, or chains of operators and calls twice as long, so that
+// about half of these are compile errors.
+pub fn nested(random: &mut Random) -> String {
+    let depth = random.below(2 * MAX_NESTING);
+    let length = random.below(2 * MAX_CHAIN);
+    match random.below(5) {
+        0 => format!("print {}1{};", "(".repeat(depth), ")".repeat(depth)),
+        1 => {
+            let operators: String = (0..depth).map(|_| random.pick(&["-", "!"])).collect();
+            format!("print {}1;", operators)
+        }
+        2 => format!("{}print 1;{}", "{ ".repeat(depth), " }".repeat(depth)),
+        3 => {
+            let operations: String = (0..length)
+                .map(|_| format!(" {} 1", random.pick(&["+", "*", "<", "==", "and", "or"])))
+                .collect();
+            format!("print 1{};", operations)
+        }
+        _ => {
+            let calls: String = (0..length).map(|_| random.pick(&["()", ".f"])).collect();
+            format!("fun f() {{ return f; }} print f{};", calls)
+        }
+    }
+}
+
+fn declaration(random: &mut Random, depth: usize) -> String {
+    match random.below(10) {
+        0..=2 => format!("var {} = {};", random.pick(&NAMES), expression(random, 3)),
+        3 => format!("var {};", random.pick(&NAMES)),
+        4..=6 => format!("print {};", expression(random, 3)),
+        7 => format!("{} = {};", random.pick(&NAMES), expression(random, 3)),
+        8 => format!("{};", expression(random, 3)),
+        _ if depth == 0 => format!("print {};", expression(random, 1)),
+        _ => {
+            let body: Vec<String> = (0..random.below(3))
+                .map(|_| declaration(random, depth - 1))
+                .collect();
+            let body = body.join(" ");
+            match random.below(6) {
+                0 => format!("{{ {} }}", body),
+                1 => format!("if ({}) {{ {} }} else {{ }}", expression(random, 2), body),
+                2 => format!("while (false) {{ {} }}", body),
+                3 => format!("fun f(p) {{ {} return p; }}", body),
+                4 => format!(
+                    "for (var i = 0; i < {}; i = i + 1) {{ {} }}",
+                    expression(random, 1),
+                    body
+                ),
+                _ => format!("class C < D {{ m() {{ {} this.x = super.m(); }} }}", body),
+            }
+        }
+    }
+}
+
+fn expression(random: &mut Random, depth: usize) -> String {
+    if depth == 0 || random.below(3) == 0 {
+        return match random.below(5) {
+            0 => random.pick(&NAMES).to_string(),
+            1 => random
+                .pick(&["0", "1", "2.5", "1234567890.0987654321", "0.000001"])
+                .to_string(),
+            2 => random
+                .pick(&["\"\"", "\"str\"", "\"two\nlines\""])
+                .to_string(),
+            _ => random.pick(&["nil", "true", "false"]).to_string(),
+        };
+    }
+    match random.below(5) {
+        0 => format!(
+            "{}{}",
+            random.pick(&["-", "!"]),
+            expression(random, depth - 1)
+        ),
+        1 => format!("({})", expression(random, depth - 1)),
+        2 => {
+            let arguments: Vec<String> = (0..random.below(6))
+                .map(|_| expression(random, depth - 1))
+                .collect();
+            format!(
+                "{}({})",
+                random.pick(&["f", "callSomething"]),
+                arguments.join(", ")
+            )
+        }
+        _ => format!(
+            "{} {} {}",
+            expression(random, depth - 1),
+            random.pick(&["+", "-", "*", "/", "==", "!=", "<", "<=", ">", ">=", "and", "or"]),
+            expression(random, depth - 1)
+        ),
+    }
+}
+
+// Pieces of Lox to splice into programs, as well as bytes that aren't.
+const SPLICES: [&str; 18] = [
+    "(", ")", "{", "}", ";", "=", "!", "-", "var", "print", "\"", "0.", ".", "//", "\n", "é", "\0",
+    "\u{feff}",
+];
+
+// The input with a few random edits, most of which leave it not quite Lox.
+pub fn mutate(random: &mut Random, input: &[u8]) -> Vec<u8> {
+    let mut bytes = input.to_vec();
+    for _ in 0..1 + random.below(3) {
+        let at = random.below(bytes.len() + 1);
+        let end = (at + random.below(8)).min(bytes.len());
+        match random.below(5) {
+            0 => {
+                bytes.drain(at..end);
+            }
+            1 => {
+                let copy = bytes[at..end].to_vec();
+                bytes.splice(at..at, copy);
+            }
+            2 => {
+                let splice = random.pick(&SPLICES).as_bytes();
+                bytes.splice(at..at, splice.iter().copied());
+            }
+            3 if at < bytes.len() => bytes[at] = random.next_u64() as u8,
+            _ => bytes.truncate(at),
+        }
+    }
+    bytes
+}
+
+// The smallest input found that still crashes, by cutting out ever smaller
+// runs of bytes for as long as it does.
+pub fn minimize(input: &[u8], crashes: &mut dyn FnMut(&[u8]) -> bool) -> Vec<u8> {
+    let mut input = input.to_vec();
+    let mut size = input.len() / 2;
+    while size > 0 {
+        let mut at = 0;
+        while at < input.len() {
+            let end = (at + size).min(input.len());
+            let mut shorter = input.clone();
+            shorter.drain(at..end);
+            if crashes(&shorter) {
+                input = shorter;
+            } else {
+                at += size;
+            }
+        }
+        size /= 2;
+    }
+    input
+}
+
+// Runs target on iterations of programs and their mutations, with every
+// distinct crash found, minimized.
+pub fn fuzz(
+    target: Target,
+    random: &mut Random,
+    iterations: usize,
+    runner: &Runner,
+) -> io::Result<Vec<Crash>> {
+    let mut crashes: Vec<Crash> = vec![];
+    for _ in 0..iterations {
+        let program = match random.below(16) {
+            0 => nested(random),
+            _ => program(random),
+        };
+        let program = match target {
+            Target::Chunk => compiled(&program),
+            _ => program.into_bytes(),
+        };
+        let input = match random.below(4) {
+            0 => program,
+            _ => mutate(random, &program),
+        };
+        let Err(message) = runner.run(target, &input)? else {
+            continue;
+        };
+        if crashes.iter().any(|crash| crash.message == message) {
+            continue;
+        }
+        let mut error = None;
+        let input = minimize(&input, &mut |input| match runner.run(target, input) {
+            Ok(result) => result.err().as_ref() == Some(&message),
+            Err(e) => {
+                error.get_or_insert(e);
+                false
+            }
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        crashes.push(Crash {
+            target,
+            input,
+            message,
+        });
+    }
+    Ok(crashes)
+}
+
+// The program as a compiled file, or as it is if it doesn't compile, which
+// the loader then has to reject.
+fn compiled(program: &str) -> Vec<u8> {
+    let vm = VM::new();
+    vm.compile(program)
+        .ok()
+        .and_then(|chunk| bytecode::save(&chunk, &vm.interner().borrow()).ok())
+        .unwrap_or_else(|| program.as_bytes().to_vec())
+}
+
+// Keeps a crash in dir, named for its target and what it contains so that
+// finding it again doesn't make a second copy.
+pub fn save(dir: &Path, crash: &Crash) -> io::Result<PathBuf> {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&crash.input);
+    let extension = match crash.target {
+        Target::Chunk => "loxc",
+        _ => "lox",
+    };
+    let path = dir.join(format!(
+        "{}-{:016x}.{}",
+        crash.target,
+        hasher.finish(),
+        extension
+    ));
+    fs::create_dir_all(dir)?;
+    fs::write(&path, &crash.input)?;
+    Ok(path)
+}
+
+// The crashes kept in dir, with the target each was found on.
+pub fn crashers(dir: &Path) -> io::Result<Vec<(Target, PathBuf)>> {
+    let mut crashers = vec![];
+    if !dir.exists() {
+        return Ok(crashers);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let target = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(target, _)| Target::parse(target));
+        if let Some(target) = target {
+            crashers.push((target, path));
+        }
+    }
+    crashers.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(crashers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::chunk::Chunk;
+    use crate::lox::compiler::MAX_CHAIN;
+    use crate::lox::differential::corpus;
+    use crate::lox::parser;
+
+    #[test]
+    fn it_generates_programs_that_parse() {
+        let mut random = Random::new(1);
+        for _ in 0..200 {
+            let program = program(&mut random);
+            assert!(parser::parse(&program).is_ok(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn it_generates_nesting_too_deep_to_compile() {
+        let mut random = Random::new(1);
+        let (mut shallow, mut deep) = (0, 0);
+        for _ in 0..50 {
+            let program = nested(&mut random);
+            match parser::parse(&program) {
+                Ok(_) => shallow += 1,
+                Err(errors) => {
+                    assert_eq!("Too much nesting.", errors[0].message, "{}", program);
+                    deep += 1;
+                }
+            }
+        }
+        assert!(shallow > 0 && deep > 0);
+    }
+
+    #[test]
+    fn it_generates_the_same_programs_from_a_seed() {
+        let programs = |seed| {
+            let mut random = Random::new(seed);
+            (0..10)
+                .map(|_| {
+                    let program = program(&mut random);
+                    mutate(&mut random, program.as_bytes())
+                })
+                .collect::<Vec<Vec<u8>>>()
+        };
+        assert_eq!(programs(7), programs(7));
+        assert_ne!(programs(7), programs(8));
+    }
+
+    #[test]
+    fn it_runs_inputs_that_are_not_utf8() {
+        for target in Target::ALL {
+            assert_eq!(Ok(()), run(target, b"print \xff\xfe;"));
+        }
+    }
+
+    #[test]
+    fn it_minimizes_crashes() {
+        let mut crashes = |input: &[u8]| input.contains(&b'x') && input.ends_with(b";");
+        assert_eq!(
+            b"x;".to_vec(),
+            minimize(b"print 1 + x * (2 - 3);", &mut crashes)
+        );
+        assert_eq!(b"".to_vec(), minimize(b"", &mut crashes));
+    }
+
+    // The invariant itself, on a sample small enough for every test run.
+    #[test]
+    fn it_finds_no_crashes() {
+        for target in Target::ALL {
+            let crashes = fuzz(target, &mut Random::new(1), 300, &Runner::InProcess);
+            assert_eq!(Ok(vec![]), crashes.map_err(|e| e.to_string()));
+        }
+    }
+
+    // The tallest trees the parser accepts: as much nesting as it allows
+    // with as long a chain as it allows inside.
+    #[test]
+    fn it_runs_the_tallest_trees_allowed() {
+        let wrap = |inner: String| {
+            let depth = MAX_NESTING - 2;
+            format!("print {}{}{};", "(".repeat(depth), inner, ")".repeat(depth))
+        };
+        for source in [
+            wrap(format!("1{}", " + 1".repeat(MAX_CHAIN))),
+            wrap(format!("1{}", " or 1".repeat(MAX_CHAIN))),
+            format!(
+                "fun f() {{ return f; }} {}",
+                wrap(format!("f{}", "()".repeat(MAX_CHAIN)))
+            ),
+        ] {
+            assert!(parser::parse(&source).is_ok(), "{}", source);
+            for target in Target::ALL {
+                assert_eq!(Ok(()), run(target, source.as_bytes()), "{}", target);
+            }
+        }
+    }
+
+    // Every program the crate keeps, through every target, and everything
+    // lox fuzz has kept through the target it crashed, so that a crash once
+    // fixed stays fixed.
+    #[test]
+    fn it_never_panics_on_the_corpus() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut inputs = vec![];
+        for dir in ["corpus", "conformance"] {
+            for path in corpus(&root.join(dir)).expect("could not read the corpus") {
+                let source = fs::read_to_string(&path).expect("could not read a program");
+                for target in [Target::Scanner, Target::Compiler, Target::Vm, Target::Tree] {
+                    inputs.push((target, path.clone(), source.clone().into_bytes()));
+                }
+                inputs.push((Target::Chunk, path, compiled(&source)));
+            }
+        }
+        for (target, path) in crashers(&root.join("fuzz/crashers")).expect("could not read") {
+            let input = fs::read(&path).expect("could not read a crasher");
+            inputs.push((target, path, input));
+        }
+        // The inputs that overflowed the stack before the front ends limited
+        // nesting and chains, written out by hand at full size.
+        for source in [
+            format!("print {}1{};", "(".repeat(50_000), ")".repeat(50_000)),
+            format!("print {}1;", "-".repeat(100_000)),
+            format!("print 1{};", " + 1".repeat(50_000)),
+            format!("print true{};", " and true".repeat(50_000)),
+            format!("fun f() {{ return f; }} print f{};", "()".repeat(50_000)),
+            format!(
+                "var a; a{} = 1; print a{};",
+                ".b".repeat(50_000),
+                ".b".repeat(50_000)
+            ),
+        ] {
+            for target in Target::ALL {
+                let input = match target {
+                    Target::Chunk => compiled(&source),
+                    _ => source.clone().into_bytes(),
+                };
+                inputs.push((target, PathBuf::from("deep nesting"), input));
+            }
+        }
+
+        // A constant the pool doesn't have, which the optimizer once indexed.
+        let chunk = Chunk::from_parts(vec![0, 5], &[(1, 2)], vec![]);
+        let input = bytecode::save(&chunk, &VM::new().interner().borrow()).expect("save");
+        inputs.push((Target::Chunk, PathBuf::from("missing constant"), input));
+
+        assert!(!inputs.is_empty());
+        for (target, path, input) in inputs {
+            assert_eq!(
+                Ok(()),
+                run(target, &input),
+                "{} on {}",
+                target,
+                path.display()
+            );
+        }
+    }
+
+    // A stand-in for lox fuzz-input that returns on scanner inputs, panics on
+    // compiler ones and aborts on the rest.
+    #[cfg(unix)]
+    #[test]
+    fn it_runs_inputs_in_children_that_can_abort() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("lox-child-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("lox");
+        fs::write(
+            &program,
+            "#!/bin/sh\ncat > /dev/null\ncase $2 in\n\
+             scanner) exit 0 ;;\n\
+             compiler) echo 'here: that' >&2; exit 101 ;;\n\
+             *) echo \"thread 'main' ($$) failed\" >&2; echo 'ended' >&2; kill -ABRT $$ ;;\nesac\n",
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        let runner = Runner::Child(program);
+        let results: Vec<Result<(), String>> = [Target::Scanner, Target::Compiler, Target::Vm]
+            .into_iter()
+            .map(|target| runner.run(target, b"print 1;").unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(Ok(()), results[0]);
+        assert_eq!(Err(String::from("here: that")), results[1]);
+        let aborted = results[2].clone().unwrap_err();
+        assert!(aborted.starts_with("signal: 6"), "{}", aborted);
+        assert!(aborted.ends_with(": ended"), "{}", aborted);
+    }
+
+    #[test]
+    fn it_catches_panics_and_where_they_happened() {
+        install_hook();
+        CATCHING.with(|catching| catching.set(true));
+        let result = panic::catch_unwind(|| panic!("on purpose"));
+        CATCHING.with(|catching| catching.set(false));
+        assert!(result.is_err());
+        let message = PANIC.with(|panic| panic.borrow_mut().take()).unwrap();
+        assert!(message.starts_with("src/lox/fuzz.rs:"), "{}", message);
+        assert!(message.ends_with(": on purpose"), "{}", message);
+    }
+
+    #[test]
+    fn it_keeps_crashes_by_target_and_contents() {
+        let dir = std::env::temp_dir().join(format!("lox-fuzz-{}", std::process::id()));
+        let crash = Crash {
+            target: Target::Compiler,
+            input: b"print 1 +".to_vec(),
+            message: String::from("somewhere: something"),
+        };
+        let path = save(&dir, &crash).unwrap();
+        assert_eq!(path, save(&dir, &crash).unwrap());
+        fs::write(dir.join("notes.txt"), "not a crash").unwrap();
+        let kept = crashers(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec![(Target::Compiler, path)], kept);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::lox::scanner::{Scanner, TokenType};
+use std::fmt::Write;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Keyword,
+    Literal,
+    Identifier,
+    Operator,
+    Comment,
+    Error,
+    Plain,
+}
+
+impl Style {
+    fn of(token_type: &TokenType) -> Style {
+        match token_type {
+            TokenType::String(_)
+            | TokenType::Number(_)
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil => Style::Literal,
+            TokenType::Identifier(_) => Style::Identifier,
+            TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Slash
+            | TokenType::Star
+            | TokenType::Bang
+            | TokenType::BangEqual
+            | TokenType::Equal
+            | TokenType::EqualEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual
+            | TokenType::Dot => Style::Operator,
+            TokenType::And
+            | TokenType::Class
+            | TokenType::Else
+            | TokenType::For
+            | TokenType::Fun
+            | TokenType::If
+            | TokenType::Or
+            | TokenType::Print
+            | TokenType::Return
+            | TokenType::Super
+            | TokenType::This
+            | TokenType::Var
+            | TokenType::While => Style::Keyword,
+            TokenType::Comment(_) => Style::Comment,
+            TokenType::Error(_) => Style::Error,
+            _ => Style::Plain,
+        }
+    }
+
+    fn ansi(&self) -> Option<&'static str> {
+        match self {
+            Style::Keyword => Some("\x1b[1;35m"),
+            Style::Literal => Some("\x1b[32m"),
+            Style::Identifier => Some("\x1b[34m"),
+            Style::Operator => Some("\x1b[33m"),
+            Style::Comment => Some("\x1b[2;37m"),
+            Style::Error => Some("\x1b[4;31m"),
+            Style::Plain => None,
+        }
+    }
+
+    fn class(&self) -> Option<&'static str> {
+        match self {
+            Style::Keyword => Some("keyword"),
+            Style::Literal => Some("literal"),
+            Style::Identifier => Some("identifier"),
+            Style::Operator => Some("operator"),
+            Style::Comment => Some("comment"),
+            Style::Error => Some("error"),
+            Style::Plain => None,
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+// Styles for the classes html uses, for pages that have none of their own.
+pub const STYLESHEET: &str = "pre.lox .line-number { display: inline-block; width: 3em; color: #999; text-decoration: none; }
+pre.lox .keyword { color: #a626a4; font-weight: bold; }
+pre.lox .literal { color: #50a14f; }
+pre.lox .identifier { color: #4078f2; }
+pre.lox .operator { color: #c18401; }
+pre.lox .comment { color: #a0a1a7; font-style: italic; }
+pre.lox .error { color: #e45649; text-decoration: underline wavy; }
+";
+
+// The source cut into styled pieces that cover all of it, in order.
+pub fn spans(source: &str) -> Vec<(Style, Range<usize>)> {
+    let mut spans = vec![];
+    let mut covered = 0;
+    for token in Scanner::with_trivia(source) {
+        let start = token.start.max(covered);
+        let end = token.end;
+        if start < end {
+            spans.push((Style::of(&token.token_type), start..end));
+            covered = end;
+        }
+    }
+    spans
+}
+
+// The source with ANSI color codes around each token.
+pub fn ansi(source: &str) -> String {
+    let mut output = String::new();
+    for (style, range) in spans(source) {
+        match style.ansi() {
+            Some(code) => {
+                output.push_str(code);
+                output.push_str(&source[range]);
+                output.push_str(RESET);
+            }
+            None => output.push_str(&source[range]),
+        }
+    }
+    output
+}
+
+// The source as an HTML fragment, with a span of a class for each token
+// and an anchor for each line, so that "[line 3]" can link to #L3. Tokens
+// that run over more than one line are split so that lines nest.
+pub fn html(source: &str) -> Result<String, std::fmt::Error> {
+    let mut lines: Vec<String> = vec![String::new()];
+    for (style, range) in spans(source) {
+        for (i, piece) in source[range].split('\n').enumerate() {
+            if i > 0 {
+                lines.push(String::new());
+            }
+            if piece.is_empty() {
+                continue;
+            }
+            let Some(line) = lines.last_mut() else {
+                continue;
+            };
+            match style.class() {
+                Some(class) => write!(line, "<span class=\"{}\">{}</span>", class, escape(piece))?,
+                None => line.push_str(&escape(piece)),
+            }
+        }
+    }
+    // A final newline ends the last line rather than starting another.
+    if source.ends_with('\n') {
+        lines.pop();
+    }
+
+    let mut output = String::from("<pre class=\"lox\">");
+    for (i, line) in lines.iter().enumerate() {
+        let number = i + 1;
+        writeln!(
+            output,
+            "<span class=\"line\" id=\"L{0}\"><a class=\"line-number\" href=\"#L{0}\">{0}</a>{1}</span>",
+            number, line
+        )?;
+    }
+    output.push_str("</pre>\n");
+    Ok(output)
+}
+
+// A whole page around html.
+pub fn html_page(title: &str, source: &str) -> Result<String, std::fmt::Error> {
+    let mut output = String::new();
+    writeln!(output, "<!DOCTYPE html>")?;
+    writeln!(output, "<html>")?;
+    writeln!(output, "<head>")?;
+    writeln!(output, "<meta charset=\"utf-8\">")?;
+    writeln!(output, "<title>{}</title>", escape(title))?;
+    write!(output, "<style>\n{}</style>\n", STYLESHEET)?;
+    writeln!(output, "</head>")?;
+    writeln!(output, "<body>")?;
+    output.push_str(&html(source)?);
+    writeln!(output, "</body>")?;
+    writeln!(output, "</html>")?;
+    Ok(output)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_styles_each_kind_of_token() {
+        let source = "var a = nil; // note\n@";
+        let styles: Vec<(Style, &str)> = spans(source)
+            .into_iter()
+            .map(|(style, range)| (style, &source[range]))
+            .collect();
+        assert_eq!(
+            vec![
+                (Style::Keyword, "var"),
+                (Style::Plain, " "),
+                (Style::Identifier, "a"),
+                (Style::Plain, " "),
+                (Style::Operator, "="),
+                (Style::Plain, " "),
+                (Style::Literal, "nil"),
+                (Style::Plain, ";"),
+                (Style::Plain, " "),
+                (Style::Comment, "// note"),
+                (Style::Plain, "\n"),
+                (Style::Error, "@"),
+            ],
+            styles
+        );
+    }
+
+    #[test]
+    fn it_covers_the_whole_source() {
+        for source in [
+            "",
+            "print \"héllo\";\n",
+            "é ü;",
+            "print \"unterminated\n",
+            "// only a comment",
+            "a\r\n\tb",
+        ] {
+            let covered: String = spans(source)
+                .into_iter()
+                .map(|(_, range)| &source[range])
+                .collect();
+            assert_eq!(source, covered);
+        }
+    }
+
+    #[test]
+    fn it_writes_ansi() {
+        assert_eq!(
+            "\x1b[1;35mprint\x1b[0m \x1b[32m1\x1b[0m \x1b[33m<\x1b[0m \x1b[34mx\x1b[0m;\n",
+            ansi("print 1 < x;\n")
+        );
+    }
+
+    #[test]
+    fn it_writes_html_with_line_anchors() {
+        assert_eq!(
+            "<pre class=\"lox\"><span class=\"line\" id=\"L1\"><a class=\"line-number\" href=\"#L1\">1</a><span class=\"keyword\">print</span> <span class=\"literal\">&quot;a&lt;</span></span>
+<span class=\"line\" id=\"L2\"><a class=\"line-number\" href=\"#L2\">2</a><span class=\"literal\">b&quot;</span>;</span>
+<span class=\"line\" id=\"L3\"><a class=\"line-number\" href=\"#L3\">3</a></span>
+<span class=\"line\" id=\"L4\"><a class=\"line-number\" href=\"#L4\">4</a><span class=\"comment\">// &amp;</span></span>
+</pre>
+",
+            html("print \"a<\nb\";\n\n// &\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn it_writes_a_page() {
+        let page = html_page("a<b.lox", "print 1;").unwrap();
+        assert!(page.starts_with("<!DOCTYPE html>\n"));
+        assert!(page.contains("<title>a&lt;b.lox</title>"));
+        assert!(page.contains("pre.lox .keyword"));
+        assert!(page.ends_with("</pre>\n</body>\n</html>\n"));
+    }
+}
@@ -6,27 +6,26 @@ pub type Symbol = u32;
 
 #[derive(Debug, Default)]
 pub struct Interner {
-    map: HashMap<Rc<String>, Symbol, FnvBuildHasher>,
-    vec: Vec<Rc<String>>,
+    map: HashMap<Rc<str>, Symbol, FnvBuildHasher>,
+    vec: Vec<Rc<str>>,
 }
 
 impl Interner {
     pub fn intern(&mut self, name: &str) -> Symbol {
-        let s = name.to_string();
-        match self.map.get(&s) {
-            Some(id) => return *id,
-            _ => {}
+        if let Some(id) = self.map.get(name) {
+            return *id;
         }
         let id = self.vec.len() as Symbol;
-        let owned = Rc::new(name.to_owned());
+        let owned: Rc<str> = Rc::from(name);
         self.map.insert(owned.clone(), id);
-        self.vec.push(owned.clone());
+        self.vec.push(owned);
 
         id
     }
 
-    pub fn lookup(&self, idx: Symbol) -> &str {
-        self.vec.get(idx as usize).unwrap().as_str()
+    // None for a symbol from some other interner.
+    pub fn lookup(&self, idx: Symbol) -> Option<&str> {
+        self.vec.get(idx as usize).map(|s| &**s)
     }
 }
 #[cfg(test)]
@@ -42,7 +41,8 @@ mod tests {
         let b = interner.intern("anotherstring");
         assert_ne!(a, b);
 
-        assert_eq!("astring", interner.lookup(a));
-        assert_eq!("anotherstring", interner.lookup(b));
+        assert_eq!(Some("astring"), interner.lookup(a));
+        assert_eq!(Some("anotherstring"), interner.lookup(b));
+        assert_eq!(None, Interner::default().lookup(a));
     }
 }
@@ -0,0 +1,502 @@
+use std::fmt::{self, Write};
+use std::io::{self, BufRead, Read};
+
+// Bounds on what a client can send, so that a bad message is refused
+// instead of exhausting memory or overflowing the parser's stack.
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+const MAX_DEPTH: usize = 128;
+
+// Just enough JSON for the tools that talk to editors. Objects keep their
+// keys in order, so what is written is predictable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            chars: text.char_indices().peekable(),
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some((at, _)) => Err(format!("Unexpected text after the value at {}.", at)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        self.as_f64()
+            .filter(|n| *n >= 0.0 && n.fract() == 0.0)
+            .map(|n| n as usize)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write!(f, "{}", quote(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", quote(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Self {
+        Json::Number(n)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+// A JSON string literal for s.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Reads a message framed the way the debug adapter and language server
+// protocols frame them, a Content-Length header and a blank line before the
+// JSON body. None means the input ended before another message. A body that
+// is too long or isn't JSON is skipped and returned as an error message, so
+// that the server can answer it and go on to the next.
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<Result<Json, String>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE_LENGTH {
+        let skipped = io::copy(&mut input.take(length as u64), &mut io::sink())?;
+        if skipped < length as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(Some(Err(format!(
+            "Message of {} bytes is longer than {}.",
+            length, MAX_MESSAGE_LENGTH
+        ))));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(match String::from_utf8(body) {
+        Ok(text) => Json::parse(&text),
+        Err(e) => Err(e.to_string()),
+    }))
+}
+
+pub fn write_message(output: &mut dyn io::Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    // How many arrays and objects the value being parsed is inside.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some((_, ' ' | '\t' | '\n' | '\r')) = self.chars.peek() {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((at, c)) => Err(format!(
+                "Expected '{}' at {} but found '{}'.",
+                expected, at, c
+            )),
+            None => Err(format!("Expected '{}' at the end.", expected)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek().copied() {
+            Some((at, '{' | '[')) if self.depth == MAX_DEPTH => {
+                Err(format!("Too much nesting at {}.", at))
+            }
+            Some((_, '{')) => self.nested(Self::object),
+            Some((_, '[')) => self.nested(Self::array),
+            Some((_, '"')) => self.string().map(Json::String),
+            Some((_, 't')) => self.word("true", Json::Bool(true)),
+            Some((_, 'f')) => self.word("false", Json::Bool(false)),
+            Some((_, 'n')) => self.word("null", Json::Null),
+            Some((_, c)) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some((at, c)) => Err(format!("Unexpected '{}' at {}.", c, at)),
+            None => Err(String::from("Expected a value at the end.")),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json, String>) -> Result<Json, String> {
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn word(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some((_, c)) = self.chars.peek().copied() {
+            if !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                break;
+            }
+            text.push(c);
+            self.chars.next();
+        }
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| format!("Invalid number '{}'.", text))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(s),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, '"')) => s.push('"'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, '/')) => s.push('/'),
+                    Some((_, 'b')) => s.push('\u{8}'),
+                    Some((_, 'f')) => s.push('\u{c}'),
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'u')) => s.push(self.escaped_char()?),
+                    Some((at, c)) => return Err(format!("Invalid escape '\\{}' at {}.", c, at)),
+                    None => return Err(String::from("Unterminated string.")),
+                },
+                Some((_, c)) => s.push(c),
+                None => return Err(String::from("Unterminated string.")),
+            }
+        }
+    }
+
+    // The code point after \u, which may be a surrogate pair.
+    fn escaped_char(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.expect('\\')?;
+            self.expect('u')?;
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| format!("Invalid code point {:x}.", code))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|(_, c)| c.to_digit(16))
+                .ok_or_else(|| String::from("Invalid \\u escape."))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if let Some((_, ']')) = self.chars.peek() {
+            self.chars.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, ']')) => return Ok(Json::Array(items)),
+                Some((at, c)) => {
+                    return Err(format!("Expected ',' or ']' at {} but found '{}'.", at, c))
+                }
+                None => return Err(String::from("Unterminated array.")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if let Some((_, '}')) = self.chars.peek() {
+            self.chars.next();
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => continue,
+                Some((_, '}')) => return Ok(Json::Object(members)),
+                Some((at, c)) => {
+                    return Err(format!("Expected ',' or '}}' at {} but found '{}'.", at, c))
+                }
+                None => return Err(String::from("Unterminated object.")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_quotes_strings() {
+        assert_eq!(r#""plain""#, quote("plain"));
+        assert_eq!(r#""say \"hi\"\\""#, quote("say \"hi\"\\"));
+        assert_eq!(r#""a\nb\tc\u001b""#, quote("a\nb\tc\u{1b}"));
+        assert_eq!(r#""é""#, quote("é"));
+    }
+
+    #[test]
+    fn it_parses_values() {
+        let value = Json::parse(
+            r#" {"seq": 1, "ok": true, "none": null, "args": {"lines": [1, -2.5e1]},
+                "text": "a\"b\\c\né😀"} "#,
+        )
+        .expect("parse failed");
+        assert_eq!(Some(1), value.get("seq").and_then(Json::as_usize));
+        assert_eq!(Some(true), value.get("ok").and_then(Json::as_bool));
+        assert_eq!(Some(&Json::Null), value.get("none"));
+        assert_eq!(
+            Some(&Json::Array(vec![Json::Number(1.0), Json::Number(-25.0)])),
+            value.get("args").and_then(|args| args.get("lines"))
+        );
+        assert_eq!(
+            Some("a\"b\\c\né😀"),
+            value.get("text").and_then(Json::as_str)
+        );
+    }
+
+    #[test]
+    fn it_writes_what_it_parses() {
+        for text in [
+            r#"{"a":[1,2.5,"x"],"b":{},"c":[],"d":null,"e":false}"#,
+            r#""line\nbreak""#,
+            "-3",
+        ] {
+            assert_eq!(text, Json::parse(text).expect("parse failed").to_string());
+        }
+        assert_eq!("null", Json::Number(f64::NAN).to_string());
+    }
+
+    #[test]
+    fn it_frames_messages() {
+        let mut framed = vec![];
+        write_message(&mut framed, &Json::object(vec![("seq", Json::from(1))])).unwrap();
+        write_message(&mut framed, &Json::from("é")).unwrap();
+        assert_eq!(
+            "Content-Length: 9\r\n\r\n{\"seq\":1}Content-Length: 4\r\n\r\n\"é\"",
+            String::from_utf8(framed.clone()).unwrap()
+        );
+
+        let mut input = io::Cursor::new(framed);
+        assert_eq!(
+            Some(Ok(Json::object(vec![("seq", Json::from(1))]))),
+            read_message(&mut input).unwrap()
+        );
+        assert_eq!(Some(Ok(Json::from("é"))), read_message(&mut input).unwrap());
+        assert_eq!(None, read_message(&mut input).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_malformed_text() {
+        for text in [
+            "",
+            "{",
+            "[1,]",
+            r#"{"a" 1}"#,
+            "tru",
+            r#""\x""#,
+            "1 2",
+            "\"open",
+        ] {
+            assert!(Json::parse(text).is_err(), "parsed: {}", text);
+        }
+    }
+
+    #[test]
+    fn it_skips_bad_messages() {
+        let deep = "[".repeat(200_000);
+        let framed = format!(
+            "Content-Length: 99999999999999999\r\n\r\nContent-Length: 3\r\n\r\n{{x}}\
+             Content-Length: {}\r\n\r\n{}Content-Length: 2\r\n\r\n{{}}",
+            deep.len(),
+            deep
+        );
+        let mut input = io::Cursor::new(framed.into_bytes());
+        // The first message claims more than there is left to skip.
+        assert!(read_message(&mut input).is_err());
+
+        let framed = format!(
+            "Content-Length: 3\r\n\r\n{{x}}Content-Length: {}\r\n\r\n{}\
+             Content-Length: {}\r\n\r\n{}Content-Length: 2\r\n\r\n{{}}",
+            deep.len(),
+            deep,
+            MAX_MESSAGE_LENGTH + 1,
+            " ".repeat(MAX_MESSAGE_LENGTH + 1)
+        );
+        let mut input = io::Cursor::new(framed.into_bytes());
+        for _ in 0..3 {
+            assert!(matches!(read_message(&mut input), Ok(Some(Err(_)))));
+        }
+        assert_eq!(
+            Some(Ok(Json::Object(vec![]))),
+            read_message(&mut input).unwrap()
+        );
+        assert_eq!(None, read_message(&mut input).unwrap());
+    }
+}
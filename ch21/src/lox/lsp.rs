@@ -0,0 +1,776 @@
+use crate::lox::analysis::{Analysis, Kind, Role};
+use crate::lox::compiler;
+use crate::lox::interner::Interner;
+use crate::lox::json::{read_message, write_message, Json};
+use crate::lox::resolver;
+use crate::lox::scanner::TokenType;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+const TOKEN_TYPES: [&str; 10] = [
+    "keyword",
+    "string",
+    "number",
+    "operator",
+    "variable",
+    "parameter",
+    "function",
+    "class",
+    "method",
+    "property",
+];
+const DECLARATION_MODIFIER: usize = 1;
+
+const PARSE_ERROR: f64 = -32700.0;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const INVALID_REQUEST: f64 = -32600.0;
+
+// A Language Server Protocol server. Documents are synced whole, and every
+// change is checked to publish its diagnostics.
+pub struct Server {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    documents: BTreeMap<String, String>,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Server {
+        Server {
+            input,
+            output,
+            documents: BTreeMap::new(),
+            shutdown: false,
+        }
+    }
+
+    // Serves messages until the client sends exit or the input ends.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(message) = read_message(&mut self.input)? {
+            let message = match message {
+                Ok(message) => message,
+                // There is no telling what id it had, so the answer has none.
+                Err(e) => {
+                    self.error(&Json::Null, PARSE_ERROR, &e)?;
+                    continue;
+                }
+            };
+            let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+            let params = message.get("params").cloned().unwrap_or(Json::Null);
+            match message.get("id") {
+                Some(id) => self.request(id, method, &params)?,
+                None if method == "exit" => return Ok(()),
+                None => self.notification(method, &params)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, id: &Json, method: &str, params: &Json) -> io::Result<()> {
+        if self.shutdown {
+            return self.error(id, INVALID_REQUEST, "The server is shut down.");
+        }
+        let result = match method {
+            "initialize" => initialize(),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/semanticTokens/full" => self.with_document(params, semantic_tokens),
+            "textDocument/documentSymbol" => self.with_document(params, document_symbols),
+            "textDocument/definition" => self.at_position(params, definition),
+            "textDocument/references" => {
+                let declaration = params
+                    .get("context")
+                    .and_then(|context| context.get("includeDeclaration"))
+                    .and_then(Json::as_bool)
+                    .unwrap_or(false);
+                self.at_position(params, |document, uri, offset| {
+                    references(document, uri, offset, declaration)
+                })
+            }
+            "textDocument/hover" => {
+                self.at_position(params, |document, _, offset| hover(document, offset))
+            }
+            _ => {
+                let message = format!("Unsupported method '{}'.", method);
+                return self.error(id, METHOD_NOT_FOUND, &message);
+            }
+        };
+        self.send(vec![("id", id.clone()), ("result", result)])
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let document = params.get("textDocument");
+        let Some(uri) = document.and_then(|d| d.get("uri")).and_then(Json::as_str) else {
+            return Ok(());
+        };
+        let text = match method {
+            "textDocument/didOpen" => document.and_then(|d| d.get("text")),
+            // Only whole documents are synced, so the last change has it all.
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .and_then(Json::as_array)
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text")),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.publish(uri, vec![]);
+            }
+            _ => return Ok(()),
+        };
+        let Some(text) = text.and_then(Json::as_str) else {
+            return Ok(());
+        };
+        self.documents.insert(uri.to_string(), text.to_string());
+        let document = Document::new(text);
+        let diagnostics = document.diagnostics();
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+        let params = Json::object(vec![
+            ("uri", Json::from(uri)),
+            ("diagnostics", Json::from(diagnostics)),
+        ]);
+        self.send(vec![
+            ("method", Json::from("textDocument/publishDiagnostics")),
+            ("params", params),
+        ])
+    }
+
+    fn with_document(&self, params: &Json, answer: fn(&Document) -> Json) -> Json {
+        match self.document(params) {
+            Some(text) => answer(&Document::new(text)),
+            None => Json::Null,
+        }
+    }
+
+    fn at_position(&self, params: &Json, answer: impl Fn(&Document, &Json, usize) -> Json) -> Json {
+        let Some(text) = self.document(params) else {
+            return Json::Null;
+        };
+        let document = Document::new(text);
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .unwrap_or(&Json::Null);
+        match params.get("position").and_then(|p| document.offset(p)) {
+            Some(offset) => answer(&document, uri, offset),
+            None => Json::Null,
+        }
+    }
+
+    fn document(&self, params: &Json) -> Option<&str> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)?;
+        self.documents.get(uri).map(String::as_str)
+    }
+
+    fn error(&mut self, id: &Json, code: f64, message: &str) -> io::Result<()> {
+        let error = Json::object(vec![
+            ("code", Json::from(code)),
+            ("message", Json::from(message)),
+        ]);
+        self.send(vec![("id", id.clone()), ("error", error)])
+    }
+
+    fn send(&mut self, mut members: Vec<(&str, Json)>) -> io::Result<()> {
+        members.insert(0, ("jsonrpc", Json::from("2.0")));
+        write_message(&mut self.output, &Json::object(members))
+    }
+}
+
+fn initialize() -> Json {
+    let legend = Json::object(vec![
+        (
+            "tokenTypes",
+            Json::from(
+                TOKEN_TYPES
+                    .iter()
+                    .map(|t| Json::from(*t))
+                    .collect::<Vec<Json>>(),
+            ),
+        ),
+        (
+            "tokenModifiers",
+            Json::from(vec![Json::from("declaration")]),
+        ),
+    ]);
+    let capabilities = Json::object(vec![
+        ("textDocumentSync", Json::from(1)),
+        ("definitionProvider", Json::from(true)),
+        ("referencesProvider", Json::from(true)),
+        ("hoverProvider", Json::from(true)),
+        ("documentSymbolProvider", Json::from(true)),
+        (
+            "semanticTokensProvider",
+            Json::object(vec![("legend", legend), ("full", Json::from(true))]),
+        ),
+    ]);
+    Json::object(vec![
+        ("capabilities", capabilities),
+        (
+            "serverInfo",
+            Json::object(vec![("name", Json::from("lox"))]),
+        ),
+    ])
+}
+
+// An open document and what is known about it. Positions in the protocol
+// count UTF-16 code units from the start of a line; spans here are bytes.
+struct Document<'a> {
+    text: &'a str,
+    line_starts: Vec<usize>,
+    analysis: Analysis,
+}
+
+impl<'a> Document<'a> {
+    fn new(text: &'a str) -> Document<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Document {
+            text,
+            line_starts,
+            analysis: Analysis::new(text),
+        }
+    }
+
+    fn line_and_character(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let character = self.text[self.line_starts[line]..offset]
+            .encode_utf16()
+            .count();
+        (line, character)
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let (line, character) = self.line_and_character(offset);
+        Json::object(vec![
+            ("line", Json::from(line)),
+            ("character", Json::from(character)),
+        ])
+    }
+
+    fn range(&self, start: usize, end: usize) -> Json {
+        Json::object(vec![
+            ("start", self.position(start)),
+            ("end", self.position(end)),
+        ])
+    }
+
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = position.get("line").and_then(Json::as_usize)?;
+        let character = position.get("character").and_then(Json::as_usize)?;
+        let start = *self.line_starts.get(line)?;
+        let mut units = 0;
+        for (i, c) in self.text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return Some(start + i);
+            }
+            units += c.len_utf16();
+        }
+        Some(self.text.len())
+    }
+
+    // The errors lox check reports: the scanner's and the parser's, or the
+    // resolver's once the document parses.
+    // What running the file would report: the VM compiler's error, and
+    // lox check's on top of it.
+    fn diagnostics(&self) -> Vec<Json> {
+        let interner = Rc::new(RefCell::new(Interner::default()));
+        let mut errors = resolver::check(self.text).err().unwrap_or_default();
+        if let Err(error) = compiler::compile(self.text, interner) {
+            errors.push(error);
+        }
+        let mut errors: Vec<((usize, usize), String)> = errors
+            .into_iter()
+            .map(|e| {
+                let span = e
+                    .token
+                    .as_ref()
+                    .map(|token| (token.start, token.end))
+                    .unwrap_or(e.previous);
+                (span, e.message)
+            })
+            .collect();
+        errors.sort();
+        errors.dedup();
+        errors
+            .into_iter()
+            .map(|((start, end), message)| {
+                Json::object(vec![
+                    ("range", self.range(start, end)),
+                    ("severity", Json::from(1)),
+                    ("source", Json::from("lox")),
+                    ("message", Json::from(message)),
+                ])
+            })
+            .collect()
+    }
+
+    fn location(&self, uri: &Json, start: usize, end: usize) -> Json {
+        Json::object(vec![
+            ("uri", uri.clone()),
+            ("range", self.range(start, end)),
+        ])
+    }
+}
+
+fn semantic_tokens(document: &Document) -> Json {
+    let occurrences: HashMap<usize, _> = document
+        .analysis
+        .occurrences
+        .iter()
+        .map(|occurrence| (occurrence.start, occurrence))
+        .collect();
+    let mut data = vec![];
+    let (mut last_line, mut last_character) = (0, 0);
+    for token in document.analysis.tokens.iter() {
+        let (token_type, modifiers) = match &token.token_type {
+            TokenType::Identifier(_) => match occurrences.get(&token.start) {
+                Some(occurrence) => {
+                    let kind = occurrence
+                        .definition
+                        .map(|d| document.analysis.definitions[d].kind);
+                    let token_type = match (occurrence.role, kind) {
+                        (Role::Property, _) => "property",
+                        (_, Some(Kind::Parameter)) => "parameter",
+                        (_, Some(Kind::Function)) => "function",
+                        (_, Some(Kind::Class)) => "class",
+                        (_, Some(Kind::Method)) => "method",
+                        _ => "variable",
+                    };
+                    let modifiers = match occurrence.role {
+                        Role::Declaration => DECLARATION_MODIFIER,
+                        _ => 0,
+                    };
+                    (token_type, modifiers)
+                }
+                None => ("variable", 0),
+            },
+            TokenType::String(_) => ("string", 0),
+            TokenType::Number(_) => ("number", 0),
+            TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Slash
+            | TokenType::Star
+            | TokenType::Bang
+            | TokenType::BangEqual
+            | TokenType::Equal
+            | TokenType::EqualEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => ("operator", 0),
+            TokenType::And
+            | TokenType::Class
+            | TokenType::Else
+            | TokenType::False
+            | TokenType::For
+            | TokenType::Fun
+            | TokenType::If
+            | TokenType::Nil
+            | TokenType::Or
+            | TokenType::Print
+            | TokenType::Return
+            | TokenType::Super
+            | TokenType::This
+            | TokenType::True
+            | TokenType::Var
+            | TokenType::While => ("keyword", 0),
+            _ => continue,
+        };
+        let index = TOKEN_TYPES
+            .iter()
+            .position(|t| *t == token_type)
+            .unwrap_or(0);
+
+        // Clients need a token per line, which matters for strings.
+        let mut start = token.start;
+        for piece in document.text[token.start..token.end].split_inclusive('\n') {
+            let end = start + piece.trim_end_matches('\n').len();
+            if end > start {
+                let (line, character) = document.line_and_character(start);
+                let (_, end_character) = document.line_and_character(end);
+                let delta = if line == last_line {
+                    character - last_character
+                } else {
+                    character
+                };
+                data.extend([
+                    line - last_line,
+                    delta,
+                    end_character - character,
+                    index,
+                    modifiers,
+                ]);
+                (last_line, last_character) = (line, character);
+            }
+            start += piece.len();
+        }
+    }
+    Json::object(vec![(
+        "data",
+        Json::from(data.into_iter().map(Json::from).collect::<Vec<Json>>()),
+    )])
+}
+
+// Functions and classes, with the methods of a class and the functions
+// declared inside a function as their children.
+fn document_symbols(document: &Document) -> Json {
+    let analysis = &document.analysis;
+    let symbols: Vec<usize> = (0..analysis.definitions.len())
+        .filter(|d| {
+            matches!(
+                analysis.definitions[*d].kind,
+                Kind::Function | Kind::Class | Kind::Method
+            )
+        })
+        .collect();
+
+    // Definitions come in source order, so each symbol's parent is the
+    // closest one before it whose extent contains it.
+    let mut children: BTreeMap<Option<usize>, Vec<usize>> = BTreeMap::new();
+    let mut open: Vec<usize> = vec![];
+    for symbol in symbols {
+        let start = analysis.definitions[symbol].extent.0;
+        open.retain(|o| analysis.definitions[*o].extent.1 > start);
+        children
+            .entry(open.last().copied())
+            .or_default()
+            .push(symbol);
+        open.push(symbol);
+    }
+
+    fn build(
+        document: &Document,
+        children: &BTreeMap<Option<usize>, Vec<usize>>,
+        parent: Option<usize>,
+    ) -> Json {
+        let symbols = children.get(&parent).cloned().unwrap_or_default();
+        Json::from(
+            symbols
+                .into_iter()
+                .map(|symbol| {
+                    let definition = &document.analysis.definitions[symbol];
+                    let kind = match definition.kind {
+                        Kind::Class => 5,
+                        Kind::Method => 6,
+                        _ => 12,
+                    };
+                    let (start, end) = definition.extent;
+                    Json::object(vec![
+                        ("name", Json::from(definition.name.as_str())),
+                        ("detail", Json::from(document.analysis.describe(symbol))),
+                        ("kind", Json::from(kind)),
+                        ("range", document.range(start, end)),
+                        (
+                            "selectionRange",
+                            document.range(definition.start, definition.end),
+                        ),
+                        ("children", build(document, children, Some(symbol))),
+                    ])
+                })
+                .collect::<Vec<Json>>(),
+        )
+    }
+    build(document, &children, None)
+}
+
+fn definition_at(document: &Document, offset: usize) -> Option<usize> {
+    document.analysis.occurrence_at(offset)?.definition
+}
+
+fn definition(document: &Document, uri: &Json, offset: usize) -> Json {
+    let Some(d) = definition_at(document, offset) else {
+        return Json::Null;
+    };
+    let definition = &document.analysis.definitions[d];
+    document.location(uri, definition.start, definition.end)
+}
+
+fn references(document: &Document, uri: &Json, offset: usize, declaration: bool) -> Json {
+    let Some(d) = definition_at(document, offset) else {
+        return Json::Null;
+    };
+    Json::from(
+        document
+            .analysis
+            .occurrences_of(d)
+            .iter()
+            .filter(|occurrence| declaration || occurrence.role != Role::Declaration)
+            .map(|occurrence| document.location(uri, occurrence.start, occurrence.end))
+            .collect::<Vec<Json>>(),
+    )
+}
+
+fn hover(document: &Document, offset: usize) -> Json {
+    let Some(occurrence) = document.analysis.occurrence_at(offset) else {
+        return Json::Null;
+    };
+    let value = match (occurrence.role, occurrence.definition) {
+        (_, Some(d)) => document.analysis.describe(d),
+        (Role::Property, None) => format!("(property) {}", occurrence.name),
+        (_, None) => format!("(undefined) {}", occurrence.name),
+    };
+    Json::object(vec![
+        (
+            "contents",
+            Json::object(vec![
+                ("kind", Json::from("plaintext")),
+                ("value", Json::from(value)),
+            ]),
+        ),
+        ("range", document.range(occurrence.start, occurrence.end)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::test_support::Shared;
+
+    // Runs the server over a scripted list of messages and returns what it
+    // sent back.
+    fn transcript(messages: &[&str]) -> Vec<String> {
+        let mut input = vec![];
+        for message in messages {
+            let message = Json::parse(message).expect("bad message");
+            write_message(&mut input, &message).unwrap();
+        }
+        serve(input)
+    }
+
+    fn serve(input: Vec<u8>) -> Vec<String> {
+        let out = Shared::default();
+        let mut server = Server::new(Box::new(io::Cursor::new(input)), Box::new(out.clone()));
+        server.serve().expect("server failed");
+
+        let mut output = io::Cursor::new(out.take());
+        let mut sent = vec![];
+        while let Some(message) = read_message(&mut output).unwrap() {
+            sent.push(message.unwrap().to_string());
+        }
+        sent
+    }
+
+    fn open(text: &str) -> String {
+        let params = Json::object(vec![(
+            "textDocument",
+            Json::object(vec![
+                ("uri", Json::from("file:///a.lox")),
+                ("languageId", Json::from("lox")),
+                ("version", Json::from(1)),
+                ("text", Json::from(text)),
+            ]),
+        )]);
+        Json::object(vec![
+            ("jsonrpc", Json::from("2.0")),
+            ("method", Json::from("textDocument/didOpen")),
+            ("params", params),
+        ])
+        .to_string()
+    }
+
+    const SOURCE: &str = "var a = 1;
+fun add(x, y) {
+  return x + y;
+}
+class Point {
+  norm() { return this.x; }
+}
+print add(a, \"é\");
+";
+
+    #[test]
+    fn it_initializes_and_shuts_down() {
+        let messages = transcript(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"workspace/symbol","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/hover","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+            r#"{"jsonrpc":"2.0","id":5,"method":"shutdown"}"#,
+        ]);
+        assert_eq!(
+            vec![
+                r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":1,"definitionProvider":true,"referencesProvider":true,"hoverProvider":true,"documentSymbolProvider":true,"semanticTokensProvider":{"legend":{"tokenTypes":["keyword","string","number","operator","variable","parameter","function","class","method","property"],"tokenModifiers":["declaration"]},"full":true}},"serverInfo":{"name":"lox"}}}"#,
+                r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32601,"message":"Unsupported method 'workspace/symbol'."}}"#,
+                r#"{"jsonrpc":"2.0","id":3,"result":null}"#,
+                r#"{"jsonrpc":"2.0","id":4,"error":{"code":-32600,"message":"The server is shut down."}}"#,
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn it_answers_messages_that_are_not_json_and_goes_on() {
+        let shutdown = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let input = format!(
+            "Content-Length: 5\r\n\r\n{{\"id\"Content-Length: {}\r\n\r\n{}",
+            shutdown.len(),
+            shutdown
+        );
+        assert_eq!(
+            vec![
+                r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"Expected ':' at the end."}}"#,
+                r#"{"jsonrpc":"2.0","id":1,"result":null}"#,
+            ],
+            serve(input.into_bytes())
+        );
+    }
+
+    #[test]
+    fn it_publishes_diagnostics_as_the_document_changes() {
+        let messages = transcript(&[
+            &open("var a = 1;\nprint a +;\n"),
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.lox","version":2},"contentChanges":[{"text":"print 1;\nvar é = \"x;\n"}]}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///a.lox","version":3},"contentChanges":[{"text":"print 1;"}]}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didClose","params":{"textDocument":{"uri":"file:///a.lox"}}}"#,
+        ]);
+        assert_eq!(
+            vec![
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":1,"character":9},"end":{"line":1,"character":10}},"severity":1,"source":"lox","message":"Expect expression."}]}}"#,
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":1,"character":4},"end":{"line":1,"character":5}},"severity":1,"source":"lox","message":"Unexpected character."},{"range":{"start":{"line":1,"character":6},"end":{"line":1,"character":7}},"severity":1,"source":"lox","message":"Expect variable name."},{"range":{"start":{"line":1,"character":8},"end":{"line":2,"character":0}},"severity":1,"source":"lox","message":"Unterminated string."}]}}"#,
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[]}}"#,
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[]}}"#,
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn it_reports_what_the_compiler_and_lox_check_report() {
+        let messages = transcript(&[
+            &open("print 1;\n"),
+            &open("print 1;\nreturn 2;\n"),
+            &open("fun f() {}\n{ print 1; }\n"),
+        ]);
+        assert_eq!(
+            vec![
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[]}}"#,
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":1,"character":0},"end":{"line":1,"character":6}},"severity":1,"source":"lox","message":"Can't return from top-level code."},{"range":{"start":{"line":1,"character":0},"end":{"line":1,"character":6}},"severity":1,"source":"lox","message":"Expect expression."}]}}"#,
+                r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":3}},"severity":1,"source":"lox","message":"Expect expression."}]}}"#,
+            ],
+            messages
+        );
+    }
+
+    // The result of each request after opening SOURCE.
+    fn results(requests: &[&str]) -> Vec<String> {
+        let mut messages = vec![open(SOURCE)];
+        messages.extend(requests.iter().map(|r| r.to_string()));
+        let messages: Vec<&str> = messages.iter().map(String::as_str).collect();
+        transcript(&messages)
+            .iter()
+            .skip(1)
+            .map(|m| Json::parse(m).unwrap().get("result").unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn it_goes_to_definitions_and_references() {
+        assert_eq!(
+            vec![
+                r#"{"uri":"file:///a.lox","range":{"start":{"line":1,"character":4},"end":{"line":1,"character":7}}}"#,
+                r#"{"uri":"file:///a.lox","range":{"start":{"line":1,"character":8},"end":{"line":1,"character":9}}}"#,
+                r#"null"#,
+                r#"[{"uri":"file:///a.lox","range":{"start":{"line":7,"character":10},"end":{"line":7,"character":11}}}]"#,
+                r#"[{"uri":"file:///a.lox","range":{"start":{"line":0,"character":4},"end":{"line":0,"character":5}}},{"uri":"file:///a.lox","range":{"start":{"line":7,"character":10},"end":{"line":7,"character":11}}}]"#,
+            ],
+            results(&[
+                r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":7,"character":7}}}"#,
+                r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":2,"character":9}}}"#,
+                r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":5,"character":23}}}"#,
+                r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/references","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":0,"character":4},"context":{"includeDeclaration":false}}}"#,
+                r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/references","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":7,"character":11},"context":{"includeDeclaration":true}}}"#,
+            ])
+        );
+    }
+
+    #[test]
+    fn it_hovers_with_the_kind_of_a_name() {
+        let hovers: Vec<String> = results(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":0,"character":4}}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":7,"character":6}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":2,"character":13}}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":5,"character":23}}}"#,
+            r#"{"jsonrpc":"2.0","id":5,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///a.lox"},"position":{"line":3,"character":0}}}"#,
+            r#"{"jsonrpc":"2.0","id":6,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///b.lox"},"position":{"line":0,"character":0}}}"#,
+        ])
+        .iter()
+        .map(|r| {
+            let result = Json::parse(r).unwrap();
+            match result.get("contents") {
+                Some(contents) => contents.get("value").unwrap().to_string(),
+                None => result.to_string(),
+            }
+        })
+        .collect();
+        assert_eq!(
+            vec![
+                r#""(global variable) a: number""#,
+                r#""(function) add(x, y)""#,
+                r#""(parameter) y""#,
+                r#""(property) x""#,
+                r#"null"#,
+                r#"null"#,
+            ],
+            hovers
+        );
+    }
+
+    #[test]
+    fn it_lists_document_symbols() {
+        assert_eq!(
+            vec![
+                r#"[{"name":"add","detail":"(function) add(x, y)","kind":12,"range":{"start":{"line":1,"character":0},"end":{"line":3,"character":1}},"selectionRange":{"start":{"line":1,"character":4},"end":{"line":1,"character":7}},"children":[]},{"name":"Point","detail":"(class) Point","kind":5,"range":{"start":{"line":4,"character":0},"end":{"line":6,"character":1}},"selectionRange":{"start":{"line":4,"character":6},"end":{"line":4,"character":11}},"children":[{"name":"norm","detail":"(method) Point.norm()","kind":6,"range":{"start":{"line":5,"character":2},"end":{"line":5,"character":27}},"selectionRange":{"start":{"line":5,"character":2},"end":{"line":5,"character":6}},"children":[]}]}]"#,
+            ],
+            results(&[
+                r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///a.lox"}}}"#,
+            ])
+        );
+    }
+
+    #[test]
+    fn it_classifies_semantic_tokens() {
+        let source = "var s = \"a\nb\";\nfun f(p) { return p.q; }";
+        let messages = transcript(&[
+            &open(source),
+            r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/semanticTokens/full","params":{"textDocument":{"uri":"file:///a.lox"}}}"#,
+        ]);
+        let result = Json::parse(&messages[1]).unwrap();
+        let data: Vec<usize> = result
+            .get("result")
+            .and_then(|r| r.get("data"))
+            .and_then(Json::as_array)
+            .unwrap()
+            .iter()
+            .filter_map(Json::as_usize)
+            .collect();
+        assert_eq!(
+            vec![
+                [0, 0, 3, 0, 0],
+                [0, 4, 1, 4, 1],
+                [0, 2, 1, 3, 0],
+                [0, 2, 2, 1, 0],
+                [1, 0, 2, 1, 0],
+                [1, 0, 3, 0, 0],
+                [0, 4, 1, 6, 1],
+                [0, 2, 1, 5, 1],
+                [0, 5, 6, 0, 0],
+                [0, 7, 1, 5, 0],
+                [0, 2, 1, 9, 0],
+            ],
+            data.chunks(5).collect::<Vec<&[usize]>>()
+        );
+    }
+}
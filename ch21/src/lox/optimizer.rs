@@ -0,0 +1,333 @@
+use crate::lox::chunk::{Chunk, Instruction, OpCode};
+use crate::lox::value::Value;
+
+// Peephole pass over a compiled chunk. Instructions are shifted onto the
+// output one at a time and the tail is rewritten for as long as a pattern
+// matches, so rewrites can cascade (True, Not, Pop is removed entirely).
+// There are no jump instructions yet, so nothing refers to an instruction
+// offset and removing instructions needs no fix ups.
+pub fn optimize(chunk: &mut Chunk) -> Result<(), String> {
+    let instructions = chunk.instructions();
+    let mut output: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        output.push(instruction);
+        while rewrite_tail(&mut output, chunk.constants()) {}
+    }
+
+    let constants = compact_constants(&mut output, chunk.constants())?;
+    chunk.replace_constants(constants);
+    chunk.replace_instructions(&output)
+}
+
+fn rewrite_tail(output: &mut Vec<Instruction>, constants: &[Value]) -> bool {
+    let n = output.len();
+    match output[..] {
+        // A comparison followed by Not is the opposite comparison.
+        [.., a, b] if b.code == OpCode::Not && negated(a.code).is_some() => {
+            let Some(code) = negated(a.code) else {
+                return false;
+            };
+            output.truncate(n - 2);
+            output.push(Instruction::new(code, a.line));
+            true
+        }
+        // A push with no side effects that is immediately popped.
+        [.., a, b] if is_pure_push(a.code) && b.code == OpCode::Pop => {
+            output.truncate(n - 2);
+            true
+        }
+        // Not of a literal is another literal.
+        [.., a, b] if b.code == OpCode::Not && literal(a.code, constants).is_some() => {
+            let code = match literal(a.code, constants) {
+                Some(value) if value.is_falsey() => OpCode::True,
+                _ => OpCode::False,
+            };
+            output.truncate(n - 2);
+            output.push(Instruction::new(code, a.line));
+            true
+        }
+        // Not cannot fail, so its result can be dropped with the Pop.
+        [.., a, b] if a.code == OpCode::Not && b.code == OpCode::Pop => {
+            output.remove(n - 2);
+            true
+        }
+        // Double negation of something that is already a boolean.
+        [.., a, b, c] if is_boolean(a.code) && b.code == OpCode::Not && c.code == OpCode::Not => {
+            output.truncate(n - 2);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn is_pure_push(code: OpCode) -> bool {
+    matches!(
+        code,
+        OpCode::Constant(_) | OpCode::SmallInt(_) | OpCode::Nil | OpCode::True | OpCode::False
+    )
+}
+
+// The comparison opcodes are defined as each other's negations (GreaterEqual
+// is !(a < b)), so these swaps hold for NaN too.
+fn negated(code: OpCode) -> Option<OpCode> {
+    match code {
+        OpCode::Equal => Some(OpCode::NotEqual),
+        OpCode::NotEqual => Some(OpCode::Equal),
+        OpCode::Greater => Some(OpCode::LessEqual),
+        OpCode::LessEqual => Some(OpCode::Greater),
+        OpCode::Less => Some(OpCode::GreaterEqual),
+        OpCode::GreaterEqual => Some(OpCode::Less),
+        _ => None,
+    }
+}
+
+fn is_boolean(code: OpCode) -> bool {
+    matches!(
+        code,
+        OpCode::True
+            | OpCode::False
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Not
+    )
+}
+
+fn literal(code: OpCode, constants: &[Value]) -> Option<Value> {
+    match code {
+        OpCode::Constant(c) => constants.get(c).copied(),
+        OpCode::SmallInt(n) => Some(Value::Number(n as f64)),
+        OpCode::Nil => Some(Value::Nil),
+        OpCode::True => Some(Value::Bool(true)),
+        OpCode::False => Some(Value::Bool(false)),
+        _ => None,
+    }
+}
+
+// Drops constants that no instruction refers to any more and renumbers the
+// ones that are left.
+fn compact_constants(
+    instructions: &mut [Instruction],
+    old_constants: &[Value],
+) -> Result<Vec<Value>, String> {
+    let mut remap: Vec<Option<usize>> = vec![None; old_constants.len()];
+    let mut constants = vec![];
+
+    for instruction in instructions.iter_mut() {
+        if let OpCode::Constant(c)
+        | OpCode::GetGlobal(c)
+        | OpCode::DefineGlobal(c)
+        | OpCode::SetGlobal(c) = &mut instruction.code
+        {
+            let old = *c;
+            let (Some(new), Some(value)) = (remap.get_mut(old), old_constants.get(old)) else {
+                return Err(String::from("Constant index out of range."));
+            };
+            *c = *new.get_or_insert_with(|| {
+                constants.push(*value);
+                constants.len() - 1
+            });
+        }
+    }
+
+    Ok(constants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_optimizes(source: Vec<OpCode>, expected: Vec<OpCode>) {
+        let mut chunk = Chunk::new();
+        for code in source {
+            chunk.write_chunk(code, 1).unwrap();
+        }
+        optimize(&mut chunk).unwrap();
+
+        let actual: Vec<OpCode> = chunk.instructions().iter().map(|i| i.code).collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn it_removes_dead_pushes() {
+        assert_optimizes(
+            vec![
+                OpCode::True,
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Pop,
+                OpCode::Return,
+            ],
+            vec![OpCode::Return],
+        );
+    }
+
+    #[test]
+    fn it_keeps_pops_with_side_effects() {
+        assert_optimizes(
+            vec![OpCode::Nil, OpCode::Negate, OpCode::Pop, OpCode::Return],
+            vec![OpCode::Nil, OpCode::Negate, OpCode::Pop, OpCode::Return],
+        );
+    }
+
+    #[test]
+    fn it_negates_literals() {
+        assert_optimizes(
+            vec![OpCode::Nil, OpCode::Not, OpCode::Print, OpCode::Return],
+            vec![OpCode::True, OpCode::Print, OpCode::Return],
+        );
+    }
+
+    #[test]
+    fn it_cascades_rewrites() {
+        assert_optimizes(
+            vec![
+                OpCode::True,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::Pop,
+                OpCode::Return,
+            ],
+            vec![OpCode::Return],
+        );
+    }
+
+    #[test]
+    fn it_removes_double_negation_of_booleans() {
+        assert_optimizes(
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::Greater,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::Greater,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+        );
+    }
+
+    #[test]
+    fn it_negates_comparisons() {
+        assert_optimizes(
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::Greater,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::LessEqual,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+        );
+
+        assert_optimizes(
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::NotEqual,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::Equal,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+        );
+    }
+
+    #[test]
+    fn it_keeps_double_negation_of_other_values() {
+        assert_optimizes(
+            vec![
+                OpCode::Nil,
+                OpCode::Negate,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+            vec![
+                OpCode::Nil,
+                OpCode::Negate,
+                OpCode::Not,
+                OpCode::Not,
+                OpCode::Print,
+                OpCode::Return,
+            ],
+        );
+    }
+
+    #[test]
+    fn it_keeps_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::True, 1).unwrap();
+        chunk.write_chunk(OpCode::Pop, 1).unwrap();
+        chunk.write_chunk(OpCode::False, 2).unwrap();
+        chunk.write_chunk(OpCode::Not, 3).unwrap();
+        chunk.write_chunk(OpCode::Print, 3).unwrap();
+        chunk.write_chunk(OpCode::Return, 4).unwrap();
+        optimize(&mut chunk).unwrap();
+
+        assert_eq!(
+            vec![
+                Instruction::new(OpCode::True, 2),
+                Instruction::new(OpCode::Print, 3),
+                Instruction::new(OpCode::Return, 4),
+            ],
+            chunk.instructions()
+        );
+    }
+
+    #[test]
+    fn it_drops_unused_constants() {
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Value::Number(1.0));
+        let two = chunk.add_constant(Value::Number(2.0));
+        chunk.write_chunk(OpCode::Constant(one), 1).unwrap();
+        chunk.write_chunk(OpCode::Pop, 1).unwrap();
+        chunk.write_chunk(OpCode::Constant(two), 2).unwrap();
+        chunk.write_chunk(OpCode::Print, 2).unwrap();
+        chunk.write_chunk(OpCode::Return, 2).unwrap();
+        optimize(&mut chunk).unwrap();
+
+        assert_eq!(
+            vec![
+                Instruction::new(OpCode::Constant(0), 2),
+                Instruction::new(OpCode::Print, 2),
+                Instruction::new(OpCode::Return, 2),
+            ],
+            chunk.instructions()
+        );
+        assert_eq!(vec![Value::Number(2.0)], chunk.constants());
+    }
+
+    #[test]
+    fn it_refuses_constants_out_of_range() {
+        let mut chunk = Chunk::from_parts(vec![0, 5], &[(1, 2)], vec![]);
+        assert_eq!(
+            Err(String::from("Constant index out of range.")),
+            optimize(&mut chunk)
+        );
+    }
+}
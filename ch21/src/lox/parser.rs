@@ -0,0 +1,892 @@
+use crate::lox::ast::*;
+use crate::lox::compiler::{ParserError, MAX_CHAIN, MAX_NESTING};
+use crate::lox::scanner::{Scanner, Token, TokenType};
+
+use std::mem;
+
+const MAX_ARGUMENTS: usize = 255;
+
+// A recursive-descent parser for the whole language, after ch13's. It
+// reports every error it can find instead of stopping at the first: after an
+// error it skips to the next statement and carries on.
+pub fn parse(source: &str) -> Result<Vec<Stmt>, Vec<ParserError>> {
+    let mut parser = Parser::new(source);
+    let mut statements = vec![];
+    while !parser.is_at_end() {
+        if let Some(statement) = parser.declaration() {
+            statements.push(statement);
+        }
+    }
+
+    if parser.errors.is_empty() {
+        Ok(statements)
+    } else {
+        // Scanner errors were found first, so put them in source order.
+        let mut errors = parser.errors;
+        errors.sort_by_key(|error| error.token.as_ref().map(|token| token.start));
+        Err(errors)
+    }
+}
+
+// An error as jlox reports it: "[line 1] Error at 'x': Expect ';' after
+// value." Scanner errors have no lexeme to point at, and errors without a
+// token are placed on the line their span starts on.
+pub fn format_error(source: &str, error: &ParserError) -> String {
+    let Some(token) = &error.token else {
+        let before = source.get(..error.previous.0).unwrap_or("");
+        let line = 1 + before.matches('\n').count();
+        return format!("[line {}] Error: {}", line, error.message);
+    };
+    let place = match &token.token_type {
+        TokenType::EOF => String::from(" at end"),
+        TokenType::Error(_) => String::new(),
+        _ => format!(" at '{}'", source.get(token.start..token.end).unwrap_or("")),
+    };
+    format!("[line {}] Error{}: {}", token.line, place, error.message)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    errors: Vec<ParserError>,
+    depth: usize,
+    // How many links the chains in the statement being parsed have.
+    chain: usize,
+    // Set once the nesting limit is hit, after which nothing else is parsed
+    // or reported: every enclosing block would only complain of its end.
+    too_deep: bool,
+}
+
+type ParseResult<T> = Result<T, ParserError>;
+
+impl Parser {
+    // Scans the source up front. Error tokens are reported here and left out,
+    // so that the parser never sees them.
+    fn new(source: &str) -> Parser {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = vec![];
+        let mut errors = vec![];
+        loop {
+            let token = scanner.scan_token();
+            match &token.token_type {
+                TokenType::Error(message) => errors.push(ParserError {
+                    message: message.clone(),
+                    previous: (token.start, token.end),
+                    token: Some(token),
+                }),
+                TokenType::EOF => {
+                    tokens.push(token);
+                    break;
+                }
+                _ => tokens.push(token),
+            }
+        }
+        Parser {
+            tokens,
+            current: 0,
+            errors,
+            depth: 0,
+            chain: 0,
+            too_deep: false,
+        }
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        // A declaration's expressions are trees of their own, even in a
+        // block inside another declaration.
+        let chain = mem::take(&mut self.chain);
+        let result = if self.match_token(TokenType::Class) {
+            self.class_declaration()
+        } else if self.match_token(TokenType::Fun) {
+            let start = self.previous();
+            self.function("function").map(|function| Stmt {
+                span: Span::new(start.start, function.span.end),
+                line: start.line,
+                kind: StmtKind::Function(function),
+            })
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+        self.chain = chain;
+
+        match result {
+            Ok(statement) => Some(statement),
+            Err(_) if self.too_deep => None,
+            Err(error) => {
+                self.errors.push(error);
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<Stmt> {
+        let start = self.previous();
+        let name = self.consume_name("Expect class name.")?;
+
+        let superclass = if self.match_token(TokenType::Less) {
+            Some(self.consume_name("Expect superclass name.")?)
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(self.stmt(
+            start,
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            },
+        ))
+    }
+
+    fn function(&mut self, kind: &str) -> ParseResult<Function> {
+        let name = self.consume_name(&format!("Expect {} name.", kind))?;
+        self.consume(
+            TokenType::LeftParen,
+            &format!("Expect '(' after {} name.", kind),
+        )?;
+        let mut params = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGUMENTS {
+                    let error = self.error_at_current("Can't have more than 255 parameters.");
+                    self.errors.push(error);
+                }
+                params.push(self.consume_name("Expect parameter name.")?);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        )?;
+        let body = self.block()?;
+        Ok(Function {
+            span: name.span.to(self.previous_span()),
+            name,
+            params,
+            body,
+        })
+    }
+
+    fn var_declaration(&mut self) -> ParseResult<Stmt> {
+        let start = self.previous();
+        let name = self.consume_name("Expect variable name.")?;
+        let initializer = if self.match_token(TokenType::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(self.stmt(start, StmtKind::Var { name, initializer }))
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::Print) {
+            let start = self.previous();
+            let value = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
+            Ok(self.stmt(start, StmtKind::Print(value)))
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_token(TokenType::While) {
+            self.while_statement()
+        } else if self.match_token(TokenType::LeftBrace) {
+            let start = self.previous();
+            let statements = self.block()?;
+            Ok(self.stmt(start, StmtKind::Block(statements)))
+        } else {
+            let start = self.peek();
+            let expression = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+            Ok(self.stmt(start, StmtKind::Expression(expression)))
+        }
+    }
+
+    // A for loop is desugared into the while loop it means, in blocks that
+    // scope the initializer and run the increment after the body.
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.previous();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        let initializer = if self.match_token(TokenType::Semicolon) {
+            None
+        } else if self.match_token(TokenType::Var) {
+            Some(self.var_declaration()?)
+        } else {
+            let start = self.peek();
+            let expression = self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+            Some(self.stmt(start, StmtKind::Expression(expression)))
+        };
+
+        let condition = if self.check(TokenType::Semicolon) {
+            let at = self.peek();
+            Expr {
+                kind: ExprKind::Literal(Literal::Bool(true)),
+                span: Span::new(at.start, at.start),
+                line: at.line,
+            }
+        } else {
+            self.expression()?
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let mut body = self.nested(Self::statement)?;
+        if let Some(increment) = increment {
+            let increment = Stmt {
+                span: increment.span,
+                line: increment.line,
+                kind: StmtKind::Expression(increment),
+            };
+            body = Stmt {
+                span: body.span,
+                line: body.line,
+                kind: StmtKind::Block(vec![body, increment]),
+            };
+        }
+        let mut body = self.stmt(
+            start.clone(),
+            StmtKind::While {
+                condition,
+                body: Box::new(body),
+            },
+        );
+        if let Some(initializer) = initializer {
+            body = self.stmt(start, StmtKind::Block(vec![initializer, body]));
+        }
+        Ok(body)
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.previous();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
+
+        let then_branch = Box::new(self.nested(Self::statement)?);
+        let else_branch = if self.match_token(TokenType::Else) {
+            Some(Box::new(self.nested(Self::statement)?))
+        } else {
+            None
+        };
+        Ok(self.stmt(
+            start,
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            },
+        ))
+    }
+
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.previous();
+        let value = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+        Ok(self.stmt(start, StmtKind::Return(value)))
+    }
+
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.previous();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let body = Box::new(self.nested(Self::statement)?);
+        Ok(self.stmt(start, StmtKind::While { condition, body }))
+    }
+
+    // The statements up to the closing brace, which has been consumed.
+    fn block(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.nested(Self::declarations)
+    }
+
+    fn declarations(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.nested(Self::assignment)
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
+
+        if self.match_token(TokenType::Equal) {
+            let equals = self.previous();
+            let value = Box::new(self.nested(Self::assignment)?);
+            let span = expr.span.to(value.span);
+            match expr.kind {
+                ExprKind::Variable(name) => {
+                    return Ok(Expr {
+                        span,
+                        line: name.line,
+                        kind: ExprKind::Assign { name, value },
+                    });
+                }
+                ExprKind::Get { object, name } => {
+                    return Ok(Expr {
+                        span,
+                        line: name.line,
+                        kind: ExprKind::Set {
+                            object,
+                            name,
+                            value,
+                        },
+                    });
+                }
+                kind => {
+                    // Reported, but the parser is not confused, so it goes on.
+                    self.errors.push(ParserError {
+                        token: Some(equals),
+                        message: String::from("Invalid assignment target."),
+                        previous: (expr.span.start, expr.span.end),
+                    });
+                    return Ok(Expr { kind, ..expr });
+                }
+            }
+        }
+
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
+        while self.match_token(TokenType::Or) {
+            let line = self.previous().line;
+            self.lengthen()?;
+            let right = self.and()?;
+            expr = logical(expr, LogicalOp::Or, right, line);
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.equality()?;
+        while self.match_token(TokenType::And) {
+            let line = self.previous().line;
+            self.lengthen()?;
+            let right = self.equality()?;
+            expr = logical(expr, LogicalOp::And, right, line);
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.comparison()?;
+        loop {
+            let op = if self.match_token(TokenType::BangEqual) {
+                BinaryOp::NotEqual
+            } else if self.match_token(TokenType::EqualEqual) {
+                BinaryOp::Equal
+            } else {
+                return Ok(expr);
+            };
+            let line = self.previous().line;
+            self.lengthen()?;
+            let right = self.comparison()?;
+            expr = binary(expr, op, right, line);
+        }
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.term()?;
+        loop {
+            let op = if self.match_token(TokenType::Greater) {
+                BinaryOp::Greater
+            } else if self.match_token(TokenType::GreaterEqual) {
+                BinaryOp::GreaterEqual
+            } else if self.match_token(TokenType::Less) {
+                BinaryOp::Less
+            } else if self.match_token(TokenType::LessEqual) {
+                BinaryOp::LessEqual
+            } else {
+                return Ok(expr);
+            };
+            let line = self.previous().line;
+            self.lengthen()?;
+            let right = self.term()?;
+            expr = binary(expr, op, right, line);
+        }
+    }
+
+    fn term(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.factor()?;
+        loop {
+            let op = if self.match_token(TokenType::Minus) {
+                BinaryOp::Subtract
+            } else if self.match_token(TokenType::Plus) {
+                BinaryOp::Add
+            } else {
+                return Ok(expr);
+            };
+            let line = self.previous().line;
+            self.lengthen()?;
+            let right = self.factor()?;
+            expr = binary(expr, op, right, line);
+        }
+    }
+
+    fn factor(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.match_token(TokenType::Slash) {
+                BinaryOp::Divide
+            } else if self.match_token(TokenType::Star) {
+                BinaryOp::Multiply
+            } else {
+                return Ok(expr);
+            };
+            let line = self.previous().line;
+            self.lengthen()?;
+            let right = self.unary()?;
+            expr = binary(expr, op, right, line);
+        }
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        let op = if self.match_token(TokenType::Bang) {
+            UnaryOp::Not
+        } else if self.match_token(TokenType::Minus) {
+            UnaryOp::Negate
+        } else {
+            return self.call();
+        };
+        let operator = self.previous();
+        let operand = Box::new(self.nested(Self::unary)?);
+        Ok(Expr {
+            span: Span::new(operator.start, operand.span.end),
+            line: operator.line,
+            kind: ExprKind::Unary { op, operand },
+        })
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.match_token(TokenType::LeftParen) {
+                self.lengthen()?;
+                expr = self.finish_call(expr)?;
+            } else if self.match_token(TokenType::Dot) {
+                self.lengthen()?;
+                let name = self.consume_name("Expect property name after '.'.")?;
+                expr = Expr {
+                    span: expr.span.to(name.span),
+                    line: name.line,
+                    kind: ExprKind::Get {
+                        object: Box::new(expr),
+                        name,
+                    },
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> ParseResult<Expr> {
+        let mut arguments = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if arguments.len() >= MAX_ARGUMENTS {
+                    let error = self.error_at_current("Can't have more than 255 arguments.");
+                    self.errors.push(error);
+                }
+                arguments.push(self.expression()?);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+        let paren = self.previous();
+
+        Ok(Expr {
+            span: Span::new(callee.span.start, paren.end),
+            line: paren.line,
+            kind: ExprKind::Call {
+                callee: Box::new(callee),
+                arguments,
+            },
+        })
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        let token = self.peek();
+        let kind = match &token.token_type {
+            TokenType::False => ExprKind::Literal(Literal::Bool(false)),
+            TokenType::True => ExprKind::Literal(Literal::Bool(true)),
+            TokenType::Nil => ExprKind::Literal(Literal::Nil),
+            TokenType::Number(n) => ExprKind::Literal(Literal::Number(*n)),
+            TokenType::String(s) => ExprKind::Literal(Literal::String(s.clone())),
+            TokenType::This => ExprKind::This,
+            TokenType::Identifier(_) => ExprKind::Variable(self.name(&token)),
+            TokenType::Super => {
+                self.advance();
+                self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+                let method = self.consume_name("Expect superclass method name.")?;
+                return Ok(Expr {
+                    span: Span::new(token.start, method.span.end),
+                    line: method.line,
+                    kind: ExprKind::Super { method },
+                });
+            }
+            TokenType::LeftParen => {
+                self.advance();
+                let expr = self.expression()?;
+                self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+                return Ok(Expr {
+                    span: Span::new(token.start, self.previous().end),
+                    line: token.line,
+                    kind: ExprKind::Grouping(Box::new(expr)),
+                });
+            }
+            _ => return Err(self.error_at_current("Expect expression.")),
+        };
+        self.advance();
+        Ok(Expr {
+            kind,
+            span: Span::new(token.start, token.end),
+            line: token.line,
+        })
+    }
+
+    // Parses something that nests inside what is being parsed, unless that
+    // is one level too many. Then the rest of the source is given up on.
+    fn nested<T>(&mut self, parse: fn(&mut Parser) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth == MAX_NESTING {
+            return Err(self.too_much_nesting());
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Each operator folded into a chain, and each call or property access
+    // strung onto one, makes the tree a level taller without the parser
+    // recursing. The links are counted against a limit of their own across
+    // a whole statement: counting them all is cruder than working out the
+    // height of each tree, but never less than it.
+    fn lengthen(&mut self) -> ParseResult<()> {
+        if self.chain == MAX_CHAIN {
+            return Err(self.too_much_nesting());
+        }
+        self.chain += 1;
+        Ok(())
+    }
+
+    fn too_much_nesting(&mut self) -> ParserError {
+        let error = self.error_at_current("Too much nesting.");
+        self.errors.push(error.clone());
+        self.too_deep = true;
+        self.current = self.tokens.len() - 1;
+        error
+    }
+
+    // Skips to what looks like the start of the next statement.
+    fn synchronize(&mut self) {
+        self.advance();
+        while !self.is_at_end() {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.peek().token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn stmt(&self, start: Token, kind: StmtKind) -> Stmt {
+        Stmt {
+            kind,
+            span: Span::new(start.start, self.previous().end),
+            line: start.line,
+        }
+    }
+
+    fn name(&self, token: &Token) -> Name {
+        let name = match &token.token_type {
+            TokenType::Identifier(name) => name.clone(),
+            _ => String::new(),
+        };
+        Name {
+            name,
+            span: Span::new(token.start, token.end),
+            line: token.line,
+        }
+    }
+
+    fn consume_name(&mut self, message: &str) -> ParseResult<Name> {
+        self.consume(TokenType::Identifier(String::new()), message)?;
+        Ok(self.name(&self.previous()))
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) -> ParseResult<()> {
+        if self.check(token_type) {
+            self.advance();
+            return Ok(());
+        }
+        Err(self.error_at_current(message))
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        !self.is_at_end()
+            && mem::discriminant(&self.tokens[self.current].token_type)
+                == mem::discriminant(&token_type)
+    }
+
+    fn advance(&mut self) {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.tokens[self.current].token_type == TokenType::EOF
+    }
+
+    fn peek(&self) -> Token {
+        self.tokens[self.current].clone()
+    }
+
+    // The last token consumed, or the first if none has been.
+    fn previous(&self) -> Token {
+        self.tokens[self.current.saturating_sub(1)].clone()
+    }
+
+    fn previous_span(&self) -> Span {
+        let previous = self.previous();
+        Span::new(previous.start, previous.end)
+    }
+
+    fn error_at_current(&self, message: &str) -> ParserError {
+        let previous = self.previous_span();
+        ParserError {
+            token: Some(self.peek()),
+            message: String::from(message),
+            previous: (previous.start, previous.end),
+        }
+    }
+}
+
+fn binary(left: Expr, op: BinaryOp, right: Expr, line: usize) -> Expr {
+    Expr {
+        span: left.span.to(right.span),
+        line,
+        kind: ExprKind::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+    }
+}
+
+fn logical(left: Expr, op: LogicalOp, right: Expr, line: usize) -> Expr {
+    Expr {
+        span: left.span.to(right.span),
+        line,
+        kind: ExprKind::Logical {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<(usize, String)> {
+        parse(source)
+            .expect_err("parse succeeded")
+            .into_iter()
+            .map(|error| (error.token.unwrap().line, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn it_formats_errors_like_jlox() {
+        let source = "print 1\nprint @;\nvar";
+        let errors: Vec<String> = parse(source)
+            .unwrap_err()
+            .iter()
+            .map(|error| format_error(source, error))
+            .collect();
+        assert_eq!(
+            vec![
+                "[line 2] Error at 'print': Expect ';' after value.",
+                "[line 2] Error: Unexpected character.",
+                "[line 3] Error at end: Expect variable name.",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn it_limits_nesting_and_stops_there() {
+        let parens = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        let blocks = |depth| format!("{}print 1;{}", "{".repeat(depth), "}".repeat(depth));
+        assert!(parse(&parens(MAX_NESTING - 1)).is_ok());
+        assert!(parse(&format!("print {}1;", "-".repeat(MAX_NESTING - 1))).is_ok());
+        assert!(parse(&blocks(MAX_NESTING - 1)).is_ok());
+        // Chains count across a statement, but not into the statements of a
+        // block.
+        let terms = |n| format!("1{}", " + 1".repeat(n));
+        let half = MAX_CHAIN / 2;
+        assert!(parse(&format!("print ({}) * ({});", terms(half), terms(half - 1))).is_ok());
+        assert!(parse(&format!("print ({}) * ({});", terms(half), terms(half))).is_err());
+        let statement = format!("print {};", terms(MAX_CHAIN));
+        assert!(parse(&format!("{{ {} {} }}", statement, statement)).is_ok());
+
+        for source in [
+            parens(MAX_NESTING),
+            format!("print {}1;", "!".repeat(100_000)),
+            blocks(MAX_NESTING),
+            format!("{}print 1;", "while (true) ".repeat(MAX_NESTING)),
+            format!("var a; {}1;", "a = ".repeat(MAX_NESTING)),
+            format!("print 1{};", " + 1".repeat(MAX_CHAIN + 1)),
+            format!("print (1{}) * 2;", " == 1".repeat(MAX_CHAIN + 1)),
+            format!("print f{};", "()".repeat(MAX_CHAIN + 1)),
+            format!("print a{};", ".b".repeat(MAX_CHAIN + 1)),
+        ] {
+            assert_eq!(
+                vec![(1, String::from("Too much nesting."))],
+                messages(&source)
+            );
+        }
+    }
+
+    #[test]
+    fn it_keeps_spans_and_lines() {
+        let source = "print a +\n  b;";
+        let statements = parse(source).unwrap();
+        assert_eq!(Span::new(0, source.len()), statements[0].span);
+        let StmtKind::Print(value) = &statements[0].kind else {
+            panic!("not a print statement");
+        };
+        assert_eq!(Span::new(6, 13), value.span);
+        assert_eq!(1, value.line);
+        let ExprKind::Binary { right, .. } = &value.kind else {
+            panic!("not a binary expression");
+        };
+        assert_eq!(2, right.line);
+    }
+
+    #[test]
+    fn it_reports_calls_at_their_closing_paren() {
+        let statements = parse("f(\n1\n);").unwrap();
+        let StmtKind::Expression(call) = &statements[0].kind else {
+            panic!("not an expression statement");
+        };
+        assert_eq!(3, call.line);
+    }
+
+    #[test]
+    fn it_reports_every_error() {
+        assert_eq!(
+            vec![
+                (1, String::from("Expect expression.")),
+                (2, String::from("Expect variable name.")),
+                (4, String::from("Expect ';' after value.")),
+            ],
+            messages("print ;\nvar 1;\nprint 2\nprint 3;")
+        );
+    }
+
+    #[test]
+    fn it_reports_scanner_errors_in_order() {
+        assert_eq!(
+            vec![
+                (1, String::from("Expect expression.")),
+                (2, String::from("Unexpected character.")),
+            ],
+            messages("print );\nprint @1;")
+        );
+    }
+
+    #[test]
+    fn it_reports_invalid_assignment_targets_and_goes_on() {
+        assert_eq!(
+            vec![
+                (1, String::from("Invalid assignment target.")),
+                (2, String::from("Expect '}' after block.")),
+            ],
+            messages("a + b = c;\n{")
+        );
+    }
+
+    #[test]
+    fn it_parses_classes() {
+        let statements = parse("class A < B {\n  init() {}\n  get(x) { return x; }\n}").unwrap();
+        let StmtKind::Class {
+            name,
+            superclass,
+            methods,
+        } = &statements[0].kind
+        else {
+            panic!("not a class");
+        };
+        assert_eq!("A", name.name);
+        assert_eq!("B", superclass.as_ref().unwrap().name);
+        assert_eq!(2, methods.len());
+        assert_eq!("x", methods[1].params[0].name);
+        assert_eq!(3, methods[1].name.line);
+    }
+}
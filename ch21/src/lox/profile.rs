@@ -0,0 +1,225 @@
+use crate::lox::chunk::Chunk;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LineProfile {
+    pub count: u64,
+    pub time: Duration,
+}
+
+// Counts the instructions a VM executes by opcode and by line, and times
+// lines with the wall clock. A step's time runs from its start to the start
+// of the next step, so it includes the profiler's own work for the step.
+//
+// Lox has no functions yet, so every line belongs to the one script frame.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    lines: Vec<usize>,
+    opcodes: HashMap<&'static str, u64>,
+    by_line: BTreeMap<usize, LineProfile>,
+    by_line_opcode: BTreeMap<(usize, &'static str), u64>,
+    current: Option<(usize, Instant)>,
+    total: Duration,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn start(&mut self, chunk: &Chunk) {
+        self.lines = chunk.lines_by_offset();
+    }
+
+    pub fn step(&mut self, chunk: &Chunk, ip: usize) {
+        let now = Instant::now();
+        self.charge(now);
+        let Some((code, _)) = chunk.decode(ip) else {
+            return;
+        };
+        let line = self.lines.get(ip).copied().unwrap_or(0);
+        *self.opcodes.entry(code.name()).or_default() += 1;
+        *self.by_line_opcode.entry((line, code.name())).or_default() += 1;
+        self.by_line.entry(line).or_default().count += 1;
+        self.current = Some((line, now));
+    }
+
+    // Charges the last step of a run, which has no next step to end it.
+    pub fn finish(&mut self) {
+        self.charge(Instant::now());
+    }
+
+    fn charge(&mut self, now: Instant) {
+        if let Some((line, started)) = self.current.take() {
+            let elapsed = now - started;
+            self.by_line.entry(line).or_default().time += elapsed;
+            self.total += elapsed;
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.values().sum()
+    }
+
+    // Most executed first.
+    pub fn opcode_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<(&'static str, u64)> = self
+            .opcodes
+            .iter()
+            .map(|(name, count)| (*name, *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    // Slowest first.
+    pub fn line_profiles(&self) -> Vec<(usize, LineProfile)> {
+        let mut lines: Vec<(usize, LineProfile)> =
+            self.by_line.iter().map(|(line, p)| (*line, *p)).collect();
+        lines.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+        lines
+    }
+
+    pub fn report(&self) -> Result<String, fmt::Error> {
+        let total = self.instructions();
+        let percent = |n: u64| 100.0 * n as f64 / total.max(1) as f64;
+        let mut output = String::new();
+        writeln!(
+            output,
+            "Executed {} instructions in {:.3} ms.",
+            total,
+            millis(self.total)
+        )?;
+
+        writeln!(output)?;
+        writeln!(output, "{:<20} {:>10} {:>7}", "opcode", "count", "%")?;
+        for (name, count) in self.opcode_counts() {
+            writeln!(
+                output,
+                "{:<20} {:>10} {:>6.1}%",
+                name,
+                count,
+                percent(count)
+            )?;
+        }
+
+        writeln!(output)?;
+        writeln!(
+            output,
+            "{:>6} {:>10} {:>7} {:>12} {:>7}",
+            "line", "count", "%", "time (ms)", "%"
+        )?;
+        let total_time = self.total.as_secs_f64().max(f64::MIN_POSITIVE);
+        for (line, profile) in self.line_profiles() {
+            writeln!(
+                output,
+                "{:>6} {:>10} {:>6.1}% {:>12.3} {:>6.1}%",
+                line,
+                profile.count,
+                percent(profile.count),
+                millis(profile.time),
+                100.0 * profile.time.as_secs_f64() / total_time
+            )?;
+        }
+        Ok(output)
+    }
+
+    // Stacks in the folded format flame graph tools read, one per line and
+    // opcode, weighted by how many times it executed.
+    pub fn folded(&self) -> Result<String, fmt::Error> {
+        let mut output = String::new();
+        for ((line, name), count) in self.by_line_opcode.iter() {
+            writeln!(output, "script;line {};{} {}", line, name, count)?;
+        }
+        Ok(output)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::vm::VM;
+
+    fn profile(source: &str) -> Profiler {
+        let mut vm = VM::new();
+        vm.set_profiler(Some(Profiler::new()));
+        vm.interpret(source).expect("run failed");
+        vm.take_profiler().expect("profiler is gone")
+    }
+
+    const SOURCE: &str = "var a = 1;\nprint a + a;\nprint a + a + a;\n";
+
+    #[test]
+    fn it_counts_opcodes() {
+        let profiler = profile(SOURCE);
+        assert_eq!(
+            vec![
+                ("OP_GET_GLOBAL", 5),
+                ("OP_ADD", 3),
+                ("OP_PRINT", 2),
+                ("OP_DEFINE_GLOBAL", 1),
+                ("OP_RETURN", 1),
+                ("OP_SMALL_INT", 1),
+            ],
+            profiler.opcode_counts()
+        );
+        assert_eq!(13, profiler.instructions());
+    }
+
+    #[test]
+    fn it_counts_lines() {
+        let profiler = profile(SOURCE);
+        let mut counts: Vec<(usize, u64)> = profiler
+            .line_profiles()
+            .iter()
+            .map(|(line, p)| (*line, p.count))
+            .collect();
+        counts.sort();
+        assert_eq!(vec![(1, 2), (2, 4), (3, 6), (4, 1)], counts);
+
+        let time: Duration = profiler.line_profiles().iter().map(|(_, p)| p.time).sum();
+        assert_eq!(profiler.total, time);
+    }
+
+    #[test]
+    fn it_adds_up_runs() {
+        let mut vm = VM::new();
+        vm.set_profiler(Some(Profiler::new()));
+        vm.interpret("print 1;").expect("run failed");
+        vm.interpret("print 2;").expect("run failed");
+        assert_eq!(6, vm.take_profiler().unwrap().instructions());
+    }
+
+    #[test]
+    fn it_writes_folded_stacks() {
+        assert_eq!(
+            "script;line 1;OP_DEFINE_GLOBAL 1
+script;line 1;OP_SMALL_INT 1
+script;line 2;OP_ADD 1
+script;line 2;OP_GET_GLOBAL 2
+script;line 2;OP_PRINT 1
+script;line 3;OP_ADD 2
+script;line 3;OP_GET_GLOBAL 3
+script;line 3;OP_PRINT 1
+script;line 4;OP_RETURN 1
+",
+            profile(SOURCE).folded().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_reports_opcodes_and_lines() {
+        let report = profile(SOURCE).report().unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("Executed 13 instructions in "));
+        assert_eq!("OP_GET_GLOBAL                 5   38.5%", lines[3]);
+        assert!(lines[10].starts_with("  line      count       %"));
+        assert_eq!(15, lines.len());
+    }
+}
@@ -0,0 +1,431 @@
+use crate::lox::ast::*;
+use crate::lox::compiler::ParserError;
+use crate::lox::parser;
+use crate::lox::scanner::{Token, TokenType};
+
+use std::collections::HashMap;
+
+// How many scopes out each local variable is from where it is used, keyed by
+// the offset of the name, 'this' or 'super' that uses it. Anything that isn't
+// here is a global.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Locals {
+    depths: HashMap<usize, usize>,
+    // The offset of the name each local use or declaration was declared by.
+    // 'this' and 'super' aren't declared by a name, so they have none.
+    declarations: HashMap<usize, usize>,
+}
+
+impl Locals {
+    pub fn depth(&self, offset: usize) -> Option<usize> {
+        self.depths.get(&offset).copied()
+    }
+
+    pub fn declaration(&self, offset: usize) -> Option<usize> {
+        self.declarations.get(&offset).copied()
+    }
+}
+
+// Parses and resolves source, with every error either pass finds. Resolving
+// only starts once the source has parsed.
+pub fn check(source: &str) -> Result<(Vec<Stmt>, Locals), Vec<ParserError>> {
+    let statements = parser::parse(source)?;
+    let locals = resolve(&statements)?;
+    Ok((statements, locals))
+}
+
+// The static checks ch13's resolver makes before running anything, which
+// also work out where each local lives.
+pub fn resolve(statements: &[Stmt]) -> Result<Locals, Vec<ParserError>> {
+    let mut resolver = Resolver {
+        scopes: vec![],
+        function: FunctionType::None,
+        class: ClassType::None,
+        locals: Locals::default(),
+        errors: vec![],
+    };
+    resolver.statements(statements);
+    if resolver.errors.is_empty() {
+        Ok(resolver.locals)
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+// A name in a scope: the offset it was declared at, and whether its
+// initializer has run.
+#[derive(Debug, Clone, Copy)]
+struct Variable {
+    declaration: Option<usize>,
+    defined: bool,
+}
+
+impl Variable {
+    const IMPLICIT: Variable = Variable {
+        declaration: None,
+        defined: true,
+    };
+}
+
+struct Resolver {
+    scopes: Vec<HashMap<String, Variable>>,
+    function: FunctionType,
+    class: ClassType,
+    locals: Locals,
+    errors: Vec<ParserError>,
+}
+
+impl Resolver {
+    fn statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match &statement.kind {
+            StmtKind::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                self.statements(statements);
+                self.scopes.pop();
+            }
+            StmtKind::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            StmtKind::Expression(expression) | StmtKind::Print(expression) => {
+                self.expression(expression)
+            }
+            StmtKind::Function(function) => {
+                self.declare(&function.name);
+                self.define(&function.name);
+                self.function(function, FunctionType::Function);
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            StmtKind::Return(value) => {
+                let keyword = keyword(TokenType::Return, "return", statement.span, statement.line);
+                if self.function == FunctionType::None {
+                    self.error(keyword.clone(), "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    if self.function == FunctionType::Initializer {
+                        self.error(keyword, "Can't return a value from an initializer.");
+                    }
+                    self.expression(value);
+                }
+            }
+            StmtKind::Var { name, initializer } => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.define(name);
+            }
+            StmtKind::While { condition, body } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+        }
+    }
+
+    fn class(&mut self, name: &Name, superclass: Option<&Name>, methods: &[Function]) {
+        let enclosing = self.class;
+        self.class = ClassType::Class;
+        self.declare(name);
+        self.define(name);
+
+        if let Some(superclass) = superclass {
+            if superclass.name == name.name {
+                self.error(identifier(superclass), "A class can't inherit from itself.");
+            }
+            self.class = ClassType::Subclass;
+            self.resolve_local(superclass.span.start, &superclass.name);
+            self.scopes
+                .push(HashMap::from([(String::from("super"), Variable::IMPLICIT)]));
+        }
+
+        self.scopes
+            .push(HashMap::from([(String::from("this"), Variable::IMPLICIT)]));
+        for method in methods {
+            let function_type = if method.name.name == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.function(method, function_type);
+        }
+        self.scopes.pop();
+
+        if superclass.is_some() {
+            self.scopes.pop();
+        }
+        self.class = enclosing;
+    }
+
+    fn function(&mut self, function: &Function, function_type: FunctionType) {
+        let enclosing = self.function;
+        self.function = function_type;
+        self.scopes.push(HashMap::new());
+        for param in &function.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.statements(&function.body);
+        self.scopes.pop();
+        self.function = enclosing;
+    }
+
+    fn expression(&mut self, expression: &Expr) {
+        match &expression.kind {
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                self.resolve_local(name.span.start, &name.name);
+            }
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Call { callee, arguments } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            ExprKind::Get { object, .. } => self.expression(object),
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Literal(_) => {}
+            ExprKind::Set { object, value, .. } => {
+                self.expression(value);
+                self.expression(object);
+            }
+            ExprKind::Super { .. } => {
+                let keyword = keyword(TokenType::Super, "super", expression.span, expression.line);
+                match self.class {
+                    ClassType::None => self.error(keyword, "Can't use 'super' outside of a class."),
+                    ClassType::Class => {
+                        self.error(keyword, "Can't use 'super' in a class with no superclass.")
+                    }
+                    ClassType::Subclass => self.resolve_local(expression.span.start, "super"),
+                }
+            }
+            ExprKind::This => {
+                if self.class == ClassType::None {
+                    let keyword =
+                        keyword(TokenType::This, "this", expression.span, expression.line);
+                    self.error(keyword, "Can't use 'this' outside of a class.");
+                } else {
+                    self.resolve_local(expression.span.start, "this");
+                }
+            }
+            ExprKind::Unary { operand, .. } => self.expression(operand),
+            ExprKind::Variable(name) => {
+                let variable = self.scopes.last().and_then(|scope| scope.get(&name.name));
+                if variable.is_some_and(|variable| !variable.defined) {
+                    self.error(
+                        identifier(name),
+                        "Can't read local variable in its own initializer.",
+                    );
+                }
+                self.resolve_local(name.span.start, &name.name);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Name) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+        self.locals
+            .declarations
+            .insert(name.span.start, name.span.start);
+        let variable = Variable {
+            declaration: Some(name.span.start),
+            defined: false,
+        };
+        if scope.insert(name.name.clone(), variable).is_some() {
+            self.error(
+                identifier(name),
+                "Already a variable with this name in this scope.",
+            );
+        }
+    }
+
+    fn define(&mut self, name: &Name) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(
+                name.name.clone(),
+                Variable {
+                    declaration: Some(name.span.start),
+                    defined: true,
+                },
+            );
+        }
+    }
+
+    fn resolve_local(&mut self, offset: usize, name: &str) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(variable) = scope.get(name) {
+                self.locals.depths.insert(offset, depth);
+                if let Some(declaration) = variable.declaration {
+                    self.locals.declarations.insert(offset, declaration);
+                }
+                return;
+            }
+        }
+    }
+
+    fn error(&mut self, token: Token, message: &str) {
+        self.errors.push(ParserError {
+            previous: (token.start, token.end),
+            token: Some(token),
+            message: String::from(message),
+        });
+    }
+}
+
+// The tree keeps names but not tokens, so errors point at tokens made up from
+// where the name or keyword was.
+fn identifier(name: &Name) -> Token {
+    Token {
+        token_type: TokenType::Identifier(name.name.clone()),
+        line: name.line,
+        start: name.span.start,
+        end: name.span.end,
+    }
+}
+
+fn keyword(token_type: TokenType, lexeme: &str, span: Span, line: usize) -> Token {
+    Token {
+        token_type,
+        line,
+        start: span.start,
+        end: span.start + lexeme.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(source: &str) -> Vec<String> {
+        check(source)
+            .expect_err("check passed")
+            .iter()
+            .map(|error| parser::format_error(source, error))
+            .collect()
+    }
+
+    fn depth_at(source: &str, needle: &str, nth: usize) -> Option<usize> {
+        let (_, locals) = check(source).expect("check failed");
+        let offset = source
+            .match_indices(needle)
+            .nth(nth)
+            .expect("no such needle")
+            .0;
+        locals.depth(offset)
+    }
+
+    #[test]
+    fn it_reports_every_violation() {
+        let source = "return 1;
+{
+  var a = a;
+  var b; var b;
+}
+print this;
+class A { f() { super.f(); } }
+class B < B {}
+class C { init() { return 1; } }
+super.x;";
+        assert_eq!(
+            vec![
+                "[line 1] Error at 'return': Can't return from top-level code.",
+                "[line 3] Error at 'a': Can't read local variable in its own initializer.",
+                "[line 4] Error at 'b': Already a variable with this name in this scope.",
+                "[line 6] Error at 'this': Can't use 'this' outside of a class.",
+                "[line 7] Error at 'super': Can't use 'super' in a class with no superclass.",
+                "[line 8] Error at 'B': A class can't inherit from itself.",
+                "[line 9] Error at 'return': Can't return a value from an initializer.",
+                "[line 10] Error at 'super': Can't use 'super' outside of a class.",
+            ],
+            errors(source)
+        );
+    }
+
+    #[test]
+    fn it_reports_parse_errors_first() {
+        assert_eq!(
+            vec!["[line 2] Error at end: Expect ';' after value."],
+            errors("return;\nprint 1")
+        );
+    }
+
+    #[test]
+    fn it_allows_what_is_legal() {
+        for source in [
+            "var a = 1; var a = a;",
+            "fun f() { return; } class C { init() { return; } }",
+            "class A { f() { return this; } } class B < A { f() { return super.f(); } }",
+            "fun f(a) { var b = a; }",
+        ] {
+            assert!(check(source).is_ok(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn it_computes_scope_depths() {
+        let source = "var G; { var A; { var B; print A; print B; print G; A = 1; } }";
+        assert_eq!(Some(1), depth_at(source, "A", 1));
+        assert_eq!(Some(0), depth_at(source, "B", 1));
+        assert_eq!(None, depth_at(source, "G", 1));
+        assert_eq!(Some(1), depth_at(source, "A", 2));
+    }
+
+    #[test]
+    fn it_records_where_locals_were_declared() {
+        let source = "var G; { var A; { var A; print A; print G; } print A; }";
+        let (_, locals) = check(source).expect("check failed");
+        let at = |nth| source.match_indices('A').nth(nth).expect("no such A").0;
+        assert_eq!(Some(at(1)), locals.declaration(at(2)));
+        assert_eq!(Some(at(0)), locals.declaration(at(3)));
+        assert_eq!(Some(at(0)), locals.declaration(at(0)));
+        assert_eq!(None, locals.declaration(source.find("G;").unwrap()));
+    }
+
+    #[test]
+    fn it_resolves_this_super_and_closures() {
+        let source = "class A < B { f(X) { fun g() { return X + this.y + super.h(); } } }";
+        assert_eq!(Some(1), depth_at(source, "X", 1));
+        // Through g's scope, f's scope, to the class's.
+        assert_eq!(Some(2), depth_at(source, "this", 0));
+        assert_eq!(Some(3), depth_at(source, "super", 0));
+        // The superclass itself is a global.
+        assert_eq!(None, depth_at(source, "B", 0));
+    }
+}
@@ -3,6 +3,7 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    trivia: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -50,6 +51,10 @@ pub enum TokenType {
     Var,
     While,
 
+    // Trivia, which is only scanned for tools that keep it.
+    Comment(String),
+    Whitespace(String),
+
     Error(String),
     EOF,
 }
@@ -58,14 +63,17 @@ pub enum TokenType {
 pub struct Token {
     pub token_type: TokenType,
     pub line: usize,
+    // The byte range of the lexeme in the source.
+    pub start: usize,
+    pub end: usize,
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == '_'
 }
 impl Iterator for Scanner {
     type Item = Token;
@@ -87,18 +95,32 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            trivia: false,
+        }
+    }
+
+    // A scanner that returns comments and runs of whitespace as tokens
+    // instead of skipping them, so that the tokens cover the whole source.
+    pub fn with_trivia(source: &str) -> Scanner {
+        Scanner {
+            trivia: true,
+            ..Scanner::new(source)
         }
     }
 
     pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+        if self.trivia {
+            if let Some(token) = self.trivia() {
+                return token;
+            }
+        } else {
+            self.skip_whitespace();
+        }
         self.start = self.current;
 
-        if self.is_at_end() {
+        let Some(c) = self.advance() else {
             return self.make_token(TokenType::EOF);
-        }
-
-        let c = self.advance();
+        };
 
         match c {
             c if is_alpha(c) => self.identifier(),
@@ -115,30 +137,44 @@ impl Scanner {
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
             '!' if self.match_token('=') => self.make_token(TokenType::BangEqual),
-            '!' => return self.make_token(TokenType::Bang),
+            '!' => self.make_token(TokenType::Bang),
             '=' if self.match_token('=') => self.make_token(TokenType::EqualEqual),
-            '=' => return self.make_token(TokenType::Equal),
+            '=' => self.make_token(TokenType::Equal),
             '<' if self.match_token('=') => self.make_token(TokenType::LessEqual),
-            '<' => return self.make_token(TokenType::Less),
+            '<' => self.make_token(TokenType::Less),
             '>' if self.match_token('=') => self.make_token(TokenType::GreaterEqual),
             '>' => self.make_token(TokenType::Greater),
             '"' => self.string(),
-            _ => self.error_token("Unexpected character."),
+            _ => {
+                // The rest of the character, so that tokens start and end
+                // where characters do.
+                while !self.source.is_char_boundary(self.current) {
+                    self.current += 1;
+                }
+                self.error_token("Unexpected character.")
+            }
         }
     }
 
+    // Positions are byte offsets so that lookups are constant time and line
+    // up with the slices taken for lexemes. Only ASCII is meaningful outside
+    // of string literals.
+    fn char_at(&self, index: usize) -> Option<char> {
+        self.source.as_bytes().get(index).map(|b| *b as char)
+    }
+
     fn is_at_end(&self) -> bool {
         self.current == self.source.len()
     }
 
-    fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
+    fn advance(&mut self) -> Option<char> {
+        let c = self.char_at(self.current)?;
         self.current += 1;
-        c
+        Some(c)
     }
 
     fn peek(&self) -> Option<char> {
-        self.source.chars().nth(self.current)
+        self.char_at(self.current)
     }
 
     fn peek_next(&self) -> Option<char> {
@@ -146,17 +182,14 @@ impl Scanner {
             return None;
         }
 
-        self.source.chars().nth(self.current + 1)
+        self.char_at(self.current + 1)
     }
     fn match_token(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.char_at(self.current) != Some(expected) {
             return false;
         }
 
-        self.current = self.current + 1;
+        self.current += 1;
 
         true
     }
@@ -165,6 +198,8 @@ impl Scanner {
         Token {
             token_type,
             line: self.line,
+            start: self.start,
+            end: self.current,
         }
     }
 
@@ -179,11 +214,10 @@ impl Scanner {
     }
 
     fn make_number(&self) -> Token {
-        let n = self.source[self.start..self.current]
-            .parse::<f64>()
-            .unwrap();
-
-        self.make_token(TokenType::Number(n))
+        match self.source[self.start..self.current].parse::<f64>() {
+            Ok(n) => self.make_token(TokenType::Number(n)),
+            Err(_) => self.error_token("Invalid number."),
+        }
     }
 
     fn error_token(&self, message: &str) -> Token {
@@ -200,13 +234,9 @@ impl Scanner {
                     self.line += 1;
                     self.advance();
                 }
-                Some('/') => {
-                    if self.peek_next() == Some('/') {
-                        while self.peek() != Some('\n') && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                Some('/') if self.peek_next() == Some('/') => {
+                    while self.peek() != Some('\n') && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => {
@@ -216,10 +246,38 @@ impl Scanner {
         }
     }
 
+    fn trivia(&mut self) -> Option<Token> {
+        self.start = self.current;
+        let line = self.line;
+        match self.peek()? {
+            ' ' | '\r' | '\t' | '\n' => {
+                while let Some(c @ (' ' | '\r' | '\t' | '\n')) = self.peek() {
+                    if c == '\n' {
+                        self.line += 1;
+                    }
+                    self.advance();
+                }
+                let text = String::from(&self.source[self.start..self.current]);
+                Some(Token {
+                    line,
+                    ..self.make_token(TokenType::Whitespace(text))
+                })
+            }
+            '/' if self.peek_next() == Some('/') => {
+                while self.peek() != Some('\n') && !self.is_at_end() {
+                    self.advance();
+                }
+                let text = String::from(&self.source[self.start..self.current]);
+                Some(self.make_token(TokenType::Comment(text)))
+            }
+            _ => None,
+        }
+    }
+
     fn string(&mut self) -> Token {
         while self.peek() != Some('"') && !self.is_at_end() {
             if self.peek() == Some('\n') {
-                self.line = self.line + 1;
+                self.line += 1;
             }
             self.advance();
         }
@@ -233,14 +291,14 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().map_or(false, is_digit) {
+        while self.peek().is_some_and(is_digit) {
             self.advance();
         }
 
-        if self.peek() == Some('.') && self.peek_next().map_or(false, is_digit) {
+        if self.peek() == Some('.') && self.peek_next().is_some_and(is_digit) {
             self.advance();
 
-            while self.peek().map_or(false, is_digit) {
+            while self.peek().is_some_and(is_digit) {
                 self.advance();
             }
         }
@@ -255,9 +313,9 @@ impl Scanner {
         rest: &str,
         token_type: TokenType,
     ) -> TokenType {
-        let substr = &self.source[self.start + start..self.start + start + length];
-
-        if substr == rest {
+        if self.current - self.start == start + length
+            && &self.source[self.start + start..self.current] == rest
+        {
             token_type
         } else {
             self.make_identifier_type()
@@ -265,38 +323,34 @@ impl Scanner {
     }
 
     fn identifier_type(&self) -> TokenType {
-        match self.source.chars().nth(self.start).unwrap() {
-            'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'f' if self.current - self.start > 1 => {
-                match self.source.chars().nth(self.start + 1).unwrap() {
-                    'a' => self.check_keyword(2, 3, "lse", TokenType::False),
-                    'o' => self.check_keyword(2, 1, "r", TokenType::For),
-                    'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
-                    _ => self.make_identifier_type(),
-                }
-            }
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
-            'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
-            'o' => self.check_keyword(1, 1, "r", TokenType::Or),
-            'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
-            't' if self.current - self.start > 1 => {
-                match self.source.chars().nth(self.start + 1).unwrap() {
-                    'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                    'r' => self.check_keyword(2, 2, "ue", TokenType::True),
-                    _ => self.make_identifier_type(),
-                }
-            }
-            'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+        match self.char_at(self.start) {
+            Some('a') => self.check_keyword(1, 2, "nd", TokenType::And),
+            Some('c') => self.check_keyword(1, 4, "lass", TokenType::Class),
+            Some('e') => self.check_keyword(1, 3, "lse", TokenType::Else),
+            Some('f') if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                Some('a') => self.check_keyword(2, 3, "lse", TokenType::False),
+                Some('o') => self.check_keyword(2, 1, "r", TokenType::For),
+                Some('u') => self.check_keyword(2, 1, "n", TokenType::Fun),
+                _ => self.make_identifier_type(),
+            },
+            Some('i') => self.check_keyword(1, 1, "f", TokenType::If),
+            Some('n') => self.check_keyword(1, 2, "il", TokenType::Nil),
+            Some('o') => self.check_keyword(1, 1, "r", TokenType::Or),
+            Some('p') => self.check_keyword(1, 4, "rint", TokenType::Print),
+            Some('r') => self.check_keyword(1, 5, "eturn", TokenType::Return),
+            Some('s') => self.check_keyword(1, 4, "uper", TokenType::Super),
+            Some('t') if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                Some('h') => self.check_keyword(2, 2, "is", TokenType::This),
+                Some('r') => self.check_keyword(2, 2, "ue", TokenType::True),
+                _ => self.make_identifier_type(),
+            },
+            Some('v') => self.check_keyword(1, 2, "ar", TokenType::Var),
+            Some('w') => self.check_keyword(1, 4, "hile", TokenType::While),
             _ => self.make_identifier_type(),
         }
     }
     fn identifier(&mut self) -> Token {
-        while self.peek().map_or(false, is_alpha) || self.peek().map_or(false, is_digit) {
+        while self.peek().is_some_and(is_alpha) || self.peek().is_some_and(is_digit) {
             self.advance();
         }
 
@@ -390,6 +444,17 @@ mod tests {
         assert_next_token(&mut scanner, TokenType::EOF);
     }
 
+    #[test]
+    fn it_can_scan_strings_with_non_ascii_characters() {
+        let mut scanner = Scanner::new("\"héllo\" x");
+
+        let token = scanner.scan_token();
+
+        assert_eq!(token.token_type, TokenType::String(String::from("héllo")));
+        assert_next_token(&mut scanner, TokenType::Identifier(String::from("x")));
+        assert_next_token(&mut scanner, TokenType::EOF);
+    }
+
     #[test]
     fn it_can_scan_ints() {
         let mut scanner = Scanner::new("1234");
@@ -440,6 +505,80 @@ mod tests {
         assert_token("while", TokenType::While);
     }
 
+    #[test]
+    fn it_can_scan_identifiers_that_start_like_keywords() {
+        for name in [
+            "andy", "an", "s", "su", "superb", "f", "t", "printer", "variable",
+        ] {
+            assert_token(name, TokenType::Identifier(String::from(name)));
+        }
+    }
+
+    #[test]
+    fn it_reports_a_whole_unexpected_character() {
+        let source = "é€;";
+        let tokens: Vec<(TokenType, &str)> = Scanner::new(source)
+            .map(|token| (token.token_type, &source[token.start..token.end]))
+            .collect();
+        let unexpected = TokenType::Error(String::from("Unexpected character."));
+        assert_eq!(
+            vec![
+                (unexpected.clone(), "é"),
+                (unexpected, "€"),
+                (TokenType::Semicolon, ";"),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn it_records_spans() {
+        let source = "var s = \"a\nb\";  x";
+        let spans: Vec<(usize, &str)> = Scanner::new(source)
+            .map(|token| (token.line, &source[token.start..token.end]))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, "var"),
+                (1, "s"),
+                (1, "="),
+                (2, "\"a\nb\""),
+                (2, ";"),
+                (2, "x")
+            ],
+            spans
+        );
+
+        let mut scanner = Scanner::new("1 ");
+        scanner.scan_token();
+        let eof = scanner.scan_token();
+        assert_eq!((2, 2), (eof.start, eof.end));
+    }
+
+    #[test]
+    fn it_can_scan_trivia() {
+        let source = "print 1; // one\n\n  print 2;";
+        let tokens: Vec<(usize, TokenType)> = Scanner::with_trivia(source)
+            .map(|token| (token.line, token.token_type))
+            .collect();
+        assert_eq!(
+            vec![
+                (1, TokenType::Print),
+                (1, TokenType::Whitespace(String::from(" "))),
+                (1, TokenType::Number(1.0)),
+                (1, TokenType::Semicolon),
+                (1, TokenType::Whitespace(String::from(" "))),
+                (1, TokenType::Comment(String::from("// one"))),
+                (1, TokenType::Whitespace(String::from("\n\n  "))),
+                (3, TokenType::Print),
+                (3, TokenType::Whitespace(String::from(" "))),
+                (3, TokenType::Number(2.0)),
+                (3, TokenType::Semicolon),
+            ],
+            tokens
+        );
+    }
+
     #[test]
     fn it_can_scan_expression() {
         let mut scanner = Scanner::new("print 1 + 2;");
@@ -0,0 +1,313 @@
+use crate::lox::chunk::{Chunk, OpCode};
+use crate::lox::json::quote;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Which steps of a run are written to the trace. Steps have to be on a line
+// in range and one of the opcodes, if those are given, and then only every
+// sample-th of the steps that match is written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFilter {
+    pub lines: Option<(usize, usize)>,
+    pub opcodes: Vec<String>,
+    pub sample: usize,
+}
+
+impl Default for TraceFilter {
+    fn default() -> Self {
+        TraceFilter {
+            lines: None,
+            opcodes: vec![],
+            sample: 1,
+        }
+    }
+}
+
+impl TraceFilter {
+    // Lines are "n" or "first-last".
+    pub fn with_lines(mut self, lines: &str) -> Option<TraceFilter> {
+        let (first, last) = lines.split_once('-').unwrap_or((lines, lines));
+        let range = (first.parse().ok()?, last.parse().ok()?);
+        if range.0 > range.1 {
+            return None;
+        }
+        self.lines = Some(range);
+        Some(self)
+    }
+
+    // Opcodes are a comma separated list of names, with or without OP_.
+    pub fn with_opcodes(mut self, opcodes: &str) -> Option<TraceFilter> {
+        for name in opcodes.split(',') {
+            let name = name.trim().to_uppercase();
+            let name = match name.starts_with("OP_") {
+                true => name,
+                false => format!("OP_{}", name),
+            };
+            OpCode::named(&name)?;
+            self.opcodes.push(name);
+        }
+        Some(self)
+    }
+
+    pub fn with_sample(mut self, sample: &str) -> Option<TraceFilter> {
+        self.sample = sample.parse().ok().filter(|n| *n > 0)?;
+        Some(self)
+    }
+
+    fn matches(&self, code: OpCode, line: usize) -> bool {
+        self.lines
+            .is_none_or(|(first, last)| first <= line && line <= last)
+            && (self.opcodes.is_empty() || self.opcodes.iter().any(|name| name == code.name()))
+    }
+}
+
+// Writes a JSON object per executed instruction. The VM calls begin before
+// each instruction and end once it knows the stack the instruction left.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    filter: TraceFilter,
+    lines: Vec<usize>,
+    step: usize,
+    matched: usize,
+    pending: Option<String>,
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("filter", &self.filter)
+            .field("step", &self.step)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, filter: TraceFilter) -> Tracer {
+        Tracer {
+            out,
+            filter,
+            lines: vec![],
+            step: 0,
+            matched: 0,
+            pending: None,
+            error: None,
+        }
+    }
+
+    pub fn to_file(path: &str, filter: TraceFilter) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), filter))
+    }
+
+    // Looking lines up in the chunk for every step would be quadratic, so
+    // each run spreads the line table out by byte first.
+    pub fn start(&mut self, chunk: &Chunk) {
+        self.lines = chunk.lines_by_offset();
+    }
+
+    // Whether the instruction at ip is written. Every call is a step.
+    pub fn select(&mut self, code: OpCode, ip: usize) -> bool {
+        self.step += 1;
+        if !self.filter.matches(code, self.line(ip)) {
+            return false;
+        }
+        self.matched += 1;
+        (self.matched - 1).is_multiple_of(self.filter.sample)
+    }
+
+    // Whether a record has begun and is waiting for its end.
+    pub fn is_open(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn begin(&mut self, code: OpCode, ip: usize, constant: Option<String>, stack: &[String]) {
+        let operands = match code {
+            OpCode::Constant(c)
+            | OpCode::GetGlobal(c)
+            | OpCode::DefineGlobal(c)
+            | OpCode::SetGlobal(c) => format!("[{}]", c),
+            OpCode::SmallInt(n) => format!("[{}]", n),
+            _ => String::from("[]"),
+        };
+        let mut record = format!(
+            "{{\"step\":{},\"ip\":{},\"line\":{},\"opcode\":\"{}\",\"operands\":{}",
+            self.step - 1,
+            ip,
+            self.line(ip),
+            code.name(),
+            operands
+        );
+        if let Some(constant) = constant {
+            record.push_str(&format!(",\"constant\":{}", quote(&constant)));
+        }
+        record.push_str(&format!(",\"stack_before\":{}", json_list(stack)));
+        self.pending = Some(record);
+    }
+
+    pub fn end(&mut self, stack: &[String], error: Option<&str>) {
+        let Some(mut record) = self.pending.take() else {
+            return;
+        };
+        record.push_str(&format!(",\"stack_after\":{}", json_list(stack)));
+        if let Some(error) = error {
+            record.push_str(&format!(",\"error\":{}", quote(error)));
+        }
+        record.push('}');
+        if self.error.is_none() {
+            self.error = writeln!(self.out, "{}", record).err();
+        }
+    }
+
+    // Reports the first write error of the run, if any.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.out.flush()
+    }
+
+    fn line(&self, ip: usize) -> usize {
+        self.lines.get(ip).copied().unwrap_or(0)
+    }
+}
+
+fn json_list(items: &[String]) -> String {
+    let quoted: Vec<String> = items.iter().map(|item| quote(item)).collect();
+    format!("[{}]", quoted.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::test_support::Shared;
+    use crate::lox::vm::{InterpretError, VM};
+
+    fn trace(source: &str, filter: TraceFilter) -> (Result<String, InterpretError>, Vec<String>) {
+        let out = Shared::default();
+        let mut vm = VM::new();
+        vm.set_tracer(Some(Tracer::new(Box::new(out.clone()), filter)));
+        let result = vm.interpret(source);
+        let text = String::from_utf8(out.take()).expect("trace is not UTF-8");
+        (result, text.lines().map(String::from).collect())
+    }
+
+    #[test]
+    fn it_does_not_change_output() {
+        let source = "var a = \"x\"; a = a + \"y\";\nprint a; print 1 + 2;";
+        let (result, records) = trace(source, TraceFilter::default());
+        assert_eq!(VM::new().interpret(source), result);
+        assert_eq!(12, records.len());
+    }
+
+    #[test]
+    fn it_writes_a_record_per_instruction() {
+        let (_, records) = trace("var a = \"x\";\nprint a + \"y\";", TraceFilter::default());
+        assert_eq!(
+            vec![
+                r#"{"step":0,"ip":0,"line":1,"opcode":"OP_CONSTANT","operands":[0],"constant":"\"x\"","stack_before":[],"stack_after":["\"x\""]}"#,
+                r#"{"step":1,"ip":2,"line":1,"opcode":"OP_DEFINE_GLOBAL","operands":[1],"constant":"\"a\"","stack_before":["\"x\""],"stack_after":[]}"#,
+                r#"{"step":2,"ip":4,"line":2,"opcode":"OP_GET_GLOBAL","operands":[1],"constant":"\"a\"","stack_before":[],"stack_after":["\"x\""]}"#,
+                r#"{"step":3,"ip":6,"line":2,"opcode":"OP_CONSTANT","operands":[2],"constant":"\"y\"","stack_before":["\"x\""],"stack_after":["\"x\"","\"y\""]}"#,
+                r#"{"step":4,"ip":8,"line":2,"opcode":"OP_ADD","operands":[],"stack_before":["\"x\"","\"y\""],"stack_after":["\"xy\""]}"#,
+                r#"{"step":5,"ip":9,"line":2,"opcode":"OP_PRINT","operands":[],"stack_before":["\"xy\""],"stack_after":[]}"#,
+                r#"{"step":6,"ip":10,"line":2,"opcode":"OP_RETURN","operands":[],"stack_before":[],"stack_after":[]}"#,
+            ],
+            records
+        );
+    }
+
+    #[test]
+    fn it_filters_lines_and_opcodes() {
+        let source = "var a = 1;\nprint a + 2;\nprint a + 3;\nprint a;";
+        let lines = TraceFilter::default().with_lines("2-3").unwrap();
+        let (_, records) = trace(source, lines);
+        assert_eq!(8, records.len());
+        assert!(records
+            .iter()
+            .all(|r| r.contains(r#""line":2"#) || r.contains(r#""line":3"#)));
+
+        let opcodes = TraceFilter::default().with_opcodes("add").unwrap();
+        let (_, records) = trace(source, opcodes);
+        assert_eq!(2, records.len());
+        assert!(records[0].starts_with(r#"{"step":4,"ip":8,"line":2,"opcode":"OP_ADD""#));
+        assert!(records[1].starts_with(r#"{"step":8,"ip":14,"line":3,"opcode":"OP_ADD""#));
+    }
+
+    #[test]
+    fn it_samples_matching_steps() {
+        let source: String = (0..10).map(|n| format!("print {};\n", n)).collect();
+        let filter = TraceFilter::default()
+            .with_opcodes("OP_PRINT")
+            .and_then(|f| f.with_sample("3"))
+            .unwrap();
+        let (_, records) = trace(&source, filter);
+        let lines: Vec<bool> = [1, 4, 7, 10]
+            .iter()
+            .zip(records.iter())
+            .map(|(line, record)| record.contains(&format!(r#""line":{},"#, line)))
+            .collect();
+        assert_eq!(vec![true; 4], lines);
+        assert_eq!(4, records.len());
+    }
+
+    #[test]
+    fn it_records_the_error_that_stops_a_run() {
+        let (result, records) = trace("print 1;\nprint -\"a\";", TraceFilter::default());
+        assert!(result.is_err());
+        assert_eq!(
+            Some(&String::from(
+                r#"{"step":3,"ip":5,"line":2,"opcode":"OP_NEGATE","operands":[],"stack_before":["\"a\""],"stack_after":[],"error":"Operand must be number.\n[line 2]"}"#
+            )),
+            records.last()
+        );
+    }
+
+    #[test]
+    fn it_parses_filters() {
+        let filter = TraceFilter::default()
+            .with_lines("3-7")
+            .and_then(|f| f.with_opcodes("add,OP_print"))
+            .and_then(|f| f.with_sample("10"));
+        assert_eq!(
+            Some(TraceFilter {
+                lines: Some((3, 7)),
+                opcodes: vec![String::from("OP_ADD"), String::from("OP_PRINT")],
+                sample: 10,
+            }),
+            filter
+        );
+
+        assert_eq!(
+            Some((4, 4)),
+            TraceFilter::default().with_lines("4").unwrap().lines
+        );
+        assert_eq!(None, TraceFilter::default().with_lines("7-3"));
+        assert_eq!(None, TraceFilter::default().with_lines("x"));
+        assert_eq!(None, TraceFilter::default().with_opcodes("OP_JUMP"));
+        assert_eq!(None, TraceFilter::default().with_sample("0"));
+    }
+
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_leaves_write_errors_on_the_vm() {
+        let mut vm = VM::new();
+        vm.set_tracer(Some(Tracer::new(Box::new(Full), TraceFilter::default())));
+        assert_eq!(Ok(String::from("1\n")), vm.interpret("print 1;"));
+        let error = vm.take_trace_error().map(|e| e.to_string());
+        assert_eq!(Some(String::from("disk full")), error);
+        assert!(vm.take_trace_error().is_none());
+    }
+}
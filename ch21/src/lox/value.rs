@@ -2,59 +2,70 @@ use crate::lox::interner::Symbol;
 use std::convert::From;
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Value {
+// The runtime errors for operands of the wrong type, which the VM and the
+// tree-walking evaluator both report.
+pub const NUMBER_OPERAND: &str = "Operand must be number.";
+pub const NUMBER_OPERANDS: &str = "Operands must be numbers.";
+pub const STRING_OPERANDS: &str = "Operands must be strings.";
+pub const ADD_OPERANDS: &str = "Operands must be numbers or strings.";
+
+// What a value is, independent of how it is stored. Without the nan-boxing
+// feature this is the representation itself; with it, values are packed into
+// a u64 and unpacked into this enum wherever code needs to match on them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unpacked {
     Bool(bool),
     Nil,
     Number(f64),
     String(Symbol),
 }
 
+#[cfg(not(feature = "nan-boxing"))]
+pub type Value = Unpacked;
+
+#[cfg(feature = "nan-boxing")]
+pub use self::nan_boxing::Value;
+
+#[cfg(not(feature = "nan-boxing"))]
 impl Value {
     pub fn is_number(&self) -> bool {
-        match &self {
-            Value::Number(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Number(_))
     }
 
     pub fn as_number(&self) -> Result<f64, String> {
         match &self {
-            Value::Number(n) => Ok(n.clone()),
-            _ => Err(String::from("Operands must be numbers.")),
+            Value::Number(n) => Ok(*n),
+            _ => Err(String::from(NUMBER_OPERANDS)),
         }
     }
 
     pub fn is_string(&self) -> bool {
-        match &self {
-            Value::String(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::String(_))
     }
 
-    pub fn as_string(&self) -> Result<&Symbol, String> {
+    pub fn as_string(&self) -> Result<Symbol, String> {
         match &self {
-            Value::String(s) => Ok(s),
-            _ => Err(String::from("Operands must be strings.")),
+            Value::String(s) => Ok(*s),
+            _ => Err(String::from(STRING_OPERANDS)),
         }
     }
 
     pub fn is_falsey(&self) -> bool {
-        match *self {
-            Value::Nil => true,
-            Value::Bool(false) => true,
-            _ => false,
-        }
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn unpack(&self) -> Unpacked {
+        *self
     }
 }
 
-impl fmt::Display for Value {
+impl fmt::Display for Unpacked {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
-            Value::Bool(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Nil => write!(f, "nil"),
-            Value::String(s) => write!(f, "{}", s),
+            Unpacked::Bool(b) => write!(f, "{}", b),
+            Unpacked::Number(n) => write!(f, "{}", n),
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::String(s) => write!(f, "{}", s),
         }
     }
 }
@@ -70,3 +81,218 @@ impl From<bool> for Value {
         Self::Bool(b)
     }
 }
+
+// Numbers are stored as their own bits. Everything else lives in the payload
+// of a quiet NaN that no arithmetic produces: nil, false and true are small
+// tags, and strings set the sign bit and keep their symbol in the low 32 bits.
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing {
+    use super::Unpacked;
+    use crate::lox::interner::Symbol;
+    use std::fmt;
+
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+    const STRING: u64 = SIGN_BIT | QNAN;
+
+    const NIL: u64 = QNAN | 1;
+    const FALSE: u64 = QNAN | 2;
+    const TRUE: u64 = QNAN | 3;
+
+    #[derive(Clone, Copy)]
+    pub struct Value(u64);
+
+    // Named like the enum variants so constructing a value reads the same
+    // under either representation.
+    #[allow(non_snake_case, non_upper_case_globals)]
+    impl Value {
+        pub const Nil: Value = Value(NIL);
+
+        pub fn Bool(b: bool) -> Value {
+            Value(if b { TRUE } else { FALSE })
+        }
+
+        // Any NaN is stored as the canonical one so that a payload can never
+        // be mistaken for a tagged value.
+        pub fn Number(n: f64) -> Value {
+            if n.is_nan() {
+                Value(f64::NAN.to_bits())
+            } else {
+                Value(n.to_bits())
+            }
+        }
+
+        pub fn String(s: Symbol) -> Value {
+            Value(STRING | s as u64)
+        }
+    }
+
+    impl Value {
+        pub fn is_number(&self) -> bool {
+            self.0 & QNAN != QNAN
+        }
+
+        pub fn as_number(&self) -> Result<f64, String> {
+            if self.is_number() {
+                Ok(f64::from_bits(self.0))
+            } else {
+                Err(String::from(NUMBER_OPERANDS))
+            }
+        }
+
+        pub fn is_string(&self) -> bool {
+            self.0 & STRING == STRING
+        }
+
+        pub fn as_string(&self) -> Result<Symbol, String> {
+            if self.is_string() {
+                Ok(self.0 as Symbol)
+            } else {
+                Err(String::from(STRING_OPERANDS))
+            }
+        }
+
+        pub fn is_falsey(&self) -> bool {
+            self.0 == NIL || self.0 == FALSE
+        }
+
+        pub fn unpack(&self) -> Unpacked {
+            match self.0 {
+                NIL => Unpacked::Nil,
+                FALSE => Unpacked::Bool(false),
+                TRUE => Unpacked::Bool(true),
+                _ if self.is_number() => Unpacked::Number(f64::from_bits(self.0)),
+                _ => Unpacked::String(self.0 as Symbol),
+            }
+        }
+    }
+
+    // Numbers compare as numbers, so NaN is not equal to itself and 0 equals
+    // -0. Every other value is equal only to the same bits.
+    impl PartialEq for Value {
+        fn eq(&self, other: &Self) -> bool {
+            if self.is_number() && other.is_number() {
+                f64::from_bits(self.0) == f64::from_bits(other.0)
+            } else {
+                self.0 == other.0
+            }
+        }
+    }
+
+    impl From<Unpacked> for Value {
+        fn from(value: Unpacked) -> Self {
+            match value {
+                Unpacked::Bool(b) => Value::Bool(b),
+                Unpacked::Nil => Value::Nil,
+                Unpacked::Number(n) => Value::Number(n),
+                Unpacked::String(s) => Value::String(s),
+            }
+        }
+    }
+
+    impl fmt::Debug for Value {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&self.unpack(), f)
+        }
+    }
+
+    impl fmt::Display for Value {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Display::fmt(&self.unpack(), f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<Value> {
+        vec![
+            Value::Nil,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Number(0.0),
+            Value::Number(-0.0),
+            Value::Number(1.5),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::NAN),
+            Value::String(0),
+            Value::String(Symbol::MAX),
+        ]
+    }
+
+    #[test]
+    fn it_unpacks_what_it_packs() {
+        let unpacked: Vec<String> = values().iter().map(|v| format!("{:?}", v)).collect();
+        assert_eq!(
+            vec![
+                "Nil",
+                "Bool(true)",
+                "Bool(false)",
+                "Number(0.0)",
+                "Number(-0.0)",
+                "Number(1.5)",
+                "Number(inf)",
+                "Number(NaN)",
+                "String(0)",
+                "String(4294967295)",
+            ],
+            unpacked
+        );
+    }
+
+    #[test]
+    fn it_knows_what_kind_of_value_it_is() {
+        let numbers: Vec<bool> = values().iter().map(|v| v.is_number()).collect();
+        let strings: Vec<bool> = values().iter().map(|v| v.is_string()).collect();
+        let falsey: Vec<bool> = values().iter().map(|v| v.is_falsey()).collect();
+        let (f, t) = (false, true);
+        assert_eq!(vec![f, f, f, t, t, t, t, t, f, f], numbers);
+        assert_eq!(vec![f, f, f, f, f, f, f, f, t, t], strings);
+        assert_eq!(vec![t, f, t, f, f, f, f, f, f, f], falsey);
+
+        assert_eq!(Ok(1.5), Value::Number(1.5).as_number());
+        assert_eq!(Ok(Symbol::MAX), Value::String(Symbol::MAX).as_string());
+        assert!(Value::Nil.as_number().is_err());
+        assert!(Value::Number(0.0).as_string().is_err());
+    }
+
+    #[test]
+    fn it_compares_numbers_as_numbers() {
+        assert_eq!(Value::Number(0.0), Value::Number(-0.0));
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+        assert_ne!(Value::Number(0.0), Value::Bool(false));
+        assert_ne!(Value::Nil, Value::Bool(false));
+        assert_ne!(Value::String(1), Value::Number(1.0));
+    }
+
+    #[test]
+    fn it_keeps_nan_a_number() {
+        let payload = f64::from_bits(0x7fff_ffff_ffff_ffff);
+        assert!(payload.is_nan());
+        assert!(Value::Number(payload).is_number());
+        assert!(Value::Number(-f64::NAN).is_number());
+        assert!(Value::Number(f64::NAN * 2.0).as_number().unwrap().is_nan());
+    }
+
+    #[test]
+    fn it_displays_values() {
+        let displayed: Vec<String> = values().iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            vec![
+                "nil",
+                "true",
+                "false",
+                "0",
+                "-0",
+                "1.5",
+                "inf",
+                "NaN",
+                "0",
+                "4294967295"
+            ],
+            displayed
+        );
+    }
+}
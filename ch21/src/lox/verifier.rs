@@ -0,0 +1,241 @@
+use crate::lox::chunk::{Chunk, OpCode, OP_RETURN};
+use crate::lox::vm::STACK_MAX;
+use std::fmt;
+
+// Where a chunk is malformed. The index counts instructions the way
+// Chunk::disassemble numbers them.
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    pub index: usize,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}] Invalid bytecode at {:04}: {}",
+            self.line, self.index, self.message
+        )
+    }
+}
+
+// Checks that the VM can run a chunk without reading past its code or
+// constants, popping an empty stack or pushing past STACK_MAX. There are no
+// jumps, so the only path is straight through the code and it has to end with
+// the chunk's one Return.
+pub fn verify(chunk: &Chunk) -> Result<(), VerifyError> {
+    let mut offset = 0;
+    let mut index = 0;
+    let mut depth = 0;
+    while offset < chunk.code.len() {
+        let error = |message: String| VerifyError {
+            index,
+            line: chunk.line(offset),
+            message,
+        };
+
+        let Some((code, next)) = chunk.decode(offset) else {
+            return Err(error(match chunk.code[offset] {
+                byte if byte > OP_RETURN => format!("Unknown opcode {}.", byte),
+                _ => String::from("Instruction is cut off by the end of the code."),
+            }));
+        };
+
+        if let Some(constant) = constant_operand(code) {
+            match chunk.constants().get(constant) {
+                None => {
+                    return Err(error(format!(
+                        "Constant {} is out of range ({} constants).",
+                        constant,
+                        chunk.constants().len()
+                    )))
+                }
+                Some(value) if !matches!(code, OpCode::Constant(_)) && !value.is_string() => {
+                    return Err(error(format!(
+                        "Global name {} is not a string constant.",
+                        constant
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        let (pops, pushes) = stack_effect(code);
+        if depth < pops {
+            return Err(error(format!(
+                "Pops {} value(s) but the stack holds {}.",
+                pops, depth
+            )));
+        }
+        depth = depth - pops + pushes;
+        if depth > STACK_MAX {
+            return Err(error(format!(
+                "Stack depth {} is more than {}.",
+                depth, STACK_MAX
+            )));
+        }
+
+        if code == OpCode::Return {
+            if next != chunk.code.len() {
+                return Err(error(String::from("Code follows the return.")));
+            }
+            return Ok(());
+        }
+
+        offset = next;
+        index += 1;
+    }
+
+    Err(VerifyError {
+        index,
+        line: chunk.line(offset),
+        message: String::from("Code does not end with a return."),
+    })
+}
+
+fn constant_operand(code: OpCode) -> Option<usize> {
+    match code {
+        OpCode::Constant(c)
+        | OpCode::GetGlobal(c)
+        | OpCode::DefineGlobal(c)
+        | OpCode::SetGlobal(c) => Some(c),
+        _ => None,
+    }
+}
+
+// How many values an instruction pops, and then how many it pushes.
+fn stack_effect(code: OpCode) -> (usize, usize) {
+    match code {
+        OpCode::Constant(_)
+        | OpCode::SmallInt(_)
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetGlobal(_) => (0, 1),
+        OpCode::SetGlobal(_) | OpCode::Not | OpCode::Negate => (1, 1),
+        OpCode::Equal
+        | OpCode::NotEqual
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide => (2, 1),
+        OpCode::DefineGlobal(_) | OpCode::Pop | OpCode::Print => (1, 0),
+        OpCode::Return => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lox::compiler::compile;
+    use crate::lox::interner::Interner;
+    use crate::lox::value::Value;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn chunk_of(codes: Vec<OpCode>) -> Chunk {
+        let mut chunk = Chunk::new();
+        for (line, code) in codes.into_iter().enumerate() {
+            chunk.write_chunk(code, line + 1).unwrap();
+        }
+        chunk
+    }
+
+    fn assert_rejects(chunk: &Chunk, index: usize, message: &str) {
+        let error = verify(chunk).expect_err("verified a malformed chunk");
+        assert_eq!((index, message), (error.index, error.message.as_str()));
+    }
+
+    #[test]
+    fn it_accepts_compiled_chunks() {
+        for source in [
+            "",
+            "print 1 + 2 * -3 >= 4;",
+            "var a = \"a\"; a = a + \"b\"; print a == nil;",
+            "1; true; !nil; var x; x = x;",
+        ] {
+            let interner = Rc::new(RefCell::new(Interner::default()));
+            let chunk = compile(source, interner).expect("compile failed");
+            assert_eq!(Ok(()), verify(&chunk), "failed with: {}", source);
+        }
+    }
+
+    #[test]
+    fn it_rejects_constants_out_of_range() {
+        let mut chunk = chunk_of(vec![OpCode::Constant(0), OpCode::Print, OpCode::Return]);
+        assert_rejects(&chunk, 0, "Constant 0 is out of range (0 constants).");
+
+        chunk.add_constant(Value::Nil);
+        assert_eq!(Ok(()), verify(&chunk));
+    }
+
+    #[test]
+    fn it_rejects_global_names_that_are_not_strings() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Value::Number(1.0));
+        chunk.write_chunk(OpCode::GetGlobal(constant), 1).unwrap();
+        chunk.write_chunk(OpCode::Print, 1).unwrap();
+        chunk.write_chunk(OpCode::Return, 1).unwrap();
+
+        assert_rejects(&chunk, 0, "Global name 0 is not a string constant.");
+    }
+
+    #[test]
+    fn it_rejects_stack_underflow() {
+        let chunk = chunk_of(vec![OpCode::Nil, OpCode::Add, OpCode::Return]);
+        assert_rejects(&chunk, 1, "Pops 2 value(s) but the stack holds 1.");
+
+        let chunk = chunk_of(vec![OpCode::Pop, OpCode::Return]);
+        let error = verify(&chunk).expect_err("verified a malformed chunk");
+        assert_eq!(
+            "[line 1] Invalid bytecode at 0000: Pops 1 value(s) but the stack holds 0.",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn it_rejects_stack_overflow() {
+        let mut codes = vec![OpCode::Nil; STACK_MAX];
+        codes.push(OpCode::Return);
+        assert_eq!(Ok(()), verify(&chunk_of(codes.clone())));
+
+        codes.insert(0, OpCode::True);
+        assert_rejects(
+            &chunk_of(codes),
+            STACK_MAX,
+            "Stack depth 257 is more than 256.",
+        );
+    }
+
+    #[test]
+    fn it_rejects_chunks_that_do_not_return() {
+        assert_rejects(&Chunk::new(), 0, "Code does not end with a return.");
+        assert_rejects(
+            &chunk_of(vec![OpCode::Nil, OpCode::Print]),
+            2,
+            "Code does not end with a return.",
+        );
+        assert_rejects(
+            &chunk_of(vec![OpCode::Return, OpCode::Nil]),
+            0,
+            "Code follows the return.",
+        );
+    }
+
+    #[test]
+    fn it_rejects_bytes_that_are_not_instructions() {
+        let mut chunk = chunk_of(vec![OpCode::Nil]);
+        chunk.code.push(200);
+        assert_rejects(&chunk, 1, "Unknown opcode 200.");
+
+        let mut chunk = chunk_of(vec![OpCode::Nil, OpCode::Constant(0x10000)]);
+        chunk.code.truncate(3);
+        assert_rejects(&chunk, 1, "Instruction is cut off by the end of the code.");
+    }
+}
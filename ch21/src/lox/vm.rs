@@ -1,41 +1,61 @@
-use crate::lox::chunk::{Chunk, Instruction, OpCode};
-use crate::lox::compiler::{compile, ParserError};
-use crate::lox::interner::Interner;
-use crate::lox::scanner::TokenType;
-use crate::lox::value::Value;
+use crate::lox::ast_compiler;
+use crate::lox::chunk::*;
+use crate::lox::compiler::{compile_with, CompileOptions, FrontEnd, ParserError};
+use crate::lox::debugger::Debugger;
+use crate::lox::fnv::FnvBuildHasher;
+use crate::lox::interner::{Interner, Symbol};
+use crate::lox::parser;
+use crate::lox::profile::Profiler;
+use crate::lox::trace::Tracer;
+use crate::lox::value::{Unpacked, Value, ADD_OPERANDS, NUMBER_OPERAND, NUMBER_OPERANDS};
+use crate::lox::verifier::verify;
 
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
 use std::fmt::Write;
+use std::io;
+use std::mem;
 use std::rc::Rc;
 
 #[derive(Debug, PartialEq)]
 pub enum InterpretError {
     CompileError(String),
     RuntimeError(String),
+    VerifyError(String),
 }
 
 pub type InterpretResult = Result<String, InterpretError>;
 
-const STACK_MAX: usize = 256;
+pub const STACK_MAX: usize = 256;
 
 #[derive(Debug)]
 pub struct VM {
     chunk: Chunk,
-    ip: usize,
+    // Whether chunk has passed the verifier, so running it again skips it.
+    verified: bool,
     stack: [Value; STACK_MAX],
     stack_top: usize,
     interner: Rc<RefCell<Interner>>,
+    globals: HashMap<Symbol, Value, FnvBuildHasher>,
+    options: CompileOptions,
+    front_end: FrontEnd,
+    tracer: Option<Tracer>,
+    // Why the trace of the last run couldn't be written, if it couldn't.
+    trace_error: Option<io::Error>,
+    profiler: Option<Profiler>,
+    debugger: Option<Box<dyn Debugger>>,
+    // What the last run printed, if it failed and didn't return it.
+    output: String,
 }
 
 macro_rules! unary_op{
     ($vm:expr,$op:tt) => {
         {
-            let a = $vm.pop().as_number();
-            if a.is_err() {
-                return Err(InterpretError::RuntimeError(String::from("Operand must be number.")))
-            }
-            $vm.push(Value::Number($op a.unwrap()));
+            let Ok(a) = $vm.pop().as_number() else {
+                return Err(InterpretError::RuntimeError(String::from(NUMBER_OPERAND)))
+            };
+            $vm.push(Value::Number($op a));
         }
     }
 }
@@ -44,155 +64,539 @@ macro_rules! binary_op{
         {
             let b = $vm.pop().as_number();
             let a = $vm.pop().as_number();
-            if a.is_err() || b.is_err() {
-                return Err(InterpretError::RuntimeError(String::from("Operands must be numbers.")));
-            }
-            $vm.push(Value::from(a.unwrap() $op b.unwrap()));
+            let (Ok(a), Ok(b)) = (a, b) else {
+                return Err(InterpretError::RuntimeError(String::from(NUMBER_OPERANDS)));
+            };
+            $vm.push(Value::from(a $op b));
+        }
+    }
+}
+// GreaterEqual and LessEqual are the negation of Less and Greater rather than
+// >= and <=, which keeps NaN comparing the way the two instruction sequence
+// they replaced did.
+macro_rules! negated_binary_op{
+    ($vm:expr,$op:tt) => {
+        {
+            let b = $vm.pop().as_number();
+            let a = $vm.pop().as_number();
+            let (Ok(a), Ok(b)) = (a, b) else {
+                return Err(InterpretError::RuntimeError(String::from(NUMBER_OPERANDS)));
+            };
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            let result = !(a $op b);
+            $vm.push(Value::from(result));
         }
     }
 }
 
 const DEFAULT_VALUE: Value = Value::Nil;
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
+        VM::with_options(CompileOptions::default())
+    }
+
+    pub fn with_options(options: CompileOptions) -> VM {
         VM {
             chunk: Chunk::new(),
-            ip: 0,
+            verified: false,
             stack: [DEFAULT_VALUE; STACK_MAX],
             stack_top: 0,
             interner: Rc::new(RefCell::new(Interner::default())),
+            globals: HashMap::default(),
+            options,
+            front_end: FrontEnd::default(),
+            tracer: None,
+            trace_error: None,
+            profiler: None,
+            debugger: None,
+            output: String::new(),
         }
     }
 
     fn reset_stack(&mut self) {
-        self.ip = 0;
         self.stack_top = 0;
     }
 
     pub fn interpret(&mut self, contents: &str) -> InterpretResult {
-        match compile(contents, self.interner.clone()) {
-            Ok(c) => {
-                self.chunk = c;
-                self.run()
-            }
-            Err(e) => {
-                let msg = VM::format_err(e).unwrap();
-                Err(InterpretError::CompileError(msg))
+        let chunk = self.compile(contents)?;
+        self.interpret_chunk(chunk)
+    }
+
+    // Compiles against this VM's interner, so the chunk can be run here.
+    pub fn compile(&self, contents: &str) -> Result<Chunk, InterpretError> {
+        let error = |e: &ParserError| parser::format_error(contents, e);
+        match self.front_end {
+            FrontEnd::SinglePass => compile_with(contents, self.interner.clone(), self.options)
+                .map_err(|e| InterpretError::CompileError(error(&e))),
+            FrontEnd::Ast => {
+                let statements = parser::parse(contents).map_err(|errors| {
+                    let messages: Vec<String> = errors.iter().map(error).collect();
+                    InterpretError::CompileError(messages.join("\n"))
+                })?;
+                ast_compiler::compile(contents, &statements, self.interner.clone(), self.options)
+                    .map_err(|e| InterpretError::CompileError(error(&e)))
             }
         }
     }
 
+    pub fn take_trace_error(&mut self) -> Option<io::Error> {
+        self.trace_error.take()
+    }
+
+    // Every compile after this goes through front_end.
+    pub fn set_front_end(&mut self, front_end: FrontEnd) {
+        self.front_end = front_end;
+    }
+
+    pub fn interner(&self) -> Rc<RefCell<Interner>> {
+        self.interner.clone()
+    }
+
+    // Every run after this writes a record per instruction to the tracer.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    // Every run after this is counted and timed by the profiler, until it is
+    // taken back for its report.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // The debugger is called before every instruction of every run after
+    // this, and can pause the run to look at the VM.
+    pub fn set_debugger(&mut self, debugger: Option<Box<dyn Debugger>>) {
+        self.debugger = debugger;
+    }
+
+    pub fn take_debugger(&mut self) -> Option<Box<dyn Debugger>> {
+        self.debugger.take()
+    }
+
+    // The values on the stack, bottom first.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack[..self.stack_top]
+    }
+
+    // The globals sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let interner = self.interner.borrow();
+        let mut globals: Vec<(String, Value)> = self
+            .globals
+            .iter()
+            .map(|(name, value)| {
+                (
+                    interner.lookup(*name).unwrap_or_default().to_string(),
+                    *value,
+                )
+            })
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    // Prints an expression against the globals, for a debugger to call while
+    // a run is paused. It runs on a VM of its own so the paused stack is left
+    // alone, but assignments it makes to globals are kept.
+    pub fn evaluate(&mut self, expression: &str) -> InterpretResult {
+        let mut scratch = VM::with_options(self.options);
+        scratch.front_end = self.front_end;
+        scratch.interner = self.interner.clone();
+        scratch.globals = mem::take(&mut self.globals);
+        let source = format!("print {};", expression.trim().trim_end_matches(';'));
+        let result = scratch.interpret(source.as_str());
+        self.globals = scratch.globals;
+        // The line of a runtime error is always the scratch source's first.
+        match result {
+            Ok(output) => Ok(output.trim_end_matches('\n').to_string()),
+            Err(InterpretError::RuntimeError(e)) => Err(InterpretError::RuntimeError(
+                e.lines().next().unwrap_or_default().to_string(),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> InterpretResult {
+        self.chunk = chunk;
+        self.verified = false;
+        self.run()
+    }
+
     pub fn run(&mut self) -> InterpretResult {
-        let mut output = String::new();
         self.reset_stack();
+        if !self.verified {
+            verify(&self.chunk).map_err(|e| InterpretError::VerifyError(e.to_string()))?;
+            self.verified = true;
+        }
+        // The chunk is moved out for the run so the loop can hold slices of
+        // its code and constants while it changes the stack.
+        let chunk = mem::take(&mut self.chunk);
         let debug = is_debug();
-        loop {
-            if debug {
-                print!("          ");
-                for slot in self.stack.iter().take(self.stack_top) {
-                    print!("[ ");
-                    print!("{}", slot);
-                    print!(" ]");
+        let hooked = self.tracer.is_some() || self.profiler.is_some() || self.debugger.is_some();
+        let mut ip = 0;
+        let mut output = String::new();
+        let result = if debug || hooked {
+            self.start_trace(&chunk);
+            let listing = debug.then(|| chunk.listing());
+            let result = self.execute::<true>(&chunk, listing.as_ref(), &mut ip, &mut output);
+            let result = self.finish_run(&chunk, ip, result, output);
+            self.finish_trace(&result);
+            self.finish_debugger(&result);
+            result
+        } else {
+            let result = self.execute::<false>(&chunk, None, &mut ip, &mut output);
+            self.finish_run(&chunk, ip, result, output)
+        };
+        self.chunk = chunk;
+        result
+    }
+
+    // What the last run printed before it failed. A run that doesn't fail
+    // returns its output instead.
+    pub fn take_output(&mut self) -> String {
+        mem::take(&mut self.output)
+    }
+
+    // A runtime error is reported with the line of the instruction it
+    // happened on, which ends just before ip, the way ch13 reports them.
+    fn finish_run(
+        &mut self,
+        chunk: &Chunk,
+        ip: usize,
+        result: Result<(), InterpretError>,
+        output: String,
+    ) -> InterpretResult {
+        match result {
+            Ok(()) => Ok(output),
+            Err(InterpretError::RuntimeError(message)) => {
+                self.output = output;
+                let line = chunk.line(ip.saturating_sub(1));
+                Err(InterpretError::RuntimeError(format!(
+                    "{}\n[line {}]",
+                    message, line
+                )))
+            }
+            Err(e) => {
+                self.output = output;
+                Err(e)
+            }
+        }
+    }
+
+    // Tracing is a const parameter so the normal loop has no check for it.
+    // Instructions are dispatched on their byte, with operands read in place.
+    // ip and output are the caller's, so that it has them when this fails.
+    fn execute<const TRACE: bool>(
+        &mut self,
+        chunk: &Chunk,
+        listing: Option<&Listing>,
+        ip: &mut usize,
+        output: &mut String,
+    ) -> Result<(), InterpretError> {
+        let code = chunk.code.as_slice();
+        let constants = chunk.constants();
+
+        // The verifier has already checked that every operand and constant
+        // is there. These checks only keep a bad chunk from panicking.
+        macro_rules! read_byte {
+            () => {{
+                *ip += 1;
+                match code.get(*ip - 1) {
+                    Some(byte) => *byte,
+                    None => {
+                        return Err(malformed(String::from("Code ends inside an instruction.")))
+                    }
                 }
-                println!("");
-                match self
-                    .chunk
-                    .disassemble_instruction(self.chunk.instructions.get(self.ip).unwrap(), self.ip)
-                {
-                    Ok(s) => print!("{}", s),
-                    Err(e) => {
-                        eprintln!("{:?}", e);
-                        return Err(InterpretError::RuntimeError("error".to_string()));
+            }};
+        }
+        macro_rules! read_index {
+            (short) => {
+                read_byte!() as usize
+            };
+            (long) => {
+                u32::from_le_bytes([read_byte!(), read_byte!(), read_byte!(), 0]) as usize
+            };
+        }
+        macro_rules! read_constant {
+            ($size:tt) => {{
+                let index = read_index!($size);
+                match constants.get(index) {
+                    Some(value) => *value,
+                    None => {
+                        return Err(malformed(format!(
+                            "Constant {} is out of range ({} constants).",
+                            index,
+                            constants.len()
+                        )))
                     }
                 }
+            }};
+        }
+        macro_rules! read_name {
+            ($size:tt) => {
+                read_constant!($size).as_string().map_err(malformed)?
+            };
+        }
+
+        loop {
+            if TRACE {
+                self.trace(chunk, *ip, listing, output)?;
             }
-            match self.read_byte().code {
-                OpCode::Constant(c) => {
-                    let constant = self.read_constant(c);
-                    self.push(constant.clone());
-                }
-                OpCode::True => self.push(Value::Bool(true)),
-                OpCode::False => self.push(Value::Bool(false)),
-                OpCode::DefineGlobal(_) => todo!(),
-                OpCode::Equal => {
+            match read_byte!() {
+                OP_CONSTANT => self.push(read_constant!(short)),
+                OP_CONSTANT_LONG => self.push(read_constant!(long)),
+                OP_SMALL_INT => self.push(Value::Number(read_byte!() as i8 as f64)),
+                OP_NIL => self.push(Value::Nil),
+                OP_TRUE => self.push(Value::Bool(true)),
+                OP_FALSE => self.push(Value::Bool(false)),
+                OP_GET_GLOBAL => self.get_global(read_name!(short))?,
+                OP_GET_GLOBAL_LONG => self.get_global(read_name!(long))?,
+                OP_DEFINE_GLOBAL => self.define_global(read_name!(short)),
+                OP_DEFINE_GLOBAL_LONG => self.define_global(read_name!(long)),
+                OP_SET_GLOBAL => self.set_global(read_name!(short))?,
+                OP_SET_GLOBAL_LONG => self.set_global(read_name!(long))?,
+                OP_EQUAL => {
                     let a = self.pop();
                     let b = self.pop();
                     self.push(Value::Bool(a == b));
                 }
-                OpCode::Greater => binary_op!(self, >),
-                OpCode::Less => binary_op!(self, <),
-                OpCode::Nil => self.push(Value::Nil),
-                OpCode::Add => {
+                OP_NOT_EQUAL => {
+                    let a = self.pop();
+                    let b = self.pop();
+                    self.push(Value::Bool(a != b));
+                }
+                OP_GREATER => binary_op!(self, >),
+                OP_GREATER_EQUAL => negated_binary_op!(self, <),
+                OP_LESS => binary_op!(self, <),
+                OP_LESS_EQUAL => negated_binary_op!(self, >),
+                OP_ADD => {
                     let b = self.pop();
                     let a = self.pop();
-                    if a.is_string() && b.is_string() {
-                        let s = self.concatinate(&a, &b);
+                    if let (Ok(a), Ok(b)) = (a.as_number(), b.as_number()) {
+                        self.push(Value::Number(a + b));
+                    } else if let (Ok(a), Ok(b)) = (a.as_string(), b.as_string()) {
+                        let s = self.concatinate(a, b)?;
                         self.push(s);
-                    } else if a.is_number() && b.is_number() {
-                        let n = a.as_number().unwrap() + b.as_number().unwrap();
-                        self.push(Value::Number(n));
                     } else {
-                        return Err(InterpretError::RuntimeError(String::from(
-                            "Operands must be numbers or strings.",
-                        )));
+                        return Err(InterpretError::RuntimeError(String::from(ADD_OPERANDS)));
                     }
                 }
-                OpCode::Subtract => binary_op!(self, -),
-                OpCode::Multiply => binary_op!(self, *),
-                OpCode::Divide => binary_op!(self, /),
-                OpCode::Negate => unary_op!(self, -),
-                OpCode::Not => {
+                OP_SUBTRACT => binary_op!(self, -),
+                OP_MULTIPLY => binary_op!(self, *),
+                OP_DIVIDE => binary_op!(self, /),
+                OP_NEGATE => unary_op!(self, -),
+                OP_NOT => {
                     let v = self.pop();
                     self.push(Value::Bool(v.is_falsey()));
                 }
-                OpCode::Pop => {
+                OP_POP => {
                     self.pop();
                 }
-                OpCode::Print => {
+                OP_PRINT => {
                     let v = self.pop();
-                    let o = match v {
-                        Value::String(s) => self.interner.borrow().lookup(s).to_string(),
+                    let o = match v.unpack() {
+                        Unpacked::String(s) => self.lookup(s)?,
                         other => format!("{}", other),
                     };
                     writeln!(output, "{}", o)
                         .map_err(|e| InterpretError::RuntimeError(e.to_string()))?;
                 }
-                OpCode::Return => {
-                    return Ok(output);
+                OP_RETURN => {
+                    return Ok(());
+                }
+                byte => {
+                    return Err(InterpretError::RuntimeError(format!(
+                        "Unknown opcode {}.",
+                        byte
+                    )))
                 }
             }
         }
     }
 
-    fn concatinate(&self, a: &Value, b: &Value) -> Value {
-        let s = self
-            .interner
-            .borrow()
-            .lookup(*a.as_string().unwrap())
-            .to_string()
-            + self.interner.borrow().lookup(*b.as_string().unwrap());
+    fn trace(
+        &mut self,
+        chunk: &Chunk,
+        ip: usize,
+        listing: Option<&Listing>,
+        output: &str,
+    ) -> Result<(), InterpretError> {
+        // The debugger is moved out while it runs, so it can be handed the VM.
+        if let Some(mut debugger) = self.debugger.take() {
+            let stepped = debugger.step(self, chunk, ip, output);
+            self.debugger = Some(debugger);
+            stepped.map_err(InterpretError::RuntimeError)?;
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.step(chunk, ip);
+        }
+        if self.tracer.is_some() {
+            self.trace_to_file(chunk, ip);
+        }
+        let Some(listing) = listing else {
+            return Ok(());
+        };
+        print!("          ");
+        for slot in self.stack.iter().take(self.stack_top) {
+            print!("[ ");
+            print!("{}", slot);
+            print!(" ]");
+        }
+        println!();
+        match chunk.disassemble_instruction(ip, listing) {
+            Ok(s) => print!("{}", s),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return Err(InterpretError::RuntimeError("error".to_string()));
+            }
+        }
+        Ok(())
+    }
 
-        Value::String(self.interner.borrow_mut().intern(s.as_str()))
+    // The record for the previous instruction is finished here, now that the
+    // stack it left is known, and the one for ip is started. The stack is
+    // only formatted when one of them is written.
+    fn trace_to_file(&mut self, chunk: &Chunk, ip: usize) {
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+        let code = chunk.decode(ip).map(|(code, _)| code);
+        let selected = code.filter(|code| tracer.select(*code, ip));
+        if selected.is_none() && !tracer.is_open() {
+            return;
+        }
+        let stack = self.trace_stack();
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+        tracer.end(&stack, None);
+        let Some(code) = selected else {
+            return;
+        };
+        let constant = match code {
+            OpCode::Constant(c)
+            | OpCode::GetGlobal(c)
+            | OpCode::DefineGlobal(c)
+            | OpCode::SetGlobal(c) => chunk
+                .constants()
+                .get(c)
+                .map(|value| format_value(value, Some(&self.interner.borrow()))),
+            _ => None,
+        };
+        tracer.begin(code, ip, constant, &stack);
     }
 
-    pub fn format_err(error: ParserError) -> Result<String, std::fmt::Error> {
-        let mut output = String::new();
-        if error.token.is_some() {
-            let token = error.token.clone().unwrap();
-            write!(output, "[line {}] Error", token.line)?;
-            match error.token.clone().unwrap().token_type {
-                TokenType::EOF => write!(output, " at end")?,
-                TokenType::Error(e) => write!(output, "{}", e)?,
-                _ => writeln!(output, " at '{:?}'", token)?,
+    fn start_trace(&mut self, chunk: &Chunk) {
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.start(chunk);
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.start(chunk);
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.start(chunk);
+        }
+    }
+
+    // Finishes the record for the instruction the run stopped at.
+    fn finish_trace(&mut self, result: &InterpretResult) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.finish();
+        }
+        let stack = match &self.tracer {
+            Some(tracer) if tracer.is_open() => self.trace_stack(),
+            _ => vec![],
+        };
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+        let error = match result {
+            Err(InterpretError::RuntimeError(e)) => Some(e.as_str()),
+            _ => None,
+        };
+        tracer.end(&stack, error);
+        self.trace_error = tracer.flush().err();
+    }
+
+    fn finish_debugger(&mut self, result: &InterpretResult) {
+        if let Some(mut debugger) = self.debugger.take() {
+            debugger.finish(self, result);
+            self.debugger = Some(debugger);
+        }
+    }
+
+    fn trace_stack(&self) -> Vec<String> {
+        let interner = self.interner.borrow();
+        self.stack[..self.stack_top]
+            .iter()
+            .map(|value| format_value(value, Some(&interner)))
+            .collect()
+    }
+
+    fn get_global(&mut self, name: Symbol) -> Result<(), InterpretError> {
+        match self.globals.get(&name) {
+            Some(value) => {
+                let value = *value;
+                self.push(value);
+                Ok(())
             }
+            None => Err(self.undefined_variable(name)),
+        }
+    }
 
-            writeln!(output, ": {}", error.message)?;
-        } else {
-            writeln!(output, "Error: {}", error.message)?;
+    fn define_global(&mut self, name: Symbol) {
+        let value = self.pop();
+        self.globals.insert(name, value);
+    }
+
+    fn set_global(&mut self, name: Symbol) -> Result<(), InterpretError> {
+        let value = *self.peek(0);
+        match self.globals.get_mut(&name) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(self.undefined_variable(name)),
+        }
+    }
+
+    fn concatinate(&self, a: Symbol, b: Symbol) -> Result<Value, InterpretError> {
+        let (a, b) = (self.lookup(a)?, self.lookup(b)?);
+        let mut s = String::with_capacity(a.len() + b.len());
+        s.push_str(&a);
+        s.push_str(&b);
+
+        Ok(Value::String(self.interner.borrow_mut().intern(s.as_str())))
+    }
+
+    // A chunk compiled against another VM's interner has strings this one
+    // doesn't know.
+    fn lookup(&self, s: Symbol) -> Result<String, InterpretError> {
+        match self.interner.borrow().lookup(s) {
+            Some(s) => Ok(s.to_string()),
+            None => Err(InterpretError::RuntimeError(format!(
+                "String {} is not interned.",
+                s
+            ))),
         }
+    }
 
-        Ok(output)
+    fn undefined_variable(&self, name: Symbol) -> InterpretError {
+        match self.lookup(name) {
+            Ok(name) => InterpretError::RuntimeError(format!("Undefined variable '{}'.", name)),
+            Err(e) => e,
+        }
     }
 
     fn push(&mut self, value: Value) {
@@ -202,18 +606,16 @@ impl VM {
 
     fn pop(&mut self) -> Value {
         self.stack_top -= 1;
-        self.stack[self.stack_top].clone()
+        self.stack[self.stack_top]
     }
 
-    fn read_byte(&mut self) -> &Instruction {
-        let byte = self.chunk.instructions.get(self.ip).unwrap();
-        self.ip += 1;
-        byte
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack_top - 1 - distance]
     }
+}
 
-    fn read_constant(&self, index: usize) -> &Value {
-        self.chunk.constants.get(index).unwrap()
-    }
+fn malformed(message: String) -> InterpretError {
+    InterpretError::VerifyError(format!("Invalid bytecode: {}", message))
 }
 
 fn is_debug() -> bool {
@@ -226,6 +628,7 @@ fn is_debug() -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lox::assembler::assemble;
 
     fn assert_interpret(source: &str, expected_value: &str) -> Rc<VM> {
         let statement = format!("print {};", source);
@@ -241,15 +644,21 @@ mod tests {
         Rc::new(vm)
     }
 
+    fn run_asm(text: &str) -> InterpretResult {
+        let mut vm = VM::new();
+        let chunk = assemble(text, &mut vm.interner.borrow_mut()).expect("assemble failed");
+        vm.interpret_chunk(chunk)
+    }
+
     #[test]
     fn it_negates() {
         let mut chunk = Chunk::new();
 
         let constant = chunk.add_constant(Value::Number(1.2));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
-        chunk.write_chunk(OpCode::Negate, 123);
-        chunk.write_chunk(OpCode::Print, 123);
-        chunk.write_chunk(OpCode::Return, 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
+        chunk.write_chunk(OpCode::Negate, 123).unwrap();
+        chunk.write_chunk(OpCode::Print, 123).unwrap();
+        chunk.write_chunk(OpCode::Return, 123).unwrap();
 
         let mut vm = VM::new();
         vm.chunk = chunk;
@@ -257,24 +666,36 @@ mod tests {
         assert_eq!("-1.2\n", vm.run().expect("failed"));
     }
 
+    #[test]
+    fn it_negates_in_assembly() {
+        let result = run_asm(
+            "0000  123 OP_CONSTANT         0 '1.2
+             0001    | OP_NEGATE
+             0002    | OP_PRINT
+             0003    | OP_RETURN",
+        );
+
+        assert_eq!("-1.2\n", result.expect("failed"));
+    }
+
     #[test]
     fn it_calculates() {
         let mut chunk = Chunk::new();
 
         let mut constant = chunk.add_constant(Value::Number(1.2));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
 
         constant = chunk.add_constant(Value::Number(3.4));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
 
-        chunk.write_chunk(OpCode::Add, 123);
+        chunk.write_chunk(OpCode::Add, 123).unwrap();
 
         constant = chunk.add_constant(Value::Number(5.6));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
 
-        chunk.write_chunk(OpCode::Divide, 123);
-        chunk.write_chunk(OpCode::Print, 123);
-        chunk.write_chunk(OpCode::Return, 123);
+        chunk.write_chunk(OpCode::Divide, 123).unwrap();
+        chunk.write_chunk(OpCode::Print, 123).unwrap();
+        chunk.write_chunk(OpCode::Return, 123).unwrap();
 
         let mut vm = VM::new();
         vm.chunk = chunk;
@@ -282,46 +703,146 @@ mod tests {
         assert_eq!("0.8214285714285714\n", vm.run().expect("failed"));
     }
 
+    #[test]
+    fn it_calculates_in_assembly() {
+        let result = run_asm(
+            "0000  123 OP_CONSTANT         0 '1.2
+             0001    | OP_CONSTANT         1 '3.4
+             0002    | OP_ADD
+             0003    | OP_CONSTANT         2 '5.6
+             0004    | OP_DIVIDE
+             0005    | OP_PRINT
+             0006    | OP_RETURN",
+        );
+
+        assert_eq!("0.8214285714285714\n", result.expect("failed"));
+    }
+
     #[test]
     fn it_cannot_add_non_numbers() {
         let mut chunk = Chunk::new();
 
         let mut constant = chunk.add_constant(Value::Number(1.2));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
 
         constant = chunk.add_constant(Value::Bool(false));
-        chunk.write_chunk(OpCode::Constant(constant), 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
 
-        chunk.write_chunk(OpCode::Add, 123);
+        chunk.write_chunk(OpCode::Add, 123).unwrap();
+        chunk.write_chunk(OpCode::Return, 123).unwrap();
 
         let mut vm = VM::new();
         vm.chunk = chunk;
 
         let expected = Err(InterpretError::RuntimeError(String::from(
-            "Operands must be numbers or strings.",
+            "Operands must be numbers or strings.\n[line 123]",
         )));
 
         assert_eq!(expected, vm.run());
     }
 
+    #[test]
+    fn it_cannot_add_non_numbers_in_assembly() {
+        let result = run_asm(
+            "0000  123 OP_CONSTANT         0 '1.2
+             0001    | OP_CONSTANT         1 'false
+             0002    | OP_ADD
+             0003    | OP_RETURN",
+        );
+
+        let expected = Err(InterpretError::RuntimeError(String::from(
+            "Operands must be numbers or strings.\n[line 123]",
+        )));
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn it_cannot_add_strings_to_numbers() {
+        let result = run_asm(
+            "0000  123 OP_CONSTANT         0 '\"a\"
+             0001    | OP_SMALL_INT        1
+             0002    | OP_ADD
+             0003    | OP_RETURN",
+        );
+
+        let expected = Err(InterpretError::RuntimeError(String::from(
+            "Operands must be numbers or strings.\n[line 123]",
+        )));
+
+        assert_eq!(expected, result);
+    }
+
     #[test]
     fn it_cannot_negate_non_numbers() {
         let mut chunk = Chunk::new();
 
         let constant = chunk.add_constant(Value::Nil);
-        chunk.write_chunk(OpCode::Constant(constant), 123);
-        chunk.write_chunk(OpCode::Negate, 123);
+        chunk.write_chunk(OpCode::Constant(constant), 123).unwrap();
+        chunk.write_chunk(OpCode::Negate, 123).unwrap();
+        chunk.write_chunk(OpCode::Return, 123).unwrap();
 
         let mut vm = VM::new();
         vm.chunk = chunk;
 
         let expected = Err(InterpretError::RuntimeError(String::from(
-            "Operand must be number.",
+            "Operand must be number.\n[line 123]",
         )));
 
         assert_eq!(expected, vm.run());
     }
 
+    #[test]
+    fn it_cannot_negate_non_numbers_in_assembly() {
+        let result = run_asm(
+            "0000  123 OP_CONSTANT         0 'nil
+             0001    | OP_NEGATE
+             0002    | OP_RETURN",
+        );
+
+        let expected = Err(InterpretError::RuntimeError(String::from(
+            "Operand must be number.\n[line 123]",
+        )));
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn it_verifies_chunks_before_running_them() {
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(OpCode::Constant(0), 7).unwrap();
+        chunk.write_chunk(OpCode::Pop, 7).unwrap();
+        chunk.write_chunk(OpCode::Pop, 8).unwrap();
+        chunk.write_chunk(OpCode::Return, 8).unwrap();
+
+        let mut vm = VM::new();
+        vm.chunk = chunk;
+
+        let expected = Err(InterpretError::VerifyError(String::from(
+            "[line 7] Invalid bytecode at 0000: Constant 0 is out of range (0 constants).",
+        )));
+        assert_eq!(expected, vm.run());
+
+        vm.chunk.add_constant(Value::Nil);
+        let expected = Err(InterpretError::VerifyError(String::from(
+            "[line 8] Invalid bytecode at 0002: Pops 1 value(s) but the stack holds 0.",
+        )));
+        assert_eq!(expected, vm.run());
+    }
+
+    #[test]
+    fn it_reports_strings_from_another_interner() {
+        let compile = || VM::new().compile("print \"a\" + x;").unwrap();
+        let expected = Err(InterpretError::RuntimeError(String::from(
+            "String 1 is not interned.\n[line 1]",
+        )));
+        assert_eq!(expected, VM::new().interpret_chunk(compile()));
+
+        let mut vm = VM::new();
+        vm.interner.borrow_mut().intern("only one");
+        assert_eq!(expected, vm.interpret_chunk(compile()));
+    }
+
     #[test]
     fn it_can_do_arthmetic() {
         assert_interpret("1 + 2", "3");
@@ -372,6 +893,12 @@ mod tests {
         assert_interpret("5 <= 5", "true");
     }
 
+    #[test]
+    fn it_compares_before_testing_equality() {
+        assert_interpret("1 == 2 < 3", "false");
+        assert_interpret("1 < 2 == true", "true");
+    }
+
     #[test]
     fn it_can_eval_logical_expressions() {
         assert_interpret("!(5 - 4 > 3 * 2 == !nil)", "true");
@@ -380,7 +907,7 @@ mod tests {
     #[test]
     fn it_can_eval_strings() {
         let vm = assert_interpret(r#""Hello, World""#, "Hello, World");
-        assert_eq!("Hello, World", vm.interner.borrow().lookup(0));
+        assert_eq!(Some("Hello, World"), vm.interner.borrow().lookup(0));
     }
 
     #[test]
@@ -394,10 +921,132 @@ mod tests {
     #[test]
     fn it_can_append_strings() {
         let vm = assert_interpret(r#""st" + "ri" + "ng""#, "string");
-        assert_eq!("st", vm.interner.borrow().lookup(0));
-        assert_eq!("ri", vm.interner.borrow().lookup(1));
-        assert_eq!("ng", vm.interner.borrow().lookup(2));
-        assert_eq!("stri", vm.interner.borrow().lookup(3));
-        assert_eq!("string", vm.interner.borrow().lookup(4));
+        assert_eq!(Some("st"), vm.interner.borrow().lookup(0));
+        assert_eq!(Some("ri"), vm.interner.borrow().lookup(1));
+        assert_eq!(Some("stri"), vm.interner.borrow().lookup(2));
+        assert_eq!(Some("ng"), vm.interner.borrow().lookup(3));
+        assert_eq!(Some("string"), vm.interner.borrow().lookup(4));
+    }
+
+    fn run_with(source: &str, options: CompileOptions) -> InterpretResult {
+        let mut vm = VM::new();
+        vm.chunk = compile_with(source, vm.interner.clone(), options).expect("compile failed");
+        vm.run()
+    }
+
+    fn assert_folds_same(source: &str) {
+        let _ = folded_result(source);
+    }
+
+    fn assert_folds_to(source: &str, expected: InterpretResult) {
+        assert_eq!(expected, folded_result(source), "failed with: {}", source);
+    }
+
+    fn folded_result(source: &str) -> InterpretResult {
+        let statement = format!("print {};", source);
+        let unfolded = run_with(statement.as_str(), CompileOptions::O0);
+        let folded = run_with(statement.as_str(), CompileOptions::O1);
+        assert_eq!(unfolded, folded, "folding changed: {}", source);
+        folded
+    }
+
+    #[test]
+    fn it_folds_arithmetic_like_it_runs() {
+        assert_folds_to("1 + 2 * 3", Ok(String::from("7\n")));
+        assert_folds_same("(-1 + 2) * 3 - -4");
+        assert_folds_same("1.2 / 5.6 + 3.4");
+        assert_folds_same("!(5 - 4 > 3 * 2 == !nil)");
+    }
+
+    #[test]
+    fn it_folds_special_numbers_like_it_runs() {
+        assert_folds_to("-0", Ok(String::from("-0\n")));
+        assert_folds_to("1 / 0", Ok(String::from("inf\n")));
+        assert_folds_to("1 / -0", Ok(String::from("-inf\n")));
+        assert_folds_to("0 / 0", Ok(String::from("NaN\n")));
+        assert_folds_same("0 * -1");
+        assert_folds_same("(0 / 0) == (0 / 0)");
+        assert_folds_same("(0 / 0) != (0 / 0)");
+        assert_folds_same("(0 / 0) < 1");
+        assert_folds_same("(0 / 0) > 1");
+        assert_folds_to("(0 / 0) >= 1", Ok(String::from("true\n")));
+        assert_folds_to("(0 / 0) <= 1", Ok(String::from("true\n")));
+    }
+
+    #[test]
+    fn it_folds_strings_like_it_runs() {
+        assert_folds_same(r#""st" + "ri" + "ng""#);
+        assert_folds_same(r#""a" == "a""#);
+        assert_folds_same(r#"!"str""#);
+    }
+
+    #[test]
+    fn it_folds_type_errors_like_it_runs() {
+        let expected = Err(InterpretError::RuntimeError(String::from(
+            "Operand must be number.\n[line 1]",
+        )));
+        assert_folds_to(r#"-"str""#, expected);
+        assert_folds_same("1 + nil");
+        assert_folds_same(r#""a" * 2"#);
+        assert_folds_same("nil > 1");
+        assert_folds_same(r#"(1 + 2) - "three""#);
+    }
+
+    #[test]
+    fn it_can_use_globals() {
+        let mut vm = VM::new();
+        let result = vm.interpret("var i = 0; i = i + 1; i = i * 10; print i;");
+
+        assert_eq!(Ok(String::from("10\n")), result);
+    }
+
+    #[test]
+    fn it_cannot_use_undefined_globals() {
+        let expected = Err(InterpretError::RuntimeError(String::from(
+            "Undefined variable 'nope'.\n[line 1]",
+        )));
+
+        assert_eq!(expected, VM::new().interpret("print nope;"));
+        assert_eq!(expected, VM::new().interpret("nope = 1;"));
+    }
+
+    #[test]
+    fn it_compiles_through_the_ast_when_asked() {
+        let mut vm = VM::new();
+        vm.set_front_end(FrontEnd::Ast);
+        assert_eq!(
+            Ok(String::from("3\nfalse\n")),
+            vm.interpret("var a = 1; a = a + 2; print a;\nprint 1 == 2 < 3;")
+        );
+        assert_eq!(
+            Err(InterpretError::CompileError(String::from(
+                "[line 2] Error: Can't compile blocks yet."
+            ))),
+            vm.interpret("print 1;\n{ print 2; }")
+        );
+        assert_eq!(
+            Err(InterpretError::CompileError(String::from(
+                "[line 1] Error at ';': Expect expression.\n[line 2] Error at end: Expect ';' after value."
+            ))),
+            vm.interpret("print 1 +;\nprint 2")
+        );
+    }
+
+    #[test]
+    fn it_can_compare_with_combined_opcodes() {
+        assert_interpret("5 != 4", "true");
+        assert_interpret("4 >= 5", "false");
+        assert_interpret("5 <= 4", "false");
+        assert_interpret("(0 / 0) >= 1", "true");
+        assert_interpret("(0 / 0) <= 1", "true");
+    }
+
+    #[test]
+    fn it_can_run_more_than_65536_distinct_constants() {
+        let source: String = (0..70_000).map(|n| format!("print {}.5;\n", n)).collect();
+        let output = VM::new().interpret(source.as_str()).expect("failed");
+
+        assert_eq!(Some("69999.5"), output.lines().last());
+        assert_eq!(70_000, output.lines().count());
     }
 }
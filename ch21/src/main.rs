@@ -1,27 +1,49 @@
-use crate::lox::vm::{InterpretError, VM};
+#![deny(clippy::unwrap_used, clippy::expect_used)]
+
+use lox::lox::assembler;
+use lox::lox::ast_printer;
+use lox::lox::bytecode;
+use lox::lox::chunk::Chunk;
+use lox::lox::compiler::{CompileOptions, FrontEnd};
+use lox::lox::conformance;
+use lox::lox::dap;
+use lox::lox::debugger::Console;
+use lox::lox::evaluator::Evaluator;
+use lox::lox::formatter::{self, FileError};
+use lox::lox::fuzz::{self, Random, Runner, Target};
+use lox::lox::highlight;
+use lox::lox::lsp;
+use lox::lox::parser;
+use lox::lox::profile::Profiler;
+use lox::lox::resolver;
+use lox::lox::trace::{TraceFilter, Tracer};
+use lox::lox::vm::{InterpretError, InterpretResult, VM};
 
 use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::path::Path;
 use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub mod lox;
-
-fn repl(vm: &mut VM) {
+fn repl(interpret: &mut dyn FnMut(&str) -> InterpretResult) {
     let mut buffer;
     let stdin = io::stdin();
     loop {
         buffer = String::new();
         print!("> ");
-        io::stdout().flush().unwrap();
+        if io::stdout().flush().is_err() {
+            break;
+        }
         match stdin.read_line(&mut buffer) {
             Ok(0) => {
-                println!("");
+                println!();
                 break;
             }
-            Ok(_) => match vm.interpret(buffer.as_str()) {
-                Err(InterpretError::CompileError(s)) => eprint!("Compile Error: {}", s),
-                Err(InterpretError::RuntimeError(s)) => eprint!("Runtime Error: {}", s),
+            Ok(_) => match interpret(buffer.as_str()) {
+                Err(InterpretError::CompileError(s)) => eprintln!("Compile Error: {}", s),
+                Err(InterpretError::RuntimeError(s)) => eprintln!("Runtime Error: {}", s),
+                Err(InterpretError::VerifyError(s)) => eprintln!("{}", s),
                 Ok(v) => print!("{}", v),
             },
             Err(e) => {
@@ -32,18 +54,92 @@ fn repl(vm: &mut VM) {
     }
 }
 
-fn run_file(vm: &mut VM, path: &str) {
-    let contents: String;
-    match fs::read_to_string(path) {
-        Ok(s) => contents = s,
+// A run that fails still shows what it printed before it failed.
+fn print_failed(vm: &mut VM, result: InterpretResult) -> InterpretResult {
+    if result.is_err() {
+        print!("{}", vm.take_output());
+    }
+    result
+}
+
+fn run_file(vm: &mut VM, path: &str) -> InterpretResult {
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
         Err(e) => {
             eprintln!("Could not open file: {e}");
             process::exit(64);
         }
+    };
+
+    if bytecode::is_bytecode(&bytes) {
+        let loaded = bytecode::load(&bytes, &mut vm.interner().borrow_mut());
+        match loaded {
+            Ok(chunk) => {
+                let result = vm.interpret_chunk(chunk);
+                print_failed(vm, result)
+            }
+            Err(e) => {
+                eprintln!("Could not load {path}: {e}");
+                process::exit(65);
+            }
+        }
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(contents) => {
+                let result = vm.interpret(contents.as_str());
+                print_failed(vm, result)
+            }
+            Err(e) => {
+                eprintln!("Could not open file: {e}");
+                process::exit(64);
+            }
+        }
+    }
+}
+
+// Runs a source file on the tree-walking evaluator instead of the VM.
+fn evaluate_file(path: &str) -> InterpretResult {
+    let contents = read_source(path);
+    let mut evaluator = Evaluator::new();
+    let result = evaluator.interpret(contents.as_str());
+    if result.is_err() {
+        print!("{}", evaluator.take_output());
+    }
+    result
+}
+
+fn report_trace_error(vm: &mut VM) {
+    if let Some(e) = vm.take_trace_error() {
+        eprintln!("Could not write the trace: {e}");
+    }
+}
+
+// Written before the result is reported, since an error exits.
+fn report_profile(vm: &mut VM, args: &Args) {
+    let Some(profiler) = vm.take_profiler() else {
+        return;
+    };
+    if args.profile {
+        match profiler.report() {
+            Ok(report) => eprint!("{}", report),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+    if let Some(path) = &args.profile_folded {
+        let written = profiler
+            .folded()
+            .map_err(|e| e.to_string())
+            .and_then(|folded| fs::write(path, folded).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("Could not write file: {e}");
+            process::exit(74);
+        }
     }
+}
 
-    match vm.interpret(contents.as_str()) {
-        Err(InterpretError::CompileError(s)) => {
+fn report(result: InterpretResult) {
+    match result {
+        Err(InterpretError::CompileError(s)) | Err(InterpretError::VerifyError(s)) => {
             eprintln!("{}", s);
             process::exit(65);
         }
@@ -55,16 +151,584 @@ fn run_file(vm: &mut VM, path: &str) {
     }
 }
 
-fn main() {
-    let mut vm = VM::new();
+fn read_source(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Could not open file: {e}");
+            process::exit(64);
+        }
+    }
+}
 
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&mut vm, &args[1]),
-        _ => {
-            eprintln!("Usage: lox [path]");
+fn write_bytecode(vm: &VM, chunk: &Chunk, output: &str) {
+    let bytes = match bytecode::save(chunk, &vm.interner().borrow()) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Could not save {output}: {e}");
+            process::exit(65);
+        }
+    };
+    if let Err(e) = fs::write(output, bytes) {
+        eprintln!("Could not write file: {e}");
+        process::exit(74);
+    }
+}
+
+fn compile_file(vm: &VM, path: &str, output: &str) {
+    let contents = read_source(path);
+    let chunk = match vm.compile(contents.as_str()) {
+        Ok(c) => c,
+        Err(InterpretError::CompileError(s))
+        | Err(InterpretError::RuntimeError(s))
+        | Err(InterpretError::VerifyError(s)) => {
+            eprintln!("{}", s);
+            process::exit(65);
+        }
+    };
+    write_bytecode(vm, &chunk, output);
+}
+
+fn disassemble_file(vm: &mut VM, path: &str) {
+    let bytes = match fs::read(path) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Could not open file: {e}");
             process::exit(64);
         }
+    };
+
+    let listing = if bytecode::is_bytecode(&bytes) {
+        let loaded = bytecode::load(&bytes, &mut vm.interner().borrow_mut());
+        match loaded {
+            Ok(chunk) => chunk.disassemble_with(path, Some(&vm.interner().borrow())),
+            Err(e) => {
+                eprintln!("Could not load {path}: {e}");
+                process::exit(65);
+            }
+        }
+    } else {
+        let contents = match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Could not open file: {e}");
+                process::exit(64);
+            }
+        };
+        match vm.compile(contents.as_str()) {
+            Ok(chunk) => chunk.disassemble_source(path, &contents, &vm.interner().borrow()),
+            Err(InterpretError::CompileError(s))
+            | Err(InterpretError::RuntimeError(s))
+            | Err(InterpretError::VerifyError(s)) => {
+                eprintln!("{}", s);
+                process::exit(65);
+            }
+        }
+    };
+
+    match listing {
+        Ok(listing) => print!("{}", listing),
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(70);
+        }
+    }
+}
+
+fn assemble_file(vm: &mut VM, path: &str, output: Option<&str>) {
+    let contents = read_source(path);
+    let assembled = assembler::assemble(contents.as_str(), &mut vm.interner().borrow_mut());
+    let chunk = match assembled {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(65);
+        }
+    };
+
+    match output {
+        Some(output) => write_bytecode(vm, &chunk, output),
+        None => {
+            let result = vm.interpret_chunk(chunk);
+            report(print_failed(vm, result));
+        }
+    }
+}
+
+fn debug_file(args: &Args, path: &str) {
+    let contents = read_source(path);
+    let mut vm = args.vm();
+    let input = Box::new(io::stdin().lock());
+    let console = Console::new(contents.as_str(), input, Box::new(io::stdout()));
+    vm.set_debugger(Some(Box::new(console)));
+    // The console has already written the program's output.
+    if let Err(e) = vm.interpret(contents.as_str()) {
+        report(Err(e));
+    }
+}
+
+// Rewrites each file formatted, or with check, only says which ones would
+// change and fails if any would.
+fn format_files(paths: &[String], check: bool) {
+    let mut unformatted = false;
+    let mut failed = false;
+    for path in paths {
+        match formatter::format_file(Path::new(path), check) {
+            Ok(true) if check => {
+                println!("Would reformat {path}");
+                unformatted = true;
+            }
+            Ok(_) => {}
+            Err(FileError::Source(e)) => {
+                eprintln!("Could not format {path}:\n{e}");
+                failed = true;
+            }
+            Err(FileError::Read(e)) => {
+                eprintln!("Could not open file: {e}");
+                process::exit(64);
+            }
+            Err(FileError::Write(e)) => {
+                eprintln!("Could not write file: {e}");
+                process::exit(74);
+            }
+        }
+    }
+    if failed {
+        process::exit(65);
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+fn highlight_file(path: &str, format: Option<&str>) {
+    let contents = read_source(path);
+    match format {
+        None | Some("ansi") => print!("{}", highlight::ansi(contents.as_str())),
+        Some("html") => match highlight::html_page(path, contents.as_str()) {
+            Ok(page) => print!("{}", page),
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(70);
+            }
+        },
+        Some(_) => usage(),
+    }
+}
+
+// Parses a file without running it, for seeing how code is read.
+fn parse_file(path: &str, ast: bool) {
+    let contents = read_source(path);
+    match parser::parse(contents.as_str()) {
+        Ok(statements) => {
+            if ast && !statements.is_empty() {
+                println!("{}", ast_printer::print(&statements));
+            }
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", parser::format_error(contents.as_str(), &error));
+            }
+            process::exit(65);
+        }
+    }
+}
+
+// Reports everything the resolver finds wrong with a file, without running it.
+fn check_file(path: &str) {
+    let contents = read_source(path);
+    if let Err(errors) = resolver::check(contents.as_str()) {
+        for error in errors {
+            eprintln!("{}", parser::format_error(contents.as_str(), &error));
+        }
+        process::exit(65);
+    }
+}
+
+// Runs every annotated .lox file under dir and exits 1 if any failed.
+fn test_dir(dir: &str) {
+    match conformance::run(Path::new(dir), &mut io::stdout()) {
+        Ok(summary) if summary.failed.is_empty() => {}
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("Could not run tests: {e}");
+            process::exit(74);
+        }
+    }
+}
+
+// Fuzzes each target, or the one asked for, and keeps what crashed in dir.
+// The seed is printed so that a run can be repeated.
+fn fuzz_into(args: &Args, dir: &str) {
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(1)
+    });
+    let iterations = args.iterations.unwrap_or(10000);
+    let targets = match args.target {
+        Some(target) => vec![target],
+        None => Target::ALL.to_vec(),
+    };
+    let runner = match env::current_exe() {
+        Ok(program) => Runner::Child(program),
+        Err(e) => {
+            eprintln!("Could not find lox to run inputs with: {e}");
+            process::exit(74);
+        }
+    };
+    println!("Fuzzing with --seed={seed}");
+
+    let mut random = Random::new(seed);
+    let mut crashed = false;
+    for target in targets {
+        let crashes = match fuzz::fuzz(target, &mut random, iterations, &runner) {
+            Ok(crashes) => crashes,
+            Err(e) => {
+                eprintln!("Could not run an input: {e}");
+                process::exit(74);
+            }
+        };
+        println!("{target}: {iterations} inputs, {} crashes", crashes.len());
+        for crash in crashes {
+            match fuzz::save(Path::new(dir), &crash) {
+                Ok(path) => println!("    {}\n        {}", crash.message, path.display()),
+                Err(e) => {
+                    eprintln!("Could not write file: {e}");
+                    process::exit(74);
+                }
+            }
+            crashed = true;
+        }
+    }
+    if crashed {
+        process::exit(1);
+    }
+}
+
+// Runs the input on stdin through target, which is how lox fuzz runs each
+// of its inputs, and exits with the panic on stderr if there was one.
+fn fuzz_input(target: &str) {
+    let target = Target::parse(target).unwrap_or_else(|| usage());
+    let mut input = vec![];
+    if let Err(e) = io::stdin().read_to_end(&mut input) {
+        eprintln!("Could not read input: {e}");
+        process::exit(74);
+    }
+    if let Err(message) = fuzz::run(target, &input) {
+        eprintln!("{message}");
+        process::exit(fuzz::PANICKED);
+    }
+}
+
+fn serve_dap() {
+    let input = Box::new(io::stdin().lock());
+    let mut server = dap::Server::new(input, Box::new(io::stdout()));
+    if let Err(e) = server.serve() {
+        eprintln!("{e}");
+        process::exit(74);
+    }
+}
+
+fn serve_lsp() {
+    let input = Box::new(io::stdin().lock());
+    let mut server = lsp::Server::new(input, Box::new(io::stdout()));
+    if let Err(e) = server.serve() {
+        eprintln!("{e}");
+        process::exit(74);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: lox [compile options] [--backend=vm|tree] [trace options] [path]");
+    eprintln!("       lox compile [compile options] <path> -o <out.loxc>");
+    eprintln!(
+        "       lox run [compile options] [--backend=vm|tree] [trace options] [profile options] <path>"
+    );
+    eprintln!("       lox asm <path> [-o <out.loxc>]");
+    eprintln!("       lox disasm [compile options] <path>");
+    eprintln!("       lox debug [compile options] <path>");
+    eprintln!("       lox fmt [--check] <path>...");
+    eprintln!("       lox highlight [--format=ansi|html] <path>");
+    eprintln!("       lox parse [--ast] <path>");
+    eprintln!("       lox check <path>");
+    eprintln!("       lox test <dir>");
+    eprintln!(
+        "       lox fuzz [--target=scanner|compiler|vm|chunk|tree] [--iterations=<n>] [--seed=<n>] <dir>"
+    );
+    eprintln!("       lox fuzz-input <target>");
+    eprintln!("       lox dap");
+    eprintln!("       lox lsp [--stdio]");
+    eprintln!();
+    eprintln!("Compile options:");
+    eprintln!("  -O0, -O1                   don't optimize, or fold constants and optimize");
+    eprintln!("  --frontend=single-pass|ast compile from tokens, or through the AST parser");
+    eprintln!();
+    eprintln!("Trace options:");
+    eprintln!("  --trace=<file>        write a JSON line per executed instruction");
+    eprintln!("  --trace-lines=<a-b>   only trace instructions on lines a to b");
+    eprintln!("  --trace-ops=<ops>     only trace these opcodes, e.g. OP_ADD,OP_PRINT");
+    eprintln!("  --trace-sample=<n>    write every nth traced instruction");
+    eprintln!();
+    eprintln!("Profile options:");
+    eprintln!("  --profile               print instruction counts and times per line");
+    eprintln!("  --profile-folded=<file> write folded stacks for flame graph tools");
+    process::exit(64);
+}
+
+struct Args {
+    options: CompileOptions,
+    front_end: FrontEnd,
+    output: Option<String>,
+    paths: Vec<String>,
+    trace: Option<String>,
+    trace_filter: TraceFilter,
+    profile: bool,
+    profile_folded: Option<String>,
+    check: bool,
+    format: Option<String>,
+    ast: bool,
+    tree: bool,
+    target: Option<Target>,
+    iterations: Option<usize>,
+    seed: Option<u64>,
+}
+
+impl Args {
+    // A VM that compiles as the arguments say.
+    fn compiler(&self) -> VM {
+        let mut vm = VM::with_options(self.options);
+        vm.set_front_end(self.front_end);
+        vm
+    }
+
+    // One that also traces and profiles runs as they say.
+    fn vm(&self) -> VM {
+        let mut vm = self.compiler();
+        if let Some(path) = &self.trace {
+            match Tracer::to_file(path, self.trace_filter.clone()) {
+                Ok(tracer) => vm.set_tracer(Some(tracer)),
+                Err(e) => {
+                    eprintln!("Could not write file: {e}");
+                    process::exit(74);
+                }
+            }
+        }
+        if self.profile || self.profile_folded.is_some() {
+            vm.set_profiler(Some(Profiler::new()));
+        }
+        vm
+    }
+}
+
+fn parse_args(args: &[String]) -> Args {
+    let mut parsed = Args {
+        options: CompileOptions::default(),
+        front_end: FrontEnd::default(),
+        output: None,
+        paths: vec![],
+        trace: None,
+        trace_filter: TraceFilter::default(),
+        profile: false,
+        profile_folded: None,
+        check: false,
+        format: None,
+        ast: false,
+        tree: false,
+        target: None,
+        iterations: None,
+        seed: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            parsed.output = Some(args.next().unwrap_or_else(|| usage()).clone());
+        } else if arg.starts_with("-O") {
+            parsed.options = CompileOptions::from_flag(arg.as_str()).unwrap_or_else(|| usage());
+        } else if let Some(name) = arg.strip_prefix("--frontend=") {
+            parsed.front_end = FrontEnd::from_name(name).unwrap_or_else(|| usage());
+        } else if let Some(path) = arg.strip_prefix("--trace=") {
+            parsed.trace = Some(path.to_string());
+        } else if let Some(lines) = arg.strip_prefix("--trace-lines=") {
+            parsed.trace_filter = parsed
+                .trace_filter
+                .with_lines(lines)
+                .unwrap_or_else(|| usage());
+        } else if let Some(ops) = arg.strip_prefix("--trace-ops=") {
+            parsed.trace_filter = parsed
+                .trace_filter
+                .with_opcodes(ops)
+                .unwrap_or_else(|| usage());
+        } else if let Some(n) = arg.strip_prefix("--trace-sample=") {
+            parsed.trace_filter = parsed
+                .trace_filter
+                .with_sample(n)
+                .unwrap_or_else(|| usage());
+        } else if arg == "--profile" {
+            parsed.profile = true;
+        } else if let Some(path) = arg.strip_prefix("--profile-folded=") {
+            parsed.profile_folded = Some(path.to_string());
+        } else if let Some(format) = arg.strip_prefix("--format=") {
+            parsed.format = Some(format.to_string());
+        } else if arg == "--check" {
+            parsed.check = true;
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            parsed.target = Some(Target::parse(target).unwrap_or_else(|| usage()));
+        } else if let Some(n) = arg.strip_prefix("--iterations=") {
+            parsed.iterations = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(n) = arg.strip_prefix("--seed=") {
+            parsed.seed = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if arg == "--ast" {
+            parsed.ast = true;
+        } else if let Some(backend) = arg.strip_prefix("--backend=") {
+            parsed.tree = match backend {
+                "vm" => false,
+                "tree" => true,
+                _ => usage(),
+            };
+        } else if arg.starts_with('-') {
+            usage();
+        } else {
+            parsed.paths.push(arg.clone());
+        }
+    }
+    // Tracing and profiling follow bytecode, which the tree backend has none of.
+    let instrumented = parsed.trace.is_some()
+        || parsed.trace_filter != TraceFilter::default()
+        || parsed.profile
+        || parsed.profile_folded.is_some();
+    if parsed.tree && instrumented {
+        usage();
+    }
+    parsed
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("compile") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], Some(output)) => compile_file(&args.compiler(), path, output),
+                _ => usage(),
+            }
+        }
+        Some("asm") => {
+            let args = parse_args(&args[1..]);
+            match &args.paths[..] {
+                [path] => assemble_file(&mut VM::new(), path, args.output.as_deref()),
+                _ => usage(),
+            }
+        }
+        Some("disasm") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], None) => disassemble_file(&mut args.compiler(), path),
+                _ => usage(),
+            }
+        }
+        Some("debug") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], None) => debug_file(&args, path),
+                _ => usage(),
+            }
+        }
+        Some("fmt") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([], _) | (_, Some(_)) => usage(),
+                (paths, None) => format_files(paths, args.check),
+            }
+        }
+        Some("highlight") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], None) => highlight_file(path, args.format.as_deref()),
+                _ => usage(),
+            }
+        }
+        Some("parse") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], None) => parse_file(path, args.ast),
+                _ => usage(),
+            }
+        }
+        Some("check") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], None) => check_file(path),
+                _ => usage(),
+            }
+        }
+        Some("test") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([dir], None) => test_dir(dir),
+                _ => usage(),
+            }
+        }
+        Some("fuzz") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([dir], None) => fuzz_into(&args, dir),
+                _ => usage(),
+            }
+        }
+        Some("fuzz-input") if args.len() == 2 => fuzz_input(&args[1]),
+        Some("dap") if args.len() == 1 => serve_dap(),
+        // Editors ask for --stdio, which is the only transport there is.
+        Some("lsp") if args[1..].iter().all(|arg| arg == "--stdio") => serve_lsp(),
+        Some("run") => {
+            let args = parse_args(&args[1..]);
+            match (&args.paths[..], &args.output) {
+                ([path], None) if args.tree => report(evaluate_file(path)),
+                ([path], None) => {
+                    let mut vm = args.vm();
+                    let result = run_file(&mut vm, path);
+                    report_trace_error(&mut vm);
+                    report_profile(&mut vm, &args);
+                    report(result);
+                }
+                _ => usage(),
+            }
+        }
+        _ => {
+            let args = parse_args(&args);
+            if args.tree {
+                match (&args.paths[..], &args.output) {
+                    ([], None) => {
+                        let mut evaluator = Evaluator::new();
+                        repl(&mut |source| {
+                            let result = evaluator.interpret(source);
+                            if result.is_err() {
+                                print!("{}", evaluator.take_output());
+                            }
+                            result
+                        });
+                    }
+                    ([path], None) => report(evaluate_file(path)),
+                    _ => usage(),
+                }
+                return;
+            }
+            let mut vm = args.vm();
+            match (&args.paths[..], &args.output) {
+                ([], None) => repl(&mut |source| {
+                    let result = vm.interpret(source);
+                    report_trace_error(&mut vm);
+                    print_failed(&mut vm, result)
+                }),
+                ([path], None) => {
+                    let result = run_file(&mut vm, path);
+                    report_trace_error(&mut vm);
+                    report_profile(&mut vm, &args);
+                    report(result);
+                }
+                _ => usage(),
+            }
+        }
     }
 }